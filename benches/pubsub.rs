@@ -0,0 +1,41 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+
+use kv::{Broadcaster, CommandResponse, Topic, Value};
+
+const SUBSCRIBERS: usize = 50_000;
+
+fn publish_to_many_subscribers(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let b = Arc::new(Broadcaster::default());
+    let topic = "lobby".to_string();
+
+    let mut receivers: Vec<_> = rt.block_on(async {
+        (0..SUBSCRIBERS).map(|_| b.clone().subscribe(topic.clone())).collect()
+    });
+
+    let value: Value = "hello".into();
+    let data: Arc<CommandResponse> = Arc::new(value.into());
+    c.bench_function("publish to 50k subscribers", |bench| {
+        bench.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let start = Instant::now();
+                rt.block_on(b.clone().publish_wait(topic.clone(), data.clone()));
+                elapsed += start.elapsed();
+
+                // drain the channels outside of the measurement, so the publisher never waits
+                for receiver in receivers.iter_mut() {
+                    while receiver.try_recv().is_ok() {}
+                }
+            }
+            elapsed
+        })
+    });
+}
+
+criterion_group!(benches, publish_to_many_subscribers);
+criterion_main!(benches);
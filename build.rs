@@ -1,6 +1,9 @@
 fn main() {
     let mut config = prost_build::Config::new();
     config.bytes(["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    // prost already derives PartialOrd for enums, so only add it to the messages we need to sort
+    for path in [".abi.Value", ".abi.ValueList", ".abi.ValueSet", ".abi.ValueMap", ".abi.KvPair"] {
+        config.type_attribute(path, "#[derive(PartialOrd)]");
+    }
     config.out_dir("src/pb").compile_protos(&["abi.proto"], &["."]).unwrap();
-}
\ No newline at end of file
+}
@@ -29,6 +29,10 @@ async fn main() -> Result<()> {
     let stream = ctrl.open_stream().await?;
     let mut client = ProstClientStream::new(stream);
 
+    // check the server version and features first
+    let response = client.execute_unary(&CommandRequest::new_hello()).await?;
+    info!("Connected to server {:?}", response.values);
+
     // send HSET, wait for response
     let request = CommandRequest::new_hset("table", "key", "value".into());
 
@@ -38,12 +42,18 @@ async fn main() -> Result<()> {
     // generate a Subscribe command
     let request = CommandRequest::new_subscribe(channel);
     let mut stream = client.execute_streaming(&request).await?;
-    let id = stream.id;
-    start_unsubscribe(ctrl.open_stream().await?, channel, id)?;
 
-    while let Some(Ok(data)) = stream.next().await {
-        println!("Got published data: {:?}", data);
+    // receive the published data for a while, then unsubscribe
+    let deadline = time::sleep(Duration::from_millis(2000));
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            Some(Ok(data)) = stream.next() => println!("Got published data: {:?}", data),
+            _ = &mut deadline => break,
+        }
     }
+    stream.cancel().await?;
+    println!("Finished unsubscribing");
 
     Ok(())
 }
@@ -59,15 +69,3 @@ fn start_publishing(stream: Compat<yamux::Stream>, name: &str) -> Result<(), KvE
 
     Ok(())
 }
-
-fn start_unsubscribe(stream: Compat<yamux::Stream>, name: &str, id: u32) -> Result<(), KvError> {
-    let cmd = CommandRequest::new_unsubscribe(name, id as _);
-    tokio::spawn(async move {
-        time::sleep(Duration::from_millis(2000)).await;
-        let mut client = ProstClientStream::new(stream);
-        let res = client.execute_unary(&cmd).await.unwrap();
-        println!("Finished unsubscribing: {:?}", res);
-    });
-
-    Ok(())
-}
\ No newline at end of file
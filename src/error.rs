@@ -1,6 +1,6 @@
-use thiserror::Error;
+use std::time::Duration;
 
-use crate::Value;
+use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum KvError {
@@ -12,17 +12,47 @@ pub enum KvError {
 
     #[error("Cannot parse command: `{0}`")]
     InvalidCommand(String),
+    #[error("Cannot execute write command on a read-only server")]
+    ReadOnly,
+    #[error("Value size {0} is larger than the limit {1}")]
+    ValueTooLarge(usize, usize),
+    #[error("Value nesting depth {0} is deeper than the limit {1}")]
+    ValueTooDeep(usize, usize),
     #[error("Cannot convert value {0} to {1}")]
     ConvertError(String, &'static str),
+    #[error("Too many responses are waiting to be read, the buffer size is {0}")]
+    BufferOverflow(usize),
+    #[error("Command is not finished in {0:?}")]
+    Timeout(Duration),
+    #[error("Storage is unavailable, the commands are rejected until it recovers")]
+    Unavailable,
+    #[error("Memory limit of {0} bytes is reached, delete some keys first")]
+    MemoryLimitExceeded(usize),
+    #[error("Chunked upload limit is reached: {0}")]
+    UploadLimitExceeded(String),
+    #[error("Replication offset {0} is not kept anymore, the log starts from {1}")]
+    ReplicationGap(u64, u64),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(&'static str),
+    #[error("Server returned status {0}: {1}")]
+    ServerError(u32, String),
     #[error("Cannot process command {0} with table: {1} and key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
+    #[error("Failed to encrypt or decrypt value")]
+    CryptoError,
+    #[error("Unsupported storage format: {0}")]
+    StorageFormat(String),
     #[error("Certificate parse error: error to load {0} {1}")]
     CertificateParseError(&'static str, &'static str),
+    #[error("Invalid certificate chain: {0}. The chain must be the leaf cert and its intermediate CAs, each cert is issued by the next one")]
+    CertificateChainError(&'static str),
 
     #[error("Failed to encode protobuf message")]
     EncodeError(#[from] prost::EncodeError),
     #[error("Failed to decode protobuf message")]
     DecodeError(#[from] prost::DecodeError),
+    #[error("Failed to encode or decode bincode value")]
+    BincodeError(#[from] bincode::Error),
     #[error("Failed to access Sled db")]
     SledError(#[from] sled::Error),
     #[error("I/O error")]
@@ -0,0 +1,85 @@
+use std::future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time;
+
+// drain the server before it's stopped, e.g. for a redeploy
+// after drain() is called, run_server_with_drain stops accepting, the connections (or yamux streams) which haven't
+// started processing are closed, and the running ones close after their current requests are done.
+// a subscription is never done by itself, it's dropped with the runtime after the drain times out
+#[derive(Debug, Clone)]
+pub struct DrainController {
+    // true once draining
+    signal: Arc<watch::Sender<bool>>,
+    // every connection holds a receiver until it's closed, so the connections are counted apart from the watchers
+    connections: Arc<watch::Sender<()>>,
+}
+
+// a connection which drain() waits for, until it's dropped
+#[derive(Debug)]
+pub(crate) struct DrainGuard {
+    signal: watch::Receiver<bool>,
+    _connection: watch::Receiver<()>,
+}
+
+impl DrainController {
+    pub fn new() -> Self {
+        Self {
+            signal: Arc::new(watch::channel(false).0),
+            connections: Arc::new(watch::channel(()).0),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    // how many connections are not closed yet
+    pub fn connections(&self) -> usize {
+        self.connections.receiver_count()
+    }
+
+    // start draining, then wait until all connections are closed or the timeout passes
+    // return true if all connections are closed in time
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.signal.send_replace(true);
+        time::timeout(timeout, self.connections.closed()).await.is_ok()
+    }
+
+    // resolve when the drain starts
+    pub async fn draining(&self) {
+        wait_draining(&mut self.signal.subscribe()).await
+    }
+
+    pub(crate) fn guard(&self) -> DrainGuard {
+        DrainGuard {
+            signal: self.signal.subscribe(),
+            _connection: self.connections.subscribe(),
+        }
+    }
+}
+
+impl Default for DrainController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DrainGuard {
+    pub fn is_draining(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    pub async fn draining(&mut self) {
+        wait_draining(&mut self.signal).await
+    }
+}
+
+async fn wait_draining(receiver: &mut watch::Receiver<bool>) {
+    // the controller is dropped, it can't drain anymore
+    if receiver.wait_for(|draining| *draining).await.is_err() {
+        future::pending::<()>().await;
+    }
+}
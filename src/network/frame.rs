@@ -1,11 +1,13 @@
 use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::Compression;
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use prost::Message;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, ReadBuf};
 use tracing::debug;
 
 use crate::{CommandRequest, CommandResponse, KvError};
@@ -21,6 +23,15 @@ const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
 const COMPRESSION_THRESHOLD: usize = 1436;
 // compression flag bit (the 4 bytes length's highest bit)
 const COMPRESSION_BIT: usize = 1 << 31;
+// read at most this many bytes of a frame at a time, so a bogus length can't allocate gigabytes at once
+const READ_CHUNK: usize = 64 * 1024;
+// a small compressed frame may expand to gigabytes, stop decompressing if the data is bigger than this.
+// a valid frame is never bigger than MAX_FRAME before it's compressed, the few MB above it are the headroom
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = MAX_FRAME + 4 * 1024 * 1024;
+// a frame whose values are nested deeper than this is rejected, e.g. a map in a map in a list is 3 deep.
+// the protobuf decoder has its own recursion limit of 100 messages, a map level takes 3 of them,
+// so a limit above 32 makes no difference: the deeper values fail to decode first
+pub const DEFAULT_MAX_VALUE_DEPTH: usize = 16;
 
 // handle Frame's encode and decode
 pub trait FrameCoder
@@ -29,6 +40,11 @@ pub trait FrameCoder
 {
     // convert a Message to a frame
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with_compression(buf, true)
+    }
+
+    // convert a Message to a frame, never compress it if compression is false, e.g. to save CPU
+    fn encode_frame_with_compression(&self, buf: &mut BytesMut, compression: bool) -> Result<(), KvError> {
         let size = self.encoded_len();
         if size > MAX_FRAME {
             return Err(KvError::FrameError);
@@ -37,7 +53,7 @@ pub trait FrameCoder
         // write length first, if need compression, set the new length later
         buf.put_u32(size as u32);
 
-        if size > COMPRESSION_THRESHOLD {
+        if compression && size > COMPRESSION_THRESHOLD {
             let mut compressed_buf = Vec::with_capacity(size);
             self.encode(&mut compressed_buf)?;
 
@@ -67,17 +83,54 @@ pub trait FrameCoder
 
     // convert a frame to a Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with_limit(buf, DEFAULT_MAX_DECOMPRESSED_SIZE)
+    }
+
+    // convert a frame to a Message, return FrameError if the decompressed data is bigger than max_decompressed
+    fn decode_frame_with_limit(buf: &mut BytesMut, max_decompressed: usize) -> Result<Self, KvError> {
+        Self::decode_frame_with_limits(buf, max_decompressed, DEFAULT_MAX_VALUE_DEPTH)
+    }
+
+    // like decode_frame_with_limit, and return ValueTooDeep if a value is nested deeper than max_depth
+    fn decode_frame_with_limits(
+        buf: &mut BytesMut,
+        max_decompressed: usize,
+        max_depth: usize,
+    ) -> Result<Self, KvError> {
+        let message = Self::decode_payload(buf, max_decompressed)?;
+        let depth = message.max_value_depth();
+        if depth > max_depth {
+            return Err(KvError::ValueTooDeep(depth, max_depth));
+        }
+        Ok(message)
+    }
+
+    // the nesting depth of the deepest value in the message
+    fn max_value_depth(&self) -> usize;
+
+    // decode the message of a frame, decompress it if needed
+    fn decode_payload(buf: &mut BytesMut, max_decompressed: usize) -> Result<Self, KvError> {
+        // the buffer may come from anywhere, check the lengths instead of panicking on a malformed frame
+        if buf.len() < LENGTH_BYTES {
+            return Err(KvError::FrameError);
+        }
         // get 4 bytes, read length and compression flag
         let header = buf.get_u32() as usize;
         let (len, compressed) = decode_header(header);
         debug!("Got a frame, length: {}, compressed: {}", len, compressed);
+        if len > buf.len() {
+            return Err(KvError::FrameError);
+        }
 
         if compressed {
-            // unzip
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut decompressed_buf = Vec::with_capacity(len * 2);
+            // unzip, read at most one byte more than the limit so we know if it's exceeded
+            let mut decoder = GzDecoder::new(&buf[..len]).take(max_decompressed as u64 + 1);
+            let mut decompressed_buf = Vec::with_capacity((len * 2).min(max_decompressed));
             decoder.read_to_end(&mut decompressed_buf)?;
             buf.advance(len);
+            if decompressed_buf.len() > max_decompressed {
+                return Err(KvError::FrameError);
+            }
 
             // decode
             Ok(Self::decode(&decompressed_buf[..])?)
@@ -90,9 +143,17 @@ pub trait FrameCoder
     }
 }
 
-impl FrameCoder for CommandRequest {}
+impl FrameCoder for CommandRequest {
+    fn max_value_depth(&self) -> usize {
+        CommandRequest::max_value_depth(self)
+    }
+}
 
-impl FrameCoder for CommandResponse {}
+impl FrameCoder for CommandResponse {
+    fn max_value_depth(&self) -> usize {
+        CommandResponse::max_value_depth(self)
+    }
+}
 
 fn decode_header(header: usize) -> (usize, bool) {
     let len = header & !COMPRESSION_BIT;
@@ -100,39 +161,66 @@ fn decode_header(header: usize) -> (usize, bool) {
     (len, compressed)
 }
 
-// read a frame from a stream
-pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+// read a frame from a stream into buf, return false if the stream is closed cleanly before a new frame
+// if the stream is closed in the middle of a frame, return FrameError.
+// the bytes read so far are kept in buf, so the read can be resumed after Pending, e.g. a cancelled select! branch.
+// buf must only hold the frame being read, at most the rest of the frame is read from the stream
+pub(crate) fn poll_read_frame<S>(stream: &mut S, cx: &mut Context<'_>, buf: &mut BytesMut) -> Poll<Result<bool, KvError>>
     where
-        S: AsyncRead + Unpin + Send,
+        S: AsyncRead + Unpin,
 {
-    // read 4 bytes length
-    let mut header = [0; LENGTH_BYTES];
-    stream.read_exact(&mut header).await?;
-    let header = u32::from_be_bytes(header) as usize;
-    let (len, _compressed) = decode_header(header);
-
-    buf.reserve(LENGTH_BYTES + len);
-    buf.put_u32(header as u32);
-    // unsafe is because from current position too position + len is not initialized
-    // but we have reserved enough space, and after reading from the stream, the space will be initialized
-    // so it is safe
-    unsafe {
-        buf.advance_mut(len);
-    }
-    stream.read_exact(&mut buf[LENGTH_BYTES..]).await?;
-
-    Ok(())
+    loop {
+        // read the 4 bytes length first, then the payload
+        let needed = match buf.len() < LENGTH_BYTES {
+            true => LENGTH_BYTES - buf.len(),
+            false => {
+                let header = u32::from_be_bytes(buf[..LENGTH_BYTES].try_into().unwrap()) as usize;
+                let (len, _compressed) = decode_header(header);
+                LENGTH_BYTES + len - buf.len()
+            }
+        };
+        if needed == 0 {
+            return Poll::Ready(Ok(true));
+        }
+
+        // the length is untrusted, grow the buffer as the data arrives instead of reserving it all upfront
+        let start = buf.len();
+        buf.resize(start + needed.min(READ_CHUNK), 0);
+        let mut read_buf = ReadBuf::new(&mut buf[start..]);
+        let result = Pin::new(&mut *stream).poll_read(cx, &mut read_buf);
+        let n = read_buf.filled().len();
+        buf.truncate(start + n);
+        ready!(result)?;
+
+        if n == 0 {
+            return match buf.is_empty() {
+                true => Poll::Ready(Ok(false)),
+                false => Poll::Ready(Err(KvError::FrameError)),
+            };
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use bytes::Bytes;
+    use proptest::prelude::*;
 
     use crate::utils::DummyStream;
-    use crate::Value;
+    use crate::{Value, ValueSet};
 
     use super::*;
 
+    // read a frame from a stream, see poll_read_frame
+    async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<bool, KvError>
+        where
+            S: AsyncRead + Unpin,
+    {
+        std::future::poll_fn(|cx| poll_read_frame(stream, cx, buf)).await
+    }
+
     #[tokio::test]
     async fn read_frame_should_work() {
         let mut buf = BytesMut::new();
@@ -141,12 +229,38 @@ mod tests {
         let mut stream = DummyStream { buf };
 
         let mut data = BytesMut::new();
-        read_frame(&mut stream, &mut data).await.unwrap();
+        assert!(read_frame(&mut stream, &mut data).await.unwrap());
 
         let request2 = CommandRequest::decode_frame(&mut data).unwrap();
         assert_eq!(request, request2);
     }
 
+    #[tokio::test]
+    async fn read_frame_from_closed_stream_should_return_false() {
+        let mut stream = DummyStream::default();
+        let mut data = BytesMut::new();
+        assert!(!read_frame(&mut stream, &mut data).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn read_frame_with_truncated_data_should_fail() {
+        let mut buf = BytesMut::new();
+        let request = CommandRequest::new_hdel("table", "key");
+        request.encode_frame(&mut buf).unwrap();
+
+        // the stream is closed in the middle of the header
+        let mut stream = DummyStream { buf: buf.clone() };
+        stream.buf.truncate(2);
+        let result = read_frame(&mut stream, &mut BytesMut::new()).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+
+        // the stream is closed in the middle of the payload
+        let mut stream = DummyStream { buf };
+        stream.buf.truncate(LENGTH_BYTES + 2);
+        let result = read_frame(&mut stream, &mut BytesMut::new()).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+    }
+
     #[test]
     fn command_request_encode_decode_should_work() {
         let mut buf = BytesMut::new();
@@ -154,7 +268,7 @@ mod tests {
         let request = CommandRequest::new_hdel("table", "key");
         request.encode_frame(&mut buf).unwrap();
 
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let request2 = CommandRequest::decode_frame(&mut buf).unwrap();
         assert_eq!(request, request2);
@@ -168,7 +282,104 @@ mod tests {
         let response: CommandResponse = values.into();
         response.encode_frame(&mut buf).unwrap();
 
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
+
+        let response2 = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(response, response2);
+    }
+
+    #[test]
+    fn list_value_encode_decode_should_work() {
+        let mut buf = BytesMut::new();
+
+        let list: Value = vec![1.into(), "hello".into(), vec![true.into()].into()].into();
+        let response: CommandResponse = list.into();
+        response.encode_frame(&mut buf).unwrap();
+
+        let response2 = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(response, response2);
+    }
+
+    #[test]
+    fn set_value_encode_decode_should_work() {
+        let mut buf = BytesMut::new();
+
+        let set = ValueSet::new(vec![2.into(), "hello".into(), 1.into()]);
+        let request = CommandRequest::new_sadd("t", "k", set.members.clone());
+        request.encode_frame(&mut buf).unwrap();
+        let request2 = CommandRequest::decode_frame(&mut buf).unwrap();
+        assert_eq!(request, request2);
+
+        let response: CommandResponse = Value::from(set).into();
+        response.encode_frame(&mut buf).unwrap();
+        let response2 = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(response, response2);
+    }
+
+    #[test]
+    fn map_value_encode_decode_should_work() {
+        let mut buf = BytesMut::new();
+
+        let address = HashMap::from([("city".to_string(), "Paris".into())]);
+        let record = HashMap::from([("name".to_string(), "alice".into()), ("address".to_string(), address.into())]);
+        let request = CommandRequest::new_hset("users", "1", record.into());
+        request.encode_frame(&mut buf).unwrap();
+
+        let request2 = CommandRequest::decode_frame(&mut buf).unwrap();
+        assert_eq!(request, request2);
+    }
+
+    #[test]
+    fn too_deeply_nested_value_decode_should_fail() {
+        let mut buf = BytesMut::new();
+
+        // the protobuf decoder limits the recursion before the depth is checked,
+        // so a malicious frame can't overflow the stack
+        let mut value: Value = 1.into();
+        for _ in 0..100 {
+            value = HashMap::from([("k".to_string(), value)]).into();
+        }
+        let response: CommandResponse = value.into();
+        response.encode_frame(&mut buf).unwrap();
+
+        assert!(matches!(CommandResponse::decode_frame(&mut buf), Err(KvError::DecodeError(_))));
+    }
+
+    #[test]
+    fn value_nested_deeper_than_limit_decode_should_fail() {
+        let nested = |depth: usize| {
+            let mut value: Value = 1.into();
+            for _ in 1..depth {
+                value = HashMap::from([("k".to_string(), value)]).into();
+            }
+            CommandRequest::new_hset("t1", "k1", value)
+        };
+        let mut buf = BytesMut::new();
+
+        nested(DEFAULT_MAX_VALUE_DEPTH).encode_frame(&mut buf).unwrap();
+        assert!(CommandRequest::decode_frame(&mut buf).is_ok());
+
+        let depth = DEFAULT_MAX_VALUE_DEPTH + 1;
+        nested(depth).encode_frame(&mut buf).unwrap();
+        let result = CommandRequest::decode_frame(&mut buf);
+        assert!(matches!(result, Err(KvError::ValueTooDeep(d, DEFAULT_MAX_VALUE_DEPTH)) if d == depth));
+        // the frame is consumed, the next one can be decoded
+        assert!(buf.is_empty());
+
+        // the limit is configurable
+        nested(5).encode_frame(&mut buf).unwrap();
+        let result = CommandRequest::decode_frame_with_limits(&mut buf, DEFAULT_MAX_DECOMPRESSED_SIZE, 4);
+        assert!(matches!(result, Err(KvError::ValueTooDeep(5, 4))));
+    }
+
+    #[test]
+    fn command_response_without_compression_should_not_be_compressed() {
+        let mut buf = BytesMut::new();
+        let value: Value = Bytes::from(vec![0u8; 16436]).into();
+        let response: CommandResponse = value.into();
+        response.encode_frame_with_compression(&mut buf, false).unwrap();
+        assert!(!is_compressed(&buf));
+        assert_eq!(buf.len(), LENGTH_BYTES + response.encoded_len());
 
         let response2 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(response, response2);
@@ -182,12 +393,125 @@ mod tests {
         let response: CommandResponse = value.into();
         response.encode_frame(&mut buf).unwrap();
 
-        assert_eq!(is_compressed(&buf), true);
+        assert!(is_compressed(&buf));
 
         let response2 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(response, response2);
     }
 
+    #[test]
+    fn decompressed_frame_bigger_than_limit_should_fail() {
+        let mut buf = BytesMut::new();
+
+        // zeros compress very well, the frame is much smaller than the decompressed data
+        let value: Value = Bytes::from(vec![0u8; 1024 * 1024]).into();
+        let response: CommandResponse = value.into();
+        response.encode_frame(&mut buf).unwrap();
+        assert!(buf.len() < 16 * 1024);
+
+        let mut buf2 = buf.clone();
+        let result = CommandResponse::decode_frame_with_limit(&mut buf, 64 * 1024);
+        assert!(matches!(result, Err(KvError::FrameError)));
+        // the frame is consumed, so the next frame can still be read
+        assert!(buf.is_empty());
+
+        let response2 = CommandResponse::decode_frame_with_limit(&mut buf2, response.encoded_len()).unwrap();
+        assert_eq!(response, response2);
+    }
+
+    #[test]
+    fn compressed_frame_bigger_than_64mb_should_decode_with_default_limit() {
+        let mut buf = BytesMut::new();
+
+        // a big frame is compressed by default, it must be accepted like the same frame without compression
+        let value: Value = Bytes::from(vec![0u8; 65 * 1024 * 1024]).into();
+        let response: CommandResponse = value.into();
+        response.encode_frame(&mut buf).unwrap();
+        assert!(is_compressed(&buf));
+
+        let response2 = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(response, response2);
+    }
+
+    #[tokio::test]
+    async fn read_frame_with_bogus_length_should_fail() {
+        // the header claims a 2GB frame, but only a few bytes follow
+        let mut buf = BytesMut::new();
+        buf.put_u32(0x7fff_ffff);
+        buf.put_slice(b"short");
+        let mut stream = DummyStream { buf };
+        let mut data = BytesMut::new();
+        let result = read_frame(&mut stream, &mut data).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+        assert!(data.capacity() < 1024 * 1024);
+    }
+
+    #[test]
+    fn decode_frame_with_bogus_length_should_fail() {
+        let mut buf = BytesMut::from(&[0u8, 0][..]);
+        assert!(matches!(CommandRequest::decode_frame(&mut buf), Err(KvError::FrameError)));
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(100 | COMPRESSION_BIT as u32);
+        buf.put_slice(b"short");
+        assert!(matches!(CommandResponse::decode_frame(&mut buf), Err(KvError::FrameError)));
+    }
+
+    // read the frames of the data like a connection does, until the data is used up or there is an error
+    fn read_all_frames(data: Vec<u8>) {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut stream = DummyStream { buf: BytesMut::from(&data[..]) };
+            loop {
+                let mut buf = BytesMut::new();
+                match read_frame(&mut stream, &mut buf).await {
+                    Ok(true) => {
+                        let _ = CommandRequest::decode_frame(&mut buf);
+                    }
+                    Ok(false) | Err(_) => break,
+                }
+            }
+        });
+    }
+
+    proptest! {
+        #[test]
+        fn decode_frame_should_not_panic_on_any_data(data in proptest::collection::vec(any::<u8>(), 0..2048)) {
+            let _ = CommandRequest::decode_frame(&mut BytesMut::from(&data[..]));
+            let _ = CommandResponse::decode_frame_with_limit(&mut BytesMut::from(&data[..]), 4096);
+        }
+
+        #[test]
+        fn decode_frame_should_not_panic_on_any_payload(
+            compressed in any::<bool>(),
+            len in 0u32..4096,
+            payload in proptest::collection::vec(any::<u8>(), 0..2048),
+        ) {
+            // a plausible header followed by garbage, so the payload parsing is reached
+            let mut buf = BytesMut::new();
+            buf.put_u32(if compressed { len | COMPRESSION_BIT as u32 } else { len });
+            buf.put_slice(&payload);
+            let _ = CommandResponse::decode_frame(&mut buf);
+        }
+
+        #[test]
+        fn read_frame_should_not_panic_on_any_data(data in proptest::collection::vec(any::<u8>(), 0..2048)) {
+            read_all_frames(data);
+        }
+
+        #[test]
+        fn encoded_frame_should_decode_to_the_same_request(
+            table in ".*",
+            key in proptest::collection::vec(any::<u8>(), 0..64),
+            value in proptest::collection::vec(any::<u8>(), 0..4096),
+        ) {
+            let request = CommandRequest::new_hset(table, key, Bytes::from(value).into());
+            let mut buf = BytesMut::new();
+            request.encode_frame(&mut buf).unwrap();
+            prop_assert_eq!(CommandRequest::decode_frame(&mut buf).unwrap(), request);
+        }
+    }
+
     fn is_compressed(buf: &BytesMut) -> bool {
         if let &[v] = &buf[..1] {
             v >> 7 == 1
@@ -1,25 +1,70 @@
-use futures::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, SinkExt, Stream, StreamExt};
+use futures::stream::SelectAll;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::info;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, info_span, Instrument};
 
+pub use drain::DrainController;
 pub use frame::FrameCoder;
-pub use multiplex::YamuxCtrl;
+pub use multiplex::{default_yamux_config, YamuxCtrl};
+pub use server::{run_listeners, run_server, run_server_with_drain, ConnectionLimit, ServerListener};
 pub use tls::{TlsClientConnector, TlsServerAcceptor};
+#[cfg(unix)]
+pub use uds::{bind_uds, connect_uds};
 
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+use crate::{Broadcaster, CommandRequest, CommandResponse, KvError, KvPair, MemTable, Service, Storage, Topic};
+use crate::command_request::RequestData;
+use crate::pb::check_status;
 use crate::network::stream::ProstStream;
-use crate::network::stream_result::StreamResult;
+pub use crate::network::stream::StreamStats;
+pub use crate::network::stream_result::{OverflowPolicy, ResubscribingStream, StreamResult};
+use crate::network::stream_result::Cancel;
+use crate::network::drain::DrainGuard;
+
+// how many responses of a request can be waiting to be sent, when the server executes requests concurrently
+const RESPONSE_CHANNEL_SIZE: usize = 64;
+
+// how long a cancelled stream waits for the server to close the connection
+const CANCEL_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
 
+mod drain;
 mod frame;
 mod stream;
 mod tls;
 mod multiplex;
+mod server;
 mod stream_result;
+#[cfg(unix)]
+mod uds;
 
 // handle the read/write of a socket accepted by the server
-pub struct ProstServerStream<S> {
+// the service can use any store and topic, e.g. a SledDb and a DurableTopic
+pub struct ProstServerStream<S, Store = MemTable, T = Arc<Broadcaster>> {
+    inner: ProstStream<S, CommandRequest, CommandResponse>,
+    service: Service<Store, T>,
+    // messages pushed by the server without a request, e.g. a shutdown warning
+    push: Option<mpsc::Receiver<CommandResponse>>,
+    // close the connection if no request arrives within this duration, None means unlimited
+    idle_timeout: Option<Duration>,
+    // how many requests of the connection can be executed at the same time
+    workers: usize,
+    // stop reading requests when the server is draining
+    drain: Option<DrainGuard>,
+}
+
+// the decoded requests of a socket accepted by the server, for custom request loops, e.g. proxies
+// the responses are sent by respond(), nothing is executed by the service
+pub struct RequestStream<S> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
-    service: Service,
 }
 
 // handle the read/write of a socket by the client
@@ -27,27 +72,350 @@ pub struct ProstClientStream<S> {
     inner: ProstStream<S, CommandResponse, CommandRequest>,
 }
 
-impl<S> ProstServerStream<S>
+impl<S, Store, T> ProstServerStream<S, Store, T>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send,
+        Store: Storage + Send + Sync + 'static,
+        T: Topic,
 {
-    pub fn new(stream: S, service: Service) -> Self {
-        Self { inner: ProstStream::new(stream), service }
+    pub fn new(stream: S, service: Service<Store, T>) -> Self {
+        // the subscriptions and the authentication of the connection are kept by its own session
+        Self::new_in_session(stream, service.new_session())
+    }
+
+    // serve one of the streams of a connection, e.g. a yamux stream, with the session of the connection
+    // create the session once per connection with Service::new_session(), so the subscriptions made on any
+    // of its streams are listed and removed by all of them, and an Auth on one stream authenticates all of them
+    pub fn new_in_session(stream: S, service: Service<Store, T>) -> Self {
+        Self { inner: ProstStream::new(stream), service, push: None, idle_timeout: None, workers: 1, drain: None }
+    }
+
+    // execute up to `workers` requests at the same time, so a slow command doesn't block the next ones
+    // responses of the requests with a request_id are sent as they complete, the others are sent in request order
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    // close the connection if the client doesn't send any request within the timeout
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    // reject the request if a compressed frame is decompressed to more than `size` bytes
+    pub fn with_max_decompressed_size(mut self, size: usize) -> Self {
+        self.inner.set_max_decompressed_size(size);
+        self
+    }
+
+    // reject the request if a value is nested deeper than `depth`, e.g. a map in a map is 2 deep
+    pub fn with_max_value_depth(mut self, depth: usize) -> Self {
+        self.inner.set_max_value_depth(depth);
+        self
+    }
+
+    // don't compress the responses, e.g. to save CPU, the compressed requests are still decoded
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.inner.set_compression(compression);
+        self
+    }
+
+    // messages received from the channel are sent to the client, interleaved with the responses
+    pub fn with_push(mut self, receiver: mpsc::Receiver<CommandResponse>) -> Self {
+        self.push = Some(receiver);
+        self
+    }
+
+    // close the connection after the current request once the controller drains,
+    // the connection is closed immediately if it's already draining when process() starts
+    pub fn with_drain(mut self, drain: &DrainController) -> Self {
+        self.drain = Some(drain.guard());
+        self
+    }
+
+    // handle the requests by yourself instead of the service, the stream ends when the client closes the socket
+    // only the frame settings (e.g. max decompressed size) are kept, the service, push and workers are not used
+    pub fn into_request_stream(self) -> RequestStream<S> {
+        RequestStream { inner: self.inner }
     }
 
     pub async fn process(mut self) -> Result<(), KvError> {
+        let _connection = self.service.connection();
+        if matches!(&self.drain, Some(drain) if drain.is_draining()) {
+            info!("Server is draining, closing the new connection");
+            return Ok(());
+        }
+        if self.workers > 1 {
+            return self.process_concurrently().await;
+        }
+
+        let stream = &mut self.inner;
+        let push = &mut self.push;
+        let drain = &mut self.drain;
+        let idle_timeout = self.idle_timeout;
+        let mut deadline = idle_timeout.map(|t| Instant::now() + t);
+        // the subscriptions (Subscribe, Watch etc.) are sent in the background, so the requests are still read
+        // while they run, e.g. the Unsubscribe sent by StreamResult::cancel(). the other requests run one by one
+        let mut subscriptions = SelectAll::new();
+        let mut was_busy = false;
+        let mut draining = false;
+        loop {
+            // the connection is not idle while it has subscriptions, it may be idle from when the last one ends
+            let busy = !subscriptions.is_empty();
+            if draining && !busy {
+                break;
+            }
+            if was_busy && !busy {
+                deadline = idle_timeout.map(|t| Instant::now() + t);
+            }
+            was_busy = busy;
+            tokio::select! {
+                request = next_request(stream, if busy { None } else { deadline }), if !draining => match request {
+                    Some(Ok(request)) => {
+                        let rejected = check_auth(&self.service, &request);
+                        let background = rejected.is_none() && request.subscription_topic().is_some();
+                        let span = info_span!(
+                            "request",
+                            command = request.command_name(),
+                            table = request.table(),
+                            request_id = request.request_id,
+                        );
+                        let service = &self.service;
+                        let subscriptions = &mut subscriptions;
+                        async {
+                            info!("received request: {:?}", request.redacted());
+                            let mut response = match rejected {
+                                Some(rejected) => rejected,
+                                None => service.execute(request),
+                            };
+                            if background {
+                                subscriptions.push(response);
+                                return Ok::<_, KvError>(());
+                            }
+                            while let Some(data) = response.next().await {
+                                stream.send(&data).await?;
+                            }
+                            Ok(())
+                        }
+                        .instrument(span)
+                        .await?;
+                        deadline = idle_timeout.map(|t| Instant::now() + t);
+                    }
+                    _ => break,
+                },
+                Some(data) = subscriptions.next(), if busy => stream.send(&data).await?,
+                Some(data) = recv_push(push) => {
+                    info!("push message: {:?}", data);
+                    stream.send(&data).await?;
+                }
+                // stop reading, the running subscriptions are still sent until they end
+                _ = wait_drain(drain), if !draining => {
+                    info!("Server is draining, closing the connection");
+                    draining = true;
+                }
+                // the last subscription ended while draining, the connection is closed at the top of the loop
+                else => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S> RequestStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    // send a response to the client, a request may get any number of responses
+    pub async fn respond(&mut self, response: &CommandResponse) -> Result<(), KvError> {
+        self.inner.send(response).await
+    }
+
+    // how much data the connection has read and written so far
+    pub fn stats(&self) -> StreamStats {
+        self.inner.stats()
+    }
+}
+
+impl<S> Stream for RequestStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Item = Result<CommandRequest, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+impl<S, Store, T> ProstServerStream<S, Store, T>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        Store: Storage + Send + Sync + 'static,
+        T: Topic,
+{
+    // execute every request in its own task, the responses are sent back by this task
+    async fn process_concurrently(mut self) -> Result<(), KvError> {
         let stream = &mut self.inner;
-        while let Some(Ok(request)) = stream.next().await {
-            info!("received request: {:?}", request);
-            let mut response = self.service.execute(request);
-            while let Some(data) = response.next().await {
-                stream.send(&data).await.unwrap();
+        let push = &mut self.push;
+        let drain = &mut self.drain;
+        let idle_timeout = self.idle_timeout;
+        let mut deadline = idle_timeout.map(|t| Instant::now() + t);
+        let permits = Arc::new(Semaphore::new(self.workers));
+
+        // the responses of the requests without a request_id, one receiver per request in request order
+        let mut ordered: VecDeque<mpsc::Receiver<Arc<CommandResponse>>> = VecDeque::new();
+        // the responses of the requests with a request_id, in any order
+        let (unordered_tx, mut unordered_rx) = mpsc::channel(RESPONSE_CHANNEL_SIZE);
+        let mut unordered_tx = Some(unordered_tx);
+        let mut unordered_done = false;
+
+        loop {
+            // no more requests, wait until all running requests are done
+            if unordered_tx.is_none() && ordered.is_empty() && unordered_done {
+                break;
+            }
+            // the connection is not idle while requests are running
+            let busy = permits.available_permits() < self.workers || !ordered.is_empty();
+            tokio::select! {
+                request = next_request(stream, if busy { None } else { deadline }),
+                    if unordered_tx.is_some() && permits.available_permits() > 0 => match request {
+                    Some(Ok(request)) => {
+                        let permit = Arc::clone(&permits).try_acquire_owned().unwrap();
+                        // checked before the request runs, so the requests sent after a valid Auth are executed
+                        let rejected = check_auth(&self.service, &request);
+                        let span = info_span!(
+                            "request",
+                            command = request.command_name(),
+                            table = request.table(),
+                            request_id = request.request_id,
+                        );
+                        let sender = match request.request_id {
+                            0 => {
+                                let (sender, receiver) = mpsc::channel(RESPONSE_CHANNEL_SIZE);
+                                ordered.push_back(receiver);
+                                sender
+                            }
+                            _ => unordered_tx.clone().unwrap(),
+                        };
+                        let service = self.service.clone();
+                        tokio::spawn(
+                            async move {
+                                info!("received request: {:?}", request.redacted());
+                                let mut response = match rejected {
+                                    Some(rejected) => rejected,
+                                    None => service.execute(request),
+                                };
+                                while let Some(data) = response.next().await {
+                                    // the connection is closed, stop executing
+                                    if sender.send(data).await.is_err() {
+                                        break;
+                                    }
+                                }
+                                drop(permit);
+                            }
+                            .instrument(span),
+                        );
+                        deadline = idle_timeout.map(|t| Instant::now() + t);
+                    }
+                    // stop reading, the senders in the running tasks keep the channel open until they're done
+                    _ => unordered_tx = None,
+                },
+                Some(data) = next_ordered(&mut ordered) => stream.send(&data).await?,
+                data = unordered_rx.recv(), if !unordered_done => match data {
+                    Some(data) => stream.send(&data).await?,
+                    None => unordered_done = true,
+                },
+                Some(data) = recv_push(push) => {
+                    info!("push message: {:?}", data);
+                    stream.send(&data).await?;
+                }
+                // stop reading like the client is gone, the running requests are still done
+                _ = wait_drain(drain), if unordered_tx.is_some() => {
+                    info!("Server is draining, waiting for the running requests");
+                    unordered_tx = None;
+                }
             }
         }
         Ok(())
     }
 }
 
+// return the 401 response if the request must not be executed by the session of the connection
+// a valid Auth authenticates the session, so all streams of the connection, the Auth itself is answered by the service
+fn check_auth<Store: Storage + Send + Sync + 'static, T: Topic>(
+    service: &Service<Store, T>,
+    request: &CommandRequest,
+) -> Option<Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>> {
+    if let Some(RequestData::Auth(v)) = &request.request_data {
+        let _ = service.authenticate(&v.token);
+        return None;
+    }
+    if service.is_authenticated() {
+        return None;
+    }
+    let mut response: CommandResponse = KvError::Unauthorized("Send Auth with a valid token first").into();
+    response.request_id = request.request_id;
+    Some(Box::pin(futures::stream::once(future::ready(Arc::new(response)))))
+}
+
+// the next response of the oldest request without a request_id, None if there is no such request
+async fn next_ordered(ordered: &mut VecDeque<mpsc::Receiver<Arc<CommandResponse>>>) -> Option<Arc<CommandResponse>> {
+    while let Some(receiver) = ordered.front_mut() {
+        match receiver.recv().await {
+            Some(data) => return Some(data),
+            // all responses of the request are sent, move to the next request
+            None => {
+                ordered.pop_front();
+            }
+        }
+    }
+    None
+}
+
+// read the next request, return None if no request arrives before the deadline
+// it's cancel-safe, a partially read frame is kept by the ProstStream, so it can lose a select! race
+async fn next_request<S>(
+    stream: &mut ProstStream<S, CommandRequest, CommandResponse>,
+    deadline: Option<Instant>,
+) -> Option<Result<CommandRequest, KvError>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match deadline {
+        Some(deadline) => match time::timeout_at(deadline, stream.next()).await {
+            Ok(request) => request,
+            Err(_) => {
+                info!("Connection is idle for too long, closing");
+                None
+            }
+        },
+        None => stream.next().await,
+    }
+}
+
+// resolve when the server starts draining, never if the connection has no drain
+async fn wait_drain(drain: &mut Option<DrainGuard>) {
+    match drain {
+        Some(drain) => drain.draining().await,
+        None => future::pending().await,
+    }
+}
+
+// receive a push message, return None if there is no push channel or it is closed
+async fn recv_push(push: &mut Option<mpsc::Receiver<CommandResponse>>) -> Option<CommandResponse> {
+    match push {
+        Some(receiver) => {
+            let data = receiver.recv().await;
+            if data.is_none() {
+                *push = None;
+            }
+            data
+        }
+        None => None,
+    }
+}
+
 impl<S> ProstClientStream<S>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -66,12 +434,197 @@ impl<S> ProstClientStream<S>
         }
     }
 
+    // send all requests and flush once, then read the responses in the same order
+    // the server handles the requests of a stream one by one, so the responses are in FIFO order
+    // only unary commands are supported, a streaming command would break the request/response pairing
+    pub async fn execute_pipeline(&mut self, requests: &[CommandRequest]) -> Result<Vec<CommandResponse>, KvError> {
+        for request in requests {
+            self.send_buffered(request).await?;
+        }
+        self.flush().await?;
+
+        let mut responses = Vec::with_capacity(requests.len());
+        for _ in requests {
+            responses.push(self.next_response().await?);
+        }
+        Ok(responses)
+    }
+
+    // buffer the request without sending it, the caller controls when to flush()
+    // the buffer is flushed automatically if it grows larger than the write buffer limit
+    pub async fn send_buffered(&mut self, request: &CommandRequest) -> Result<(), KvError> {
+        self.inner.feed(request).await
+    }
+
+    // send all the buffered requests
+    pub async fn flush(&mut self) -> Result<(), KvError> {
+        self.inner.flush().await
+    }
+
+    // read the next response, used with send_buffered()
+    pub async fn next_response(&mut self) -> Result<CommandResponse, KvError> {
+        match self.inner.next().await {
+            Some(response) => response,
+            None => Err(KvError::Internal("Did not receive response".into())),
+        }
+    }
+
+    // set how many bytes can be buffered by send_buffered() before they are flushed automatically
+    pub fn set_write_buf_limit(&mut self, limit: usize) {
+        self.inner.set_write_buf_limit(limit);
+    }
+
+    // set if the big requests are compressed, the compressed responses are decoded anyway
+    pub fn set_compression(&mut self, compression: bool) {
+        self.inner.set_compression(compression);
+    }
+
+    // how much data the connection has read and written so far
+    pub fn stats(&self) -> StreamStats {
+        self.inner.stats()
+    }
+
+    // upload a big binary value in chunks of `chunk_size` bytes, every chunk is sent in its own frame
+    // so the other streams of a multiplexed connection are not blocked for the whole upload
+    pub async fn set_chunked(
+        &mut self,
+        table: &str,
+        key: impl Into<Bytes>,
+        data: Bytes,
+        chunk_size: usize,
+    ) -> Result<(), KvError> {
+        let key = key.into();
+        // an empty value is sent as one empty chunk
+        let chunks: Vec<Bytes> = match data.is_empty() {
+            true => vec![data.clone()],
+            false => data.chunks(chunk_size.max(1)).map(|chunk| data.slice_ref(chunk)).collect(),
+        };
+        let mut transfer_id = 0;
+        let count = chunks.len();
+        for (seq, chunk) in chunks.into_iter().enumerate() {
+            let last = seq + 1 == count;
+            let request = CommandRequest::new_hsetchunked(table, key.clone(), transfer_id, seq as u32, chunk, last);
+            let response = self.execute_unary(&request).await?;
+            check_status(&response)?;
+            if let Some(id) = response.values.first() {
+                transfer_id = i64::try_from(id)? as u64;
+            }
+        }
+        Ok(())
+    }
+
+    // download a big binary value in chunks of `chunk_size` bytes, 0 means the server's default (1MB)
+    pub async fn get_chunked(&mut self, table: &str, key: impl Into<Bytes>, chunk_size: u32) -> Result<Bytes, KvError> {
+        self.inner.send(&CommandRequest::new_hgetchunked(table, key, chunk_size)).await?;
+
+        let mut data = BytesMut::new();
+        let mut expected = 0;
+        loop {
+            let response = self.next_response().await?;
+            check_status(&response)?;
+            let (seq, count, chunk) = match &response.values[..] {
+                [seq, count, chunk] => (i64::try_from(seq)?, i64::try_from(count)?, Bytes::try_from(chunk)?),
+                _ => return Err(KvError::Internal("Invalid chunk".into())),
+            };
+            if seq != expected {
+                return Err(KvError::Internal(format!("Expect chunk {}, got {}", expected, seq)));
+            }
+            data.extend_from_slice(&chunk);
+            expected += 1;
+            if expected >= count {
+                return Ok(data.freeze());
+            }
+        }
+    }
+
+    // read all pairs of a table in chunks of at most `chunk_size` pairs, the stream ends after the last chunk
+    // the server reads ahead only a few chunks, so a big table can be processed without holding it in memory
+    pub fn get_all_chunked(
+        &mut self,
+        table: &str,
+        chunk_size: u32,
+    ) -> impl Stream<Item=Result<Vec<KvPair>, KvError>> + Send + '_ {
+        let request = CommandRequest::new_hget_all_chunked(table, chunk_size.max(1));
+        futures::stream::unfold((self, Some(request), false), |(client, request, done)| async move {
+            if done {
+                return None;
+            }
+            let result = match request {
+                Some(request) => client.inner.send(&request).await,
+                None => Ok(()),
+            };
+            let chunk = match result {
+                Ok(()) => client.next_response().await,
+                Err(e) => Err(e),
+            }
+            .and_then(|response| {
+                check_status(&response)?;
+                let last = match response.values.first() {
+                    Some(last) => bool::try_from(last)?,
+                    None => return Err(KvError::Internal("Invalid chunk".into())),
+                };
+                Ok((response.pairs, last))
+            });
+            match chunk {
+                Ok((pairs, last)) => Some((Ok(pairs), (client, None, last))),
+                Err(e) => Some((Err(e), (client, None, true))),
+            }
+        })
+    }
+
+    // the connection is kept open, so the subscription can be cancelled by StreamResult::cancel()
     pub async fn execute_streaming(self, request: &CommandRequest) -> Result<StreamResult, KvError> {
         let mut stream = self.inner;
         stream.send(request).await?;
-        stream.close().await?;
 
-        StreamResult::new(stream).await
+        let (sender, receiver) = mpsc::channel(RESPONSE_CHANNEL_SIZE);
+        let (cancel_tx, cancel_rx) = mpsc::channel(1);
+        tokio::spawn(forward_stream(stream, sender, cancel_rx));
+
+        let result = StreamResult::new(ReceiverStream::new(receiver)).await?;
+        Ok(match request.subscription_topic() {
+            Some(topic) => result.with_canceller(topic, cancel_tx),
+            None => result,
+        })
+    }
+}
+
+// forward the responses of a streaming request to the channel, until the connection or the receiver is closed
+// a cancel sends the Unsubscribe and closes the write half, then the responses left are discarded until the server
+// closes the connection, so the Unsubscribe isn't lost because the connection is reset with unread data
+async fn forward_stream<S>(
+    mut stream: ProstStream<S, CommandResponse, CommandRequest>,
+    sender: mpsc::Sender<Result<CommandResponse, KvError>>,
+    mut cancel: mpsc::Receiver<Cancel>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let cancelled = loop {
+        tokio::select! {
+            data = stream.next() => match data {
+                Some(data) => if sender.send(data).await.is_err() {
+                    break cancel.try_recv().ok();
+                },
+                None => break cancel.try_recv().ok(),
+            },
+            Some(cancelled) = cancel.recv() => break Some(cancelled),
+            _ = sender.closed() => break cancel.try_recv().ok(),
+        }
+    };
+    let (request, reply) = match cancelled {
+        Some(cancelled) => cancelled,
+        None => return,
+    };
+    drop(sender);
+
+    let result = match stream.send(&request).await {
+        Ok(()) => stream.close().await,
+        Err(e) => Err(e),
+    };
+    let _ = reply.send(result);
+    let drain = async { while stream.next().await.is_some() {} };
+    if time::timeout(CANCEL_DRAIN_TIMEOUT, drain).await.is_err() {
+        info!("Server didn't close the cancelled stream in time, closing");
     }
 }
 
@@ -93,9 +646,11 @@ pub mod utils {
             _cx: &mut std::task::Context<'_>,
             buf: &mut tokio::io::ReadBuf<'_>,
         ) -> Poll<std::io::Result<()>> {
-            let len = buf.capacity();
+            // if there is no data, nothing is read, which means the stream is closed
+            let this = self.get_mut();
+            let len = buf.remaining().min(this.buf.len());
 
-            let data = self.get_mut().buf.split_to(len);
+            let data = this.buf.split_to(len);
 
             buf.put_slice(&data);
             Poll::Ready(Ok(()))
@@ -131,11 +686,15 @@ pub mod utils {
 #[cfg(test)]
 mod tests {
     use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     use bytes::Bytes;
     use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
 
-    use crate::{assert_response_ok, MemTable, ServiceInner, Value};
+    use crate::{
+        assert_response_ok, Broadcaster, DurableTopic, ErrorCode, KeyspaceNotifier, MemTable, ServiceInner, Value,
+    };
 
     use super::*;
 
@@ -163,6 +722,28 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_server_pipeline_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let requests = vec![
+            CommandRequest::new_hset("table", "k1", "v1".into()),
+            CommandRequest::new_hset("table", "k1", "v2".into()),
+            CommandRequest::new_hget("table", "k1"),
+        ];
+        let responses = client.execute_pipeline(&requests).await?;
+
+        assert_eq!(responses.len(), 3);
+        assert_response_ok(&responses[0], &[Value::default()], &[]);
+        assert_response_ok(&responses[1], &["v1".into()], &[]);
+        assert_response_ok(&responses[2], &["v2".into()], &[]);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn client_server_compression_should_work() -> anyhow::Result<()> {
         let addr = start_server().await?;
@@ -171,7 +752,7 @@ mod tests {
         let mut client = ProstClientStream::new(stream);
 
         let v: Value = Bytes::from(vec![0u8; 16384]).into();
-        let request = CommandRequest::new_hset("table", "key", v.clone().into());
+        let request = CommandRequest::new_hset("table", "key", v.clone());
         let response = client.execute_unary(&request).await?;
 
         assert_response_ok(&response, &[Value::default()], &[]);
@@ -180,11 +761,497 @@ mod tests {
         let response = client.execute_unary(&request).await?;
 
         // second time, response should be the first value
-        assert_response_ok(&response, &[v.into()], &[]);
+        assert_response_ok(&response, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_without_compression_should_work_with_compressing_server() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+        client.set_compression(false);
+
+        // the request is sent as it is, the compressed response is still decoded
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        client.execute_unary(&CommandRequest::new_hset("table", "key", v.clone())).await?;
+        assert!(client.stats().bytes_written > 16384);
+        let response = client.execute_unary(&CommandRequest::new_hget("table", "key")).await?;
+        assert_response_ok(&response, &[v], &[]);
+        assert!(client.stats().bytes_read < 16384);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_chunked_transfer_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let data = Bytes::from((0..10_000u32).map(|i| i as u8).collect::<Vec<_>>());
+        client.set_chunked("blobs", "big", data.clone(), 1024).await?;
+        assert_eq!(client.get_chunked("blobs", "big", 3000).await?, data);
+
+        // the value is a normal binary value once it's uploaded
+        let response = client.execute_unary(&CommandRequest::new_hget("blobs", "big")).await?;
+        assert_response_ok(&response, &[data.into()], &[]);
+
+        client.set_chunked("blobs", "empty", Bytes::new(), 1024).await?;
+        assert_eq!(client.get_chunked("blobs", "empty", 0).await?, Bytes::new());
+
+        let result = client.get_chunked("blobs", "missing", 0).await;
+        assert!(matches!(result, Err(KvError::ServerError(404, _))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_chunked_get_all_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+
+        for i in 0..10 {
+            let request = CommandRequest::new_hset("big", format!("k{}", i), i.into());
+            client.execute_unary(&request).await?;
+        }
+
+        let chunks: Vec<_> = client.get_all_chunked("big", 4).collect().await;
+        let sizes: Vec<_> = chunks.iter().map(|chunk| chunk.as_ref().unwrap().len()).collect();
+        assert_eq!(sizes, vec![4, 4, 2]);
+
+        // an empty table is one empty chunk, and the connection is still usable after the stream
+        let chunks: Vec<_> = client.get_all_chunked("empty", 4).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].as_ref().unwrap().is_empty());
+        let response = client.execute_unary(&CommandRequest::new_hget("big", "k1")).await?;
+        assert_response_ok(&response, &[1.into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_dropped_during_a_stream_response_should_end_the_connection_with_error() -> anyhow::Result<()> {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        for i in 0..100 {
+            service.execute(CommandRequest::new_hset("big", format!("k{}", i), i.into())).next().await;
+        }
+        // the small buffer fills up, so the server is still sending the chunks when the client is dropped
+        let (client, server) = tokio::io::duplex(64);
+        let server = tokio::spawn(ProstServerStream::new(server, service).process());
+
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        client.send(&CommandRequest::new_hget_all_chunked("big", 1)).await?;
+        assert_eq!(client.next().await.unwrap()?.status, 200);
+        drop(client);
+
+        let result = time::timeout(Duration::from_secs(1), server).await??;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_stream_should_work() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        // a proxy which rejects the deletes and forwards the other requests to the service
+        tokio::spawn(async move {
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut requests = ProstServerStream::new(stream, service.clone()).into_request_stream();
+            while let Some(Ok(request)) = requests.next().await {
+                if request.command_name() == "hdel" {
+                    let response = KvError::ReadOnly.into();
+                    requests.respond(&response).await.unwrap();
+                    continue;
+                }
+                let mut response = service.execute(request);
+                while let Some(data) = response.next().await {
+                    requests.respond(&data).await.unwrap();
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let response = client.execute_unary(&CommandRequest::new_hset("table", "key", "value".into())).await?;
+        assert_response_ok(&response, &[Value::default()], &[]);
+        let response = client.execute_unary(&CommandRequest::new_hdel("table", "key")).await?;
+        assert_eq!(response.status, 403);
+        let response = client.execute_unary(&CommandRequest::new_hget("table", "key")).await?;
+        assert_response_ok(&response, &["value".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_push_should_work() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel(8);
+
+        tokio::spawn(async move {
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let (stream, _) = listener.accept().await.unwrap();
+            let server = ProstServerStream::new(stream, service).with_push(receiver);
+            server.process().await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // the client receives the pushed message without sending a request
+        let notice: CommandResponse = Value::from("draining").into();
+        sender.send(notice.clone()).await?;
+        let data = client.inner.next().await.unwrap()?;
+        assert_eq!(data, notice);
+
+        // normal requests still work after the push channel is closed
+        drop(sender);
+        let request = CommandRequest::new_hset("table", "key", "value".into());
+        let response = client.execute_unary(&request).await?;
+        assert_response_ok(&response, &[Value::default()], &[]);
 
         Ok(())
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn idle_connection_should_be_closed() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let server = ProstServerStream::new(server, service).with_idle_timeout(Duration::from_millis(50));
+        let server = tokio::spawn(server.process());
+        let mut client = ProstClientStream::new(client);
+
+        // the requests in time keep the connection alive, though they're 60ms apart in total
+        for key in ["k1", "k2"] {
+            time::sleep(Duration::from_millis(30)).await;
+            let request = CommandRequest::new_hset("table", key, "value".into());
+            let response = client.execute_unary(&request).await?;
+            assert_response_ok(&response, &[Value::default()], &[]);
+        }
+
+        // no more requests, the server should close the connection after the timeout
+        let start = Instant::now();
+        let result = time::timeout(Duration::from_secs(1), server).await?;
+        assert!(result?.is_ok());
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resubscribing_stream_should_reconnect() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        // send the connection tasks out, so the test can break the connections
+        let (conn_tx, mut conn_rx) = mpsc::unbounded_channel();
+        let svc = service.clone();
+        let acceptor = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                // reset the connection when its task is aborted, a graceful close doesn't resubscribe
+                stream.set_zero_linger().unwrap();
+                let server = ProstServerStream::new(stream, svc.clone());
+                conn_tx.send(tokio::spawn(server.process())).unwrap();
+            }
+        });
+
+        let connector = move || async move { Ok(TcpStream::connect(addr).await?) };
+        let mut stream = ResubscribingStream::new(connector, CommandRequest::new_subscribe("lobby"))
+            .await?
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(40))
+            .with_max_attempts(3);
+        let first_id = stream.id().unwrap();
+
+        publish(&service, "lobby", "before").await;
+        let data = time::timeout(Duration::from_secs(1), stream.next()).await?.unwrap()?;
+        assert_eq!(data.values, vec![Value::from("before")]);
+
+        // break the connection, the stream should subscribe again on a new connection
+        conn_rx.recv().await.unwrap().abort();
+        let publisher = {
+            let service = service.clone();
+            tokio::spawn(async move {
+                loop {
+                    publish(&service, "lobby", "after").await;
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+        let data = time::timeout(Duration::from_secs(1), stream.next()).await?.unwrap()?;
+        assert_eq!(data.values, vec![Value::from("after")]);
+        assert_ne!(stream.id(), Some(first_id));
+        publisher.abort();
+
+        // the server is gone, give up after max attempts
+        acceptor.abort();
+        conn_rx.recv().await.unwrap().abort();
+        let result = time::timeout(Duration::from_secs(1), stream.next()).await?;
+        assert!(matches!(result, Some(Err(_))));
+        assert!(stream.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resubscribing_stream_should_end_when_the_server_closes_the_connection() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        // a server which sends one message to the subscriber, then closes the connection gracefully
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = ProstStream::<_, CommandRequest, CommandResponse>::new(stream);
+                stream.next().await.unwrap().unwrap();
+                stream.send(&Value::from(1).into()).await.unwrap();
+                stream.send(&Value::from("hello").into()).await.unwrap();
+                stream.close().await.unwrap();
+            }
+        });
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let connector = move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move { Ok(TcpStream::connect(addr).await?) }
+        };
+        let mut stream = ResubscribingStream::new(connector, CommandRequest::new_subscribe("lobby"))
+            .await?
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(40));
+
+        let data = time::timeout(Duration::from_secs(1), stream.next()).await?.unwrap()?;
+        assert_eq!(data.values, vec![Value::from("hello")]);
+        assert!(time::timeout(Duration::from_secs(1), stream.next()).await?.is_none());
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_workers_should_not_block_on_slow_requests() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            // requests for the `slow` table take a while
+            let service: Service = ServiceInner::new(MemTable::new())
+                .fn_received_async(|request: &CommandRequest| {
+                    let slow = request.table() == "slow";
+                    Box::pin(async move {
+                        if slow {
+                            time::sleep(Duration::from_millis(100)).await;
+                        }
+                    })
+                })
+                .into();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, service.clone()).with_workers(4);
+                tokio::spawn(server.process());
+            }
+        });
+
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+
+        // with request ids, the fast request is answered first
+        let requests = vec![
+            CommandRequest::new_hget("slow", "k1").with_request_id(1),
+            CommandRequest::new_hset("fast", "k1", "v1".into()).with_request_id(2),
+        ];
+        let responses = client.execute_pipeline(&requests).await?;
+        let ids: Vec<_> = responses.iter().map(|r| r.request_id).collect();
+        assert_eq!(ids, vec![2, 1]);
+
+        // without request ids, the responses keep the request order
+        let requests = vec![
+            CommandRequest::new_hset("slow", "k1", "v1".into()),
+            CommandRequest::new_hget("fast", "k1"),
+        ];
+        let responses = client.execute_pipeline(&requests).await?;
+        assert_response_ok(&responses[0], &[Value::default()], &[]);
+        assert_response_ok(&responses[1], &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelled_stream_should_unsubscribe_on_server() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let broadcaster = Arc::new(Broadcaster::default());
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let service = service.with_topic(Arc::clone(&broadcaster));
+
+        let svc = service.clone();
+        tokio::spawn(async move {
+            // the first connection runs the requests one by one, the other concurrently
+            for workers in [1, 4] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let server = ProstServerStream::new(stream, svc.clone()).with_workers(workers);
+                tokio::spawn(server.process());
+            }
+        });
+
+        for _ in 0..2 {
+            let client = ProstClientStream::new(TcpStream::connect(addr).await?);
+            let mut stream = client.execute_streaming(&CommandRequest::new_subscribe("lobby")).await?;
+            publish(&service, "lobby", "hello").await;
+            let data = time::timeout(Duration::from_secs(1), stream.next()).await?.unwrap()?;
+            assert_eq!(data.values, vec![Value::from("hello")]);
+            assert!(broadcaster.has_topic("lobby"));
+
+            stream.cancel().await?;
+            time::timeout(Duration::from_secs(1), async {
+                while broadcaster.has_topic("lobby") {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_serve_a_service_with_durable_topic() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), MemTable::new()));
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let service = service.with_topic(Arc::clone(&topic));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+            }
+        });
+
+        let client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let mut stream = client.execute_streaming(&CommandRequest::new_subscribe("lobby")).await?;
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let request = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        assert_response_ok(&client.execute_unary(&request).await?, &[], &[]);
+
+        let data = time::timeout(Duration::from_secs(1), stream.next()).await?.unwrap()?;
+        assert_eq!(data.values, vec![Value::from("hello")]);
+        // the message published over the connection is logged by the topic
+        let messages = topic.replay("lobby", 0, 10)?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].1.values, vec![Value::from("hello")]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_should_receive_changes_made_by_another_client() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        // the store publishes the changes to the topic the watchers are subscribed to
+        let topic = Arc::new(Broadcaster::default());
+        let store = KeyspaceNotifier::new(MemTable::new(), Arc::clone(&topic));
+        let service = Service::from(ServiceInner::new(store)).with_topic(topic);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+            }
+        });
+
+        let watcher = ProstClientStream::new(TcpStream::connect(addr).await?);
+        let mut stream = watcher.execute_streaming(&CommandRequest::new_watch("score", "math")).await?;
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        client.execute_unary(&CommandRequest::new_hset("score", "math", 10.into())).await?;
+
+        let data = time::timeout(Duration::from_secs(1), stream.next()).await?.unwrap()?;
+        assert_response_ok(&data, &["set".into(), 10.into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_should_be_authenticated_before_other_commands() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).fn_verify_token(|token| token == "secret").into();
+        tokio::spawn(async move {
+            // the first connection runs the requests one by one, the others concurrently
+            for workers in [1, 4] {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, service.clone()).with_workers(workers).process());
+            }
+        });
+
+        for _ in 0..2 {
+            let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+            let response = client.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+            assert_eq!(response.status, 401);
+            assert_eq!(response.error_code, ErrorCode::Unauthorized as i32);
+            let response = client.execute_unary(&CommandRequest::new_auth("wrong")).await?;
+            assert_eq!(response.status, 401);
+
+            // the requests sent right after a valid Auth are executed
+            let requests = vec![CommandRequest::new_auth("secret"), CommandRequest::new_hget("t1", "k1")];
+            let responses = client.execute_pipeline(&requests).await?;
+            assert_response_ok(&responses[0], &[], &[]);
+            // the rejected Hset wrote nothing
+            assert_eq!(responses[1].status, 404);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_streams_should_share_the_connection_authentication() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).fn_verify_token(|token| token == "secret").into();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                // one session per connection, shared by its streams
+                let service = service.new_session();
+                YamuxCtrl::new_server(stream, None, move |s| {
+                    let server = ProstServerStream::new_in_session(s.compat(), service.clone());
+                    async move {
+                        let _ = server.process().await;
+                        Ok(())
+                    }
+                });
+            }
+        });
+
+        let mut ctrl = YamuxCtrl::new_client(TcpStream::connect(addr).await?, None);
+        let mut client = ProstClientStream::new(ctrl.open_stream().await?);
+        let response = client.execute_unary(&CommandRequest::new_auth("secret")).await?;
+        assert_response_ok(&response, &[], &[]);
+
+        // another stream of the authenticated connection
+        let mut client = ProstClientStream::new(ctrl.open_stream().await?);
+        let response = client.execute_unary(&CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(response.status, 404);
+
+        // another connection must send its own Auth
+        let mut ctrl = YamuxCtrl::new_client(TcpStream::connect(addr).await?, None);
+        let mut client = ProstClientStream::new(ctrl.open_stream().await?);
+        let response = client.execute_unary(&CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(response.status, 401);
+        Ok(())
+    }
+
+    async fn publish(service: &Service, topic: &str, data: &str) {
+        let request = CommandRequest::new_publish(topic, vec![data.into()]);
+        let _ = service.execute(request).next().await;
+    }
+
     async fn start_server() -> anyhow::Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await?;
         let addr = listener.local_addr()?;
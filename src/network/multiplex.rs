@@ -1,11 +1,21 @@
 use std::future::Future;
 use std::marker::PhantomData;
+use std::time::Duration;
 
 use futures::{future, TryStreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time;
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::warn;
 use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
 
+/// the config used if the caller doesn't provide one, window updates are sent after the data is read
+pub fn default_yamux_config() -> Config {
+    let mut config = Config::default();
+    config.set_window_update_mode(WindowUpdateMode::OnRead);
+    config
+}
+
 /// Yamux control structure
 pub struct YamuxCtrl<S> {
     /// yamux control, use it to create new stream
@@ -17,12 +27,13 @@ impl<S> YamuxCtrl<S>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static
 {
-    /// create yamux client
+    /// create yamux client, if config is None, use default_yamux_config(), otherwise the config is used as is
     pub fn new_client(stream: S, config: Option<Config>) -> Self {
         Self::new(stream, config, true, |_stream| future::ready(Ok(())))
     }
 
     /// create yamux server, we need to handle the stream in the serverside
+    /// if config is None, use default_yamux_config(), otherwise the config is used as is
     pub fn new_server<F, Fut>(stream: S, config: Option<Config>, f: F) -> Self
         where
             F: FnMut(yamux::Stream) -> Fut,
@@ -44,9 +55,8 @@ impl<S> YamuxCtrl<S>
             Mode::Server
         };
 
-        // create config
-        let mut config = config.unwrap_or_default();
-        config.set_window_update_mode(WindowUpdateMode::OnRead);
+        // don't override the caller's settings, e.g. a bigger window for bulk transfers
+        let config = config.unwrap_or_else(default_yamux_config);
 
         // yamux:Stream used futures's strait, so we need to compat() to tokio's trait
         let conn = Connection::new(stream.compat(), config, mode);
@@ -58,7 +68,7 @@ impl<S> YamuxCtrl<S>
 
         Self {
             ctrl,
-            _conn: PhantomData::default(),
+            _conn: PhantomData,
         }
     }
 
@@ -66,6 +76,27 @@ impl<S> YamuxCtrl<S>
         let stream = self.ctrl.open_stream().await?;
         Ok(stream.compat())
     }
+
+    /// open a new stream, if there are too many streams, retry with exponential backoff
+    pub async fn open_stream_with_retry(
+        &mut self,
+        max_retries: usize,
+        backoff: Duration,
+    ) -> Result<Compat<yamux::Stream>, ConnectionError> {
+        let mut delay = backoff;
+        let mut retries = 0;
+        loop {
+            match self.open_stream().await {
+                Err(ConnectionError::TooManyStreams) if retries < max_retries => {
+                    warn!("Too many streams, retry to open stream in {:?}", delay);
+                    time::sleep(delay).await;
+                    retries += 1;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -73,14 +104,13 @@ mod tests {
     use std::net::SocketAddr;
 
     use anyhow::Result;
-    use futures::AsyncReadExt;
+    use futures::StreamExt;
     use tokio::net::{TcpListener, TcpStream};
     use tokio_rustls::server;
     use tracing::warn;
 
-    use crate::{assert_response_ok, CommandRequest, KvError, MemTable, ProstClientStream, ProstServerStream, Service, ServiceInner, Storage, TlsServerAcceptor};
+    use crate::{assert_response_ok, CommandRequest, KvError, KvPair, MemTable, ProstClientStream, ProstServerStream, Service, ServiceInner, Storage, TlsServerAcceptor};
     use crate::network::tls::tls_utils::{tls_acceptor, tls_connector};
-    use crate::utils::DummyStream;
 
     use super::*;
 
@@ -128,10 +158,12 @@ mod tests {
             Service: From<ServiceInner<Store>>
     {
         let f = |stream, service: Service| {
+            // all streams of the connection share its session
+            let service = service.new_session();
             YamuxCtrl::new_server(stream, None, move |s| {
                 let svc = service.clone();
                 async move {
-                    let stream = ProstServerStream::new(s.compat(), svc);
+                    let stream = ProstServerStream::new_in_session(s.compat(), svc);
                     stream.process().await.unwrap();
                     Ok(())
                 }
@@ -165,4 +197,109 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn yamux_streams_should_share_the_connection_session() -> Result<()> {
+        let acceptor = tls_acceptor(false)?;
+        let addr = start_yamux_server("127.0.0.1:0", acceptor, MemTable::new()).await?;
+
+        let connector = tls_connector(false)?;
+        let stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+
+        let subscriber = ProstClientStream::new(ctrl.open_stream().await?);
+        let subscription = subscriber.execute_streaming(&CommandRequest::new_subscribe("lobby")).await?;
+        let id = subscription.id;
+
+        // another stream of the connection lists the subscription
+        let mut client = ProstClientStream::new(ctrl.open_stream().await?);
+        let res = client.execute_unary(&CommandRequest::new_subscriptions()).await?;
+        assert_response_ok(&res, &[], &[KvPair::new("lobby", (id as i64).into())]);
+
+        // another connection has its own session
+        let stream = tls_connector(false)?.connect(TcpStream::connect(addr).await?).await?;
+        let mut other = YamuxCtrl::new_client(stream, None);
+        let mut other = ProstClientStream::new(other.open_stream().await?);
+        let res = other.execute_unary(&CommandRequest::new_subscriptions()).await?;
+        assert_response_ok(&res, &[], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_unsubscribe_all_should_remove_the_subscriptions_of_other_streams() -> Result<()> {
+        let acceptor = tls_acceptor(false)?;
+        let addr = start_yamux_server("127.0.0.1:0", acceptor, MemTable::new()).await?;
+
+        let connector = tls_connector(false)?;
+        let stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+
+        let subscriber = ProstClientStream::new(ctrl.open_stream().await?);
+        let mut subscription = subscriber.execute_streaming(&CommandRequest::new_subscribe("lobby")).await?;
+
+        let mut client = ProstClientStream::new(ctrl.open_stream().await?);
+        let res = client.execute_unary(&CommandRequest::new_unsubscribe_all()).await?;
+        assert_response_ok(&res, &[1.into()], &[]);
+
+        // the subscription made on the other stream is removed, so it gets no more messages
+        let res = client.execute_unary(&CommandRequest::new_subscriptions()).await?;
+        assert_response_ok(&res, &[], &[]);
+        client.execute_unary(&CommandRequest::new_publish("lobby", vec!["hello".into()])).await?;
+        let next = time::timeout(Duration::from_millis(100), subscription.next()).await;
+        assert!(!matches!(next, Ok(Some(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_ctrl_with_custom_window_should_work() -> Result<()> {
+        let acceptor = tls_acceptor(false)?;
+        let addr = start_yamux_server("127.0.0.1:0", acceptor, MemTable::new()).await?;
+
+        let connector = tls_connector(false)?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(stream).await?;
+
+        let mut config = Config::default();
+        config.set_receive_window(16 * 1024 * 1024);
+        config.set_max_buffer_size(16 * 1024 * 1024);
+        config.set_window_update_mode(WindowUpdateMode::OnReceive);
+        let mut ctrl = YamuxCtrl::new_client(stream, Some(config));
+
+        let stream = ctrl.open_stream().await?;
+        let mut client = ProstClientStream::new(stream);
+
+        // bigger than the default 256KB window
+        let value: crate::Value = bytes::Bytes::from(vec![1u8; 1024 * 1024]).into();
+        client.execute_unary(&CommandRequest::new_hset("t1", "k1", value.clone())).await?;
+        let res = client.execute_unary(&CommandRequest::new_hget("t1", "k1")).await?;
+        assert_response_ok(&res, &[value], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yamux_open_stream_with_retry_should_backoff() -> Result<()> {
+        let acceptor = tls_acceptor(false)?;
+        let addr = start_yamux_server("127.0.0.1:0", acceptor, MemTable::new()).await?;
+
+        let connector = tls_connector(false)?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(stream).await?;
+
+        let mut config = Config::default();
+        config.set_max_num_streams(1);
+        let mut ctrl = YamuxCtrl::new_client(stream, Some(config));
+
+        let _stream = ctrl.open_stream_with_retry(2, Duration::from_millis(10)).await?;
+
+        // the stream limit is reached, it should retry 2 times (10ms + 20ms) then fail
+        let start = time::Instant::now();
+        let result = ctrl.open_stream_with_retry(2, Duration::from_millis(10)).await;
+        assert!(matches!(result, Err(ConnectionError::TooManyStreams)));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,351 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+use crate::{DrainController, KvError, ProstServerStream, Service, Storage, TlsServerAcceptor, Topic};
+
+// how many connections the server handles at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimit {
+    Unlimited,
+    // stop accepting until a connection is closed, the new connections wait in the listen backlog
+    Wait(usize),
+    // accept the new connections and close them immediately
+    Reject(usize),
+}
+
+// a listener of run_listeners, the connections are plaintext unless it has a TLS acceptor
+pub struct ServerListener {
+    listener: TcpListener,
+    tls: Option<TlsServerAcceptor>,
+    limit: ConnectionLimit,
+}
+
+impl ServerListener {
+    pub fn new(listener: TcpListener) -> Self {
+        Self { listener, tls: None, limit: ConnectionLimit::Unlimited }
+    }
+
+    pub fn with_tls(mut self, acceptor: TlsServerAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    // the connections are limited per listener, default is Unlimited
+    pub fn with_limit(mut self, limit: ConnectionLimit) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    fn run<Store, T>(
+        self,
+        service: Service<Store, T>,
+        drain: DrainController,
+    ) -> BoxFuture<'static, Result<(), KvError>>
+        where
+            Store: Storage + Send + Sync + 'static,
+            T: Topic,
+    {
+        let Self { listener, tls, limit } = self;
+        match tls {
+            Some(tls) => run_server_with_drain(listener, service, limit, drain, move |stream| {
+                let tls = tls.clone();
+                async move { tls.accept(stream).await }
+            })
+            .boxed(),
+            None => run_server_with_drain(listener, service, limit, drain, |stream| async move { Ok(stream) }).boxed(),
+        }
+    }
+}
+
+// serve one service on all the listeners, e.g. TLS for the clients and plaintext for the local health checks
+// return once all listeners stop because the controller drains, or on the first listener's error, the others stop then
+pub async fn run_listeners<Store, T>(
+    listeners: Vec<ServerListener>,
+    service: Service<Store, T>,
+    drain: DrainController,
+) -> Result<(), KvError>
+    where
+        Store: Storage + Send + Sync + 'static,
+        T: Topic,
+{
+    let servers = listeners.into_iter().map(|listener| listener.run(service.clone(), drain.clone()));
+    future::try_join_all(servers).await.map(|_| ())
+}
+
+// serve the service on every connection accepted from the listener, at most `limit` connections at the same time
+// `accept` prepares the accepted socket, e.g. the TLS handshake, it runs in the connection's task,
+// so a slow or failed handshake doesn't block the other connections. the handshake counts against the limit
+pub async fn run_server<S, F, Fut, Store, T>(
+    listener: TcpListener,
+    service: Service<Store, T>,
+    limit: ConnectionLimit,
+    accept: F,
+) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Fn(TcpStream) -> Fut,
+        Fut: Future<Output=Result<S, KvError>> + Send + 'static,
+        Store: Storage + Send + Sync + 'static,
+        T: Topic,
+{
+    run_server_with_drain(listener, service, limit, DrainController::new(), accept).await
+}
+
+// same as run_server, but return once the controller drains, the listener is closed then.
+// the accepted connections are processed until their current requests are done, see DrainController
+pub async fn run_server_with_drain<S, F, Fut, Store, T>(
+    listener: TcpListener,
+    service: Service<Store, T>,
+    limit: ConnectionLimit,
+    drain: DrainController,
+    accept: F,
+) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Fn(TcpStream) -> Fut,
+        Fut: Future<Output=Result<S, KvError>> + Send + 'static,
+        Store: Storage + Send + Sync + 'static,
+        T: Topic,
+{
+    let permits = match limit {
+        ConnectionLimit::Unlimited => None,
+        ConnectionLimit::Wait(max) | ConnectionLimit::Reject(max) => Some(Arc::new(Semaphore::new(max))),
+    };
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept_within_limit(&listener, &permits, limit) => accepted?,
+            _ = drain.draining() => {
+                info!("Server is draining, stop accepting connections");
+                return Ok(());
+            }
+        };
+        let Some((stream, addr, permit)) = accepted else {
+            continue;
+        };
+        info!("Got connection from {:?}", addr);
+
+        let stream = accept(stream);
+        let service = service.clone();
+        let drain = drain.clone();
+        tokio::spawn(async move {
+            let result = match stream.await {
+                Ok(stream) => ProstServerStream::new(stream, service).with_drain(&drain).process().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Failed to process connection from {:?}: {:?}", addr, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+// accept the next connection with a permit if the connections are limited, None if it's rejected
+async fn accept_within_limit(
+    listener: &TcpListener,
+    permits: &Option<Arc<Semaphore>>,
+    limit: ConnectionLimit,
+) -> Result<Option<(TcpStream, SocketAddr, Option<OwnedSemaphorePermit>)>, KvError> {
+    // the semaphore is never closed, so acquiring can't fail
+    let permit = match (permits, limit) {
+        (Some(permits), ConnectionLimit::Wait(_)) => Some(Arc::clone(permits).acquire_owned().await.unwrap()),
+        _ => None,
+    };
+    let (stream, addr) = listener.accept().await?;
+    let permit = match (permits, limit) {
+        (Some(permits), ConnectionLimit::Reject(max)) => match Arc::clone(permits).try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                warn!("Reject connection from {:?}, already {} connections", addr, max);
+                return Ok(None);
+            }
+        },
+        _ => permit,
+    };
+    Ok(Some((stream, addr, permit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use futures::StreamExt;
+    use tempfile::tempdir;
+    use tokio::time;
+
+    use crate::{Broadcaster, CommandRequest, DurableTopic, MemTable, ProstClientStream, ServiceInner, SledDb};
+    use crate::network::tls::tls_utils::{tls_acceptor, tls_connector};
+
+    use super::*;
+
+    async fn start_server(limit: ConnectionLimit) -> Result<SocketAddr> {
+        start_server_with_drain(limit, DrainController::new()).await
+    }
+
+    async fn start_server_with_drain(limit: ConnectionLimit, drain: DrainController) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(run_server_with_drain(listener, service, limit, drain, |stream| async move { Ok(stream) }));
+        Ok(addr)
+    }
+
+    async fn ping(client: &mut ProstClientStream<TcpStream>) -> Result<(), KvError> {
+        client.execute_unary(&CommandRequest::new_ping("")).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn connections_over_limit_should_wait() -> Result<()> {
+        let addr = start_server(ConnectionLimit::Wait(1)).await?;
+
+        let mut first = ProstClientStream::new(TcpStream::connect(addr).await?);
+        ping(&mut first).await?;
+
+        // the second connection is in the backlog until the first one is closed
+        let mut second = ProstClientStream::new(TcpStream::connect(addr).await?);
+        assert!(time::timeout(Duration::from_millis(50), ping(&mut second)).await.is_err());
+        drop(first);
+        time::timeout(Duration::from_secs(1), ping(&mut second)).await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connections_over_limit_should_be_rejected() -> Result<()> {
+        let addr = start_server(ConnectionLimit::Reject(1)).await?;
+
+        let mut first = ProstClientStream::new(TcpStream::connect(addr).await?);
+        ping(&mut first).await?;
+
+        let mut second = ProstClientStream::new(TcpStream::connect(addr).await?);
+        assert!(ping(&mut second).await.is_err());
+        drop(first);
+
+        // the slot is free again after the first connection is closed
+        time::timeout(Duration::from_secs(1), async {
+            loop {
+                let mut client = ProstClientStream::new(TcpStream::connect(addr).await.unwrap());
+                if ping(&mut client).await.is_ok() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_serve_a_sled_service() -> Result<()> {
+        let dir = tempdir()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service: Service<SledDb> = ServiceInner::new(SledDb::new(dir.path())).into();
+        tokio::spawn(run_server(listener, service, ConnectionLimit::Wait(8), |stream| async move { Ok(stream) }));
+
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        client.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        let data = client.execute_unary(&CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(data.values, vec!["v1".into()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_should_stop_accepting_and_close_idle_connections() -> Result<()> {
+        let drain = DrainController::new();
+        let addr = start_server_with_drain(ConnectionLimit::Unlimited, drain.clone()).await?;
+        let mut client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        ping(&mut client).await?;
+        assert_eq!(drain.connections(), 1);
+
+        // the idle connection is closed right away
+        assert!(drain.drain(Duration::from_secs(1)).await);
+        assert!(ping(&mut client).await.is_err());
+        time::sleep(Duration::from_millis(10)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drain_should_wait_for_running_streams() -> Result<()> {
+        let drain = DrainController::new();
+        let addr = start_server_with_drain(ConnectionLimit::Unlimited, drain.clone()).await?;
+        let client = ProstClientStream::new(TcpStream::connect(addr).await?);
+        // the subscription id is received, the subscription is running on the server
+        let _stream = client.execute_streaming(&CommandRequest::new_subscribe("lobby")).await?;
+
+        // the subscription is not done, so the drain times out
+        assert!(!drain.drain(Duration::from_millis(50)).await);
+        assert_eq!(drain.connections(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listeners_should_share_one_service() -> Result<()> {
+        let plain = TcpListener::bind("127.0.0.1:0").await?;
+        let tls = TcpListener::bind("127.0.0.1:0").await?;
+        let (plain_addr, tls_addr) = (plain.local_addr()?, tls.local_addr()?);
+        let listeners = vec![
+            ServerListener::new(plain),
+            ServerListener::new(tls).with_tls(tls_acceptor(false)?).with_limit(ConnectionLimit::Wait(8)),
+        ];
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let drain = DrainController::new();
+        let server = tokio::spawn(run_listeners(listeners, service, drain.clone()));
+
+        let mut plain = ProstClientStream::new(TcpStream::connect(plain_addr).await?);
+        plain.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+
+        let stream = tls_connector(false)?.connect(TcpStream::connect(tls_addr).await?).await?;
+        let mut tls = ProstClientStream::new(stream);
+        let data = tls.execute_unary(&CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(data.values, vec!["v1".into()]);
+
+        // the TLS listener doesn't accept plaintext
+        let mut client = ProstClientStream::new(TcpStream::connect(tls_addr).await?);
+        assert!(ping(&mut client).await.is_err());
+
+        // both listeners stop when the server drains
+        drop((plain, tls));
+        assert!(drain.drain(Duration::from_secs(1)).await);
+        time::timeout(Duration::from_secs(1), server).await???;
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn listeners_should_serve_a_service_with_durable_topic() -> Result<()> {
+        let first = TcpListener::bind("127.0.0.1:0").await?;
+        let second = TcpListener::bind("127.0.0.1:0").await?;
+        let (first_addr, second_addr) = (first.local_addr()?, second.local_addr()?);
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), MemTable::new()));
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let service = service.with_topic(Arc::clone(&topic));
+        let listeners = vec![ServerListener::new(first), ServerListener::new(second)];
+        tokio::spawn(run_listeners(listeners, service, DrainController::new()));
+
+        // the subscriber and the publisher are connected to different listeners of the same service
+        let client = ProstClientStream::new(TcpStream::connect(first_addr).await?);
+        let mut stream = client.execute_streaming(&CommandRequest::new_subscribe("lobby")).await?;
+        let mut client = ProstClientStream::new(TcpStream::connect(second_addr).await?);
+        client.execute_unary(&CommandRequest::new_publish("lobby", vec!["hello".into()])).await?;
+
+        let data = time::timeout(Duration::from_secs(1), stream.next()).await?.unwrap()?;
+        assert_eq!(data.values, vec!["hello".into()]);
+        assert_eq!(topic.replay("lobby", 0, 10)?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -1,13 +1,30 @@
+use std::io::{self, ErrorKind};
 use std::marker::PhantomData;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use bytes::BytesMut;
-use futures::{FutureExt, ready, Sink, Stream};
+use futures::{ready, Sink, Stream};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{FrameCoder, KvError};
-use crate::network::frame::read_frame;
+use crate::network::frame::{DEFAULT_MAX_DECOMPRESSED_SIZE, DEFAULT_MAX_VALUE_DEPTH, poll_read_frame};
+
+// if the buffered data is more than this, flush it before buffering more
+const DEFAULT_WRITE_BUF_LIMIT: usize = 64 * 1024;
+
+// counters of the data moved by a ProstStream
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStats {
+    // bytes read from the inner stream, including the frame headers
+    pub bytes_read: u64,
+    // bytes written to the inner stream, including the frame headers
+    pub bytes_written: u64,
+    // frames decoded from the read data
+    pub frames_decoded: u64,
+    // frames encoded into the write buffer
+    pub frames_encoded: u64,
+}
 
 /// stream that handles KV server prost frame
 pub struct ProstStream<S, In, Out> {
@@ -17,8 +34,18 @@ pub struct ProstStream<S, In, Out> {
     write_buf: BytesMut,
     // how many bytes have been written
     written: usize,
+    // flush the write buffer before buffering more data if it's larger than this
+    write_buf_limit: usize,
     // read buffer
     read_buf: BytesMut,
+    // a compressed frame bigger than this after decompression is rejected
+    max_decompressed_size: usize,
+    // a frame with a value nested deeper than this is rejected
+    max_value_depth: usize,
+    // compress the big frames before sending them, the compressed frames are always decoded
+    compression: bool,
+    // how much data the stream has moved
+    stats: StreamStats,
 
     _in: PhantomData<In>,
     _out: PhantomData<Out>,
@@ -34,20 +61,26 @@ impl<S, In, Out> Stream for ProstStream<S, In, Out>
     type Item = Result<In, KvError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // after last time calling poll_next(), the read_buf should be empty
-        assert!(self.read_buf.is_empty());
-
-        // get rest from the read_buf, separate the buffer from self
-        let mut rest = self.read_buf.split_off(0);
-
-        // read a frame from the stream
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
+        let this = &mut *self;
 
-        // get data, merge the buffer
-        self.read_buf.unsplit(rest);
+        // the partial frame is kept in read_buf, so a dropped next() (e.g. in select!) loses no data
+        let before = this.read_buf.len();
+        let result = poll_read_frame(&mut this.stream, cx, &mut this.read_buf);
+        this.stats.bytes_read += (this.read_buf.len() - before) as u64;
+        match ready!(result) {
+            Ok(true) => {}
+            // the stream is closed, there is no more data
+            Ok(false) => return Poll::Ready(None),
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        }
 
-        Poll::Ready(Some(In::decode_frame(&mut self.read_buf)))
+        let result = In::decode_frame_with_limits(&mut this.read_buf, this.max_decompressed_size, this.max_value_depth);
+        // a frame which fails to decode may be left in the buffer, the next read starts from an empty one
+        this.read_buf.clear();
+        if result.is_ok() {
+            this.stats.frames_decoded += 1;
+        }
+        Poll::Ready(Some(result))
     }
 }
 
@@ -61,13 +94,18 @@ impl<S, In, Out> Sink<&Out> for ProstStream<S, In, Out>
     // if send() failed, return KvError
     type Error = KvError;
 
-    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // callers may buffer many frames with feed(), don't let the buffer grow unbounded
+        if self.write_buf.len() >= self.write_buf_limit {
+            return self.poll_flush(cx);
+        }
         Poll::Ready(Ok(()))
     }
 
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.write_buf)?;
+        item.encode_frame_with_compression(&mut this.write_buf, this.compression)?;
+        this.stats.frames_encoded += 1;
         Ok(())
     }
 
@@ -76,7 +114,12 @@ impl<S, In, Out> Sink<&Out> for ProstStream<S, In, Out>
 
         while this.written != this.write_buf.len() {
             let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.write_buf[this.written..]))?;
+            // the peer is closed, retrying would spin forever
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::new(ErrorKind::WriteZero, "failed to write frame").into()));
+            }
             this.written += n;
+            this.stats.bytes_written += n as u64;
         }
 
         // after flush, reset written to 0
@@ -104,11 +147,41 @@ impl<S, In, Out> ProstStream<S, In, Out>
             stream,
             write_buf: BytesMut::new(),
             written: 0,
+            write_buf_limit: DEFAULT_WRITE_BUF_LIMIT,
             read_buf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default(),
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            max_value_depth: DEFAULT_MAX_VALUE_DEPTH,
+            compression: true,
+            stats: StreamStats::default(),
+            _in: PhantomData,
+            _out: PhantomData,
         }
     }
+
+    // set how many bytes can be buffered before they are flushed automatically
+    pub fn set_write_buf_limit(&mut self, limit: usize) {
+        self.write_buf_limit = limit;
+    }
+
+    // set the biggest size a compressed frame can be decompressed to
+    pub fn set_max_decompressed_size(&mut self, size: usize) {
+        self.max_decompressed_size = size;
+    }
+
+    // set how deep the values of a received frame can be nested
+    pub fn set_max_value_depth(&mut self, depth: usize) {
+        self.max_value_depth = depth;
+    }
+
+    // set if the big frames are compressed before sending, the received compressed frames are decoded anyway
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    // how much data the stream has read and written so far
+    pub fn stats(&self) -> StreamStats {
+        self.stats
+    }
 }
 
 // in general, our ProstStream is Unpin
@@ -116,14 +189,44 @@ impl<S, In, Out> Unpin for ProstStream<S, In, Out> where S: Unpin {}
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use anyhow::Result;
     use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
 
-    use crate::CommandRequest;
+    use crate::{CommandRequest, FrameCoder};
     use crate::utils::DummyStream;
 
     use super::*;
 
+    // a stream whose peer is closed, nothing can be written to it
+    struct WriteZeroStream;
+
+    impl AsyncRead for WriteZeroStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for WriteZeroStream {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(0))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
     #[tokio::test]
     async fn prost_stream_should_work() -> Result<()> {
         let buf = BytesMut::new();
@@ -133,12 +236,109 @@ mod tests {
         let request = CommandRequest::new_hdel("table", "key");
         stream.send(&request).await?;
 
-        if let Some(Ok(s)) = stream.next().await {
-            assert_eq!(s, request);
-        } else {
-            assert!(false);
+        match stream.next().await {
+            Some(Ok(s)) => assert_eq!(s, request),
+            _ => panic!("should receive the request back"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_end_when_closed() {
+        let stream = DummyStream::default();
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn prost_stream_feed_should_buffer_until_flush() -> Result<()> {
+        let buf = BytesMut::new();
+        let stream = DummyStream { buf };
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+
+        let request = CommandRequest::new_hdel("table", "key");
+        stream.feed(&request).await?;
+        stream.feed(&request).await?;
+        assert!(stream.stream.buf.is_empty());
+
+        stream.flush().await?;
+        assert!(!stream.stream.buf.is_empty());
+        assert!(stream.write_buf.is_empty());
+
+        // when the limit is reached, the buffered data is flushed before buffering more
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(DummyStream::default());
+        stream.set_write_buf_limit(1);
+        stream.feed(&request).await?;
+        assert!(stream.stream.buf.is_empty());
+        stream.feed(&request).await?;
+        assert!(!stream.stream.buf.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_stats_should_count_bytes_and_frames() -> Result<()> {
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(DummyStream::default());
+        assert_eq!(stream.stats(), StreamStats::default());
+
+        let request = CommandRequest::new_hdel("table", "key");
+        stream.feed(&request).await?;
+        stream.feed(&request).await?;
+        // nothing is written before flush
+        assert_eq!(stream.stats().frames_encoded, 2);
+        assert_eq!(stream.stats().bytes_written, 0);
+
+        stream.flush().await?;
+        let written = stream.stream.buf.len() as u64;
+        assert_eq!(stream.stats().bytes_written, written);
+
+        stream.next().await.unwrap()?;
+        stream.next().await.unwrap()?;
+        let stats = stream.stats();
+        assert_eq!(stats.frames_decoded, 2);
+        assert_eq!(stats.bytes_read, written);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prost_stream_next_should_be_cancel_safe() -> Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = ProstStream::<_, CommandRequest, CommandRequest>::new(client);
+        let mut server = ProstStream::<_, CommandRequest, CommandRequest>::new(server);
+
+        let request = CommandRequest::new_hset("table", "key", "value".into());
+        let mut frame = BytesMut::new();
+        request.encode_frame(&mut frame)?;
+        let rest = frame.split_off(frame.len() / 2);
+
+        // the first half of the frame is read by a next() which is then dropped, like a select! branch losing
+        client.stream.write_all(&frame).await?;
+        tokio::select! {
+            _ = server.next() => panic!("the frame is not complete"),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
         }
+        assert!(!server.read_buf.is_empty());
+
+        client.stream.write_all(&rest).await?;
+        assert_eq!(server.next().await.unwrap()?, request);
+
+        // the stream is still in sync, the next frame is decoded too
+        client.send(&request).await?;
+        assert_eq!(server.next().await.unwrap()?, request);
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn prost_stream_should_fail_if_nothing_can_be_written() {
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(WriteZeroStream);
+
+        let request = CommandRequest::new_hdel("table", "key");
+        match stream.send(&request).await {
+            Err(KvError::IoError(e)) => assert_eq!(e.kind(), ErrorKind::WriteZero),
+            other => panic!("expect a write zero error, got {:?}", other),
+        }
+    }
 }
\ No newline at end of file
@@ -1,14 +1,37 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures::{Stream, StreamExt};
+use futures::future::BoxFuture;
+use futures::{ready, stream, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, warn};
 
-use crate::{CommandResponse, KvError};
+use crate::{CommandRequest, CommandResponse, KvError, ProstClientStream};
+
+// ask the connection of a stream to send the Unsubscribe, and report if it's sent
+pub(crate) type Cancel = (CommandRequest, oneshot::Sender<Result<(), KvError>>);
+
+// unsubscribe from the topic over the connection which made the subscription
+struct Canceller {
+    topic: String,
+    sender: mpsc::Sender<Cancel>,
+}
 
 /// get the subscription id, and use Deref/DerefMut to make it use like Stream
+/// dropping it closes the connection without unsubscribing, the server keeps the subscription
+/// until it fails to send to the closed connection or the topic is torn down, use cancel() to clean up
 pub struct StreamResult {
     pub id: u32,
     inner: Pin<Box<dyn Stream<Item=Result<CommandResponse, KvError>> + Send>>,
+    canceller: Option<Canceller>,
 }
 
 impl StreamResult {
@@ -36,8 +59,120 @@ impl StreamResult {
         Ok(StreamResult {
             id: id?,
             inner: Box::pin(stream),
+            canceller: None,
         })
     }
+
+    // make cancel() send the Unsubscribe of the topic to the connection behind the sender
+    pub(crate) fn with_canceller(mut self, topic: String, sender: mpsc::Sender<Cancel>) -> Self {
+        self.canceller = Some(Canceller { topic, sender });
+        self
+    }
+
+    /// unsubscribe on the server over the same connection, then close the connection
+    /// the server must execute requests concurrently (see ProstServerStream::with_workers) to read the Unsubscribe
+    /// while the subscription is running, otherwise the connection is closed after a short wait, like a drop
+    pub async fn cancel(self) -> Result<(), KvError> {
+        let StreamResult { id, inner, canceller } = self;
+        let canceller = canceller.ok_or_else(|| KvError::Internal("Stream can't be cancelled".into()))?;
+        let (reply, result) = oneshot::channel();
+        let request = CommandRequest::new_unsubscribe(canceller.topic, id);
+        canceller
+            .sender
+            .send((request, reply))
+            .await
+            .map_err(|_| KvError::Internal("Connection is closed".into()))?;
+        // stop reading the responses, the connection only sends the Unsubscribe from now on
+        drop(inner);
+
+        result
+            .await
+            .map_err(|_| KvError::Internal("Connection is closed".into()))?
+    }
+}
+
+/// what to do when a buffered StreamResult is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// drop the oldest buffered response to make room for the new one
+    DropOldest,
+    /// return a BufferOverflow error after the buffered responses, and stop reading
+    Error,
+}
+
+// responses read in the background, waiting for the consumer
+#[derive(Default)]
+struct Buffer {
+    queue: VecDeque<Result<CommandResponse, KvError>>,
+    // no more responses will be added
+    closed: bool,
+}
+
+// abort the background reader when the stream is dropped
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl StreamResult {
+    /// read the responses in the background, and keep at most `capacity` of them for a slow consumer
+    /// the server isn't blocked by the consumer, `policy` decides what happens when the buffer is full
+    pub fn with_buffer(self, capacity: usize, policy: OverflowPolicy) -> Self {
+        let buffer = Arc::new((Mutex::new(Buffer::default()), Notify::new()));
+        let reader = tokio::spawn(read_to_buffer(self.inner, Arc::clone(&buffer), capacity.max(1), policy));
+
+        let state = (buffer, AbortOnDrop(reader));
+        let inner = stream::unfold(state, |(buffer, reader)| async move {
+            loop {
+                {
+                    let mut state = buffer.0.lock().unwrap();
+                    if let Some(item) = state.queue.pop_front() {
+                        drop(state);
+                        return Some((item, (buffer, reader)));
+                    }
+                    if state.closed {
+                        return None;
+                    }
+                }
+                buffer.1.notified().await;
+            }
+        });
+
+        Self { id: self.id, inner: Box::pin(inner), canceller: self.canceller }
+    }
+}
+
+async fn read_to_buffer(
+    mut stream: Pin<Box<dyn Stream<Item=Result<CommandResponse, KvError>> + Send>>,
+    buffer: Arc<(Mutex<Buffer>, Notify)>,
+    capacity: usize,
+    policy: OverflowPolicy,
+) {
+    while let Some(item) = stream.next().await {
+        let overflowed = {
+            let mut state = buffer.0.lock().unwrap();
+            let full = state.queue.len() >= capacity;
+            match (full, policy) {
+                (true, OverflowPolicy::Error) => state.queue.push_back(Err(KvError::BufferOverflow(capacity))),
+                (true, OverflowPolicy::DropOldest) => {
+                    state.queue.pop_front();
+                    state.queue.push_back(item);
+                }
+                (false, _) => state.queue.push_back(item),
+            }
+            full && policy == OverflowPolicy::Error
+        };
+        buffer.1.notify_one();
+        if overflowed {
+            warn!("Subscriber buffer is full, stop reading");
+            break;
+        }
+    }
+    buffer.0.lock().unwrap().closed = true;
+    buffer.1.notify_one();
 }
 
 impl Deref for StreamResult {
@@ -52,4 +187,188 @@ impl DerefMut for StreamResult {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.inner
     }
-}
\ No newline at end of file
+}
+type Subscriber = Arc<dyn Fn() -> BoxFuture<'static, Result<StreamResult, KvError>> + Send + Sync>;
+
+enum State {
+    Streaming(StreamResult),
+    Resubscribing(BoxFuture<'static, Result<StreamResult, KvError>>),
+    Done,
+}
+
+/// a subscription which reconnects and subscribes again when the connection fails
+/// only a transport error (e.g. a reset connection) resubscribes. the stream ends when the server closes the connection
+/// gracefully (e.g. it's shutting down), and cancel() unsubscribes without resubscribing.
+/// it retries with exponential backoff, and ends with the last error after max attempts
+/// messages published while it is reconnecting are lost, drop it to stop the subscription
+pub struct ResubscribingStream {
+    subscriber: Subscriber,
+    state: State,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    // None means retry forever
+    max_attempts: Option<usize>,
+}
+
+impl ResubscribingStream {
+    /// connect with the connector and send the subscribe request, the connector is called again to reconnect
+    pub async fn new<S, F, Fut>(connector: F, request: CommandRequest) -> Result<Self, KvError>
+        where
+            S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+            F: Fn() -> Fut + Send + Sync + 'static,
+            Fut: Future<Output=Result<S, KvError>> + Send + 'static,
+    {
+        let subscriber: Subscriber = Arc::new(move || {
+            let connect = connector();
+            let request = request.clone();
+            Box::pin(async move {
+                let client = ProstClientStream::new(connect.await?);
+                client.execute_streaming(&request).await
+            })
+        });
+
+        let stream = subscriber().await?;
+        Ok(Self {
+            subscriber,
+            state: State::Streaming(stream),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        })
+    }
+
+    /// wait `initial` after the first failed attempt, and double it after each failure up to `max`
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// give up after `attempts` failed reconnects in a row
+    pub fn with_max_attempts(mut self, attempts: usize) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// the id of the current subscription, it changes after resubscribing
+    pub fn id(&self) -> Option<u32> {
+        match &self.state {
+            State::Streaming(stream) => Some(stream.id),
+            _ => None,
+        }
+    }
+
+    /// unsubscribe on the server and stop, see StreamResult::cancel
+    pub async fn cancel(self) -> Result<(), KvError> {
+        match self.state {
+            State::Streaming(stream) => stream.cancel().await,
+            _ => Ok(()),
+        }
+    }
+
+    fn resubscribe(&self) -> BoxFuture<'static, Result<StreamResult, KvError>> {
+        let subscriber = Arc::clone(&self.subscriber);
+        let (mut backoff, max_backoff, max_attempts) = (self.initial_backoff, self.max_backoff, self.max_attempts);
+        Box::pin(async move {
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                match subscriber().await {
+                    Ok(stream) => {
+                        info!("Resubscribed after {} attempts, new subscription id: {}", attempts, stream.id);
+                        return Ok(stream);
+                    }
+                    Err(e) if max_attempts.is_some_and(|max| attempts >= max) => return Err(e),
+                    Err(e) => {
+                        warn!("Failed to resubscribe (attempt {}): {:?}, retry in {:?}", attempts, e, backoff);
+                        time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(max_backoff);
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl Stream for ResubscribingStream {
+    type Item = Result<CommandResponse, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match &mut self.state {
+                State::Streaming(stream) => match ready!(stream.poll_next_unpin(cx)) {
+                    Some(Ok(response)) => return Poll::Ready(Some(Ok(response))),
+                    // the responses are Ok even if their status is an error, so an error is from the connection
+                    Some(Err(e)) => {
+                        warn!("Subscription stream failed: {:?}, resubscribing", e);
+                        self.state = State::Resubscribing(self.resubscribe());
+                    }
+                    // the server closed the connection on purpose, don't subscribe again
+                    None => {
+                        info!("Subscription stream is closed by the server");
+                        self.state = State::Done;
+                    }
+                },
+                State::Resubscribing(future) => match ready!(future.as_mut().poll(cx)) {
+                    Ok(stream) => self.state = State::Streaming(stream),
+                    Err(e) => {
+                        self.state = State::Done;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                },
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Value;
+
+    use super::*;
+
+    // a subscription with id 1 and the values 0..n
+    async fn subscription(n: i64) -> StreamResult {
+        let id: CommandResponse = Value::from(1).into();
+        let items = std::iter::once(id).chain((0..n).map(|i| Value::from(i).into())).map(Ok);
+        StreamResult::new(stream::iter(items.collect::<Vec<_>>())).await.unwrap()
+    }
+
+    async fn collect_values(mut stream: StreamResult) -> Vec<Result<i64, KvError>> {
+        let mut values = vec![];
+        while let Some(item) = stream.next().await {
+            values.push(item.and_then(|response| i64::try_from(&response)));
+        }
+        values
+    }
+
+    #[tokio::test]
+    async fn buffered_stream_should_drop_oldest() {
+        let stream = subscription(10).await.with_buffer(3, OverflowPolicy::DropOldest);
+        assert_eq!(stream.id, 1);
+        // let the background reader read everything
+        time::sleep(Duration::from_millis(50)).await;
+
+        let values: Vec<_> = collect_values(stream).await.into_iter().map(|v| v.unwrap()).collect();
+        assert_eq!(values, vec![7, 8, 9]);
+    }
+
+    #[tokio::test]
+    async fn buffered_stream_should_error_on_overflow() {
+        let stream = subscription(10).await.with_buffer(3, OverflowPolicy::Error);
+        time::sleep(Duration::from_millis(50)).await;
+
+        let values = collect_values(stream).await;
+        assert_eq!(values.len(), 4);
+        assert!(values[..3].iter().zip(0..).all(|(v, i)| matches!(v, Ok(x) if *x == i)));
+        assert!(matches!(values[3], Err(KvError::BufferOverflow(3))));
+    }
+
+    #[tokio::test]
+    async fn buffered_stream_should_pass_everything_if_not_full() {
+        let stream = subscription(5).await.with_buffer(16, OverflowPolicy::Error);
+        let values: Vec<_> = collect_values(stream).await.into_iter().map(|v| v.unwrap()).collect();
+        assert_eq!(values, vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -12,6 +12,9 @@ use crate::KvError;
 // KV server's own ALPN (Application Layer Protocol Negotiation)
 const ALPN_KV: &str = "kv";
 
+// DER encoded OID 1.2.840.10045.2.1 (ecPublicKey), the algorithm of an EC private key in PKCS8
+const EC_PUBLIC_KEY_OID: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+
 // Has a TLS ServerConfig, and have a method `accept` to convert lower protocol to TLS
 #[derive(Clone)]
 pub struct TlsServerAcceptor {
@@ -109,14 +112,67 @@ impl TlsServerAcceptor {
     }
 }
 
+// load the certs of a PEM, in the chain order rustls expects: the leaf first, then each cert's issuer
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
     let mut cert = Cursor::new(cert);
-    pemfile::certs(&mut cert)
-        .map_err(|_| KvError::CertificateParseError("server", "cert"))
+    let certs = pemfile::certs(&mut cert)
+        .map_err(|_| KvError::CertificateParseError("server", "cert"))?;
+    order_chain(certs)
 }
 
-fn load_key(key: &str) -> Result<PrivateKey, KvError> {
-    let mut key = Cursor::new(key);
+// reorder the certs so the leaf is first and every cert is followed by its issuer
+// the leaf is the only cert which doesn't issue another one, the root CA may be in the chain or not
+fn order_chain(certs: Vec<Certificate>) -> Result<Vec<Certificate>, KvError> {
+    if certs.len() < 2 {
+        return Ok(certs);
+    }
+    let names = certs
+        .iter()
+        .map(|cert| cert_names(&cert.0))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(KvError::CertificateChainError("cannot read the issuer or subject of a cert"))?;
+
+    let issues_another = |i: usize| (0..names.len()).any(|j| j != i && names[j].0 == names[i].1);
+    let leaves: Vec<usize> = (0..names.len()).filter(|i| !issues_another(*i)).collect();
+    let mut order = match leaves[..] {
+        [leaf] => vec![leaf],
+        [] => return Err(KvError::CertificateChainError("no leaf cert, the certs issue each other")),
+        _ => return Err(KvError::CertificateChainError("more than one leaf cert")),
+    };
+    while order.len() < names.len() {
+        let (issuer, subject) = names[order[order.len() - 1]];
+        // a self-signed root CA ends the chain
+        let next = (0..names.len()).find(|i| issuer != subject && !order.contains(i) && names[*i].1 == issuer);
+        match next {
+            Some(i) => order.push(i),
+            None => return Err(KvError::CertificateChainError("a cert is not in the chain of the leaf cert")),
+        }
+    }
+
+    let mut certs: Vec<_> = certs.into_iter().map(Some).collect();
+    Ok(order.into_iter().filter_map(|i| certs[i].take()).collect())
+}
+
+// get the issuer and subject of a DER cert (RFC 5280), as their DER encoding so they can be compared
+fn cert_names(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (_, cert, _) = der_decode(cert)?;
+    let (_, mut fields, _) = der_decode(cert)?;
+    let mut elements = Vec::new();
+    while !fields.is_empty() {
+        let (tag, _, rest) = der_decode(fields)?;
+        elements.push((tag, &fields[..fields.len() - rest.len()]));
+        fields = rest;
+    }
+    // the version is optional, then the serial number, signature algorithm, issuer, validity and subject
+    let start = match elements.first()? {
+        (0xa0, _) => 1,
+        _ => 0,
+    };
+    Some((elements.get(start + 2)?.1, elements.get(start + 4)?.1))
+}
+
+fn load_key(pem: &str) -> Result<PrivateKey, KvError> {
+    let mut key = Cursor::new(pem);
 
     // try PKCS8 to load private key first
     if let Ok(mut keys) = pemfile::pkcs8_private_keys(&mut key) {
@@ -133,18 +189,90 @@ fn load_key(key: &str) -> Result<PrivateKey, KvError> {
         }
     }
 
+    // try SEC1 EC key, e.g. generated by `openssl ecparam -genkey`
+    if let Ok(pems) = pem::parse_many(pem) {
+        let key = pems
+            .iter()
+            .filter(|pem| pem.tag == "EC PRIVATE KEY")
+            .find_map(|pem| sec1_to_pkcs8(&pem.contents));
+        if let Some(key) = key {
+            return Ok(PrivateKey(key));
+        }
+    }
+
     Err(KvError::CertificateParseError("private", "key"))
 }
 
+// rustls only loads EC keys in PKCS8, wrap the SEC1 key (RFC 5915) into a PKCS8 PrivateKeyInfo (RFC 5208)
+fn sec1_to_pkcs8(sec1: &[u8]) -> Option<Vec<u8>> {
+    let mut algorithm = EC_PUBLIC_KEY_OID.to_vec();
+    algorithm.extend_from_slice(sec1_curve(sec1)?);
+
+    // version 0
+    let mut info = vec![0x02, 0x01, 0x00];
+    info.extend(der_encode(0x30, &algorithm));
+    info.extend(der_encode(0x04, sec1));
+    Some(der_encode(0x30, &info))
+}
+
+// get the curve OID from the parameters of a SEC1 key, the key is useless without it
+fn sec1_curve(sec1: &[u8]) -> Option<&[u8]> {
+    let (tag, mut fields, _) = der_decode(sec1)?;
+    if tag != 0x30 {
+        return None;
+    }
+    while !fields.is_empty() {
+        let (tag, value, rest) = der_decode(fields)?;
+        // parameters are tagged with [0]
+        if tag == 0xa0 {
+            return Some(value);
+        }
+        fields = rest;
+    }
+    None
+}
+
+// read a DER element, return its tag, value and the data after it
+fn der_decode(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, data) = data.split_first()?;
+    let (len, data) = match first {
+        0..=0x7f => (first as usize, data),
+        // keys and certs are small, 2 bytes of length are enough
+        0x81..=0x82 => {
+            let (len, data) = data.split_at_checked((first & 0x7f) as usize)?;
+            (len.iter().fold(0, |len, b| len << 8 | *b as usize), data)
+        }
+        _ => return None,
+    };
+    let (value, rest) = data.split_at_checked(len)?;
+    Some((tag, value, rest))
+}
+
+fn der_encode(tag: u8, value: &[u8]) -> Vec<u8> {
+    let len = value.len();
+    let mut data = vec![tag];
+    match len {
+        0..=0x7f => data.push(len as u8),
+        0x80..=0xff => data.extend([0x81, len as u8]),
+        _ => data.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    data.extend_from_slice(value);
+    data
+}
+
 #[cfg(test)]
 pub mod tls_utils {
     use crate::{KvError, TlsClientConnector, TlsServerAcceptor};
 
-    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
-    const CLIENT_CERT: &str = include_str!("../../fixtures/client.cert");
+    pub const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    pub const CLIENT_CERT: &str = include_str!("../../fixtures/client.cert");
     const CLIENT_KEY: &str = include_str!("../../fixtures/client.key");
-    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
-    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+    pub const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    pub const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+    // self-signed, so it's also the CA cert
+    const SERVER_EC_CERT: &str = include_str!("../../fixtures/server_ec.cert");
+    const SERVER_EC_KEY: &str = include_str!("../../fixtures/server_ec.key");
 
     pub fn tls_connector(client_cert: bool) -> Result<TlsClientConnector, KvError> {
         let ca = Some(CA_CERT);
@@ -163,6 +291,14 @@ pub mod tls_utils {
             false => TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, None),
         }
     }
+
+    pub fn tls_ec_connector() -> Result<TlsClientConnector, KvError> {
+        TlsClientConnector::new("kvserver.acme.inc", None, Some(SERVER_EC_CERT))
+    }
+
+    pub fn tls_ec_acceptor() -> Result<TlsServerAcceptor, KvError> {
+        TlsServerAcceptor::new(SERVER_EC_CERT, SERVER_EC_KEY, None)
+    }
 }
 
 #[cfg(test)]
@@ -176,9 +312,11 @@ mod tests {
         net::{TcpListener, TcpStream},
     };
 
-    use crate::network::tls::tls_utils::tls_connector;
+    use crate::network::tls::load_certs;
+    use crate::network::tls::tls_utils::{tls_connector, tls_ec_acceptor, tls_ec_connector};
+    use crate::{KvError, TlsServerAcceptor};
 
-    use super::tls_utils::tls_acceptor;
+    use super::tls_utils::{tls_acceptor, CA_CERT, CLIENT_CERT, SERVER_CERT, SERVER_KEY};
 
     #[tokio::test]
     async fn tls_should_work() -> Result<()> {
@@ -222,8 +360,51 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn tls_with_ec_key_should_work() -> Result<()> {
+        let addr = start_echo_server(tls_ec_acceptor()?).await?;
+        let connector = tls_ec_connector()?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_reversed_cert_chain_should_work() -> Result<()> {
+        // the CA comes before the server cert, the chain is reordered when it's loaded
+        let chain = format!("{}{}", CA_CERT, SERVER_CERT);
+        let certs = load_certs(&chain)?;
+        assert_eq!(certs, load_certs(&format!("{}{}", SERVER_CERT, CA_CERT))?);
+        assert_eq!(certs[0], load_certs(SERVER_CERT)?[0]);
+
+        let addr = start_echo_server(TlsServerAcceptor::new(&chain, SERVER_KEY, None)?).await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = tls_connector(false)?.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn cert_chain_with_two_leaves_should_fail() {
+        let chain = format!("{}{}", SERVER_CERT, CLIENT_CERT);
+        let result = TlsServerAcceptor::new(&chain, SERVER_KEY, None);
+        assert!(matches!(result, Err(KvError::CertificateChainError(_))));
+    }
+
     async fn start_server(client_cert: bool) -> Result<SocketAddr> {
-        let acceptor = tls_acceptor(client_cert)?;
+        start_echo_server(tls_acceptor(client_cert)?).await
+    }
+
+    async fn start_echo_server(acceptor: TlsServerAcceptor) -> Result<SocketAddr> {
 
         let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = echo.local_addr().unwrap();
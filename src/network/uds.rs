@@ -0,0 +1,61 @@
+use std::path::Path;
+
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{info, warn};
+
+use crate::{KvError, ProstClientStream, ProstServerStream, Service, Storage, Topic};
+
+// serve the service on every connection accepted from the unix socket listener
+pub async fn bind_uds<Store, T>(listener: UnixListener, service: Service<Store, T>) -> Result<(), KvError>
+    where
+        Store: Storage + Send + Sync + 'static,
+        T: Topic,
+{
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Got unix socket connection from {:?}", addr);
+        let stream = ProstServerStream::new(stream, service.clone());
+        tokio::spawn(async move {
+            if let Err(e) = stream.process().await {
+                warn!("Failed to process unix socket connection: {:?}", e);
+            }
+        });
+    }
+}
+
+// connect to a unix socket, return the client stream
+pub async fn connect_uds(path: impl AsRef<Path>) -> Result<ProstClientStream<UnixStream>, KvError> {
+    let stream = UnixStream::connect(path).await?;
+    Ok(ProstClientStream::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    use crate::{assert_response_ok, CommandRequest, MemTable, ServiceInner, Value};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn uds_client_server_should_work() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kv.sock");
+
+        let listener = UnixListener::bind(&path)?;
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(bind_uds(listener, service));
+
+        let mut client = connect_uds(&path).await?;
+        let request = CommandRequest::new_hset("table", "key", "value".into());
+        let response = client.execute_unary(&request).await?;
+        assert_response_ok(&response, &[Value::default()], &[]);
+
+        let request = CommandRequest::new_hget("table", "key");
+        let response = client.execute_unary(&request).await?;
+        assert_response_ok(&response, &["value".into()], &[]);
+
+        Ok(())
+    }
+}
@@ -1,13 +1,15 @@
 /// command requests from the client
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12")]
+    /// optional id to correlate the responses with the request, 0 means not set
+    /// use a big tag so that request_data can keep growing
+    #[prost(uint64, tag="100")]
+    pub request_id: u64,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd)]
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
         #[prost(message, tag="1")]
@@ -34,10 +36,87 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag="12")]
         Publish(super::Publish),
+        #[prost(message, tag="13")]
+        Watch(super::Watch),
+        #[prost(message, tag="14")]
+        Hsetnx(super::Hsetnx),
+        #[prost(message, tag="15")]
+        Lpush(super::Lpush),
+        #[prost(message, tag="16")]
+        Lrange(super::Lrange),
+        #[prost(message, tag="17")]
+        Stats(super::Stats),
+        #[prost(message, tag="18")]
+        PublishAndSubscribe(super::PublishAndSubscribe),
+        #[prost(message, tag="19")]
+        Flushall(super::Flushall),
+        #[prost(message, tag="20")]
+        Hrange(super::Hrange),
+        #[prost(message, tag="21")]
+        Ack(super::Ack),
+        #[prost(message, tag="22")]
+        Hello(super::Hello),
+        #[prost(message, tag="23")]
+        Hmgetall(super::Hmgetall),
+        #[prost(message, tag="24")]
+        Time(super::Time),
+        #[prost(message, tag="25")]
+        Hstrlen(super::Hstrlen),
+        #[prost(message, tag="26")]
+        Hdelprefix(super::Hdelprefix),
+        #[prost(message, tag="27")]
+        Ping(super::Ping),
+        #[prost(message, tag="28")]
+        Readiness(super::Readiness),
+        #[prost(message, tag="29")]
+        Renametable(super::Renametable),
+        #[prost(message, tag="30")]
+        Hswap(super::Hswap),
+        #[prost(message, tag="31")]
+        Sadd(super::Sadd),
+        #[prost(message, tag="32")]
+        Srem(super::Srem),
+        #[prost(message, tag="33")]
+        Smembers(super::Smembers),
+        #[prost(message, tag="34")]
+        Hsetchanged(super::Hsetchanged),
+        #[prost(message, tag="35")]
+        Hsetchunked(super::Hsetchunked),
+        #[prost(message, tag="36")]
+        Hgetchunked(super::Hgetchunked),
+        #[prost(message, tag="37")]
+        Hincrfloat(super::Hincrfloat),
+        #[prost(message, tag="38")]
+        Subscriptions(super::Subscriptions),
+        #[prost(message, tag="39")]
+        Hgetor(super::Hgetor),
+        #[prost(message, tag="40")]
+        Htouch(super::Htouch),
+        #[prost(message, tag="41")]
+        Unsubscribeall(super::Unsubscribeall),
+        #[prost(message, tag="42")]
+        Hfindvalue(super::Hfindvalue),
+        #[prost(message, tag="43")]
+        Hqueryindex(super::Hqueryindex),
+        #[prost(message, tag="44")]
+        Hmgetex(super::Hmgetex),
+        #[prost(message, tag="45")]
+        Hgetreset(super::Hgetreset),
+        #[prost(message, tag="46")]
+        Hmove(super::Hmove),
+        #[prost(message, tag="47")]
+        Hdiff(super::Hdiff),
+        #[prost(message, tag="48")]
+        Hexpirebefore(super::Hexpirebefore),
+        #[prost(message, tag="49")]
+        ReplicateFrom(super::ReplicateFrom),
+        #[prost(message, tag="50")]
+        Hinitifempty(super::Hinitifempty),
+        #[prost(message, tag="51")]
+        Auth(super::Auth),
     }
 }
 /// command responses from the server
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     #[prost(uint32, tag="1")]
@@ -51,34 +130,104 @@ pub struct CommandResponse {
     /// kv pairs when status == 2xx
     #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<KvPair>,
+    /// the request_id of the request which generates the response
+    #[prost(uint64, tag="5")]
+    pub request_id: u64,
+    /// id of a message published in ack mode, the subscriber should ack it with this id
+    #[prost(uint64, tag="6")]
+    pub message_id: u64,
+    /// machine-readable error kind when status != 2xx, clients can branch on it instead of the message
+    #[prost(enumeration="ErrorCode", tag="7")]
+    pub error_code: i32,
 }
 /// query a key from a table, return the value
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// query a key from a table, return the default if the key does not exist
+/// return the value and a bool which is true if the default is used, the status is 200 either way
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetor {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, optional, tag="3")]
+    pub default: ::core::option::Option<Value>,
 }
 /// query all keys from a table, return all key-value pairs
-#[derive(PartialOrd)]
+/// if pattern is not empty, only return the pairs whose key matches the glob pattern
+/// `*` matches any sequence of characters (including empty), `?` matches exactly one character
+/// if chunk_size is not 0, the pairs are sent in a stream of frames with at most chunk_size pairs each,
+/// every frame has values \[last\], last is true for the final frame
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub pattern: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub chunk_size: u32,
+}
+/// find the pairs of a table whose value equals the given value, return the matched key-value pairs
+/// there is no index, it's a full scan of the table on the server, it only saves the bandwidth of Hgetall
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hfindvalue {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub value: ::core::option::Option<Value>,
+}
+/// find the pairs of a table whose map value has the field equal to the given value, e.g. field `address.city`
+/// the field must be indexed by Storage::create_index on the server, otherwise it's an invalid command
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hqueryindex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub field: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// compare two tables on the server, return the pairs of table_a whose key is missing in table_b or has another value
+/// setting the returned pairs in table_b makes it match table_a, except the keys only in table_b which are not returned,
+/// use Hdiff with the tables swapped to find them. both tables are scanned, it's O(A+B)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdiff {
+    #[prost(string, tag="1")]
+    pub table_a: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub table_b: ::prost::alloc::string::String,
+}
+/// query all keys from multiple tables in one command, return all key-value pairs
+/// the keys are prefixed with the table name, e.g. `table:key`
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmgetall {
+    #[prost(string, repeated, tag="1")]
+    pub tables: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// query multiple keys from a table, return all values
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes="bytes", repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+}
+/// query multiple keys from a table, return values [exists, value] for each key, so a missing key
+/// ([false, default]) can be told from a key with the default value ([true, default])
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmgetex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
 }
 /// set a key-value pair to a table, if table does not exist, create it
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
     #[prost(string, tag="1")]
@@ -87,7 +236,6 @@ pub struct Hset {
     pub pair: ::core::option::Option<KvPair>,
 }
 /// set multiple key-value pairs to a table, if table does not exist, create it
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
     #[prost(string, tag="1")]
@@ -95,47 +243,291 @@ pub struct Hmset {
     #[prost(message, repeated, tag="2")]
     pub pairs: ::prost::alloc::vec::Vec<KvPair>,
 }
+/// set multiple key-value pairs to a table atomically, only if the table is empty or does not exist
+/// e.g. to seed the defaults once however many instances start. return true if the pairs are set, false if the table already has a key, then nothing is changed
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hinitifempty {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub pairs: ::prost::alloc::vec::Vec<KvPair>,
+}
+/// set a key-value pair to a table only if the key does not exist
+/// return true if the value is set, false if the key already exists
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetnx {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// set a key-value pair to a table only if the value is different from the current one
+/// return true if the value is written, false if the key already has the same value
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetchanged {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// upload a big binary value in chunks, one chunk per request, the value is written after the last chunk
+/// the first chunk has transfer_id 0 and seq 0, the server assigns the transfer id
+/// return [transfer id, next expected seq], a chunk with a wrong seq is rejected and can be resent
+/// a transfer which gets no chunk for a while is dropped, and a 429 is returned if the server buffers too many
+/// transfers or bytes, see ServiceInner::with_upload_limits
+/// the keys watchers are not notified
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetchunked {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(uint64, tag="3")]
+    pub transfer_id: u64,
+    #[prost(uint32, tag="4")]
+    pub seq: u32,
+    #[prost(bytes="bytes", tag="5")]
+    pub data: ::prost::bytes::Bytes,
+    #[prost(bool, tag="6")]
+    pub last: bool,
+}
+/// download a big binary value in chunks of chunk_size bytes, 0 means 1MB
+/// return a stream of [seq, number of chunks, data]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetchunked {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(uint32, tag="3")]
+    pub chunk_size: u32,
+}
+/// exchange the values of two keys in a table atomically, both keys must exist
+/// return the previous values of key1 and key2
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hswap {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key1: ::prost::bytes::Bytes,
+    #[prost(bytes="bytes", tag="3")]
+    pub key2: ::prost::bytes::Bytes,
+}
+/// move a key from one table to another atomically, the value of the key in to_table is replaced
+/// return the moved value, or 404 if the key does not exist in from_table
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hmove {
+    #[prost(string, tag="1")]
+    pub from_table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub to_table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="3")]
+    pub key: ::prost::bytes::Bytes,
+}
 /// delete a key from a table, return the previous value
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
 }
 /// delete multiple keys from a table, return the previous values
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes="bytes", repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+}
+/// delete all keys starting with the prefix from a table, return the number of deleted keys
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdelprefix {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub prefix: ::prost::bytes::Bytes,
+}
+/// delete all keys of a table whose value is a timestamp before the cutoff, return the number of deleted keys
+/// the cutoff is in milliseconds since the unix epoch, e.g. from Time. only the Timestamp values expire,
+/// the other values are kept, including the integers
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpirebefore {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(int64, tag="2")]
+    pub cutoff: i64,
 }
 /// check if a key exists in a table, return true if exists
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, tag="2")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
 }
 /// check if multiple keys exist in a table, return true if all exist
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
-    #[prost(string, repeated, tag="2")]
-    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(bytes="bytes", repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::bytes::Bytes>,
+}
+/// push values to the head of a list, values are pushed one by one so the last one becomes the head
+/// if the key does not exist, create an empty list first. return the length of the list
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpush {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, repeated, tag="3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// add members to a set, members which are already in the set are ignored
+/// if the key does not exist, create an empty set first. return how many members are added
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sadd {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// remove members from a set, return how many members are removed
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Srem {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(message, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// get all members of a set, in the order of ValueSet
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Smembers {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// add delta to a float value atomically, an integer value is converted to float
+/// if the key does not exist, it's set to delta. return the new value
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrfloat {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(double, tag="3")]
+    pub delta: f64,
+}
+/// replace an integer value with 0 atomically and return the old value, e.g. to report a counter per interval
+/// return 404 if the key does not exist
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetreset {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// make a key expire after ttl_ms without reading or writing its value, e.g. to keep a session alive
+/// 0 removes the expiry, a set removes it too. return true if the key exists, false otherwise
+/// return 400 if the storage has no key expiry, see ExpiringStore
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Htouch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(uint64, tag="3")]
+    pub ttl_ms: u64,
+}
+/// get the values of a list from start to stop (inclusive)
+/// negative index counts from the end of the list, -1 is the last value
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(int64, tag="3")]
+    pub start: i64,
+    #[prost(int64, tag="4")]
+    pub stop: i64,
+}
+/// get the size of a value without returning it, return the size as an integer
+/// it's the byte length for string and binary values, and the encoded length for other values
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hstrlen {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// get the statistics of a table, return pairs of `keys` (key count) and `bytes` (approximate size)
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Stats {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// rename a table, if the `to` table exists, all its data is replaced by the `from` table
+/// renaming a table which doesn't exist returns 404, the `to` table is not changed
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Renametable {
+    #[prost(string, tag="1")]
+    pub from: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub to: ::prost::alloc::string::String,
+}
+/// remove all data in all tables, return the number of removed keys
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Flushall {
+}
+/// get the server's current time, return the milliseconds since the unix epoch as an integer
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Time {
+}
+/// check if the server process is alive, it doesn't touch the storage
+/// return the payload, or "PONG" if the payload is empty
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ping {
+    #[prost(string, tag="1")]
+    pub payload: ::prost::alloc::string::String,
+}
+/// check if the server is ready to serve, it reads the storage
+/// return "READY", or the storage error if the storage is broken
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Readiness {
+}
+/// get the server version and the features it supports, clients can send it right after connecting
+/// return two values: the version string, and a list of feature names
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+}
+/// query the keys in [start, end) from a table, return the key-value pairs sorted by key
+/// an empty end means no upper bound
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub start: ::prost::bytes::Bytes,
+    #[prost(bytes="bytes", tag="3")]
+    pub end: ::prost::bytes::Bytes,
 }
 /// response value
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5, 6, 7, 8, 9")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -153,18 +545,59 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag="5")]
         Bool(bool),
+        #[prost(message, tag="6")]
+        List(super::ValueList),
+        #[prost(message, tag="7")]
+        Set(super::ValueSet),
+        #[prost(message, tag="8")]
+        Map(super::ValueMap),
+        /// milliseconds since the unix epoch, the only values Hexpirebefore expires
+        #[prost(int64, tag="9")]
+        Timestamp(i64),
     }
 }
+/// ordered list of values
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueList {
+    #[prost(message, repeated, tag="1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// set of unique values, the members are sorted by type (in the order of the Value variants), then by value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueSet {
+    #[prost(message, repeated, tag="1")]
+    pub members: ::prost::alloc::vec::Vec<Value>,
+}
+/// structured record, the pairs are sorted by key when built from a map
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueMap {
+    #[prost(message, repeated, tag="1")]
+    pub pairs: ::prost::alloc::vec::Vec<KvPair>,
+}
 /// subscribe to a topic
 /// if succeed, the first returned CommandResponse will include a global unique subscription id
-#[derive(PartialOrd)]
+/// if history is true, the recent messages kept by the server are received before the new ones
+/// the subscription is removed and the stream is closed when ttl_ms or max_messages is hit
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Subscribe {
     #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
+    #[prost(bool, tag="2")]
+    pub history: bool,
+    /// if set, only the messages whose first value equals it are sent to the subscriber
+    #[prost(message, optional, tag="3")]
+    pub filter: ::core::option::Option<Value>,
+    /// how long the subscription lives in milliseconds, 0 means unlimited
+    #[prost(uint64, tag="4")]
+    pub ttl_ms: u64,
+    /// how many published messages the subscription receives, 0 means unlimited
+    #[prost(uint32, tag="5")]
+    pub max_messages: u32,
 }
 /// unsubscribe a topic
-#[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Unsubscribe {
     #[prost(string, tag="1")]
@@ -172,21 +605,110 @@ pub struct Unsubscribe {
     #[prost(uint32, tag="2")]
     pub id: u32,
 }
+/// unsubscribe all the subscriptions made by the current connection, return how many are removed
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribeall {
+}
+/// list the subscriptions made by the current connection
+/// return a pair for every subscription, the key is the topic and the value is the subscription id
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscriptions {
+}
 /// publish data to a topic
-#[derive(PartialOrd)]
+/// if wait is true, the response is sent after all subscribers have received the data,
+/// so a slow subscriber slows down the publisher instead of piling up data in the server
+/// if ack is true, the data is resent to a subscriber until it sends back an Ack with the message id (at most 5 times
+/// by default, then it's dropped or published to the server's dead letter topic),
+/// and the response includes the message id. wait is ignored in this mode
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Publish {
     #[prost(string, tag="1")]
     pub topic: ::prost::alloc::string::String,
     #[prost(message, repeated, tag="2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
+    #[prost(bool, tag="3")]
+    pub wait: bool,
+    #[prost(bool, tag="4")]
+    pub ack: bool,
+}
+/// acknowledge a message published in ack mode is received by the subscription
+/// return true if the message was waiting for the ack
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ack {
+    #[prost(uint32, tag="1")]
+    pub id: u32,
+    #[prost(uint64, tag="2")]
+    pub message_id: u64,
+}
+/// watch the changes of a key in a table
+/// every set/del of the key will be sent to the watcher as a CommandResponse
+/// the changes are published by the storage, the server's store must be wrapped in a KeyspaceNotifier
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="2")]
+    pub key: ::prost::bytes::Bytes,
+}
+/// stream the write commands of the server from the offset, then the new ones as they're executed, for a replica
+/// the server must keep a ReplicationLog. the first returned CommandResponse includes the next offset of the log,
+/// then each response has the encoded CommandRequest as a binary value and its offset as the message_id.
+/// a replica resumes from the last applied offset + 1, it gets a 410 if the offset is not kept anymore
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicateFrom {
+    #[prost(uint64, tag="1")]
+    pub offset: u64,
+}
+/// authenticate the connection with a shared secret, e.g. a bearer token passed on by a proxy terminating TLS
+/// if the server verifies tokens, it must be the first command of a connection, the others get a 401 until it succeeds
+/// an Auth on one stream of a multiplexed connection authenticates all its streams
+/// return OK if the token is valid, 401 otherwise. any token is accepted by a server which doesn't verify tokens
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag="1")]
+    pub token: ::prost::alloc::string::String,
+}
+/// publish data to a topic and subscribe to the reply topic in one command
+/// it subscribes before publishing, so no reply will be missed
+/// the first returned CommandResponse will include the subscription id of the reply topic
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PublishAndSubscribe {
+    #[prost(string, tag="1")]
+    pub publish_topic: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub data: ::prost::alloc::vec::Vec<Value>,
+    #[prost(string, tag="3")]
+    pub reply_topic: ::prost::alloc::string::String,
 }
 /// key-value pair
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct KvPair {
-    #[prost(string, tag="1")]
-    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="bytes", tag="1")]
+    pub key: ::prost::bytes::Bytes,
     #[prost(message, optional, tag="2")]
     pub value: ::core::option::Option<Value>,
 }
+/// kinds of errors returned in CommandResponse
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorCode {
+    /// no error
+    None = 0,
+    NotFound = 1,
+    InvalidCommand = 2,
+    ReadOnly = 3,
+    ValueTooLarge = 4,
+    ConvertError = 5,
+    StorageError = 6,
+    CryptoError = 7,
+    FrameError = 8,
+    Internal = 9,
+    Timeout = 10,
+    Unavailable = 11,
+    MemoryLimit = 12,
+    ReplicationGap = 13,
+    Unauthorized = 14,
+    UploadLimit = 15,
+    ValueTooDeep = 16,
+}
@@ -1,3 +1,8 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+
 use bytes::Bytes;
 use http::StatusCode;
 use prost::Message;
@@ -5,112 +10,905 @@ use prost::Message;
 use abi::*;
 use abi::command_request::RequestData;
 
-use crate::KvError;
+use crate::{keyspace_topic, KvError, TableStats};
 
 pub mod abi;
 
-impl CommandRequest {
-    pub fn new_hset(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+impl CommandRequest {
+    // check if the command changes the data in the storage
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Hset(_))
+                | Some(RequestData::Hmset(_))
+                | Some(RequestData::Hsetnx(_))
+                | Some(RequestData::Hsetchanged(_))
+                | Some(RequestData::Hsetchunked(_))
+                | Some(RequestData::Hinitifempty(_))
+                | Some(RequestData::Lpush(_))
+                | Some(RequestData::Hincrfloat(_))
+                | Some(RequestData::Hgetreset(_))
+                | Some(RequestData::Htouch(_))
+                | Some(RequestData::Hdel(_))
+                | Some(RequestData::Hmdel(_))
+                | Some(RequestData::Hdelprefix(_))
+                | Some(RequestData::Hexpirebefore(_))
+                | Some(RequestData::Renametable(_))
+                | Some(RequestData::Hswap(_))
+                | Some(RequestData::Hmove(_))
+                | Some(RequestData::Sadd(_))
+                | Some(RequestData::Srem(_))
+                | Some(RequestData::Flushall(_))
+        )
+    }
+
+    // the command reads or writes a whole table (or all tables), it may take much longer than the others
+    pub fn is_scan(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Hgetall(_))
+                | Some(RequestData::Hfindvalue(_))
+                | Some(RequestData::Hdiff(_))
+                | Some(RequestData::Hmgetall(_))
+                | Some(RequestData::Hrange(_))
+                | Some(RequestData::Hdelprefix(_))
+                | Some(RequestData::Hexpirebefore(_))
+                | Some(RequestData::Stats(_))
+                | Some(RequestData::Renametable(_))
+                | Some(RequestData::Flushall(_))
+        )
+    }
+
+    // the command may touch the storage, e.g. it's not a pub/sub command or Ping
+    pub fn uses_storage(&self) -> bool {
+        !self.is_pubsub()
+            && !matches!(
+                self.request_data,
+                Some(RequestData::Ping(_))
+                    | Some(RequestData::Time(_))
+                    | Some(RequestData::ReplicateFrom(_))
+                    | Some(RequestData::Auth(_))
+            )
+    }
+
+    // the command is handled by the topic instead of the storage
+    pub fn is_pubsub(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Subscribe(_))
+                | Some(RequestData::Unsubscribe(_))
+                | Some(RequestData::Unsubscribeall(_))
+                | Some(RequestData::Subscriptions(_))
+                | Some(RequestData::Publish(_))
+                | Some(RequestData::PublishAndSubscribe(_))
+                | Some(RequestData::Watch(_))
+                | Some(RequestData::Ack(_))
+        )
+    }
+
+    // the topic the command subscribes to, None if it doesn't make a subscription
+    pub fn subscription_topic(&self) -> Option<String> {
+        match &self.request_data {
+            Some(RequestData::Subscribe(v)) => Some(v.topic.clone()),
+            Some(RequestData::PublishAndSubscribe(v)) => Some(v.reply_topic.clone()),
+            Some(RequestData::Watch(v)) => Some(keyspace_topic(&v.table, &v.key)),
+            _ => None,
+        }
+    }
+
+    // the nesting depth of the deepest value in the command, 0 if it has no value
+    pub fn max_value_depth(&self) -> usize {
+        let values: Vec<&Value> = match &self.request_data {
+            Some(RequestData::Hgetor(v)) => v.default.iter().collect(),
+            Some(RequestData::Hfindvalue(v)) => v.value.iter().collect(),
+            Some(RequestData::Hqueryindex(v)) => v.value.iter().collect(),
+            Some(RequestData::Hset(v)) => v.pair.iter().filter_map(|pair| pair.value.as_ref()).collect(),
+            Some(RequestData::Hmset(v)) => v.pairs.iter().filter_map(|pair| pair.value.as_ref()).collect(),
+            Some(RequestData::Hinitifempty(v)) => v.pairs.iter().filter_map(|pair| pair.value.as_ref()).collect(),
+            Some(RequestData::Hsetnx(v)) => v.value.iter().collect(),
+            Some(RequestData::Hsetchanged(v)) => v.value.iter().collect(),
+            Some(RequestData::Lpush(v)) => v.values.iter().collect(),
+            Some(RequestData::Sadd(v)) => v.members.iter().collect(),
+            Some(RequestData::Srem(v)) => v.members.iter().collect(),
+            Some(RequestData::Subscribe(v)) => v.filter.iter().collect(),
+            Some(RequestData::Publish(v)) => v.data.iter().collect(),
+            Some(RequestData::PublishAndSubscribe(v)) => v.data.iter().collect(),
+            _ => vec![],
+        };
+        values.into_iter().map(Value::depth).max().unwrap_or(0)
+    }
+
+    // the encoded size of the biggest value this command writes to the storage, 0 if it writes no value
+    pub fn max_value_len(&self) -> usize {
+        let values: Vec<&Value> = match &self.request_data {
+            Some(RequestData::Hset(v)) => v.pair.iter().filter_map(|pair| pair.value.as_ref()).collect(),
+            Some(RequestData::Hmset(v)) => v.pairs.iter().filter_map(|pair| pair.value.as_ref()).collect(),
+            Some(RequestData::Hinitifempty(v)) => v.pairs.iter().filter_map(|pair| pair.value.as_ref()).collect(),
+            Some(RequestData::Hsetnx(v)) => v.value.iter().collect(),
+            Some(RequestData::Hsetchanged(v)) => v.value.iter().collect(),
+            Some(RequestData::Lpush(v)) => v.values.iter().collect(),
+            Some(RequestData::Sadd(v)) => v.members.iter().collect(),
+            _ => vec![],
+        };
+        values.into_iter().map(|v| v.encoded_len()).max().unwrap_or(0)
+    }
+
+    // the name of the command, used in logs
+    pub fn command_name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetor(_)) => "hgetor",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hfindvalue(_)) => "hfindvalue",
+            Some(RequestData::Hdiff(_)) => "hdiff",
+            Some(RequestData::Hqueryindex(_)) => "hqueryindex",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hmgetex(_)) => "hmgetex",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hinitifempty(_)) => "hinitifempty",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Unsubscribeall(_)) => "unsubscribeall",
+            Some(RequestData::Subscriptions(_)) => "subscriptions",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Watch(_)) => "watch",
+            Some(RequestData::Hsetnx(_)) => "hsetnx",
+            Some(RequestData::Hsetchanged(_)) => "hsetchanged",
+            Some(RequestData::Hsetchunked(_)) => "hsetchunked",
+            Some(RequestData::Hincrfloat(_)) => "hincrfloat",
+            Some(RequestData::Hgetreset(_)) => "hgetreset",
+            Some(RequestData::Htouch(_)) => "htouch",
+            Some(RequestData::Hgetchunked(_)) => "hgetchunked",
+            Some(RequestData::Lpush(_)) => "lpush",
+            Some(RequestData::Lrange(_)) => "lrange",
+            Some(RequestData::Stats(_)) => "stats",
+            Some(RequestData::PublishAndSubscribe(_)) => "publish_and_subscribe",
+            Some(RequestData::Flushall(_)) => "flushall",
+            Some(RequestData::Hrange(_)) => "hrange",
+            Some(RequestData::Ack(_)) => "ack",
+            Some(RequestData::Hello(_)) => "hello",
+            Some(RequestData::Hmgetall(_)) => "hmgetall",
+            Some(RequestData::Time(_)) => "time",
+            Some(RequestData::Hstrlen(_)) => "hstrlen",
+            Some(RequestData::Hdelprefix(_)) => "hdelprefix",
+            Some(RequestData::Hexpirebefore(_)) => "hexpirebefore",
+            Some(RequestData::Ping(_)) => "ping",
+            Some(RequestData::ReplicateFrom(_)) => "replicatefrom",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::Readiness(_)) => "readiness",
+            Some(RequestData::Renametable(_)) => "renametable",
+            Some(RequestData::Hswap(_)) => "hswap",
+            Some(RequestData::Hmove(_)) => "hmove",
+            Some(RequestData::Sadd(_)) => "sadd",
+            Some(RequestData::Srem(_)) => "srem",
+            Some(RequestData::Smembers(_)) => "smembers",
+            None => "unknown",
+        }
+    }
+
+    // the table the command works on, empty if the command is not for a table
+    pub fn table(&self) -> &str {
+        match &self.request_data {
+            Some(RequestData::Hget(v)) => &v.table,
+            Some(RequestData::Hgetor(v)) => &v.table,
+            Some(RequestData::Hgetall(v)) => &v.table,
+            Some(RequestData::Hfindvalue(v)) => &v.table,
+            Some(RequestData::Hdiff(v)) => &v.table_a,
+            Some(RequestData::Hqueryindex(v)) => &v.table,
+            Some(RequestData::Hmget(v)) => &v.table,
+            Some(RequestData::Hmgetex(v)) => &v.table,
+            Some(RequestData::Hset(v)) => &v.table,
+            Some(RequestData::Hmset(v)) => &v.table,
+            Some(RequestData::Hinitifempty(v)) => &v.table,
+            Some(RequestData::Hdel(v)) => &v.table,
+            Some(RequestData::Hmdel(v)) => &v.table,
+            Some(RequestData::Hexist(v)) => &v.table,
+            Some(RequestData::Hmexist(v)) => &v.table,
+            Some(RequestData::Watch(v)) => &v.table,
+            Some(RequestData::Hsetnx(v)) => &v.table,
+            Some(RequestData::Hsetchanged(v)) => &v.table,
+            Some(RequestData::Hsetchunked(v)) => &v.table,
+            Some(RequestData::Hgetchunked(v)) => &v.table,
+            Some(RequestData::Hincrfloat(v)) => &v.table,
+            Some(RequestData::Hgetreset(v)) => &v.table,
+            Some(RequestData::Htouch(v)) => &v.table,
+            Some(RequestData::Lpush(v)) => &v.table,
+            Some(RequestData::Lrange(v)) => &v.table,
+            Some(RequestData::Stats(v)) => &v.table,
+            Some(RequestData::Hrange(v)) => &v.table,
+            Some(RequestData::Hstrlen(v)) => &v.table,
+            Some(RequestData::Hdelprefix(v)) => &v.table,
+            Some(RequestData::Hexpirebefore(v)) => &v.table,
+            Some(RequestData::Renametable(v)) => &v.from,
+            Some(RequestData::Hswap(v)) => &v.table,
+            Some(RequestData::Hmove(v)) => &v.from_table,
+            Some(RequestData::Sadd(v)) => &v.table,
+            Some(RequestData::Srem(v)) => &v.table,
+            Some(RequestData::Smembers(v)) => &v.table,
+            _ => "",
+        }
+    }
+
+    // all the tables the command names
+    pub fn tables(&self) -> Vec<&str> {
+        match &self.request_data {
+            Some(RequestData::Renametable(v)) => vec![&v.from, &v.to],
+            Some(RequestData::Hmove(v)) => vec![&v.from_table, &v.to_table],
+            Some(RequestData::Hdiff(v)) => vec![&v.table_a, &v.table_b],
+            Some(RequestData::Hmgetall(v)) => v.tables.iter().map(|t| t.as_str()).collect(),
+            _ => Some(self.table()).filter(|t| !t.is_empty()).into_iter().collect(),
+        }
+    }
+
+    // reject an empty table name or key before the request is sent, the storages would accept them
+    fn check_not_empty(self) -> Result<Self, KvError> {
+        if self.table().is_empty() {
+            return Err(KvError::InvalidCommand(format!("Table name of {} can't be empty", self.command_name())));
+        }
+        if self.keys().iter().any(|key| key.is_empty()) {
+            return Err(KvError::InvalidCommand(format!("Key of {} can't be empty", self.command_name())));
+        }
+        Ok(self)
+    }
+
+    // all the keys the command names, the prefixes and range bounds are not keys
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match &self.request_data {
+            Some(RequestData::Hget(v)) => vec![&v.key],
+            Some(RequestData::Hgetor(v)) => vec![&v.key],
+            Some(RequestData::Hmget(v)) => v.keys.iter().map(|k| k.as_ref()).collect(),
+            Some(RequestData::Hmgetex(v)) => v.keys.iter().map(|k| k.as_ref()).collect(),
+            Some(RequestData::Hset(v)) => v.pair.iter().map(|pair| pair.key.as_ref()).collect(),
+            Some(RequestData::Hmset(v)) => v.pairs.iter().map(|pair| pair.key.as_ref()).collect(),
+            Some(RequestData::Hinitifempty(v)) => v.pairs.iter().map(|pair| pair.key.as_ref()).collect(),
+            Some(RequestData::Hdel(v)) => vec![&v.key],
+            Some(RequestData::Hmdel(v)) => v.keys.iter().map(|k| k.as_ref()).collect(),
+            Some(RequestData::Hexist(v)) => vec![&v.key],
+            Some(RequestData::Hmexist(v)) => v.keys.iter().map(|k| k.as_ref()).collect(),
+            Some(RequestData::Watch(v)) => vec![&v.key],
+            Some(RequestData::Hsetnx(v)) => vec![&v.key],
+            Some(RequestData::Hsetchanged(v)) => vec![&v.key],
+            Some(RequestData::Hsetchunked(v)) => vec![&v.key],
+            Some(RequestData::Hgetchunked(v)) => vec![&v.key],
+            Some(RequestData::Hincrfloat(v)) => vec![&v.key],
+            Some(RequestData::Hgetreset(v)) => vec![&v.key],
+            Some(RequestData::Htouch(v)) => vec![&v.key],
+            Some(RequestData::Lpush(v)) => vec![&v.key],
+            Some(RequestData::Lrange(v)) => vec![&v.key],
+            Some(RequestData::Hstrlen(v)) => vec![&v.key],
+            Some(RequestData::Hswap(v)) => vec![&v.key1, &v.key2],
+            Some(RequestData::Hmove(v)) => vec![&v.key],
+            Some(RequestData::Sadd(v)) => vec![&v.key],
+            Some(RequestData::Srem(v)) => vec![&v.key],
+            Some(RequestData::Smembers(v)) => vec![&v.key],
+            _ => vec![],
+        }
+    }
+
+    // set the id to correlate the responses with this request
+    pub fn with_request_id(mut self, id: u64) -> Self {
+        self.request_id = id;
+        self
+    }
+
+    // the request to log, the token of Auth is hidden so it's never written to the logs
+    pub fn redacted(&self) -> Cow<'_, Self> {
+        match &self.request_data {
+            Some(RequestData::Auth(_)) => Cow::Owned(CommandRequest::new_auth("***").with_request_id(self.request_id)),
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    pub fn new_hset(table: impl Into<String>, key: impl Into<Bytes>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table.into(),
+                pair: Some(KvPair::new(key, value)),
+            })),
+            ..Default::default()
+        }
+    }
+
+    // same as new_hset, but fail if the table or the key is empty
+    pub fn try_new_hset(table: impl Into<String>, key: impl Into<Bytes>, value: Value) -> Result<Self, KvError> {
+        Self::new_hset(table, key, value).check_not_empty()
+    }
+
+    pub fn new_hget(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hget(Hget {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    // same as new_hget, but fail if the table or the key is empty
+    pub fn try_new_hget(table: impl Into<String>, key: impl Into<Bytes>) -> Result<Self, KvError> {
+        Self::new_hget(table, key).check_not_empty()
+    }
+
+    pub fn new_hgetor(table: impl Into<String>, key: impl Into<Bytes>, default: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetor(Hgetor {
+                table: table.into(),
+                key: key.into(),
+                default: Some(default),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hget_all(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                pattern: String::new(),
+                chunk_size: 0,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hget_all_chunked(table: impl Into<String>, chunk_size: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                pattern: String::new(),
+                chunk_size,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hget_all_match(table: impl Into<String>, pattern: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                pattern: pattern.into(),
+                chunk_size: 0,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hfindvalue(table: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hfindvalue(Hfindvalue {
+                table: table.into(),
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hqueryindex(table: impl Into<String>, field: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hqueryindex(Hqueryindex {
+                table: table.into(),
+                field: field.into(),
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdiff(table_a: impl Into<String>, table_b: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdiff(Hdiff {
+                table_a: table_a.into(),
+                table_b: table_b.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmget_all(tables: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmgetall(Hmgetall { tables })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmget(table: impl Into<String>, keys: Vec<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmgetex(table: impl Into<String>, keys: Vec<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmgetex(Hmgetex {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmset(table: impl Into<String>, pairs: Vec<KvPair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hinitifempty(table: impl Into<String>, pairs: Vec<KvPair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hinitifempty(Hinitifempty {
+                table: table.into(),
+                pairs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hsetnx(table: impl Into<String>, key: impl Into<Bytes>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetnx(Hsetnx {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    // one chunk of a chunked upload, the first chunk has transfer_id 0, the server returns the assigned id
+    pub fn new_hsetchunked(
+        table: impl Into<String>,
+        key: impl Into<Bytes>,
+        transfer_id: u64,
+        seq: u32,
+        data: impl Into<Bytes>,
+        last: bool,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetchunked(Hsetchunked {
+                table: table.into(),
+                key: key.into(),
+                transfer_id,
+                seq,
+                data: data.into(),
+                last,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetchunked(table: impl Into<String>, key: impl Into<Bytes>, chunk_size: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetchunked(Hgetchunked {
+                table: table.into(),
+                key: key.into(),
+                chunk_size,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hincrfloat(table: impl Into<String>, key: impl Into<Bytes>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrfloat(Hincrfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hgetreset(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetreset(Hgetreset {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_htouch(table: impl Into<String>, key: impl Into<Bytes>, ttl: Duration) -> Self {
+        Self {
+            request_data: Some(RequestData::Htouch(Htouch {
+                table: table.into(),
+                key: key.into(),
+                ttl_ms: ttl.as_millis() as u64,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hsetchanged(table: impl Into<String>, key: impl Into<Bytes>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetchanged(Hsetchanged {
+                table: table.into(),
+                key: key.into(),
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lpush(table: impl Into<String>, key: impl Into<Bytes>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpush(Lpush {
+                table: table.into(),
+                key: key.into(),
+                values,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_lrange(table: impl Into<String>, key: impl Into<Bytes>, start: i64, stop: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Lrange(Lrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_sadd(table: impl Into<String>, key: impl Into<Bytes>, members: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Sadd(Sadd {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_srem(table: impl Into<String>, key: impl Into<Bytes>, members: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Srem(Srem {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_smembers(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Smembers(Smembers {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hstrlen(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hstrlen(Hstrlen {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_stats(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Stats(Stats {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_renametable(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Renametable(Renametable {
+                from: from.into(),
+                to: to.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_flushall() -> Self {
+        Self {
+            request_data: Some(RequestData::Flushall(Flushall {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hrange(table: impl Into<String>, start: impl Into<Bytes>, end: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hrange(Hrange {
+                table: table.into(),
+                start: start.into(),
+                end: end.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_time() -> Self {
+        Self {
+            request_data: Some(RequestData::Time(Time {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_ping(payload: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Ping(Ping {
+                payload: payload.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_readiness() -> Self {
+        Self {
+            request_data: Some(RequestData::Readiness(Readiness {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hello() -> Self {
+        Self {
+            request_data: Some(RequestData::Hello(Hello {})),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hswap(table: impl Into<String>, key1: impl Into<Bytes>, key2: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hswap(Hswap {
+                table: table.into(),
+                key1: key1.into(),
+                key2: key2.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmove(from_table: impl Into<String>, to_table: impl Into<String>, key: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmove(Hmove {
+                from_table: from_table.into(),
+                to_table: to_table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdel(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdel(Hdel {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmdel(table: impl Into<String>, keys: Vec<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmdel(Hmdel {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdelprefix(table: impl Into<String>, prefix: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdelprefix(Hdelprefix {
+                table: table.into(),
+                prefix: prefix.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexpirebefore(table: impl Into<String>, cutoff: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpirebefore(Hexpirebefore { table: table.into(), cutoff })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hexist(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexist(Hexist {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hmexist(table: impl Into<String>, keys: Vec<Bytes>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmexist(Hmexist {
+                table: table.into(),
+                keys,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_replicate_from(offset: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::ReplicateFrom(ReplicateFrom { offset })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_auth(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth { token: token.into() })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_subscribe(name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                history: false,
+                filter: None,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_subscribe_with_history(name: impl Into<String>) -> Self {
         Self {
-            request_data: Some(RequestData::Hset(Hset {
-                table: table.into(),
-                pair: Some(KvPair::new(key, value)),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                history: true,
+                filter: None,
+                ..Default::default()
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
+    // only receive the messages whose first value equals the filter
+    pub fn new_subscribe_with_filter(name: impl Into<String>, filter: impl Into<Value>) -> Self {
         Self {
-            request_data: Some(RequestData::Hget(Hget {
-                table: table.into(),
-                key: key.into(),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                history: false,
+                filter: Some(filter.into()),
+                ..Default::default()
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hget_all(table: impl Into<String>) -> Self {
+    // the subscription is removed after `ttl` or after receiving `max_messages` messages, whichever comes first
+    pub fn new_subscribe_with_limits(name: impl Into<String>, ttl: Option<Duration>, max_messages: Option<u32>) -> Self {
         Self {
-            request_data: Some(RequestData::Hgetall(Hgetall {
-                table: table.into(),
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: name.into(),
+                ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64).unwrap_or(0),
+                max_messages: max_messages.unwrap_or(0),
+                ..Default::default()
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_unsubscribe(name: impl Into<String>, id: u32) -> Self {
         Self {
-            request_data: Some(RequestData::Hmget(Hmget {
-                table: table.into(),
-                keys,
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                topic: name.into(),
+                id,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmset(table: impl Into<String>, pairs: Vec<KvPair>) -> Self {
+    // unsubscribe all the subscriptions of the current connection
+    pub fn new_unsubscribe_all() -> Self {
         Self {
-            request_data: Some(RequestData::Hmset(Hmset {
-                table: table.into(),
-                pairs,
-            })),
+            request_data: Some(RequestData::Unsubscribeall(Unsubscribeall {})),
+            ..Default::default()
         }
     }
 
-    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+    // list the subscriptions of the current connection
+    pub fn new_subscriptions() -> Self {
         Self {
-            request_data: Some(RequestData::Hdel(Hdel {
-                table: table.into(),
-                key: key.into(),
-            })),
+            request_data: Some(RequestData::Subscriptions(Subscriptions {})),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_publish(name: impl Into<String>, data: Vec<Value>) -> Self {
         Self {
-            request_data: Some(RequestData::Hmdel(Hmdel {
-                table: table.into(),
-                keys,
+            request_data: Some(RequestData::Publish(Publish {
+                topic: name.into(),
+                data,
+                wait: false,
+                ack: false,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+    pub fn new_publish_wait(name: impl Into<String>, data: Vec<Value>) -> Self {
         Self {
-            request_data: Some(RequestData::Hexist(Hexist {
-                table: table.into(),
-                key: key.into(),
+            request_data: Some(RequestData::Publish(Publish {
+                topic: name.into(),
+                data,
+                wait: true,
+                ack: false,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+    pub fn new_publish_with_ack(name: impl Into<String>, data: Vec<Value>) -> Self {
         Self {
-            request_data: Some(RequestData::Hmexist(Hmexist {
-                table: table.into(),
-                keys,
+            request_data: Some(RequestData::Publish(Publish {
+                topic: name.into(),
+                data,
+                wait: false,
+                ack: true,
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_subscribe(name: impl Into<String>) -> Self {
+    pub fn new_ack(id: u32, message_id: u64) -> Self {
         Self {
-            request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            request_data: Some(RequestData::Ack(Ack { id, message_id })),
+            ..Default::default()
         }
     }
 
-    pub fn new_unsubscribe(name: impl Into<String>, id: u32) -> Self {
+    pub fn new_publish_and_subscribe(
+        publish_topic: impl Into<String>,
+        data: Vec<Value>,
+        reply_topic: impl Into<String>,
+    ) -> Self {
         Self {
-            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
-                topic: name.into(),
-                id,
+            request_data: Some(RequestData::PublishAndSubscribe(PublishAndSubscribe {
+                publish_topic: publish_topic.into(),
+                data,
+                reply_topic: reply_topic.into(),
             })),
+            ..Default::default()
         }
     }
 
-    pub fn new_publish(name: impl Into<String>, data: Vec<Value>) -> Self {
+    pub fn new_watch(table: impl Into<String>, key: impl Into<Bytes>) -> Self {
         Self {
-            request_data: Some(RequestData::Publish(Publish {
-                topic: name.into(),
-                data,
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                key: key.into(),
             })),
+            ..Default::default()
         }
     }
 }
@@ -145,42 +943,146 @@ impl From<Vec<Value>> for CommandResponse {
     }
 }
 
+impl From<TableStats> for CommandResponse {
+    fn from(stats: TableStats) -> Self {
+        vec![
+            KvPair::new("keys", (stats.keys as i64).into()),
+            KvPair::new("bytes", (stats.bytes as i64).into()),
+        ]
+        .into()
+    }
+}
+
 impl From<KvError> for CommandResponse {
     fn from(error: KvError) -> Self {
         let status_code = match error {
             KvError::NotFound(_, _) => StatusCode::NOT_FOUND.as_u16(),
             KvError::InvalidCommand(_) => StatusCode::BAD_REQUEST.as_u16(),
-            _ => StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            KvError::ReadOnly => StatusCode::FORBIDDEN.as_u16(),
+            KvError::ValueTooLarge(_, _) => StatusCode::BAD_REQUEST.as_u16(),
+            KvError::ValueTooDeep(_, _) => StatusCode::BAD_REQUEST.as_u16(),
+            KvError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT.as_u16(),
+            KvError::Unavailable => StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+            KvError::MemoryLimitExceeded(_) => StatusCode::INSUFFICIENT_STORAGE.as_u16(),
+            KvError::UploadLimitExceeded(_) => StatusCode::TOO_MANY_REQUESTS.as_u16(),
+            KvError::ReplicationGap(_, _) => StatusCode::GONE.as_u16(),
+            KvError::Unauthorized(_) => StatusCode::UNAUTHORIZED.as_u16(),
+            KvError::ServerError(status, _) => status as u16,
+            KvError::FrameError
+            | KvError::ConvertError(_, _)
+            | KvError::BufferOverflow(_)
+            | KvError::StorageError(..)
+            | KvError::StorageFormat(_)
+            | KvError::CryptoError
+            | KvError::CertificateParseError(_, _)
+            | KvError::CertificateChainError(_)
+            | KvError::EncodeError(_)
+            | KvError::DecodeError(_)
+            | KvError::BincodeError(_)
+            | KvError::SledError(_)
+            | KvError::IoError(_)
+            | KvError::TlsError(_)
+            | KvError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
         };
 
         Self {
             status: status_code as u32,
             message: error.to_string(),
+            error_code: ErrorCode::from(&error) as i32,
             ..Default::default()
         }
     }
 }
 
+impl From<&KvError> for ErrorCode {
+    fn from(error: &KvError) -> Self {
+        match error {
+            KvError::NotFound(_, _) => ErrorCode::NotFound,
+            KvError::InvalidCommand(_) => ErrorCode::InvalidCommand,
+            KvError::ReadOnly => ErrorCode::ReadOnly,
+            KvError::ValueTooLarge(_, _) => ErrorCode::ValueTooLarge,
+            KvError::ValueTooDeep(_, _) => ErrorCode::ValueTooDeep,
+            KvError::ConvertError(_, _) => ErrorCode::ConvertError,
+            KvError::StorageError(..) | KvError::StorageFormat(_) | KvError::SledError(_) => ErrorCode::StorageError,
+            KvError::CryptoError => ErrorCode::CryptoError,
+            KvError::FrameError => ErrorCode::FrameError,
+            KvError::Timeout(_) => ErrorCode::Timeout,
+            KvError::Unavailable => ErrorCode::Unavailable,
+            KvError::MemoryLimitExceeded(_) => ErrorCode::MemoryLimit,
+            KvError::UploadLimitExceeded(_) => ErrorCode::UploadLimit,
+            KvError::ReplicationGap(_, _) => ErrorCode::ReplicationGap,
+            KvError::Unauthorized(_) => ErrorCode::Unauthorized,
+            // the status and message of a ServerError come from another server, there's no code to keep
+            KvError::ServerError(_, _)
+            | KvError::BufferOverflow(_)
+            | KvError::CertificateParseError(_, _)
+            | KvError::CertificateChainError(_)
+            | KvError::EncodeError(_)
+            | KvError::DecodeError(_)
+            | KvError::BincodeError(_)
+            | KvError::IoError(_)
+            | KvError::TlsError(_)
+            | KvError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+}
+
 impl CommandResponse {
     pub fn ok() -> Self {
-        let mut result = CommandResponse::default();
-        result.status = StatusCode::OK.as_u16() as _;
-        result
+        CommandResponse {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        }
     }
 
     pub fn format(&self) -> String {
         format!("{:?}", self)
     }
+
+    // the nesting depth of the deepest value in the response, 0 if it has no value
+    pub fn max_value_depth(&self) -> usize {
+        let values = self.values.iter().chain(self.pairs.iter().filter_map(|pair| pair.value.as_ref()));
+        values.map(Value::depth).max().unwrap_or(0)
+    }
 }
 
 impl Value {
     pub fn format(&self) -> String {
         format!("{:?}", self)
     }
+
+    // a point in time in milliseconds since the unix epoch, see Hexpirebefore
+    pub fn timestamp(millis: i64) -> Self {
+        Self {
+            value: Some(value::Value::Timestamp(millis)),
+        }
+    }
+
+    // the byte length for string and binary values, the encoded length for other values
+    pub fn size(&self) -> usize {
+        match &self.value {
+            Some(value::Value::String(s)) => s.len(),
+            Some(value::Value::Binary(b)) => b.len(),
+            _ => self.encoded_len(),
+        }
+    }
+
+    // how deep the value is nested, 1 for a scalar. a list, set or map is one deeper than its deepest member
+    pub fn depth(&self) -> usize {
+        let members = match &self.value {
+            Some(value::Value::List(list)) => list.values.iter().map(Value::depth).max(),
+            Some(value::Value::Set(set)) => set.members.iter().map(Value::depth).max(),
+            Some(value::Value::Map(map)) => {
+                map.pairs.iter().filter_map(|pair| pair.value.as_ref()).map(Value::depth).max()
+            }
+            _ => return 1,
+        };
+        1 + members.unwrap_or(0)
+    }
 }
 
 impl KvPair {
-    pub fn new(key: impl Into<String>, value: Value) -> Self {
+    pub fn new(key: impl Into<Bytes>, value: Value) -> Self {
         Self {
             key: key.into(),
             value: Some(value),
@@ -212,6 +1114,14 @@ impl From<i64> for Value {
     }
 }
 
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Self {
+            value: Some(value::Value::Float(f)),
+        }
+    }
+}
+
 impl From<bool> for Value {
     fn from(b: bool) -> Self {
         Self {
@@ -220,6 +1130,144 @@ impl From<bool> for Value {
     }
 }
 
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Self {
+            value: Some(value::Value::List(ValueList { values })),
+        }
+    }
+}
+
+impl From<ValueSet> for Value {
+    fn from(set: ValueSet) -> Self {
+        Self {
+            value: Some(value::Value::Set(set)),
+        }
+    }
+}
+
+impl From<ValueMap> for Value {
+    fn from(map: ValueMap) -> Self {
+        Self {
+            value: Some(value::Value::Map(map)),
+        }
+    }
+}
+
+impl From<HashMap<String, Value>> for ValueMap {
+    fn from(map: HashMap<String, Value>) -> Self {
+        // sort the pairs, so the same map is always encoded to the same bytes
+        let mut pairs: Vec<KvPair> = map.into_iter().map(|(k, v)| KvPair::new(k, v)).collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Self { pairs }
+    }
+}
+
+impl From<HashMap<String, Value>> for Value {
+    fn from(map: HashMap<String, Value>) -> Self {
+        ValueMap::from(map).into()
+    }
+}
+
+impl ValueSet {
+    // build a set from the values, the duplicated values are removed
+    pub fn new(values: Vec<Value>) -> Self {
+        let mut set = Self::default();
+        for value in values {
+            set.insert(value);
+        }
+        set
+    }
+
+    // add a member, return false if it's already in the set
+    pub fn insert(&mut self, value: Value) -> bool {
+        match self.members.binary_search_by(|m| cmp_members(m, &value)) {
+            Ok(_) => false,
+            Err(i) => {
+                self.members.insert(i, value);
+                true
+            }
+        }
+    }
+
+    // remove a member, return false if it's not in the set
+    pub fn remove(&mut self, value: &Value) -> bool {
+        match self.members.binary_search_by(|m| cmp_members(m, value)) {
+            Ok(i) => {
+                self.members.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn contains(&self, value: &Value) -> bool {
+        self.members.binary_search_by(|m| cmp_members(m, value)).is_ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+// order of the set members: by the Value variant, then by value
+// it's a total order: floats are compared with total_cmp, so NaN equals itself and is ordered after +inf,
+// and the lists, sets and maps are compared item by item with the same order
+fn cmp_members(a: &Value, b: &Value) -> Ordering {
+    match (&a.value, &b.value) {
+        (Some(a), Some(b)) => cmp_variants(a, b),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+fn cmp_variants(a: &value::Value, b: &value::Value) -> Ordering {
+    use value::Value::*;
+    match (a, b) {
+        (String(a), String(b)) => a.cmp(b),
+        (Binary(a), Binary(b)) => a.cmp(b),
+        (Integer(a), Integer(b)) => a.cmp(b),
+        (Float(a), Float(b)) => a.total_cmp(b),
+        (Bool(a), Bool(b)) => a.cmp(b),
+        (Timestamp(a), Timestamp(b)) => a.cmp(b),
+        (List(a), List(b)) => cmp_items(&a.values, &b.values, cmp_members),
+        (Set(a), Set(b)) => cmp_items(&a.members, &b.members, cmp_members),
+        (Map(a), Map(b)) => cmp_items(&a.pairs, &b.pairs, |a, b| {
+            a.key.cmp(&b.key).then_with(|| match (&a.value, &b.value) {
+                (Some(a), Some(b)) => cmp_members(a, b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            })
+        }),
+        // different variants are ordered as they're declared
+        (a, b) => variant_rank(a).cmp(&variant_rank(b)),
+    }
+}
+
+fn cmp_items<T>(a: &[T], b: &[T], cmp: impl Fn(&T, &T) -> Ordering) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| cmp(a, b))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
+
+fn variant_rank(value: &value::Value) -> u8 {
+    match value {
+        value::Value::String(_) => 0,
+        value::Value::Binary(_) => 1,
+        value::Value::Integer(_) => 2,
+        value::Value::Float(_) => 3,
+        value::Value::Bool(_) => 4,
+        value::Value::List(_) => 5,
+        value::Value::Set(_) => 6,
+        value::Value::Map(_) => 7,
+        value::Value::Timestamp(_) => 8,
+    }
+}
+
 impl<const N: usize> From<&[u8; N]> for Value {
     fn from(bytes: &[u8; N]) -> Self {
         Bytes::copy_from_slice(&bytes[..]).into()
@@ -234,8 +1282,8 @@ impl From<Bytes> for Value {
     }
 }
 
-impl From<(String, Value)> for KvPair {
-    fn from((key, value): (String, Value)) -> Self {
+impl From<(Vec<u8>, Value)> for KvPair {
+    fn from((key, value): (Vec<u8>, Value)) -> Self {
         KvPair::new(key, value)
     }
 }
@@ -269,6 +1317,105 @@ impl TryFrom<&Value> for i64 {
     }
 }
 
+impl TryFrom<&Value> for f64 {
+    type Error = KvError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value.value {
+            Some(value::Value::Float(f)) => Ok(f),
+            _ => Err(KvError::ConvertError(value.format(), "float")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for bool {
+    type Error = KvError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value.value {
+            Some(value::Value::Bool(b)) => Ok(b),
+            _ => Err(KvError::ConvertError(value.format(), "bool")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for String {
+    type Error = KvError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match &value.value {
+            Some(value::Value::String(s)) => Ok(s.clone()),
+            _ => Err(KvError::ConvertError(value.format(), "string")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for Bytes {
+    type Error = KvError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match &value.value {
+            Some(value::Value::Binary(b)) => Ok(b.clone()),
+            _ => Err(KvError::ConvertError(value.format(), "binary")),
+        }
+    }
+}
+
+impl TryFrom<Value> for ValueSet {
+    type Error = KvError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value.value {
+            Some(value::Value::Set(set)) => Ok(set),
+            _ => Err(KvError::ConvertError(value.format(), "set")),
+        }
+    }
+}
+
+impl TryFrom<Value> for ValueMap {
+    type Error = KvError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value.value {
+            Some(value::Value::Map(map)) => Ok(map),
+            _ => Err(KvError::ConvertError(value.format(), "map")),
+        }
+    }
+}
+
+impl TryFrom<ValueMap> for HashMap<String, Value> {
+    type Error = KvError;
+
+    fn try_from(map: ValueMap) -> Result<Self, Self::Error> {
+        map.pairs
+            .into_iter()
+            .map(|pair| match String::from_utf8(pair.key.to_vec()) {
+                Ok(key) => Ok((key, pair.value.unwrap_or_default())),
+                Err(_) => Err(KvError::ConvertError(format!("{:?}", pair.key), "string")),
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<Value> for HashMap<String, Value> {
+    type Error = KvError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        ValueMap::try_from(value)?.try_into()
+    }
+}
+
+impl TryFrom<Value> for Vec<Value> {
+    type Error = KvError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value.value {
+            Some(value::Value::List(list)) => Ok(list.values),
+            _ => Err(KvError::ConvertError(value.format(), "list")),
+        }
+    }
+}
+
 impl TryFrom<&CommandResponse> for i64 {
     type Error = KvError;
 
@@ -276,9 +1423,160 @@ impl TryFrom<&CommandResponse> for i64 {
         if value.status != StatusCode::OK.as_u16() as u32 {
             return Err(KvError::ConvertError(value.format(), "CommandResponse"));
         }
-        match value.values.get(0) {
+        match value.values.first() {
             Some(v) => v.try_into(),
             None => Err(KvError::ConvertError(value.format(), "CommandResponse")),
         }
     }
-}
\ No newline at end of file
+}
+
+impl TryFrom<CommandResponse> for Vec<KvPair> {
+    type Error = KvError;
+
+    fn try_from(value: CommandResponse) -> Result<Self, Self::Error> {
+        check_status(&value)?;
+        Ok(value.pairs)
+    }
+}
+
+impl TryFrom<CommandResponse> for Vec<Value> {
+    type Error = KvError;
+
+    fn try_from(value: CommandResponse) -> Result<Self, Self::Error> {
+        check_status(&value)?;
+        Ok(value.values)
+    }
+}
+
+// return the status and message of the response as an error if it's not 200
+pub(crate) fn check_status(response: &CommandResponse) -> Result<(), KvError> {
+    match response.status {
+        status if status == StatusCode::OK.as_u16() as u32 => Ok(()),
+        status => Err(KvError::ServerError(status, response.message.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_new_should_reject_empty_names() {
+        assert!(CommandRequest::try_new_hget("t1", "k1").is_ok());
+        assert!(CommandRequest::try_new_hset("t1", "k1", 1.into()).is_ok());
+
+        let e = CommandRequest::try_new_hget("", "k1").unwrap_err();
+        assert!(matches!(e, KvError::InvalidCommand(message) if message.contains("Table name of hget")));
+        let e = CommandRequest::try_new_hset("t1", "", 1.into()).unwrap_err();
+        assert!(matches!(e, KvError::InvalidCommand(message) if message.contains("Key of hset")));
+    }
+
+    #[test]
+    fn error_response_should_have_error_code() {
+        let response: CommandResponse = KvError::NotFound("t1".into(), "k1".into()).into();
+        assert_eq!(response.error_code(), ErrorCode::NotFound);
+        let response: CommandResponse = KvError::ReadOnly.into();
+        assert_eq!(response.error_code(), ErrorCode::ReadOnly);
+        let response: CommandResponse = KvError::ConvertError("v".into(), "integer").into();
+        assert_eq!(response.error_code(), ErrorCode::ConvertError);
+        let response: CommandResponse = KvError::Internal("oops".into()).into();
+        assert_eq!(response.error_code(), ErrorCode::Internal);
+
+        let response: CommandResponse = Value::from(1).into();
+        assert_eq!(response.error_code(), ErrorCode::None);
+    }
+
+    #[test]
+    fn response_try_into_pairs_and_values_should_work() {
+        let pairs = vec![KvPair::new("k1", 1.into()), KvPair::new("k2", "v2".into())];
+        let response: CommandResponse = pairs.clone().into();
+        assert_eq!(Vec::<KvPair>::try_from(response).unwrap(), pairs);
+
+        let values: Vec<Value> = vec![1.into(), "v2".into()];
+        let response: CommandResponse = values.clone().into();
+        assert_eq!(Vec::<Value>::try_from(response).unwrap(), values);
+
+        let response: CommandResponse = KvError::NotFound("t1".into(), "k1".into()).into();
+        let message = response.message.clone();
+        let err = Vec::<KvPair>::try_from(response.clone()).unwrap_err();
+        assert!(matches!(err, KvError::ServerError(404, m) if m == message));
+        assert!(matches!(Vec::<Value>::try_from(response), Err(KvError::ServerError(404, _))));
+    }
+
+    #[test]
+    fn value_try_into_scalar_should_work() {
+        let v: Value = 10.into();
+        assert_eq!(i64::try_from(&v).unwrap(), 10);
+        let v = Value { value: Some(value::Value::Float(1.5)) };
+        assert_eq!(f64::try_from(&v).unwrap(), 1.5);
+        let v: Value = true.into();
+        assert!(bool::try_from(&v).unwrap());
+        let v: Value = "hello".into();
+        assert_eq!(String::try_from(&v).unwrap(), "hello");
+        let v: Value = b"data".into();
+        assert_eq!(Bytes::try_from(&v).unwrap(), Bytes::from_static(b"data"));
+    }
+
+    #[test]
+    fn value_try_into_wrong_scalar_should_fail() {
+        let s: Value = "hello".into();
+        let i: Value = 10.into();
+        assert!(matches!(i64::try_from(&s), Err(KvError::ConvertError(_, "integer"))));
+        assert!(matches!(f64::try_from(&i), Err(KvError::ConvertError(_, "float"))));
+        assert!(matches!(bool::try_from(&s), Err(KvError::ConvertError(_, "bool"))));
+        assert!(matches!(String::try_from(&i), Err(KvError::ConvertError(_, "string"))));
+        assert!(matches!(Bytes::try_from(&s), Err(KvError::ConvertError(_, "binary"))));
+        assert!(String::try_from(&Value::default()).is_err());
+    }
+
+    #[test]
+    fn value_depth_should_count_the_nested_values() {
+        assert_eq!(Value::from(1).depth(), 1);
+        assert_eq!(Value::from(Vec::<Value>::new()).depth(), 1);
+        let list = Value::from(vec![Value::from(1), Value::from(vec![Value::from(2)])]);
+        assert_eq!(list.depth(), 3);
+        let map = Value::from(HashMap::from([("list".to_string(), list)]));
+        assert_eq!(map.depth(), 4);
+
+        let request = CommandRequest::new_publish("lobby", vec![1.into(), map.clone()]);
+        assert_eq!(request.max_value_depth(), 4);
+        let response = CommandResponse::from(vec![Value::from(1), map]);
+        assert_eq!(response.max_value_depth(), 4);
+        assert_eq!(CommandRequest::new_hget("t1", "k1").max_value_depth(), 0);
+    }
+
+    #[test]
+    fn value_map_should_convert_from_and_to_hashmap() {
+        let record = HashMap::from([("name".to_string(), "alice".into()), ("age".to_string(), 30.into())]);
+        let v: Value = record.clone().into();
+
+        // the pairs are sorted by key
+        let map = ValueMap::try_from(v.clone()).unwrap();
+        let keys: Vec<_> = map.pairs.iter().map(|pair| pair.key.clone()).collect();
+        assert_eq!(keys, vec![Bytes::from("age"), Bytes::from("name")]);
+
+        assert_eq!(HashMap::<String, Value>::try_from(v).unwrap(), record);
+        assert!(matches!(HashMap::<String, Value>::try_from(Value::from(1)), Err(KvError::ConvertError(_, "map"))));
+
+        let map = ValueMap { pairs: vec![KvPair::new(Bytes::from_static(b"\xff"), 1.into())] };
+        assert!(HashMap::<String, Value>::try_from(map).is_err());
+    }
+
+    #[test]
+    fn value_set_should_order_floats_totally() {
+        let mut set = ValueSet::default();
+        for f in [f64::NAN, 1.0, f64::NEG_INFINITY, f64::NAN, -0.0, 0.0, f64::INFINITY, 1.0] {
+            set.insert(f.into());
+        }
+        // NaN is a member once, after +inf
+        let members: Vec<f64> = set.members.iter().map(|m| f64::try_from(m).unwrap()).collect();
+        assert_eq!(members.len(), 6);
+        assert_eq!(members[..5], [f64::NEG_INFINITY, -0.0, 0.0, 1.0, f64::INFINITY]);
+        assert!(members[5].is_nan());
+        assert!(set.contains(&f64::NAN.into()));
+
+        assert!(set.remove(&f64::NAN.into()));
+        assert!(!set.contains(&f64::NAN.into()));
+        assert!(set.contains(&1.0.into()));
+    }
+}
@@ -1,7 +1,16 @@
 use anyhow::Result;
-use tokio::net::{TcpListener};
-use tracing::info;
-use kv::{MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor};
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::signal;
+use tracing::{info, warn};
+use kv::{run_listeners, ConnectionLimit, DrainController, MemTable, ServerListener, Service, ServiceInner, TlsServerAcceptor};
+
+// stop accepting new connections when this many are open
+const MAX_CONNECTIONS: usize = 1024;
+
+// how long the running requests can take after ctrl-c, the remaining connections are dropped then
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -11,20 +20,29 @@ async fn main() -> Result<()> {
     let server_key = include_str!("../fixtures/server.key");
 
     let addr = "127.0.0.1:9527";
+    // plaintext for the health checks on this host, it's never reachable from outside
+    let plain_addr = "127.0.0.1:9526";
     let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
     let service: Service = ServiceInner::new(MemTable::new()).into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening on {}", addr);
-
-    loop {
-        let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
-        info!("Got connection from {:?}", addr);
-        let stream = tls.accept(stream).await?;
-        let stream = ProstServerStream::new(stream, service.clone());
-        tokio::spawn(async move {
-            stream.process().await
-        });
+    let listeners = vec![
+        ServerListener::new(TcpListener::bind(addr).await?)
+            .with_tls(acceptor)
+            .with_limit(ConnectionLimit::Wait(MAX_CONNECTIONS)),
+        ServerListener::new(TcpListener::bind(plain_addr).await?).with_limit(ConnectionLimit::Reject(MAX_CONNECTIONS)),
+    ];
+    info!("Listening on {} (TLS) and {} (plaintext)", addr, plain_addr);
+
+    let drain = DrainController::new();
+    let server = run_listeners(listeners, service, drain.clone());
+    tokio::select! {
+        result = server => result?,
+        _ = signal::ctrl_c() => {
+            info!("Draining {} connections", drain.connections());
+            if !drain.drain(DRAIN_TIMEOUT).await {
+                warn!("Drain timed out, dropping {} connections", drain.connections());
+            }
+        }
     }
 
-}
\ No newline at end of file
+    Ok(())
+}
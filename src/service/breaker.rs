@@ -0,0 +1,148 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::KvError;
+
+// stop sending the commands to a broken storage for a while, so they fail fast instead of slowly
+//
+// closed: the commands are executed, `threshold` storage errors in a row within `window` open the breaker.
+// open: the commands get a 503 without touching the storage until `cooldown` passes.
+// half open: the next command is executed to test the storage, the breaker is closed if it succeeds,
+// otherwise it's open for another cooldown. the other commands get a 503 while the test is running.
+// if the test records no result within a cooldown, e.g. its response stream is dropped before it's polled,
+// the next command tests the storage instead, so the breaker can't be stuck half open
+// a storage error is a 5xx response, e.g. a sled or I/O error, or a command timeout, but not a full MemTable
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    // the errors in a row and when the first of them happened
+    Closed { errors: u32, since: Instant },
+    Open { until: Instant },
+    // a command is testing the storage, another one may test it after `until`
+    HalfOpen { until: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            cooldown,
+            state: Mutex::new(State::Closed { errors: 0, since: Instant::now() }),
+        }
+    }
+
+    // return an error if the command must not touch the storage
+    // the caller should record() the result of an allowed command, a half open breaker waits a cooldown for it
+    pub fn check(&self) -> Result<(), KvError> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } | State::HalfOpen { until } if now >= until => {
+                info!("Circuit breaker is half open, testing the storage");
+                *state = State::HalfOpen { until: now + self.cooldown };
+                Ok(())
+            }
+            State::Open { .. } | State::HalfOpen { .. } => Err(KvError::Unavailable),
+        }
+    }
+
+    pub fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        *state = match (*state, failed) {
+            // any success shows the storage works, even one started before the breaker opened
+            (_, false) => State::Closed { errors: 0, since: now },
+            (State::Closed { errors, since }, true) => {
+                // the errors before the window are too old to count
+                let (errors, since) = match errors {
+                    0 => (1, now),
+                    _ if now.duration_since(since) > self.window => (1, now),
+                    _ => (errors + 1, since),
+                };
+                if errors >= self.threshold {
+                    warn!("Circuit breaker is open after {} storage errors", errors);
+                    State::Open { until: now + self.cooldown }
+                } else {
+                    State::Closed { errors, since }
+                }
+            }
+            (State::HalfOpen { .. }, true) => {
+                warn!("Storage is still failing, circuit breaker is open again");
+                State::Open { until: now + self.cooldown }
+            }
+            // a command which was running when the breaker opened
+            (State::Open { until }, true) => State::Open { until },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn breaker_should_open_then_half_open_after_cooldown() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10), Duration::from_millis(20));
+        breaker.record(true);
+        assert!(breaker.check().is_ok());
+        breaker.record(true);
+        assert!(matches!(breaker.check(), Err(KvError::Unavailable)));
+
+        // only one command tests the storage, it fails, so the breaker is open again
+        thread::sleep(Duration::from_millis(30));
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+        breaker.record(true);
+        assert!(breaker.check().is_err());
+
+        thread::sleep(Duration::from_millis(30));
+        assert!(breaker.check().is_ok());
+        breaker.record(false);
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn errors_out_of_window_should_not_open_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(10), Duration::from_secs(10));
+        breaker.record(true);
+        thread::sleep(Duration::from_millis(20));
+        breaker.record(true);
+        assert!(breaker.check().is_ok());
+
+        // a success resets the errors in a row
+        breaker.record(false);
+        breaker.record(true);
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn half_open_breaker_should_test_again_if_no_result_is_recorded() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10), Duration::from_millis(20));
+        breaker.record(true);
+
+        // the test command never records its result
+        thread::sleep(Duration::from_millis(30));
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+
+        // another command tests the storage after a cooldown
+        thread::sleep(Duration::from_millis(30));
+        assert!(breaker.check().is_ok());
+        breaker.record(false);
+        assert!(breaker.check().is_ok());
+    }
+}
@@ -0,0 +1,383 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::stream;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+use crate::{glob_match, CommandResponse, Hgetall, Hgetchunked, Hsetchunked, KvError, KvPair, Storage, Value};
+use crate::service::ServiceInner;
+use crate::service::topic_service::StreamingResponse;
+
+// chunk size of Hgetchunked if the request doesn't set it
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+// how many frames of a chunked Hgetall can be read ahead of the client
+const PAIR_CHUNKS_AHEAD: usize = 4;
+
+// the limits of the unfinished Hsetchunked transfers if the service doesn't set them
+pub const DEFAULT_MAX_UPLOADS: usize = 1024;
+pub const DEFAULT_MAX_UPLOAD_BYTES: usize = 1024 * 1024 * 1024;
+pub const DEFAULT_UPLOAD_IDLE_TTL: Duration = Duration::from_secs(60);
+
+// a value being uploaded by Hsetchunked
+struct Upload {
+    table: String,
+    key: Bytes,
+    next_seq: u32,
+    data: Vec<u8>,
+    // when the last chunk arrived
+    updated: Instant,
+}
+
+// the unfinished Hsetchunked transfers, the chunks are kept in memory until the last one arrives
+// a transfer which gets no chunk for `idle_ttl` is dropped, the idle transfers are swept when a transfer starts
+// or a limit is reached. at most `max_uploads` transfers with `max_bytes` in total are kept, the chunks over
+// the limits are rejected with UploadLimitExceeded
+pub struct Uploads {
+    uploads: DashMap<u64, Upload>,
+    next_id: AtomicU64,
+    // the bytes of all unfinished transfers
+    buffered: AtomicUsize,
+    max_uploads: usize,
+    max_bytes: usize,
+    idle_ttl: Duration,
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_UPLOADS, DEFAULT_MAX_UPLOAD_BYTES, DEFAULT_UPLOAD_IDLE_TTL)
+    }
+}
+
+impl Uploads {
+    pub fn new(max_uploads: usize, max_bytes: usize, idle_ttl: Duration) -> Self {
+        Self {
+            uploads: DashMap::new(),
+            next_id: AtomicU64::new(0),
+            buffered: AtomicUsize::new(0),
+            max_uploads,
+            max_bytes,
+            idle_ttl,
+        }
+    }
+
+    // add a chunk to its transfer, write the whole value to the storage if it's the last chunk
+    // return [transfer id, next expected seq]
+    // a chunk with a wrong seq is rejected, the transfer is kept so the client can resend from the expected seq
+    pub fn add_chunk(&self, chunk: Hsetchunked, store: &impl Storage, max_value_bytes: Option<usize>) -> CommandResponse {
+        let id = match chunk.transfer_id {
+            0 if chunk.seq == 0 => match self.start(&chunk) {
+                Ok(id) => id,
+                Err(e) => return e.into(),
+            },
+            0 => return KvError::InvalidCommand("the first chunk must have seq 0".into()).into(),
+            id => id,
+        };
+
+        let mut result = self.append(id, &chunk);
+        if matches!(result, Err(KvError::UploadLimitExceeded(_))) && self.sweep() > 0 {
+            // the idle transfers are gone, there may be room for the chunk now
+            result = self.append(id, &chunk);
+        }
+        let (next_seq, len) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                // a rejected first chunk doesn't leave an empty transfer behind
+                if chunk.transfer_id == 0 {
+                    self.remove(id);
+                }
+                return e.into();
+            }
+        };
+
+        if let Some(limit) = max_value_bytes.filter(|limit| len > *limit) {
+            self.remove(id);
+            return KvError::ValueTooLarge(len, limit).into();
+        }
+
+        if chunk.last {
+            let upload = match self.remove(id) {
+                Some(upload) => upload,
+                None => return KvError::InvalidCommand(format!("unknown transfer {}", id)).into(),
+            };
+            debug!("Chunked transfer {} is finished with {} bytes", id, len);
+            let value: Value = Bytes::from(upload.data).into();
+            if let Err(e) = store.set(&upload.table, upload.key.to_vec(), value) {
+                return e.into();
+            }
+        }
+
+        vec![Value::from(id as i64), Value::from(next_seq as i64)].into()
+    }
+
+    // start a transfer and return its id
+    fn start(&self, chunk: &Hsetchunked) -> Result<u64, KvError> {
+        self.sweep();
+        if self.uploads.len() >= self.max_uploads {
+            return Err(KvError::UploadLimitExceeded(format!("{} transfers are unfinished", self.uploads.len())));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let upload = Upload {
+            table: chunk.table.clone(),
+            key: chunk.key.clone(),
+            next_seq: 0,
+            data: vec![],
+            updated: Instant::now(),
+        };
+        self.uploads.insert(id, upload);
+        debug!("Chunked transfer {} is started", id);
+        Ok(id)
+    }
+
+    // append the chunk to its transfer, return the next expected seq and the bytes received so far
+    fn append(&self, id: u64, chunk: &Hsetchunked) -> Result<(u32, usize), KvError> {
+        let unknown = || KvError::InvalidCommand(format!("unknown transfer {}", id));
+        let mut upload = self.uploads.get_mut(&id).ok_or_else(unknown)?;
+        if upload.updated.elapsed() >= self.idle_ttl {
+            drop(upload);
+            self.remove(id);
+            return Err(unknown());
+        }
+        if upload.table != chunk.table || upload.key != chunk.key {
+            return Err(KvError::InvalidCommand(format!("transfer {} is for another key", id)));
+        }
+        if upload.next_seq != chunk.seq {
+            let message = format!("transfer {} expects chunk {}, got {}", id, upload.next_seq, chunk.seq);
+            return Err(KvError::InvalidCommand(message));
+        }
+        // reserve the bytes before the chunk is buffered, so the concurrent transfers can't exceed the limit
+        let len = chunk.data.len();
+        let reserved = self
+            .buffered
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_add(len).filter(|n| *n <= self.max_bytes));
+        if reserved.is_err() {
+            return Err(KvError::UploadLimitExceeded(format!("{} bytes are buffered", self.max_bytes)));
+        }
+        upload.data.extend_from_slice(&chunk.data);
+        upload.next_seq += 1;
+        upload.updated = Instant::now();
+        Ok((upload.next_seq, upload.data.len()))
+    }
+
+    fn remove(&self, id: u64) -> Option<Upload> {
+        self.remove_if(id, |_| true)
+    }
+
+    fn remove_if(&self, id: u64, f: impl FnOnce(&Upload) -> bool) -> Option<Upload> {
+        let (_, upload) = self.uploads.remove_if(&id, |_, upload| f(upload))?;
+        self.buffered.fetch_sub(upload.data.len(), Ordering::AcqRel);
+        Some(upload)
+    }
+
+    // drop the idle transfers, return how many are dropped
+    fn sweep(&self) -> usize {
+        let idle: Vec<u64> = self
+            .uploads
+            .iter()
+            .filter(|upload| upload.updated.elapsed() >= self.idle_ttl)
+            .map(|upload| *upload.key())
+            .collect();
+        // a transfer may get a chunk after it's listed
+        let dropped = idle
+            .into_iter()
+            .filter_map(|id| self.remove_if(id, |upload| upload.updated.elapsed() >= self.idle_ttl))
+            .count();
+        if dropped > 0 {
+            debug!("{} idle chunked transfers are dropped", dropped);
+        }
+        dropped
+    }
+}
+
+impl Hgetall {
+    // every response has at most chunk_size pairs and values [last]
+    // the table is iterated by a blocking task which waits while the frames are not sent yet,
+    // so a lazy storage (e.g. SledDb) only keeps a few chunks in memory. MemTable iterates a copy of the table
+    pub fn execute_chunked<Store: Storage + Send + Sync + 'static>(self, inner: Arc<ServiceInner<Store>>) -> StreamingResponse {
+        let (sender, receiver) = mpsc::channel(PAIR_CHUNKS_AHEAD);
+        let chunk_size = self.chunk_size.max(1) as usize;
+        tokio::task::spawn_blocking(move || {
+            let pairs = match inner.store.get_iter(&self.table) {
+                Ok(pairs) => pairs,
+                Err(e) => {
+                    let _ = sender.blocking_send(Arc::new(e.into()));
+                    return;
+                }
+            };
+            let pattern = self.pattern;
+            let mut pairs = pairs.filter(|pair| pattern.is_empty() || glob_match(&pattern, &pair.key)).peekable();
+            loop {
+                let chunk: Vec<KvPair> = pairs.by_ref().take(chunk_size).collect();
+                let last = pairs.peek().is_none();
+                let mut response: CommandResponse = chunk.into();
+                response.values = vec![last.into()];
+                // stop reading if the client is gone
+                if sender.blocking_send(Arc::new(response)).is_err() || last {
+                    return;
+                }
+            }
+        });
+        Box::pin(ReceiverStream::new(receiver))
+    }
+}
+
+impl Hgetchunked {
+    // every response is [seq, number of chunks, data], an empty value is sent as one empty chunk
+    // the whole value is read from the storage first, the chunks share its memory
+    pub fn execute(self, store: &impl Storage) -> StreamingResponse {
+        let data = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => Bytes::try_from(&v),
+            Ok(None) => Err(KvError::NotFound(self.table, String::from_utf8_lossy(&self.key).into())),
+            Err(e) => Err(e),
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                let response: CommandResponse = e.into();
+                return Box::pin(stream::once(async { Arc::new(response) }));
+            }
+        };
+
+        let chunk_size = match self.chunk_size as usize {
+            0 => DEFAULT_CHUNK_SIZE,
+            size => size,
+        };
+        let count = data.len().div_ceil(chunk_size).max(1);
+        Box::pin(stream::iter((0..count).map(move |seq| {
+            let chunk = data.slice((seq * chunk_size).min(data.len())..((seq + 1) * chunk_size).min(data.len()));
+            let values = vec![Value::from(seq as i64), Value::from(count as i64), chunk.into()];
+            Arc::new(values.into())
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use futures::StreamExt;
+
+    use crate::{assert_response_error, assert_response_ok, ErrorCode, MemTable};
+
+    use super::*;
+
+    fn chunk(transfer_id: u64, seq: u32, data: &'static [u8], last: bool) -> Hsetchunked {
+        Hsetchunked {
+            table: "blobs".into(),
+            key: Bytes::from_static(b"k1"),
+            transfer_id,
+            seq,
+            data: Bytes::from_static(data),
+            last,
+        }
+    }
+
+    #[test]
+    fn chunked_upload_should_work() {
+        let store = MemTable::new();
+        let uploads = Uploads::default();
+
+        let response = uploads.add_chunk(chunk(0, 0, b"hello ", false), &store, None);
+        let id: i64 = (&response.values[0]).try_into().unwrap();
+        assert_response_ok(&response, &[id.into(), 1.into()], &[]);
+
+        // nothing is written before the last chunk
+        assert_eq!(store.get("blobs", b"k1").unwrap(), None);
+
+        // a wrong seq is rejected, the transfer can continue from the expected seq
+        let response = uploads.add_chunk(chunk(id as u64, 2, b"!", true), &store, None);
+        assert_response_error(&response, 400, "expects chunk 1");
+
+        let response = uploads.add_chunk(chunk(id as u64, 1, b"world", true), &store, None);
+        assert_response_ok(&response, &[id.into(), 2.into()], &[]);
+        assert_eq!(store.get("blobs", b"k1").unwrap(), Some(Value::from(b"hello world")));
+
+        // the finished transfer is removed
+        let response = uploads.add_chunk(chunk(id as u64, 2, b"!", true), &store, None);
+        assert_response_error(&response, 400, "unknown transfer");
+    }
+
+    #[test]
+    fn chunked_upload_bigger_than_limit_should_fail() {
+        let store = MemTable::new();
+        let uploads = Uploads::default();
+
+        let response = uploads.add_chunk(chunk(0, 0, b"hello ", false), &store, Some(8));
+        let id: i64 = (&response.values[0]).try_into().unwrap();
+        let response = uploads.add_chunk(chunk(id as u64, 1, b"world", true), &store, Some(8));
+        assert_response_error(&response, 400, "larger than the limit");
+        assert!(uploads.uploads.is_empty());
+        assert_eq!(store.get("blobs", b"k1").unwrap(), None);
+    }
+
+    #[test]
+    fn idle_chunked_uploads_should_be_dropped() {
+        let store = MemTable::new();
+        let uploads = Uploads::new(1, 100, Duration::from_millis(20));
+
+        let response = uploads.add_chunk(chunk(0, 0, b"hello ", false), &store, None);
+        let id: i64 = (&response.values[0]).try_into().unwrap();
+        let response = uploads.add_chunk(chunk(0, 0, b"hello ", false), &store, None);
+        assert_response_error(&response, 429, "1 transfers are unfinished");
+        assert_eq!(response.error_code, ErrorCode::UploadLimit as i32);
+
+        // the idle transfer is swept when another one starts
+        thread::sleep(Duration::from_millis(30));
+        let response = uploads.add_chunk(chunk(0, 0, b"hi", false), &store, None);
+        assert_eq!(response.status, 200);
+        assert_eq!(uploads.buffered.load(Ordering::Acquire), 2);
+        let response = uploads.add_chunk(chunk(id as u64, 1, b"world", true), &store, None);
+        assert_response_error(&response, 400, "unknown transfer");
+
+        // a transfer which is idle too long can't continue either
+        let id = id + 1;
+        thread::sleep(Duration::from_millis(30));
+        let response = uploads.add_chunk(chunk(id as u64, 1, b"world", true), &store, None);
+        assert_response_error(&response, 400, "unknown transfer");
+        assert_eq!(store.get("blobs", b"k1").unwrap(), None);
+    }
+
+    #[test]
+    fn chunked_uploads_over_the_byte_limit_should_be_rejected() {
+        let store = MemTable::new();
+        let uploads = Uploads::new(4, 8, Duration::from_secs(60));
+
+        let response = uploads.add_chunk(chunk(0, 0, b"hello ", false), &store, None);
+        let id: i64 = (&response.values[0]).try_into().unwrap();
+        let response = uploads.add_chunk(chunk(0, 0, b"abc", false), &store, None);
+        assert_response_error(&response, 429, "8 bytes are buffered");
+        assert_eq!(uploads.uploads.len(), 1);
+
+        // the rejected chunk can be resent once the other transfer is finished
+        let response = uploads.add_chunk(chunk(id as u64, 1, b"!", true), &store, None);
+        assert_response_ok(&response, &[id.into(), 2.into()], &[]);
+        assert_eq!(uploads.buffered.load(Ordering::Acquire), 0);
+        let response = uploads.add_chunk(chunk(0, 0, b"abc", true), &store, None);
+        assert_eq!(response.status, 200);
+        assert_eq!(store.get("blobs", b"k1").unwrap(), Some(Value::from(b"abc")));
+    }
+
+    #[tokio::test]
+    async fn chunked_download_should_work() {
+        let store = MemTable::new();
+        store.set("blobs", b"k1".to_vec(), b"hello world".into()).unwrap();
+
+        let request = Hgetchunked { table: "blobs".into(), key: Bytes::from_static(b"k1"), chunk_size: 4 };
+        let responses: Vec<_> = request.execute(&store).collect().await;
+        let chunks: Vec<&[u8]> = vec![b"hell", b"o wo", b"rld"];
+        assert_eq!(responses.len(), chunks.len());
+        for (seq, (response, chunk)) in responses.iter().zip(chunks).enumerate() {
+            assert_response_ok(response, &[(seq as i64).into(), 3.into(), Bytes::from(chunk).into()], &[]);
+        }
+
+        let request = Hgetchunked { table: "blobs".into(), key: Bytes::from_static(b"k2"), chunk_size: 4 };
+        let responses: Vec<_> = request.execute(&store).collect().await;
+        assert_eq!(responses.len(), 1);
+        assert_response_error(&responses[0], 404, "Not found");
+    }
+}
@@ -1,10 +1,46 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
 use crate::*;
 
+// the features which are provided by a command, with a request of that command.
+// a feature is only reported if its command is handled, see command_features_should_be_dispatched
+fn command_features() -> Vec<(&'static str, CommandRequest)> {
+    vec![
+        ("pubsub", CommandRequest::new_subscribe("")),
+        ("publish_ack", CommandRequest::new_ack(0, 0)),
+        ("watch", CommandRequest::new_watch("", "")),
+        ("list", CommandRequest::new_lrange("", "", 0, -1)),
+        ("range", CommandRequest::new_hrange("", "", "")),
+        ("match", CommandRequest::new_hget_all_match("", "*")),
+    ]
+}
+
+// the features supported by this server, reported by Hello
+fn server_features() -> Vec<Value> {
+    let mut features = vec!["gzip", "tls", "yamux"];
+    if cfg!(unix) {
+        features.push("uds");
+    }
+    features.extend(command_features().into_iter().map(|(feature, _)| feature));
+    features.into_iter().map(Value::from).collect()
+}
+
 impl CommandService for Hget {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match store.get(&self.table, &self.key) {
             Ok(Some(value)) => value.into(),
-            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Ok(None) => KvError::NotFound(self.table, String::from_utf8_lossy(&self.key).into()).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetor {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(Some(value)) => vec![value, false.into()].into(),
+            Ok(None) => vec![self.default.unwrap_or_default(), true.into()].into(),
             Err(e) => e.into(),
         }
     }
@@ -13,7 +49,7 @@ impl CommandService for Hget {
 impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match self.pair {
-            Some(pair) => match store.set(&self.table, pair.key, pair.value.unwrap_or_default()) {
+            Some(pair) => match store.set(&self.table, pair.key.to_vec(), pair.value.unwrap_or_default()) {
                 Ok(Some(value)) => value.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -23,9 +59,254 @@ impl CommandService for Hset {
     }
 }
 
+impl CommandService for Hsetnx {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.set_if_absent(&self.table, self.key.to_vec(), self.value.unwrap_or_default()) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetchanged {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.set_if_changed(&self.table, self.key.to_vec(), self.value.unwrap_or_default()) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lpush {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.lpush(&self.table, self.key.to_vec(), self.values) {
+            Ok(len) => Value::from(len as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetreset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get_and_reset(&self.table, &self.key) {
+            Ok(old) => Value::from(old).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Htouch {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.touch(&self.table, &self.key, Duration::from_millis(self.ttl_ms)) {
+            Ok(exists) => Value::from(exists).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrfloat {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr_float(&self.table, self.key.to_vec(), self.delta) {
+            Ok(new) => Value::from(new).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Sadd {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.sadd(&self.table, self.key.to_vec(), self.members) {
+            Ok(added) => Value::from(added as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Srem {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.srem(&self.table, self.key.to_vec(), self.members) {
+            Ok(removed) => Value::from(removed as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Smembers {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => match ValueSet::try_from(v) {
+                Ok(set) => set.members.into(),
+                Err(e) => e.into(),
+            },
+            Ok(None) => KvError::NotFound(self.table, String::from_utf8_lossy(&self.key).into()).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lrange {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let list: Vec<Value> = match store.get(&self.table, &self.key) {
+            Ok(Some(v)) => match v.try_into() {
+                Ok(list) => list,
+                Err(e) => return e.into(),
+            },
+            Ok(None) => return KvError::NotFound(self.table, String::from_utf8_lossy(&self.key).into()).into(),
+            Err(e) => return e.into(),
+        };
+
+        // negative index counts from the end, like redis LRANGE
+        let len = list.len() as i64;
+        let normalize = |i: i64| if i < 0 { len + i } else { i };
+        let start = normalize(self.start).max(0);
+        let stop = normalize(self.stop).min(len - 1);
+        if start > stop {
+            return Vec::<Value>::new().into();
+        }
+
+        list[start as usize..=stop as usize].to_vec().into()
+    }
+}
+
+impl CommandService for Hstrlen {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.value_size(&self.table, &self.key) {
+            Ok(Some(size)) => Value::from(size as i64).into(),
+            Ok(None) => KvError::NotFound(self.table, String::from_utf8_lossy(&self.key).into()).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hexpirebefore {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.expire_before(&self.table, self.cutoff) {
+            Ok(count) => Value::from(count as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdelprefix {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.del_by_prefix(&self.table, &self.prefix) {
+            Ok(count) => Value::from(count as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Stats {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.table_stats(&self.table) {
+            Ok(stats) => stats.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Renametable {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.rename_table(&self.from, &self.to) {
+            Ok(()) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Flushall {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.clear() {
+            Ok(keys) => Value::from(keys as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// the table read by Readiness, nothing is written to it and the storages don't create it on a read
+const READINESS_TABLE: &str = "__readiness__";
+
+impl CommandService for Readiness {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(READINESS_TABLE, b"") {
+            Ok(_) => Value::from("READY").into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hello {
+    fn execute(self, _store: &impl Storage) -> CommandResponse {
+        vec![Value::from(env!("CARGO_PKG_VERSION")), Value::from(server_features())].into()
+    }
+}
+
 impl CommandService for Hgetall {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
+        let result = match self.pattern.as_str() {
+            "" => store.get_all(&self.table),
+            pattern => store.get_matched(&self.table, pattern),
+        };
+        match result {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hfindvalue {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let value = self.value.unwrap_or_default();
+        match store.get_iter(&self.table) {
+            Ok(pairs) => pairs.filter(|pair| pair.value.as_ref() == Some(&value)).collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hqueryindex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.query_index(&self.table, &self.field, &self.value.unwrap_or_default()) {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdiff {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // only table_b is kept in memory, table_a is compared while it's iterated
+        let others: HashMap<_, _> = match store.get_iter(&self.table_b) {
+            Ok(pairs) => pairs.map(|pair| (pair.key, pair.value)).collect(),
+            Err(e) => return e.into(),
+        };
+        match store.get_iter(&self.table_a) {
+            Ok(pairs) => pairs.filter(|pair| others.get(&pair.key) != Some(&pair.value)).collect::<Vec<_>>().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmgetall {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut result = Vec::new();
+        for table in self.tables {
+            match store.get_all(&table) {
+                Ok(pairs) => result.extend(
+                    pairs
+                        .into_iter()
+                        .map(|pair| KvPair { key: [table.as_bytes(), b":", &pair.key].concat().into(), ..pair }),
+                ),
+                Err(e) => return e.into(),
+            }
+        }
+        result.into()
+    }
+}
+
+impl CommandService for Hrange {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.get_range(&self.table, &self.start, &self.end) {
             Ok(pairs) => pairs.into(),
             Err(e) => e.into(),
         }
@@ -45,12 +326,27 @@ impl CommandService for Hmget {
     }
 }
 
+impl CommandService for Hmgetex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut values = Vec::with_capacity(self.keys.len() * 2);
+        for key in self.keys {
+            match store.get(&self.table, &key) {
+                Ok(Some(v)) => values.extend([true.into(), v]),
+                Ok(None) => values.extend([false.into(), Value::default()]),
+                // unlike Hmget, an error is not reported as a missing key
+                Err(e) => return e.into(),
+            }
+        }
+        values.into()
+    }
+}
+
 impl CommandService for Hmset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         self.pairs
             .into_iter()
             .map(
-                |pair| match store.set(&self.table, pair.key, pair.value.unwrap_or_default()) {
+                |pair| match store.set(&self.table, pair.key.to_vec(), pair.value.unwrap_or_default()) {
                     Ok(Some(v)) => v,
                     _ => Value::default(),
                 },
@@ -60,6 +356,34 @@ impl CommandService for Hmset {
     }
 }
 
+impl CommandService for Hinitifempty {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.init_if_empty(&self.table, self.pairs) {
+            Ok(initialized) => Value::from(initialized).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hswap {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.swap(&self.table, &self.key1, &self.key2) {
+            Ok((old1, old2)) => vec![old1, old2].into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmove {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.move_key(&self.from_table, &self.to_table, &self.key) {
+            Ok(Some(value)) => value.into(),
+            Ok(None) => KvError::NotFound(self.from_table, String::from_utf8_lossy(&self.key).into()).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
 impl CommandService for Hdel {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match store.del(&self.table, &self.key) {
@@ -106,28 +430,430 @@ impl CommandService for Hmexist {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::collections::HashMap;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use prost::Message;
+
     use super::*;
 
     #[test]
     fn hset_should_work() {
         let store = MemTable::new();
         let request = CommandRequest::new_hset("t1", "hello", "world".into());
-        let response = dispatch(request.clone(), &store);
+        let response = dispatch(request.clone(), &store).unwrap();
         assert_response_ok(&response, &[Value::default()], &[]);
 
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_ok(&response, &["world".into()], &[]);
     }
 
+    #[test]
+    fn hsetchanged_should_work() {
+        let store = MemTable::new();
+        let request = CommandRequest::new_hsetchanged("score", "math", 10.into());
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[true.into()], &[]);
+
+        let request = CommandRequest::new_hsetchanged("score", "math", 10.into());
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[false.into()], &[]);
+
+        let request = CommandRequest::new_hsetchanged("score", "math", 20.into());
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[true.into()], &[]);
+
+        let request = CommandRequest::new_hget("score", "math");
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[20.into()], &[]);
+    }
+
+    #[test]
+    fn hincrfloat_should_work() {
+        let store = MemTable::new();
+        let request = CommandRequest::new_hincrfloat("avg", "latency", 1.5);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[1.5.into()], &[]);
+
+        let request = CommandRequest::new_hincrfloat("avg", "latency", 2.0);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[3.5.into()], &[]);
+
+        dispatch(CommandRequest::new_hset("avg", "name", "v".into()), &store);
+        let request = CommandRequest::new_hincrfloat("avg", "name", 1.0);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_error(&response, 500, "Cannot convert");
+    }
+
+    #[test]
+    fn hgetreset_should_work() {
+        let store = MemTable::new();
+        let response = dispatch(CommandRequest::new_hgetreset("metrics", "requests"), &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+
+        dispatch(CommandRequest::new_hset("metrics", "requests", 42.into()), &store);
+        let response = dispatch(CommandRequest::new_hgetreset("metrics", "requests"), &store).unwrap();
+        assert_response_ok(&response, &[42.into()], &[]);
+        let response = dispatch(CommandRequest::new_hget("metrics", "requests"), &store).unwrap();
+        assert_response_ok(&response, &[0.into()], &[]);
+    }
+
+    #[test]
+    fn hsetnx_should_work() {
+        let store = MemTable::new();
+        let request = CommandRequest::new_hsetnx("lock", "job", "worker1".into());
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[true.into()], &[]);
+
+        let request = CommandRequest::new_hsetnx("lock", "job", "worker2".into());
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[false.into()], &[]);
+
+        let request = CommandRequest::new_hget("lock", "job");
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &["worker1".into()], &[]);
+    }
+
+    #[test]
+    fn lpush_lrange_should_work() {
+        let store = MemTable::new();
+        let request = CommandRequest::new_lpush("list", "k", vec![1.into(), 2.into(), 3.into()]);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[3.into()], &[]);
+
+        let request = CommandRequest::new_lrange("list", "k", 0, -1);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[3.into(), 2.into(), 1.into()], &[]);
+
+        let request = CommandRequest::new_lrange("list", "k", 1, 10);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[2.into(), 1.into()], &[]);
+
+        let request = CommandRequest::new_lrange("list", "k", -1, 0);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[], &[]);
+
+        let request = CommandRequest::new_lrange("list", "missing", 0, -1);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+    }
+
+    #[test]
+    fn sadd_srem_smembers_should_work() {
+        let store = MemTable::new();
+        let request = CommandRequest::new_sadd("set", "k", vec!["b".into(), "a".into(), "b".into()]);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[2.into()], &[]);
+
+        let request = CommandRequest::new_sadd("set", "k", vec![1.into(), "a".into()]);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[1.into()], &[]);
+
+        // members are ordered by their type first, then by value
+        let request = CommandRequest::new_smembers("set", "k");
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &["a".into(), "b".into(), 1.into()], &[]);
+
+        let request = CommandRequest::new_srem("set", "k", vec!["a".into(), "c".into()]);
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &[1.into()], &[]);
+
+        let request = CommandRequest::new_smembers("set", "k");
+        let response = dispatch(request, &store).unwrap();
+        assert_response_ok(&response, &["b".into(), 1.into()], &[]);
+
+        let request = CommandRequest::new_smembers("set", "missing");
+        let response = dispatch(request, &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+
+        dispatch(CommandRequest::new_hset("set", "str", "v".into()), &store);
+        let request = CommandRequest::new_smembers("set", "str");
+        let response = dispatch(request, &store).unwrap();
+        assert_eq!(response.error_code, ErrorCode::ConvertError as i32);
+    }
+
+    #[test]
+    fn stats_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("score", "math", 10.into()), &store);
+        dispatch(CommandRequest::new_hset("score", "english", 20.into()), &store);
+
+        let request = CommandRequest::new_stats("score");
+        let response = dispatch(request, &store).unwrap();
+        let bytes = "math".len() + "english".len() + 2 * Value::from(10).encoded_len();
+        let pairs = vec![
+            KvPair::new("bytes", (bytes as i64).into()),
+            KvPair::new("keys", 2.into()),
+        ];
+        assert_response_ok(&response, &[], &pairs);
+    }
+
+    #[test]
+    fn hexpirebefore_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("metrics", "cpu:1000", Value::timestamp(1_000)), &store);
+        dispatch(CommandRequest::new_hset("metrics", "cpu:2000", Value::timestamp(2_000)), &store);
+        dispatch(CommandRequest::new_hset("metrics", "host", "web1".into()), &store);
+        // an integer is not a timestamp, however small it is
+        dispatch(CommandRequest::new_hset("metrics", "load", 1.into()), &store);
+
+        let response = dispatch(CommandRequest::new_hexpirebefore("metrics", 1_500), &store).unwrap();
+        assert_response_ok(&response, &[1.into()], &[]);
+
+        let response = dispatch(CommandRequest::new_hget_all("metrics"), &store).unwrap();
+        let pairs = [
+            KvPair::new("cpu:2000", Value::timestamp(2_000)),
+            KvPair::new("host", "web1".into()),
+            KvPair::new("load", 1.into()),
+        ];
+        assert_response_ok(&response, &[], &pairs);
+    }
+
+    #[test]
+    fn htouch_should_work() {
+        let store = ExpiringStore::new(MemTable::new());
+        dispatch(CommandRequest::new_hset("sessions", "s1", "alice".into()), &store);
+
+        let response = dispatch(CommandRequest::new_htouch("sessions", "s1", Duration::from_millis(20)), &store);
+        assert_response_ok(&response.unwrap(), &[true.into()], &[]);
+        let response = dispatch(CommandRequest::new_htouch("sessions", "s2", Duration::from_millis(20)), &store);
+        assert_response_ok(&response.unwrap(), &[false.into()], &[]);
+
+        std::thread::sleep(Duration::from_millis(30));
+        let response = dispatch(CommandRequest::new_hget("sessions", "s1"), &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+
+        // the storage has no expiry
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("sessions", "s1", "alice".into()), &store);
+        let response = dispatch(CommandRequest::new_htouch("sessions", "s1", Duration::from_secs(1)), &store).unwrap();
+        assert_response_error(&response, 400, "has no expiry");
+    }
+
+    #[test]
+    fn hdelprefix_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("user", "session:1", 1.into()), &store);
+        dispatch(CommandRequest::new_hset("user", "session:2", 2.into()), &store);
+        dispatch(CommandRequest::new_hset("user", "name", "tyr".into()), &store);
+
+        let response = dispatch(CommandRequest::new_hdelprefix("user", "session:"), &store).unwrap();
+        assert_response_ok(&response, &[2.into()], &[]);
+
+        let response = dispatch(CommandRequest::new_hget_all("user"), &store).unwrap();
+        assert_response_ok(&response, &[], &[KvPair::new("name", "tyr".into())]);
+
+        let response = dispatch(CommandRequest::new_hdelprefix("user", "session:"), &store).unwrap();
+        assert_response_ok(&response, &[0.into()], &[]);
+    }
+
+    #[test]
+    fn renametable_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("scores", "math", 10.into()), &store);
+        dispatch(CommandRequest::new_hset("scores_v2", "math", 20.into()), &store);
+
+        let response = dispatch(CommandRequest::new_renametable("scores_v2", "scores"), &store).unwrap();
+        assert_response_ok(&response, &[], &[]);
+
+        let response = dispatch(CommandRequest::new_hget("scores", "math"), &store).unwrap();
+        assert_response_ok(&response, &[20.into()], &[]);
+        let response = dispatch(CommandRequest::new_hget_all("scores_v2"), &store).unwrap();
+        assert_response_ok(&response, &[], &[]);
+
+        // the `to` table is kept if `from` doesn't exist
+        let response = dispatch(CommandRequest::new_renametable("scores_v2", "scores"), &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+        let response = dispatch(CommandRequest::new_hget("scores", "math"), &store).unwrap();
+        assert_response_ok(&response, &[20.into()], &[]);
+    }
+
+    #[test]
+    fn hswap_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("rank", "first", "alice".into()), &store);
+        dispatch(CommandRequest::new_hset("rank", "second", "bob".into()), &store);
+
+        let response = dispatch(CommandRequest::new_hswap("rank", "first", "second"), &store).unwrap();
+        assert_response_ok(&response, &["alice".into(), "bob".into()], &[]);
+        let response = dispatch(CommandRequest::new_hmget("rank", vec!["first".into(), "second".into()]), &store);
+        assert_response_ok(&response.unwrap(), &["bob".into(), "alice".into()], &[]);
+
+        let response = dispatch(CommandRequest::new_hswap("rank", "first", "third"), &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+    }
+
+    #[test]
+    fn hmove_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("pending", "job1", "resize".into()), &store);
+
+        let response = dispatch(CommandRequest::new_hmove("pending", "done", "job1"), &store).unwrap();
+        assert_response_ok(&response, &["resize".into()], &[]);
+        let response = dispatch(CommandRequest::new_hget("done", "job1"), &store).unwrap();
+        assert_response_ok(&response, &["resize".into()], &[]);
+        let response = dispatch(CommandRequest::new_hexist("pending", "job1"), &store).unwrap();
+        assert_response_ok(&response, &[false.into()], &[]);
+
+        let response = dispatch(CommandRequest::new_hmove("pending", "done", "job1"), &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+    }
+
+    #[test]
+    fn flushall_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("score", "math", 10.into()), &store);
+        dispatch(CommandRequest::new_hset("user", "name", "tyr".into()), &store);
+
+        let response = dispatch(CommandRequest::new_flushall(), &store).unwrap();
+        assert_response_ok(&response, &[2.into()], &[]);
+
+        let response = dispatch(CommandRequest::new_hget("score", "math"), &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+    }
+
+    #[test]
+    fn hrange_should_work() {
+        let store = BTreeMemTable::new();
+        for key in ["c", "a", "d", "b"] {
+            dispatch(CommandRequest::new_hset("score", key, key.into()), &store);
+        }
+
+        let response = dispatch(CommandRequest::new_hrange("score", "b", "d"), &store).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.pairs, vec![KvPair::new("b", "b".into()), KvPair::new("c", "c".into())]);
+
+        let response = dispatch(CommandRequest::new_hrange("score", "b", ""), &store).unwrap();
+        let keys: Vec<_> = response.pairs.into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec!["b", "c", "d"]);
+    }
+
+    #[test]
+    fn hgetor_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("config", "timeout", 30.into()), &store);
+
+        let response = dispatch(CommandRequest::new_hgetor("config", "timeout", 10.into()), &store).unwrap();
+        assert_response_ok(&response, &[30.into(), false.into()], &[]);
+        let response = dispatch(CommandRequest::new_hgetor("config", "retries", 3.into()), &store).unwrap();
+        assert_response_ok(&response, &[3.into(), true.into()], &[]);
+    }
+
+    #[test]
+    fn hfindvalue_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("users", "alice", "admin".into()), &store);
+        dispatch(CommandRequest::new_hset("users", "bob", "guest".into()), &store);
+        dispatch(CommandRequest::new_hset("users", "carol", "admin".into()), &store);
+
+        let response = dispatch(CommandRequest::new_hfindvalue("users", "admin".into()), &store).unwrap();
+        let mut pairs = response.pairs.clone();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(pairs, vec![KvPair::new("alice", "admin".into()), KvPair::new("carol", "admin".into())]);
+
+        let response = dispatch(CommandRequest::new_hfindvalue("users", "root".into()), &store).unwrap();
+        assert_response_ok(&response, &[], &[]);
+    }
+
+    #[test]
+    fn hdiff_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("v1", "alice", "admin".into()), &store);
+        dispatch(CommandRequest::new_hset("v1", "bob", "guest".into()), &store);
+        dispatch(CommandRequest::new_hset("v1", "carol", "admin".into()), &store);
+        dispatch(CommandRequest::new_hset("v2", "alice", "admin".into()), &store);
+        dispatch(CommandRequest::new_hset("v2", "bob", "admin".into()), &store);
+        dispatch(CommandRequest::new_hset("v2", "dave", "guest".into()), &store);
+
+        let response = dispatch(CommandRequest::new_hdiff("v1", "v2"), &store).unwrap();
+        let pairs = [KvPair::new("bob", "guest".into()), KvPair::new("carol", "admin".into())];
+        assert_response_ok(&response, &[], &pairs);
+
+        // the keys only in table_b are found with the tables swapped
+        let response = dispatch(CommandRequest::new_hdiff("v2", "v1"), &store).unwrap();
+        assert_response_ok(&response, &[], &[KvPair::new("bob", "admin".into()), KvPair::new("dave", "guest".into())]);
+
+        let response = dispatch(CommandRequest::new_hdiff("v1", "v1"), &store).unwrap();
+        assert_response_ok(&response, &[], &[]);
+    }
+
+    #[test]
+    fn hqueryindex_should_work() {
+        let store = IndexedStore::new(MemTable::new());
+        let request = CommandRequest::new_hqueryindex("users", "role", "admin".into());
+        let response = dispatch(request.clone(), &store).unwrap();
+        assert_response_error(&response, 400, "No index");
+
+        store.create_index("users", "role").unwrap();
+        let alice: Value = HashMap::from([("role".to_string(), Value::from("admin"))]).into();
+        let bob: Value = HashMap::from([("role".to_string(), Value::from("guest"))]).into();
+        dispatch(CommandRequest::new_hset("users", "alice", alice.clone()), &store);
+        dispatch(CommandRequest::new_hset("users", "bob", bob), &store);
+
+        let response = dispatch(request, &store).unwrap();
+        assert_eq!(response.pairs, vec![KvPair::new("alice", alice)]);
+    }
+
+    #[test]
+    fn hstrlen_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("blob", "avatar", Bytes::from(vec![0u8; 1024]).into()), &store);
+        dispatch(CommandRequest::new_hset("blob", "name", "alice".into()), &store);
+        dispatch(CommandRequest::new_hset("blob", "age", 30.into()), &store);
+
+        let response = dispatch(CommandRequest::new_hstrlen("blob", "avatar"), &store).unwrap();
+        assert_response_ok(&response, &[1024.into()], &[]);
+        let response = dispatch(CommandRequest::new_hstrlen("blob", "name"), &store).unwrap();
+        assert_response_ok(&response, &[5.into()], &[]);
+        let response = dispatch(CommandRequest::new_hstrlen("blob", "age"), &store).unwrap();
+        assert_response_ok(&response, &[(Value::from(30).encoded_len() as i64).into()], &[]);
+
+        let response = dispatch(CommandRequest::new_hstrlen("blob", "missing"), &store).unwrap();
+        assert_response_error(&response, 404, "Not found");
+    }
+
+    #[test]
+    fn hello_should_work() {
+        let store = MemTable::new();
+        let response = dispatch(CommandRequest::new_hello(), &store).unwrap();
+        assert_response_ok(&response, &[env!("CARGO_PKG_VERSION").into(), server_features().into()], &[]);
+
+        let features: Vec<Value> = response.values[1].clone().try_into().unwrap();
+        assert!(features.contains(&"pubsub".into()));
+    }
+
+    #[tokio::test]
+    async fn command_features_should_be_dispatched() {
+        let store = MemTable::new();
+        for (feature, request) in command_features() {
+            let response = match dispatch(request.clone(), &store) {
+                Some(response) => response,
+                None => {
+                    let mut stream = dispatch_stream(request, Arc::new(Broadcaster::default()));
+                    stream.next().await.unwrap().as_ref().clone()
+                }
+            };
+            let message = response.message.to_lowercase();
+            assert!(
+                !message.contains("invalid command") && !message.contains("not a streaming command"),
+                "the command of feature {} is not handled: {:?}",
+                feature,
+                response
+            );
+        }
+    }
+
     #[test]
     fn hget_should_work() {
         let store = MemTable::new();
         let request = CommandRequest::new_hset("score", "math", 10.into());
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_ok(&response, &[Value::default()], &[]);
 
         let request = CommandRequest::new_hget("score", "math");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_ok(&response, &[10.into()], &[]);
     }
 
@@ -135,7 +861,7 @@ mod tests {
     fn hget_with_non_existing_key_should_return_404() {
         let store = MemTable::new();
         let request = CommandRequest::new_hget("score", "math");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_error(&response, 404, "Not found");
     }
 
@@ -154,7 +880,7 @@ mod tests {
         }
 
         let request = CommandRequest::new_hget_all("score");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
 
         let pairs = vec![
             KvPair::new("chinese", 30.into()),
@@ -164,6 +890,42 @@ mod tests {
         assert_response_ok(&response, &[], &pairs);
     }
 
+    #[test]
+    fn hgetall_with_pattern_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("user", "user:1:active", true.into()), &store);
+        dispatch(CommandRequest::new_hset("user", "user:2:inactive", false.into()), &store);
+        dispatch(CommandRequest::new_hset("user", "user:3:active", true.into()), &store);
+
+        let request = CommandRequest::new_hget_all_match("user", "user:*:active");
+        let response = dispatch(request, &store).unwrap();
+
+        let pairs = vec![
+            KvPair::new("user:1:active", true.into()),
+            KvPair::new("user:3:active", true.into()),
+        ];
+        assert_response_ok(&response, &[], &pairs);
+    }
+
+    #[test]
+    fn hmgetall_should_work() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("score", "math", 10.into()), &store);
+        dispatch(CommandRequest::new_hset("score", "english", 20.into()), &store);
+        dispatch(CommandRequest::new_hset("user", "name", "tyr".into()), &store);
+        dispatch(CommandRequest::new_hset("other", "name", "other".into()), &store);
+
+        let tables = vec!["score".into(), "user".into(), "empty".into()];
+        let response = dispatch(CommandRequest::new_hmget_all(tables), &store).unwrap();
+
+        let pairs = vec![
+            KvPair::new("score:english", 20.into()),
+            KvPair::new("score:math", 10.into()),
+            KvPair::new("user:name", "tyr".into()),
+        ];
+        assert_response_ok(&response, &[], &pairs);
+    }
+
     #[test]
     fn hmset_should_work() {
         let store = MemTable::new();
@@ -174,12 +936,38 @@ mod tests {
             KvPair::new("math", 40.into()),
         ];
         let request = CommandRequest::new_hmset("score", pairs);
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
 
         let values = vec![Value::default(), Value::default(), Value::default(), 10.into()];
         assert_response_ok(&response, &values, &[]);
     }
 
+    #[test]
+    fn hinitifempty_should_work() {
+        let store = MemTable::new();
+        let pairs = vec![KvPair::new("timeout", 30.into()), KvPair::new("retries", 3.into())];
+        let response = dispatch(CommandRequest::new_hinitifempty("config", pairs.clone()), &store).unwrap();
+        assert_response_ok(&response, &[true.into()], &[]);
+
+        // the table is initialized already, the new defaults are not written
+        let request = CommandRequest::new_hinitifempty("config", vec![KvPair::new("timeout", 60.into())]);
+        assert_response_ok(&dispatch(request, &store).unwrap(), &[false.into()], &[]);
+        assert_eq!(store.get("config", b"timeout").unwrap(), Some(30.into()));
+        assert_eq!(store.get_all("config").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn hmgetex_should_tell_missing_keys_from_default_values() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("score", "math", 10.into()), &store);
+        dispatch(CommandRequest::new_hset("score", "art", Value::default()), &store);
+
+        let request = CommandRequest::new_hmgetex("score", vec!["math".into(), "art".into(), "music".into()]);
+        let response = dispatch(request, &store).unwrap();
+        let values = [true.into(), 10.into(), true.into(), Value::default(), false.into(), Value::default()];
+        assert_response_ok(&response, &values, &[]);
+    }
+
     #[test]
     fn hmget_should_work() {
         let store = MemTable::new();
@@ -195,7 +983,7 @@ mod tests {
         }
 
         let request = CommandRequest::new_hmget("score", vec!["math".into(), "chinese".into()]);
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
 
         let values: Vec<Value> = vec![40.into(), 30.into()];
         assert_response_ok(&response, &values, &[]);
@@ -209,15 +997,15 @@ mod tests {
         dispatch(cmd, &store);
 
         let request = CommandRequest::new_hdel("score", "math");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_ok(&response, &[40.into()], &[]);
 
         let request = CommandRequest::new_hget("score", "math");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_error(&response, 404, "Not found");
 
         let request = CommandRequest::new_hdel("score", "math");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_ok(&response, &[Value::default()], &[]);
     }
 
@@ -236,13 +1024,13 @@ mod tests {
         }
 
         let request = CommandRequest::new_hmdel("score", vec!["math".into(), "chinese".into()]);
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
 
         let values: Vec<Value> = vec![40.into(), 30.into()];
         assert_response_ok(&response, &values, &[]);
 
         let request = CommandRequest::new_hget_all("score");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
 
         let pairs = vec![KvPair::new("english", 20.into())];
         assert_response_ok(&response, &[], &pairs);
@@ -256,11 +1044,11 @@ mod tests {
         dispatch(cmd, &store);
 
         let request = CommandRequest::new_hexist("score", "math");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_ok(&response, &[true.into()], &[]);
 
         let request = CommandRequest::new_hexist("score", "english");
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
         assert_response_ok(&response, &[false.into()], &[]);
     }
 
@@ -278,7 +1066,7 @@ mod tests {
         }
 
         let request = CommandRequest::new_hmexist("score", vec!["math".into(), "art".into(), "chinese".into()]);
-        let response = dispatch(request, &store);
+        let response = dispatch(request, &store).unwrap();
 
         let values: Vec<Value> = vec![true.into(), false.into(), true.into()];
         assert_response_ok(&response, &values, &[]);
@@ -0,0 +1,342 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use prost::Message;
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, warn};
+
+use crate::{Broadcaster, CommandResponse, KvError, Storage, SubscriptionLimits, Topic, Value};
+
+// prefix of the tables which keep the published messages of the topics
+pub const TOPIC_LOG_PREFIX: &str = "__topiclog__";
+
+// how many messages a replaying subscriber can have in its channel, also the page size of a replay
+const REPLAY_CAPACITY: usize = 128;
+
+// get the table which the messages of a topic are appended to
+pub fn topic_log_table(name: &str) -> String {
+    format!("{}:{}", TOPIC_LOG_PREFIX, name)
+}
+
+// a Broadcaster which also appends every published message to a per-topic log in the storage
+// a message is logged even if the topic has no subscribers, so it can be replayed from its offset later.
+// the offsets of a topic start from 0, the key of a message is its offset in big endian, so get_range keeps the order.
+// the log is never trimmed, it grows with every publish
+pub struct DurableTopic<Store> {
+    broadcaster: Arc<Broadcaster>,
+    store: Store,
+    // the offsets of each topic, loaded from the log when the topic is first used
+    logs: DashMap<String, Arc<TopicLog>>,
+}
+
+// the offsets of a topic. an offset is taken before its message is written, so concurrent publishes don't wait
+// for each other's writes. the replaying subscribers only read the committed offsets, which are all written
+struct TopicLog {
+    next: AtomicU64,
+    // every offset below it is written (or failed), the replaying subscribers are notified when it changes
+    committed: watch::Sender<u64>,
+    // the written offsets above the committed one, waiting for the writes before them
+    written: Mutex<BTreeSet<u64>>,
+}
+
+impl TopicLog {
+    fn new(next: u64) -> Self {
+        Self {
+            next: AtomicU64::new(next),
+            committed: watch::channel(next).0,
+            written: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn take(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // mark the offset as written, and move the committed offset over the contiguous written ones
+    fn commit(&self, offset: u64) {
+        let mut written = self.written.lock().unwrap();
+        written.insert(offset);
+        let mut committed = *self.committed.borrow();
+        while written.remove(&committed) {
+            committed += 1;
+        }
+        self.committed.send_if_modified(|current| {
+            let modified = *current != committed;
+            *current = committed;
+            modified
+        });
+    }
+}
+
+impl<Store: Storage> DurableTopic<Store> {
+    pub fn new(broadcaster: Broadcaster, store: Store) -> Self {
+        Self {
+            broadcaster: Arc::new(broadcaster),
+            store,
+            logs: DashMap::new(),
+        }
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    // get at most limit committed messages of a topic from the offset, with their offsets
+    pub fn replay(&self, name: &str, offset: u64, limit: usize) -> Result<Vec<(u64, CommandResponse)>, KvError> {
+        let committed = *self.log(name)?.committed.borrow();
+        self.read(name, offset, committed.min(offset.saturating_add(limit as u64)))
+    }
+
+    // read the messages whose offsets are in start..end. the offsets are dense, so at most end - start are read
+    fn read(&self, name: &str, start: u64, end: u64) -> Result<Vec<(u64, CommandResponse)>, KvError> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+        self.store
+            .get_range(&topic_log_table(name), &start.to_be_bytes(), &end.to_be_bytes())?
+            .into_iter()
+            .map(|pair| {
+                let offset = decode_offset(&pair.key)?;
+                let data = Bytes::try_from(&pair.value.unwrap_or_default())?;
+                Ok((offset, CommandResponse::decode(data)?))
+            })
+            .collect()
+    }
+
+    // the offsets of a topic, created from the last logged message if the topic is not used yet
+    // the log is scanned without holding a lock, if two callers race the first one inserted is kept
+    fn log(&self, name: &str) -> Result<Arc<TopicLog>, KvError> {
+        if let Some(log) = self.logs.get(name) {
+            return Ok(Arc::clone(&log));
+        }
+        let last = self.store.get_iter(&topic_log_table(name))?.try_fold(None, |last, pair| {
+            decode_offset(&pair.key).map(|offset| last.max(Some(offset)))
+        })?;
+        let next = last.map_or(0, |offset| offset + 1);
+        let log = self.logs.entry(name.to_string()).or_insert_with(|| Arc::new(TopicLog::new(next)));
+        Ok(Arc::clone(&log))
+    }
+
+    // append the message to the log of the topic, return its offset
+    fn append(&self, name: &str, value: &CommandResponse) -> Result<u64, KvError> {
+        let log = self.log(name)?;
+        let offset = log.take();
+        let data = Value::from(Bytes::from(value.encode_to_vec()));
+        let result = self.store.set(&topic_log_table(name), offset.to_be_bytes().to_vec(), data);
+        // a failed offset is committed too, it is a gap in the log, otherwise the later messages are never replayed
+        log.commit(offset);
+        result?;
+        debug!("Message {} is appended to topic {}", offset, name);
+        Ok(offset)
+    }
+
+    fn append_or_warn(&self, name: &str, value: &CommandResponse) {
+        if let Err(e) = self.append(name, value) {
+            warn!("Failed to append message to topic {}. Error: {:?}", name, e);
+        }
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> DurableTopic<Store> {
+    // receive the logged messages of a topic from the offset, then the new ones as they're published
+    // unlike subscribe(), there is no subscription id, the replay stops when the receiver is dropped.
+    // the message_id of a message is its offset, so a subscriber can resume from message_id + 1.
+    // the log is read a page at a time, so a long log is not loaded into memory at once
+    pub fn subscribe_from(self: &Arc<Self>, name: String, offset: u64) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let (sender, receiver) = mpsc::channel(REPLAY_CAPACITY);
+        let topic = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut notified = match topic.log(&name) {
+                Ok(log) => log.committed.subscribe(),
+                Err(e) => {
+                    let _ = sender.send(Arc::new(e.into())).await;
+                    return;
+                }
+            };
+            let mut offset = offset;
+            loop {
+                // mark the committed offset as seen before reading, so a publish during the read is not missed
+                let committed = *notified.borrow_and_update();
+                while offset < committed {
+                    let end = committed.min(offset.saturating_add(REPLAY_CAPACITY as u64));
+                    let messages = match topic.read(&name, offset, end) {
+                        Ok(messages) => messages,
+                        Err(e) => {
+                            let _ = sender.send(Arc::new(e.into())).await;
+                            return;
+                        }
+                    };
+                    for (message_offset, mut data) in messages {
+                        data.message_id = message_offset;
+                        if sender.send(Arc::new(data)).await.is_err() {
+                            return;
+                        }
+                    }
+                    offset = end;
+                }
+
+                tokio::select! {
+                    changed = notified.changed() => if changed.is_err() { return },
+                    _ = sender.closed() => return,
+                }
+            }
+        });
+        receiver
+    }
+}
+
+fn decode_offset(key: &[u8]) -> Result<u64, KvError> {
+    let bytes = key
+        .try_into()
+        .map_err(|_| KvError::Internal(format!("Invalid topic log key: {:?}", key)))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl<Store: Storage + Send + Sync + 'static> Topic for Arc<DurableTopic<Store>> {
+    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
+        Arc::clone(&self.broadcaster).subscribe(name)
+    }
+
+    fn subscribe_with_history(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
+        Arc::clone(&self.broadcaster).subscribe_with_history(name)
+    }
+
+    fn subscribe_with_filter(self, name: String, filter: Value, history: bool) -> mpsc::Receiver<Arc<CommandResponse>> {
+        Arc::clone(&self.broadcaster).subscribe_with_filter(name, filter, history)
+    }
+
+    fn subscribe_with_limits(
+        self,
+        name: String,
+        filter: Option<Value>,
+        history: bool,
+        limits: SubscriptionLimits,
+    ) -> mpsc::Receiver<Arc<CommandResponse>> {
+        Arc::clone(&self.broadcaster).subscribe_with_limits(name, filter, history, limits)
+    }
+
+    fn unsubscribe(self, name: String, id: u32) {
+        Arc::clone(&self.broadcaster).unsubscribe(name, id)
+    }
+
+    fn publish(self, name: String, value: Arc<CommandResponse>) {
+        self.append_or_warn(&name, &value);
+        Arc::clone(&self.broadcaster).publish(name, value)
+    }
+
+    fn publish_wait(self, name: String, value: Arc<CommandResponse>) -> BoxFuture<'static, ()> {
+        self.append_or_warn(&name, &value);
+        Arc::clone(&self.broadcaster).publish_wait(name, value)
+    }
+
+    fn publish_with_ack(self, name: String, value: CommandResponse) -> u64 {
+        self.append_or_warn(&name, &value);
+        Arc::clone(&self.broadcaster).publish_with_ack(name, value)
+    }
+
+    fn ack(self, id: u32, message_id: u64) -> bool {
+        Arc::clone(&self.broadcaster).ack(id, message_id)
+    }
+
+    fn has_topic(&self, name: &str) -> bool {
+        self.broadcaster.has_topic(name)
+    }
+
+    fn topic_count(&self) -> usize {
+        self.broadcaster.topic_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use crate::{assert_response_ok, MemTable};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn durable_topic_should_log_messages_without_subscribers() {
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), MemTable::new()));
+        for i in 0..3 {
+            let v: Value = (i as i64).into();
+            topic.clone().publish("lobby".into(), Arc::new(v.into()));
+        }
+
+        let messages = topic.replay("lobby", 1, 10).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, 1);
+        assert_response_ok(&messages[0].1, &[1.into()], &[]);
+        assert_eq!(messages[1].0, 2);
+
+        // the offsets continue from the log after restart
+        let store = topic.store().clone();
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), store));
+        topic.clone().publish("lobby".into(), Arc::new(Value::from(3).into()));
+        assert_eq!(topic.replay("lobby", 3, 10).unwrap()[0].0, 3);
+    }
+
+    #[tokio::test]
+    async fn subscribe_from_should_replay_then_receive_new_messages() {
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), MemTable::new()));
+        topic.clone().publish("lobby".into(), Arc::new(Value::from("old").into()));
+
+        let mut stream = topic.subscribe_from("lobby".into(), 0);
+        let data = stream.recv().await.unwrap();
+        assert_eq!(data.message_id, 0);
+        assert_response_ok(&data, &["old".into()], &[]);
+
+        topic.clone().publish("lobby".into(), Arc::new(Value::from("new").into()));
+        let data = time::timeout(Duration::from_secs(1), stream.recv()).await.unwrap().unwrap();
+        assert_eq!(data.message_id, 1);
+        assert_response_ok(&data, &["new".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn replay_should_return_at_most_limit_messages() {
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), MemTable::new()));
+        for i in 0..5 {
+            topic.clone().publish("lobby".into(), Arc::new(Value::from(i).into()));
+        }
+
+        let offsets: Vec<_> = topic.replay("lobby", 1, 2).unwrap().into_iter().map(|(offset, _)| offset).collect();
+        assert_eq!(offsets, vec![1, 2]);
+        assert_eq!(topic.replay("lobby", 4, 10).unwrap().len(), 1);
+        assert!(topic.replay("lobby", 5, 10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribe_from_should_replay_a_long_log_in_pages() {
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), MemTable::new()));
+        let count = REPLAY_CAPACITY as i64 * 2 + 10;
+        for i in 0..count {
+            topic.clone().publish("lobby".into(), Arc::new(Value::from(i).into()));
+        }
+
+        let mut stream = topic.subscribe_from("lobby".into(), 0);
+        for i in 0..count {
+            let data = time::timeout(Duration::from_secs(1), stream.recv()).await.unwrap().unwrap();
+            assert_eq!(data.message_id, i as u64);
+            assert_response_ok(&data, &[i.into()], &[]);
+        }
+    }
+
+    #[test]
+    fn topic_log_should_commit_contiguous_offsets_only() {
+        let log = TopicLog::new(0);
+        let (first, second, third) = (log.take(), log.take(), log.take());
+        // the later writes finish first, they're not visible until the first one is written
+        log.commit(second);
+        log.commit(third);
+        assert_eq!(*log.committed.borrow(), 0);
+        log.commit(first);
+        assert_eq!(*log.committed.borrow(), 3);
+        assert!(log.written.lock().unwrap().is_empty());
+    }
+}
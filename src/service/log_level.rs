@@ -0,0 +1,14 @@
+// how much a service logs about the commands it executes, each service has its own level
+//
+// the global tracing filter still applies: a statement is only logged if both the service's level and the filter
+// allow it, so set the filter to debug and give the other services a lower level to debug a single service
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    // log nothing about the commands
+    #[default]
+    Off,
+    // log the status of every executed command
+    Info,
+    // also log the whole requests and responses, including the responses modified by the hooks
+    Debug,
+}
@@ -0,0 +1,117 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+// the accumulated numbers of a command
+#[derive(Debug, Default, Clone, Copy)]
+struct CommandStats {
+    count: u64,
+    // the responses with a non-2xx status
+    errors: u64,
+    // the time until the first response, e.g. the subscription id of a streaming command
+    latency: Duration,
+}
+
+// counters of the service, formatted by Service::metrics_text()
+// they're only accumulated, never reset, so the scraper can compute the rates
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commands: DashMap<&'static str, CommandStats>,
+    connections: AtomicU64,
+    connections_total: AtomicU64,
+}
+
+// an open connection, it's counted until dropped
+pub struct Connection {
+    metrics: Arc<Metrics>,
+}
+
+impl Metrics {
+    pub fn record(&self, command: &'static str, latency: Duration, ok: bool) {
+        let mut stats = self.commands.entry(command).or_default();
+        stats.count += 1;
+        stats.latency += latency;
+        if !ok {
+            stats.errors += 1;
+        }
+    }
+
+    pub fn connection(self: &Arc<Self>) -> Connection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        Connection { metrics: Arc::clone(self) }
+    }
+
+    // the metrics in the Prometheus text exposition format, the commands are sorted by name
+    pub fn to_text(&self, topics: usize) -> String {
+        let mut commands: Vec<_> = self.commands.iter().map(|item| (*item.key(), *item.value())).collect();
+        commands.sort_by_key(|(command, _)| *command);
+
+        let mut text = String::new();
+        write_header(&mut text, "kv_commands_total", "counter", "Number of executed commands.");
+        for (command, stats) in &commands {
+            let _ = writeln!(text, "kv_commands_total{{command=\"{}\"}} {}", command, stats.count);
+        }
+        write_header(&mut text, "kv_command_errors_total", "counter", "Number of commands with an error response.");
+        for (command, stats) in &commands {
+            let _ = writeln!(text, "kv_command_errors_total{{command=\"{}\"}} {}", command, stats.errors);
+        }
+        write_header(&mut text, "kv_command_duration_seconds", "summary", "Time until the first response of a command.");
+        for (command, stats) in &commands {
+            let _ = writeln!(
+                text,
+                "kv_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                command,
+                stats.latency.as_secs_f64()
+            );
+            let _ = writeln!(text, "kv_command_duration_seconds_count{{command=\"{}\"}} {}", command, stats.count);
+        }
+        write_header(&mut text, "kv_connections", "gauge", "Number of open connections.");
+        let _ = writeln!(text, "kv_connections {}", self.connections.load(Ordering::Relaxed));
+        write_header(&mut text, "kv_connections_total", "counter", "Number of accepted connections.");
+        let _ = writeln!(text, "kv_connections_total {}", self.connections_total.load(Ordering::Relaxed));
+        write_header(&mut text, "kv_topics", "gauge", "Number of topics with subscribers.");
+        let _ = writeln!(text, "kv_topics {}", topics);
+        text
+    }
+}
+
+fn write_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_should_be_formatted_as_prometheus_text() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.record("hget", Duration::from_millis(2), true);
+        metrics.record("hget", Duration::from_millis(3), false);
+        metrics.record("hset", Duration::from_millis(1), true);
+        let _first = metrics.connection();
+        drop(metrics.connection());
+
+        let text = metrics.to_text(2);
+        assert!(text.contains("# TYPE kv_commands_total counter\n"));
+        assert!(text.contains("kv_commands_total{command=\"hget\"} 2\n"));
+        assert!(text.contains("kv_commands_total{command=\"hset\"} 1\n"));
+        assert!(text.contains("kv_command_errors_total{command=\"hget\"} 1\n"));
+        assert!(text.contains("kv_command_duration_seconds_sum{command=\"hget\"} 0.005\n"));
+        assert!(text.contains("kv_command_duration_seconds_count{command=\"hget\"} 2\n"));
+        assert!(text.contains("kv_connections 1\n"));
+        assert!(text.contains("kv_connections_total 2\n"));
+        assert!(text.contains("kv_topics 2\n"));
+    }
+}
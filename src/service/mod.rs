@@ -1,41 +1,106 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use futures::stream;
-use tracing::debug;
+use futures::{future, stream, StreamExt};
+use futures::future::BoxFuture;
+use tokio_stream::wrappers::ReceiverStream;
+use http::StatusCode;
+use tracing::{debug, info, info_span, warn, Span};
 
-use crate::{CommandRequest, CommandResponse, KvError, MemTable, Storage};
+use crate::{CommandRequest, CommandResponse, ErrorCode, Hgetall, KvError, MemTable, Storage, Value};
 #[cfg(test)]
-use crate::{KvPair, Value};
+use crate::KvPair;
 use crate::command_request::RequestData;
-use crate::service::topic::{Broadcaster, Topic};
+use crate::pb::check_status;
+use crate::service::breaker::CircuitBreaker;
+use crate::service::chunk_service::Uploads;
+use crate::service::metrics::Metrics;
+use crate::service::scheduler::Scheduler;
+use crate::service::session::Session;
 use crate::service::topic_service::{StreamingResponse, TopicService};
 
+pub use durable_topic::{DurableTopic, topic_log_table};
+pub use log_level::LogLevel;
+pub use metrics::Connection;
+pub use name_policy::NamePolicy;
+pub use replication::{decode_replicated, ReplicationLog};
+pub use topic::{Broadcaster, SubscriptionLimits, Topic, TopicEvent};
+pub use topic_service::keyspace_topic;
+
+mod breaker;
+mod chunk_service;
+mod durable_topic;
 mod command_service;
+mod log_level;
+mod metrics;
+mod name_policy;
+mod replication;
+mod scheduler;
+mod session;
 mod topic_service;
 mod topic;
 
+// hook which can do async work, e.g. I/O, it gets a reference and must return a 'static future
+pub type AsyncHook<Args> = Box<dyn Fn(&Args) -> BoxFuture<'static, ()> + Send + Sync>;
+
+// hook which can change the request before it's handled, e.g. rewrite the table name
+pub type RewriteHook = Box<dyn Fn(&mut CommandRequest) + Send + Sync>;
+
+// hook which checks the token of an Auth command, return true if it's valid
+pub type TokenVerifier = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
 pub trait CommandService {
     fn execute(self, store: &impl Storage) -> CommandResponse;
 }
 
-pub struct Service<Store = MemTable> {
+// the pub/sub commands are handled by the topic, e.g. a DurableTopic instead of the default Broadcaster
+pub struct Service<Store = MemTable, T = Arc<Broadcaster>> {
     inner: Arc<ServiceInner<Store>>,
-    broadcaster: Arc<Broadcaster>,
+    topic: T,
+    // the state of the connection using the service, shared by the clones
+    session: Arc<Session>,
 }
 
 pub struct ServiceInner<Store> {
     store: Store,
-    on_received: Vec<fn(&CommandRequest)>,
-    on_executed: Vec<fn(&CommandResponse)>,
-    on_before_send: Vec<fn(&mut CommandResponse)>,
-    on_after_send: Vec<fn()>,
+    on_rewrite: Vec<RewriteHook>,
+    // the sync hooks can be replaced on a running service
+    on_received: RwLock<Vec<fn(&CommandRequest)>>,
+    on_executed: RwLock<Vec<fn(&CommandResponse)>>,
+    on_before_send: RwLock<Vec<fn(&mut CommandResponse)>>,
+    on_after_send: RwLock<Vec<fn()>>,
+    on_received_async: Vec<AsyncHook<CommandRequest>>,
+    on_executed_async: Vec<AsyncHook<CommandResponse>>,
+    // reject all write commands, e.g. for a replica
+    read_only: bool,
+    // reject the write commands with a value bigger than this (encoded size), None means unlimited
+    max_value_bytes: Option<usize>,
+    // the unary commands taking longer than this get a 504 response, None means no timeout
+    command_timeout: Option<Duration>,
+    // the unfinished Hsetchunked transfers
+    uploads: Uploads,
+    // reject the commands with invalid table names or keys
+    name_policy: NamePolicy,
+    // limit how many commands are executed at the same time, None means unlimited
+    scheduler: Option<Scheduler>,
+    // the command counts and latencies, and the connection counts
+    metrics: Arc<Metrics>,
+    // reject the commands while the storage is failing, None means always execute them
+    breaker: Option<CircuitBreaker>,
+    // what the service logs about the commands, on top of the global tracing filter
+    log_level: LogLevel,
+    // the write commands streamed to the replicas, None means the service can't be replicated
+    replication: Option<Arc<ReplicationLog>>,
+    // the connections must send a valid token with Auth first, None means no authentication
+    verify_token: Option<TokenVerifier>,
 }
 
-impl<Store> Clone for Service<Store> {
+impl<Store, T: Clone> Clone for Service<Store, T> {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
-            broadcaster: Arc::clone(&self.broadcaster),
+            topic: self.topic.clone(),
+            session: Arc::clone(&self.session),
         }
     }
 }
@@ -66,30 +131,368 @@ impl<Args> NotifyMut<Args> for Vec<fn(&mut Args)> {
     }
 }
 
-impl<Store: Storage> Service<Store> {
-    pub fn execute(&self, request: CommandRequest) -> StreamingResponse {
-        self.inner.on_received.notify(&request);
-        let mut response = dispatch(request.clone(), &self.inner.store);
+impl<Store: Storage + Send + Sync + 'static, T: Topic> Service<Store, T> {
+    // replace the default broadcaster, e.g. to use one with a slow consumer policy
+    pub fn with_broadcaster(self, broadcaster: Broadcaster) -> Service<Store> {
+        self.with_topic(Arc::new(broadcaster))
+    }
+
+    // handle the pub/sub commands by another topic implementation
+    pub fn with_topic<T2: Topic>(self, topic: T2) -> Service<Store, T2> {
+        Service { inner: self.inner, topic, session: self.session }
+    }
+
+    // a copy of the service with its own session, every connection should use one
+    pub fn new_session(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            topic: self.topic.clone(),
+            session: Default::default(),
+        }
+    }
+
+    // the metrics of the service in the Prometheus text exposition format
+    pub fn metrics_text(&self) -> String {
+        self.inner.metrics.to_text(self.topic.topic_count())
+    }
+
+    // the connections must be authenticated by Auth before the other commands, see ServiceInner::fn_verify_token
+    pub fn requires_auth(&self) -> bool {
+        self.inner.verify_token.is_some()
+    }
+
+    // check the token of an Auth command, any token is valid if the service doesn't verify them
+    pub fn verify_token(&self, token: &str) -> Result<(), KvError> {
+        match &self.inner.verify_token {
+            Some(verify) if !verify(token) => Err(KvError::Unauthorized("Invalid token")),
+            _ => Ok(()),
+        }
+    }
+
+    // authenticate the session if the token is valid
+    pub fn authenticate(&self, token: &str) -> Result<(), KvError> {
+        self.verify_token(token)?;
+        self.session.authenticate();
+        Ok(())
+    }
+
+    // whether the session may run the commands other than Auth
+    pub fn is_authenticated(&self) -> bool {
+        !self.requires_auth() || self.session.is_authenticated()
+    }
 
-        if response == CommandResponse::default() {
-            dispatch_stream(request, Arc::clone(&self.broadcaster));
+    // count a connection in the metrics until the returned guard is dropped
+    pub fn connection(&self) -> Connection {
+        self.inner.metrics.connection()
+    }
+
+    pub fn execute(&self, mut request: CommandRequest) -> StreamingResponse {
+        for f in &self.inner.on_rewrite {
+            f(&mut request);
+        }
+
+        // the latency of a command is the time until its first response
+        let command = request.command_name();
+        let started = Instant::now();
+        let metrics = Arc::clone(&self.inner.metrics);
+        let mut recorded = false;
+        let response = self.execute_scheduled(request);
+        Box::pin(response.inspect(move |data| {
+            if !recorded {
+                recorded = true;
+                metrics.record(command, started.elapsed(), check_status(data).is_ok());
+            }
+        }))
+    }
+
+    fn execute_scheduled(&self, request: CommandRequest) -> StreamingResponse {
+        // the commands which don't touch the storage are never limited, e.g. pub/sub or ReplicateFrom
+        let scheduled = self.inner.scheduler.is_some() && request.uses_storage();
+        if self.inner.on_received_async.is_empty() && !scheduled {
+            return self.execute_now(request);
+        }
+
+        // run the async received hooks first, the command is executed when the stream is polled
+        let hooks: Vec<_> = self.inner.on_received_async.iter().map(|f| f(&request)).collect();
+        let service = self.clone();
+        Box::pin(
+            stream::once(async move {
+                future::join_all(hooks).await;
+                let slot = match &service.inner.scheduler {
+                    Some(scheduler) if scheduled => Some(scheduler.acquire(request.is_scan()).await),
+                    _ => None,
+                };
+                let response = service.execute_now(request);
+                match slot {
+                    // keep the slot until the response is consumed, a command with a timeout runs in the stream
+                    Some(slot) => Box::pin(response.map(move |data| {
+                        let _slot = &slot;
+                        data
+                    })),
+                    None => response,
+                }
+            })
+            .flatten(),
+        )
+    }
+
+    fn execute_now(&self, request: CommandRequest) -> StreamingResponse {
+        // group all logs of this command, the span is exited when execute() returns
+        let span = info_span!(
+            "execute",
+            command = request.command_name(),
+            table = request.table(),
+            request_id = request.request_id,
+        );
+        let _enter = span.enter();
+
+        self.inner.on_received.read().unwrap().notify(&request);
+        if self.inner.logs(LogLevel::Debug) {
+            debug!("Received request: {:?}", request.redacted());
+        }
+        let request_id = request.request_id;
+        let dispatched = if let Some(RequestData::Auth(v)) = &request.request_data {
+            // the connection authenticates its session when the Auth is received, the service only checks the token
+            Some(match self.verify_token(&v.token) {
+                Ok(()) => CommandResponse::ok(),
+                Err(e) => e.into(),
+            })
+        } else if self.inner.read_only && request.is_write() {
+            Some(KvError::ReadOnly.into())
+        } else if let Some(e) = self.check_value_size(&request) {
+            Some(e.into())
+        } else if let Err(e) = self.inner.name_policy.check(&request) {
+            Some(e.into())
+        } else if let Some(RequestData::Subscriptions(_)) = &request.request_data {
+            Some(self.session.subscriptions_response())
+        } else if let Some(RequestData::Unsubscribeall(_)) = &request.request_data {
+            Some(self.unsubscribe_all())
+        } else if let Some(RequestData::ReplicateFrom(v)) = &request.request_data {
+            return with_request_id(self.replicate_from(v.offset), request_id);
+        } else if let Some(RequestData::Hsetchunked(v)) = &request.request_data {
+            // the chunks are kept by the service until the last one arrives
+            let store = &self.inner.store;
+            Some(self.inner.uploads.add_chunk(v.clone(), store, self.inner.max_value_bytes))
+        } else if let Some(RequestData::Hgetchunked(v)) = &request.request_data {
+            return with_request_id(v.clone().execute(&self.inner.store), request_id);
+        } else if let Some(RequestData::Hgetall(v @ Hgetall { chunk_size: 1.., .. })) = &request.request_data {
+            // the pairs are sent in a stream of frames instead of one big response
+            return with_request_id(v.clone().execute_chunked(Arc::clone(&self.inner)), request_id);
+        } else if let Err(e) = self.inner.check_breaker(&request) {
+            Some(e.into())
+        } else if let Some(timeout) = self.inner.command_timeout {
+            return self.execute_with_timeout(request, timeout);
         } else {
-            self.inner.on_executed.notify(&response);
-            self.inner.on_before_send.notify(&mut response);
-            if !self.inner.on_after_send.is_empty() {
-                debug!("Modified response: {:?}", response);
+            let response = dispatch(request.clone(), &self.inner.store);
+            self.inner.record_breaker(request.uses_storage(), response.as_ref());
+            response
+        };
+        let mut response = match dispatched {
+            Some(response) => response,
+            None => {
+                let response = self.execute_stream(request);
+                return with_request_id(response, request_id);
             }
+        };
+        response.request_id = request_id;
+        self.replicate(&request, &response);
+        self.respond(response)
+    }
+
+    // run the command on the blocking thread pool, so we can stop waiting for it after `timeout`
+    fn execute_with_timeout(&self, request: CommandRequest, timeout: Duration) -> StreamingResponse {
+        let service = self.clone();
+        let span = Span::current();
+        let request_id = request.request_id;
+        let uses_storage = request.uses_storage();
+        Box::pin(
+            stream::once(async move {
+                let worker = service.clone();
+                let task = tokio::task::spawn_blocking(move || {
+                    let _enter = span.enter();
+                    let response = dispatch(request.clone(), &worker.inner.store);
+                    if let Some(response) = &response {
+                        worker.replicate(&request, response);
+                    }
+                    (request, response)
+                });
+                let mut response: CommandResponse = match tokio::time::timeout(timeout, task).await {
+                    Ok(Ok((_, Some(response)))) => response,
+                    Ok(Ok((request, None))) => {
+                        let response = service.execute_stream(request);
+                        return with_request_id(response, request_id);
+                    }
+                    Ok(Err(e)) => KvError::Internal(e.to_string()).into(),
+                    Err(_) => KvError::Timeout(timeout).into(),
+                };
+                // a timeout counts as a storage error, a stuck disk is as broken as a failing one
+                service.inner.record_breaker(uses_storage, Some(&response));
+                response.request_id = request_id;
+                service.respond(response)
+            })
+            .flatten(),
+        )
+    }
+
+    // remove all subscriptions of the session from the topic, their streams end
+    fn unsubscribe_all(&self) -> CommandResponse {
+        let subscriptions = self.session.subscriptions();
+        for (id, topic) in &subscriptions {
+            self.session.remove(*id);
+            self.topic.clone().unsubscribe(topic.clone(), *id);
         }
+        Value::from(subscriptions.len() as i64).into()
+    }
 
-        Box::pin(stream::once(async { Arc::new(response) }))
+    // run a streaming command, the subscriptions it makes are recorded in the session
+    fn execute_stream(&self, request: CommandRequest) -> StreamingResponse {
+        if let Some(RequestData::Unsubscribe(v)) = &request.request_data {
+            self.session.remove(v.id);
+        }
+        let topic = request.subscription_topic();
+        let response = dispatch_stream(request, self.topic.clone());
+        match topic {
+            Some(topic) => self.session.track(topic, response),
+            None => response,
+        }
     }
+
+    // run the hooks on the response of a unary command, then send it
+    fn respond(&self, mut response: CommandResponse) -> StreamingResponse {
+        self.inner.on_executed.read().unwrap().notify(&response);
+        if self.inner.logs(LogLevel::Info) {
+            info!("Executed with status {}", response.status);
+        }
+        let hooks: Vec<_> = self.inner.on_executed_async.iter().map(|f| f(&response)).collect();
+        self.inner.on_before_send.read().unwrap().notify(&mut response);
+        if self.inner.logs(LogLevel::Debug) && !self.inner.on_after_send.read().unwrap().is_empty() {
+            debug!("Modified response: {:?}", response);
+        }
+
+        Box::pin(stream::once(async move {
+            future::join_all(hooks).await;
+            Arc::new(response)
+        }))
+    }
+
+    // replace the hooks of a running service, the commands being executed may still use the old ones
+    pub fn set_received_hooks(&self, hooks: Vec<fn(&CommandRequest)>) {
+        *self.inner.on_received.write().unwrap() = hooks;
+    }
+
+    pub fn set_executed_hooks(&self, hooks: Vec<fn(&CommandResponse)>) {
+        *self.inner.on_executed.write().unwrap() = hooks;
+    }
+
+    pub fn set_before_send_hooks(&self, hooks: Vec<fn(&mut CommandResponse)>) {
+        *self.inner.on_before_send.write().unwrap() = hooks;
+    }
+
+    pub fn set_after_send_hooks(&self, hooks: Vec<fn()>) {
+        *self.inner.on_after_send.write().unwrap() = hooks;
+    }
+
+    // apply a command streamed by ReplicateFrom from the primary, return its offset
+    // the replica resumes from the offset + 1 after a reconnect. the command is applied even if the service is read only,
+    // the checks and the hooks of execute() are skipped, the primary has done them
+    pub fn apply_replicated(&self, data: &CommandResponse) -> Result<u64, KvError> {
+        let (offset, request) = decode_replicated(data)?;
+        let response = dispatch(request.clone(), &self.inner.store)
+            .ok_or_else(|| KvError::InvalidCommand(format!("{} can't be replicated", request.command_name())))?;
+        check_status(&response)?;
+        // a replica can have its own replicas
+        self.replicate(&request, &response);
+        Ok(offset)
+    }
+
+    // stream the logged write commands from the offset, see ReplicateFrom
+    fn replicate_from(&self, offset: u64) -> StreamingResponse {
+        match &self.inner.replication {
+            Some(log) => Box::pin(ReceiverStream::new(log.stream_from(offset))),
+            None => {
+                let response = KvError::InvalidCommand("Replication is not enabled on this server".into()).into();
+                Box::pin(stream::once(async { Arc::new(response) }))
+            }
+        }
+    }
+
+    // append a write command to the replication log if it succeeded, the reads and the failed writes change nothing
+    fn replicate(&self, request: &CommandRequest, response: &CommandResponse) {
+        let Some(log) = &self.inner.replication else {
+            return;
+        };
+        if !request.is_write() || check_status(response).is_err() {
+            return;
+        }
+        match &request.request_data {
+            // the transfer ids are different on the replicas, so the uploaded value is logged as one Hset
+            Some(RequestData::Hsetchunked(v)) if v.last => match self.inner.store.get(&v.table, &v.key) {
+                Ok(Some(value)) => {
+                    log.append(CommandRequest::new_hset(v.table.clone(), v.key.clone(), value));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to replicate the chunked value of table {}. Error: {:?}", v.table, e),
+            },
+            Some(RequestData::Hsetchunked(_)) => {}
+            // an init which changed nothing doesn't need to be replayed
+            Some(RequestData::Hinitifempty(_)) if response.values.first() != Some(&true.into()) => {}
+            _ => {
+                log.append(request.clone());
+            }
+        }
+    }
+
+    // check the values before they reach the storage
+    fn check_value_size(&self, request: &CommandRequest) -> Option<KvError> {
+        let limit = self.inner.max_value_bytes?;
+        let len = request.max_value_len();
+        (len > limit).then_some(KvError::ValueTooLarge(len, limit))
+    }
+}
+
+// set the request id to every response in the stream
+fn with_request_id(response: StreamingResponse, request_id: u64) -> StreamingResponse {
+    if request_id == 0 {
+        return response;
+    }
+
+    // the responses may be shared by multiple subscribers, so set the id on a copy
+    Box::pin(response.map(move |data| {
+        let mut data = (*data).clone();
+        data.request_id = request_id;
+        Arc::new(data)
+    }))
 }
 
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
             inner: Arc::new(inner),
-            broadcaster: Default::default(),
+            topic: Default::default(),
+            session: Default::default(),
+        }
+    }
+}
+
+impl<Store> ServiceInner<Store> {
+    fn check_breaker(&self, request: &CommandRequest) -> Result<(), KvError> {
+        match &self.breaker {
+            Some(breaker) if request.uses_storage() => breaker.check(),
+            _ => Ok(()),
+        }
+    }
+
+    fn logs(&self, level: LogLevel) -> bool {
+        self.log_level >= level
+    }
+
+    // a streaming command (None) is not a storage command, it's not recorded
+    fn record_breaker(&self, uses_storage: bool, response: Option<&CommandResponse>) {
+        if let (Some(breaker), true, Some(response)) = (&self.breaker, uses_storage, response) {
+            // a MemTable over its memory limit still works, it only rejects the writes needing more memory
+            let failed = response.status >= StatusCode::INTERNAL_SERVER_ERROR.as_u16() as u32
+                && response.error_code != ErrorCode::MemoryLimit as i32;
+            breaker.record(failed);
         }
     }
 }
@@ -98,48 +501,196 @@ impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
-            on_received: vec![],
-            on_executed: vec![],
-            on_before_send: vec![],
-            on_after_send: vec![],
+            on_rewrite: vec![],
+            on_received: RwLock::new(vec![]),
+            on_executed: RwLock::new(vec![]),
+            on_before_send: RwLock::new(vec![]),
+            on_after_send: RwLock::new(vec![]),
+            on_received_async: vec![],
+            on_executed_async: vec![],
+            read_only: false,
+            max_value_bytes: None,
+            command_timeout: None,
+            uploads: Uploads::default(),
+            name_policy: NamePolicy::default(),
+            scheduler: None,
+            metrics: Default::default(),
+            breaker: None,
+            log_level: LogLevel::Off,
+            replication: None,
+            verify_token: None,
         }
     }
+
+    // make the service reject all write commands
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    // reject the write commands with a value whose encoded size is bigger than `limit`
+    pub fn max_value_bytes(mut self, limit: usize) -> Self {
+        self.max_value_bytes = Some(limit);
+        self
+    }
+
+    // only accept the table names and keys allowed by the policy, all names are accepted by default
+    pub fn name_policy(mut self, policy: NamePolicy) -> Self {
+        self.name_policy = policy;
+        self
+    }
+
+    // limit how long a unary command can take, the command is run on the blocking thread pool then.
+    // a command which times out can't be cancelled: the storage call runs to completion in the
+    // background, so a timed out write may still be applied (and its keyspace events published).
+    // the streaming commands (Publish, Subscribe, Watch etc.) are not limited by the timeout
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    // keep at most `max_uploads` unfinished Hsetchunked transfers with `max_bytes` in total, the chunks over the
+    // limits get a 429. a transfer which gets no chunk for `idle_ttl` is dropped, see Uploads for the defaults
+    pub fn with_upload_limits(mut self, max_uploads: usize, max_bytes: usize, idle_ttl: Duration) -> Self {
+        self.uploads = Uploads::new(max_uploads, max_bytes, idle_ttl);
+        self
+    }
+
+    // execute at most `limit` commands at the same time, the others wait for their turn in arrival order
+    // the scans (Hgetall, Hrange, Stats etc.) can take at most half of the slots, so they can't starve the cheap commands
+    // (with a limit of 1 they get an extra slot instead, see Scheduler)
+    // the pub/sub commands are not limited
+    pub fn with_concurrency_limit(mut self, limit: usize) -> Self {
+        self.scheduler = Some(Scheduler::new(limit));
+        self
+    }
+
+    // reply 503 without touching the storage for `cooldown`, after `threshold` storage errors in a row within `window`
+    // then one command is let through to test the storage, see CircuitBreaker for the details.
+    // the chunked transfers (Hsetchunked, Hgetchunked and Hgetall with chunk_size) are not guarded
+    pub fn with_circuit_breaker(mut self, threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        self.breaker = Some(CircuitBreaker::new(threshold, window, cooldown));
+        self
+    }
+
+    // append the write commands to the log, so the replicas can stream them with ReplicateFrom
+    pub fn with_replication_log(mut self, log: Arc<ReplicationLog>) -> Self {
+        self.replication = Some(log);
+        self
+    }
+
+    // log the commands of this service up to `level`, nothing is logged by default
+    pub fn with_log_level(mut self, level: LogLevel) -> Self {
+        self.log_level = level;
+        self
+    }
+
+    // require every connection to send an Auth with a token accepted by f, the commands before it get a 401
+    pub fn fn_verify_token(mut self, f: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.verify_token = Some(Box::new(f));
+        self
+    }
+
+    // change the request before anything else sees it, the rewrite hooks run in the order they're added
+    // the other hooks, the checks (e.g. read only) and the storage all get the rewritten request
+    pub fn fn_rewrite(mut self, f: impl Fn(&mut CommandRequest) + Send + Sync + 'static) -> Self {
+        self.on_rewrite.push(Box::new(f));
+        self
+    }
+
     pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
-        self.on_received.push(f);
+        self.on_received.get_mut().unwrap().push(f);
         self
     }
 
     pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
-        self.on_executed.push(f);
+        self.on_executed.get_mut().unwrap().push(f);
         self
     }
 
     pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
-        self.on_before_send.push(f);
+        self.on_before_send.get_mut().unwrap().push(f);
         self
     }
 
     pub fn fn_after_send(mut self, f: fn()) -> Self {
-        self.on_after_send.push(f);
+        self.on_after_send.get_mut().unwrap().push(f);
+        self
+    }
+
+    // the command is executed after all the async received hooks are finished
+    pub fn fn_received_async(
+        mut self,
+        f: impl Fn(&CommandRequest) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    ) -> Self {
+        self.on_received_async.push(Box::new(f));
+        self
+    }
+
+    // the response is sent after all the async executed hooks are finished
+    pub fn fn_executed_async(
+        mut self,
+        f: impl Fn(&CommandResponse) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    ) -> Self {
+        self.on_executed_async.push(Box::new(f));
         self
     }
 }
 
-pub fn dispatch(request: CommandRequest, store: &impl Storage) -> CommandResponse {
-    match request.request_data {
+// return None if it's not a unary command, then we can try to handle it by dispatch_stream
+pub fn dispatch(request: CommandRequest, store: &impl Storage) -> Option<CommandResponse> {
+    let response = match request.request_data {
         Some(RequestData::Hget(v)) => v.execute(store),
+        Some(RequestData::Hgetor(v)) => v.execute(store),
         Some(RequestData::Hgetall(v)) => v.execute(store),
+        Some(RequestData::Hfindvalue(v)) => v.execute(store),
+        Some(RequestData::Hdiff(v)) => v.execute(store),
+        Some(RequestData::Hqueryindex(v)) => v.execute(store),
         Some(RequestData::Hmget(v)) => v.execute(store),
+        Some(RequestData::Hmgetex(v)) => v.execute(store),
         Some(RequestData::Hset(v)) => v.execute(store),
         Some(RequestData::Hmset(v)) => v.execute(store),
+        Some(RequestData::Hinitifempty(v)) => v.execute(store),
+        Some(RequestData::Hsetnx(v)) => v.execute(store),
+        Some(RequestData::Hsetchanged(v)) => v.execute(store),
+        Some(RequestData::Lpush(v)) => v.execute(store),
+        Some(RequestData::Hincrfloat(v)) => v.execute(store),
+        Some(RequestData::Hgetreset(v)) => v.execute(store),
+        Some(RequestData::Htouch(v)) => v.execute(store),
+        Some(RequestData::Lrange(v)) => v.execute(store),
+        Some(RequestData::Sadd(v)) => v.execute(store),
+        Some(RequestData::Srem(v)) => v.execute(store),
+        Some(RequestData::Smembers(v)) => v.execute(store),
+        Some(RequestData::Stats(v)) => v.execute(store),
+        Some(RequestData::Flushall(v)) => v.execute(store),
+        Some(RequestData::Renametable(v)) => v.execute(store),
+        Some(RequestData::Hrange(v)) => v.execute(store),
+        Some(RequestData::Hello(v)) => v.execute(store),
+        Some(RequestData::Hmgetall(v)) => v.execute(store),
+        Some(RequestData::Hstrlen(v)) => v.execute(store),
+        // no storage access, just return the server's clock
+        Some(RequestData::Time(_)) => match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => Value::from(now.as_millis() as i64).into(),
+            Err(e) => KvError::Internal(e.to_string()).into(),
+        },
+        // no storage access, so it works even if the storage is broken
+        Some(RequestData::Ping(v)) => match v.payload.as_str() {
+            "" => Value::from("PONG").into(),
+            payload => Value::from(payload).into(),
+        },
+        Some(RequestData::Readiness(v)) => v.execute(store),
+        Some(RequestData::Hswap(v)) => v.execute(store),
+        Some(RequestData::Hmove(v)) => v.execute(store),
         Some(RequestData::Hdel(v)) => v.execute(store),
         Some(RequestData::Hmdel(v)) => v.execute(store),
+        Some(RequestData::Hdelprefix(v)) => v.execute(store),
+        Some(RequestData::Hexpirebefore(v)) => v.execute(store),
         Some(RequestData::Hexist(v)) => v.execute(store),
         Some(RequestData::Hmexist(v)) => v.execute(store),
         None => KvError::InvalidCommand("invalid command".into()).into(),
-        // if cannot handle, return an empty Response, then we can try to handle it by dispatch_stream
-        _ => CommandResponse::default(),
-    }
+        _ => return None,
+    };
+    Some(response)
 }
 
 pub fn dispatch_stream(request: CommandRequest, topic: impl Topic) -> StreamingResponse {
@@ -147,19 +698,44 @@ pub fn dispatch_stream(request: CommandRequest, topic: impl Topic) -> StreamingR
         Some(RequestData::Publish(v)) => v.execute(topic),
         Some(RequestData::Subscribe(v)) => v.execute(topic),
         Some(RequestData::Unsubscribe(v)) => v.execute(topic),
-        // if comes here, then logic error, crash
-        _ => unreachable!(),
+        Some(RequestData::Watch(v)) => v.execute(topic),
+        Some(RequestData::PublishAndSubscribe(v)) => v.execute(topic),
+        Some(RequestData::Ack(v)) => v.execute(topic),
+        // if comes here, then it is not a streaming command, don't crash the connection's task
+        _ => {
+            let response = KvError::InvalidCommand("not a streaming command".into()).into();
+            Box::pin(stream::once(async { Arc::new(response) }))
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {
-    use std::thread;
+pub fn assert_response_ok(response: &CommandResponse, values: &[Value], pairs: &[KvPair]) {
+    let mut sorted_pairs = response.pairs.clone();
+    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(response.status, 200);
+    assert_eq!(response.message, "");
+    assert_eq!(response.values, values);
+    assert_eq!(sorted_pairs, pairs);
+}
+
+#[cfg(test)]
+pub fn assert_response_error(response: &CommandResponse, code: u32, message: &str) {
+    assert_eq!(response.status, code);
+    assert!(response.message.contains(message));
+    assert_eq!(response.values, &[]);
+    assert_eq!(response.pairs, &[]);
+}
 
+#[cfg(test)]
+mod tests {
     use futures::StreamExt;
     use http::StatusCode;
+    use prost::Message;
     use tracing::info;
 
+    use crate::{ErrorCode, KeyspaceNotifier};
+
     use super::*;
 
     #[tokio::test]
@@ -176,12 +752,482 @@ mod tests {
             assert_response_ok(&data, &[Value::default()], &[]);
         }).await.unwrap();
 
-
         let mut response = service.execute(CommandRequest::new_hget("score", "math"));
         let data = response.next().await.unwrap();
         assert_response_ok(&data, &[10.into()], &[]);
     }
 
+    #[tokio::test]
+    async fn watch_should_receive_key_changes() {
+        // the changes are published by the storage, to the topic the service subscribes the watchers to
+        let topic = Arc::new(Broadcaster::default());
+        let store = KeyspaceNotifier::new(MemTable::new(), topic.clone());
+        let service = Service::from(ServiceInner::new(store)).with_topic(topic);
+
+        let mut watcher = service.execute(CommandRequest::new_watch("score", "math"));
+        let id: i64 = watcher.next().await.unwrap().as_ref().try_into().unwrap();
+        assert!(id > 0);
+
+        // changes to other keys should not be sent to the watcher
+        service.execute(CommandRequest::new_hset("score", "english", 20.into())).next().await;
+        service.execute(CommandRequest::new_hset("score", "math", 10.into())).next().await;
+        let data = watcher.next().await.unwrap();
+        assert_response_ok(&data, &["set".into(), 10.into()], &[]);
+
+        service.execute(CommandRequest::new_hdel("score", "math")).next().await;
+        let data = watcher.next().await.unwrap();
+        assert_response_ok(&data, &["del".into()], &[]);
+
+        // deleting a non-existing key changes nothing
+        service.execute(CommandRequest::new_hdel("score", "math")).next().await;
+        service.execute(CommandRequest::new_hmset("score", vec![KvPair::new("math", 30.into())])).next().await;
+        let data = watcher.next().await.unwrap();
+        assert_response_ok(&data, &["set".into(), 30.into()], &[]);
+
+        // setting the same value changes nothing
+        service.execute(CommandRequest::new_hsetchanged("score", "math", 30.into())).next().await;
+        service.execute(CommandRequest::new_hsetchanged("score", "math", 40.into())).next().await;
+        let data = watcher.next().await.unwrap();
+        assert_response_ok(&data, &["set".into(), 40.into()], &[]);
+    }
+
+    #[test]
+    fn dispatch_should_only_handle_unary_commands() {
+        let store = MemTable::new();
+        assert!(dispatch(CommandRequest::new_hget("score", "math"), &store).is_some());
+        assert!(dispatch(CommandRequest::new_subscribe("lobby"), &store).is_none());
+        assert!(dispatch(CommandRequest::new_publish("lobby", vec![]), &store).is_none());
+
+        let response = dispatch(CommandRequest::default(), &store).unwrap();
+        assert_response_error(&response, 400, "invalid command");
+    }
+
+    #[tokio::test]
+    async fn dispatch_stream_with_unary_command_should_return_error() {
+        let broadcaster = Arc::new(Broadcaster::default());
+        let mut response = dispatch_stream(CommandRequest::new_hget("score", "math"), broadcaster);
+        let data = response.next().await.unwrap();
+        assert_response_error(&data, 400, "not a streaming command");
+    }
+
+    #[tokio::test]
+    async fn auth_should_verify_token() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        assert!(!service.requires_auth());
+        let data = service.execute(CommandRequest::new_auth("anything")).next().await.unwrap();
+        assert_eq!(data.status, 200);
+
+        let service: Service = ServiceInner::new(MemTable::new()).fn_verify_token(|token| token == "secret").into();
+        assert!(service.requires_auth());
+        let data = service.execute(CommandRequest::new_auth("secret").with_request_id(3)).next().await.unwrap();
+        assert_eq!((data.status, data.request_id), (200, 3));
+        let data = service.execute(CommandRequest::new_auth("wrong")).next().await.unwrap();
+        assert_eq!(data.status, 401);
+        assert_eq!(data.error_code, ErrorCode::Unauthorized as i32);
+
+        // the token is never logged
+        let logged = format!("{:?}", CommandRequest::new_auth("secret").redacted());
+        assert!(!logged.contains("secret"), "{}", logged);
+    }
+
+    #[tokio::test]
+    async fn request_id_should_be_copied_to_responses() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        let request = CommandRequest::new_hset("score", "math", 10.into()).with_request_id(42);
+        let data = service.execute(request).next().await.unwrap();
+        assert_eq!(data.request_id, 42);
+
+        let data = service.execute(CommandRequest::new_hget("score", "math")).next().await.unwrap();
+        assert_eq!(data.request_id, 0);
+
+        // every response of a stream carries the request id
+        let request = CommandRequest::new_subscribe("lobby").with_request_id(7);
+        let mut stream = service.execute(request);
+        let data = stream.next().await.unwrap();
+        assert_eq!(data.request_id, 7);
+
+        let request = CommandRequest::new_publish("lobby", vec!["hello".into()]).with_request_id(8);
+        let data = service.execute(request).next().await.unwrap();
+        assert_eq!(data.request_id, 8);
+
+        let data = stream.next().await.unwrap();
+        assert_eq!(data.request_id, 7);
+        assert_eq!(data.values, &["hello".into()]);
+    }
+
+    #[test]
+    fn time_should_return_server_time() {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        let response = dispatch(CommandRequest::new_time(), &MemTable::new()).unwrap();
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+
+        let now: i64 = (&response).try_into().unwrap();
+        assert!(before <= now && now <= after);
+    }
+
+    #[test]
+    fn ping_should_work_when_storage_is_broken() {
+        let response = dispatch(CommandRequest::new_ping(""), &BrokenStore).unwrap();
+        assert_response_ok(&response, &["PONG".into()], &[]);
+        let response = dispatch(CommandRequest::new_ping("lb-probe"), &BrokenStore).unwrap();
+        assert_response_ok(&response, &["lb-probe".into()], &[]);
+
+        // readiness touches the storage
+        let response = dispatch(CommandRequest::new_readiness(), &BrokenStore).unwrap();
+        assert_response_error(&response, 500, "storage is broken");
+        let response = dispatch(CommandRequest::new_readiness(), &MemTable::new()).unwrap();
+        assert_response_ok(&response, &["READY".into()], &[]);
+    }
+
+    // every storage access fails
+    struct BrokenStore;
+
+    impl Storage for BrokenStore {
+        fn get(&self, _: &str, _: &[u8]) -> Result<Option<Value>, KvError> {
+            Err(broken())
+        }
+
+        fn set(&self, _: &str, _: Vec<u8>, _: Value) -> Result<Option<Value>, KvError> {
+            Err(broken())
+        }
+
+        fn set_if_absent(&self, _: &str, _: Vec<u8>, _: Value) -> Result<bool, KvError> {
+            Err(broken())
+        }
+
+        fn init_if_empty(&self, _: &str, _: Vec<KvPair>) -> Result<bool, KvError> {
+            Err(broken())
+        }
+
+        fn get_or_set_with(&self, _: &str, _: Vec<u8>, _: impl FnOnce() -> Value) -> Result<Value, KvError> {
+            Err(broken())
+        }
+
+        fn set_if_changed(&self, _: &str, _: Vec<u8>, _: Value) -> Result<bool, KvError> {
+            Err(broken())
+        }
+
+        fn lpush(&self, _: &str, _: Vec<u8>, _: Vec<Value>) -> Result<usize, KvError> {
+            Err(broken())
+        }
+
+        fn sadd(&self, _: &str, _: Vec<u8>, _: Vec<Value>) -> Result<usize, KvError> {
+            Err(broken())
+        }
+
+        fn incr_float(&self, _: &str, _: Vec<u8>, _: f64) -> Result<f64, KvError> {
+            Err(broken())
+        }
+
+        fn get_and_reset(&self, _: &str, _: &[u8]) -> Result<i64, KvError> {
+            Err(broken())
+        }
+
+        fn srem(&self, _: &str, _: Vec<u8>, _: Vec<Value>) -> Result<usize, KvError> {
+            Err(broken())
+        }
+
+        fn del(&self, _: &str, _: &[u8]) -> Result<Option<Value>, KvError> {
+            Err(broken())
+        }
+
+        fn swap(&self, _: &str, _: &[u8], _: &[u8]) -> Result<(Value, Value), KvError> {
+            Err(broken())
+        }
+
+        fn move_key(&self, _: &str, _: &str, _: &[u8]) -> Result<Option<Value>, KvError> {
+            Err(broken())
+        }
+
+        fn get_iter(&self, _: &str) -> Result<Box<dyn Iterator<Item = KvPair>>, KvError> {
+            Err(broken())
+        }
+
+        fn iter_all(&self) -> Result<Box<dyn Iterator<Item = (String, KvPair)>>, KvError> {
+            Err(broken())
+        }
+
+        fn rename_table(&self, _: &str, _: &str) -> Result<(), KvError> {
+            Err(broken())
+        }
+
+        fn clear(&self) -> Result<u64, KvError> {
+            Err(broken())
+        }
+    }
+
+    fn broken() -> KvError {
+        KvError::Internal("storage is broken".into())
+    }
+
+    #[tokio::test]
+    async fn publish_and_subscribe_should_work() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        let mut worker = service.execute(CommandRequest::new_subscribe("jobs"));
+        worker.next().await.unwrap();
+
+        let request = CommandRequest::new_publish_and_subscribe("jobs", vec!["job1".into()], "replies");
+        let mut replies = service.execute(request);
+        let id: i64 = replies.next().await.unwrap().as_ref().try_into().unwrap();
+        assert!(id > 0);
+
+        // the worker receives the job and replies on the reply topic
+        let data = worker.next().await.unwrap();
+        assert_response_ok(&data, &["job1".into()], &[]);
+        service.execute(CommandRequest::new_publish("replies", vec!["done".into()])).next().await;
+
+        let data = replies.next().await.unwrap();
+        assert_response_ok(&data, &["done".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn subscriptions_should_list_the_session_subscriptions() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        let mut lobby = service.execute(CommandRequest::new_subscribe("lobby"));
+        let id1: i64 = lobby.next().await.unwrap().as_ref().try_into().unwrap();
+        let mut jobs = service.execute(CommandRequest::new_subscribe("jobs"));
+        let id2: i64 = jobs.next().await.unwrap().as_ref().try_into().unwrap();
+
+        let data = service.execute(CommandRequest::new_subscriptions()).next().await.unwrap();
+        let pairs = &[KvPair::new("jobs", id2.into()), KvPair::new("lobby", id1.into())];
+        assert_response_ok(&data, &[], pairs);
+
+        // another session doesn't see them
+        let other = service.new_session();
+        let data = other.execute(CommandRequest::new_subscriptions()).next().await.unwrap();
+        assert_response_ok(&data, &[], &[]);
+
+        // the subscription is gone after unsubscribe, or when its stream is dropped
+        service.execute(CommandRequest::new_unsubscribe("lobby", id1 as _)).next().await.unwrap();
+        assert_eq!(service.session.subscriptions(), vec![(id2 as u32, "jobs".to_string())]);
+        drop(jobs);
+        assert!(service.session.subscriptions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_all_should_remove_the_session_subscriptions() {
+        let broadcaster = Arc::new(Broadcaster::default());
+        let service = Service::from(ServiceInner::new(MemTable::new())).with_topic(Arc::clone(&broadcaster));
+        let other = service.new_session();
+
+        let mut lobby = service.execute(CommandRequest::new_subscribe("lobby"));
+        lobby.next().await.unwrap();
+        let mut jobs = service.execute(CommandRequest::new_subscribe("jobs"));
+        jobs.next().await.unwrap();
+        let mut others = other.execute(CommandRequest::new_subscribe("lobby"));
+        others.next().await.unwrap();
+
+        let data = service.execute(CommandRequest::new_unsubscribe_all()).next().await.unwrap();
+        assert_response_ok(&data, &[2.into()], &[]);
+        assert!(service.session.subscriptions().is_empty());
+        assert!(lobby.next().await.is_none());
+        assert!(jobs.next().await.is_none());
+        assert!(!broadcaster.has_topic("jobs"));
+
+        // the subscriptions of the other session are kept
+        assert_eq!(other.session.subscriptions().len(), 1);
+        assert!(broadcaster.has_topic("lobby"));
+    }
+
+    #[tokio::test]
+    async fn service_with_durable_topic_should_log_publishes() {
+        let topic = Arc::new(DurableTopic::new(Broadcaster::default(), MemTable::new()));
+        let service = Service::from(ServiceInner::new(MemTable::new())).with_topic(topic.clone());
+
+        let mut stream = service.execute(CommandRequest::new_subscribe("lobby"));
+        stream.next().await.unwrap();
+        let request = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        service.execute(request).next().await.unwrap();
+
+        let data = stream.next().await.unwrap();
+        assert_response_ok(&data, &["hello".into()], &[]);
+        let messages = topic.replay("lobby", 0, 10).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_response_ok(&messages[0].1, &["hello".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn service_metrics_should_count_commands_and_topics() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).next().await;
+        service.execute(CommandRequest::new_hget("t1", "k1")).next().await;
+        service.execute(CommandRequest::new_hget("t1", "k2")).next().await;
+        let mut stream = service.execute(CommandRequest::new_subscribe("lobby"));
+        stream.next().await.unwrap();
+        let _connection = service.connection();
+
+        let text = service.metrics_text();
+        assert!(text.contains("kv_commands_total{command=\"hget\"} 2\n"));
+        assert!(text.contains("kv_command_errors_total{command=\"hget\"} 1\n"));
+        assert!(text.contains("kv_commands_total{command=\"subscribe\"} 1\n"));
+        assert!(text.contains("kv_connections 1\n"));
+        assert!(text.contains("kv_topics 1\n"));
+    }
+
+    #[tokio::test]
+    async fn service_with_concurrency_limit_should_work() {
+        let service: Service = ServiceInner::new(MemTable::new()).with_concurrency_limit(1).into();
+
+        // the first command keeps its slot until its response is consumed
+        let mut first = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let mut second = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_response_ok(&first.next().await.unwrap(), &[Value::default()], &[]);
+        drop(first);
+        assert_response_ok(&second.next().await.unwrap(), &["v1".into()], &[]);
+        drop(second);
+
+        // the pub/sub commands are not limited
+        let _slot = service.inner.scheduler.as_ref().unwrap().acquire(false).await;
+        let mut stream = service.execute(CommandRequest::new_subscribe("lobby"));
+        assert!(stream.next().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn publish_with_ack_should_work() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+
+        let mut worker = service.execute(CommandRequest::new_subscribe("jobs"));
+        let id: i64 = worker.next().await.unwrap().as_ref().try_into().unwrap();
+
+        let request = CommandRequest::new_publish_with_ack("jobs", vec!["job1".into()]);
+        let data = service.execute(request).next().await.unwrap();
+        let message_id: i64 = data.as_ref().try_into().unwrap();
+
+        let data = worker.next().await.unwrap();
+        assert_eq!(data.message_id, message_id as u64);
+        assert_eq!(data.values, &["job1".into()]);
+
+        let request = CommandRequest::new_ack(id as _, message_id as _);
+        let data = service.execute(request.clone()).next().await.unwrap();
+        assert_response_ok(&data, &[true.into()], &[]);
+
+        // the message is not waiting for ack anymore
+        let data = service.execute(request).next().await.unwrap();
+        assert_response_ok(&data, &[false.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn value_bigger_than_limit_should_be_rejected() {
+        let value: Value = "a".repeat(100).into();
+        let limit = value.encoded_len();
+        let service: Service = ServiceInner::new(MemTable::new()).max_value_bytes(limit).into();
+
+        // just under the limit
+        let data = service.execute(CommandRequest::new_hset("t", "k1", value.clone())).next().await.unwrap();
+        assert_response_ok(&data, &[Value::default()], &[]);
+
+        // just over the limit
+        let big: Value = "a".repeat(101).into();
+        let data = service.execute(CommandRequest::new_hset("t", "k2", big.clone())).next().await.unwrap();
+        assert_response_error(&data, 400, "larger than the limit");
+        let pairs = vec![KvPair::new("k3", value), KvPair::new("k4", big)];
+        let data = service.execute(CommandRequest::new_hmset("t", pairs)).next().await.unwrap();
+        assert_response_error(&data, 400, "larger than the limit");
+
+        // nothing is written for the rejected commands
+        let data = service.execute(CommandRequest::new_hmexist("t", vec!["k2".into(), "k3".into()])).next().await.unwrap();
+        assert_response_ok(&data, &[false.into(), false.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn read_only_service_should_reject_writes() {
+        let service: Service = ServiceInner::new(MemTable::new()).read_only().into();
+
+        let data = service.execute(CommandRequest::new_flushall()).next().await.unwrap();
+        assert_response_error(&data, 403, "read-only");
+
+        let data = service.execute(CommandRequest::new_hset("score", "math", 10.into())).next().await.unwrap();
+        assert_response_error(&data, 403, "read-only");
+
+        let data = service.execute(CommandRequest::new_hdelprefix("score", "m")).next().await.unwrap();
+        assert_response_error(&data, 403, "read-only");
+
+        let data = service.execute(CommandRequest::new_hget("score", "math")).next().await.unwrap();
+        assert_response_error(&data, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn command_timeout_should_work() {
+        let store = MemTable::new();
+        for i in 0..100_000 {
+            store.set("big", format!("k{}", i).into_bytes(), i.into()).unwrap();
+        }
+
+        let service: Service = ServiceInner::new(store.clone()).command_timeout(Duration::ZERO).into();
+        let mut request = CommandRequest::new_hget_all("big");
+        request.request_id = 7;
+        let data = service.execute(request).next().await.unwrap();
+        assert_response_error(&data, 504, "not finished");
+        assert_eq!(data.error_code, ErrorCode::Timeout as i32);
+        assert_eq!(data.request_id, 7);
+
+        let service: Service = ServiceInner::new(store).command_timeout(Duration::from_secs(10)).into();
+        let data = service.execute(CommandRequest::new_hget("big", "k1")).next().await.unwrap();
+        assert_response_ok(&data, &[1.into()], &[]);
+
+        // streaming commands are not limited by the timeout
+        let mut stream = service.execute(CommandRequest::new_subscribe("topic"));
+        let data = stream.next().await.unwrap();
+        assert_eq!(data.status, 200);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_should_reject_commands_while_storage_is_broken() {
+        let inner = ServiceInner::new(BrokenStore).with_circuit_breaker(2, Duration::from_secs(10), Duration::from_secs(10));
+        let service: Service<BrokenStore> = inner.into();
+        for _ in 0..2 {
+            let data = service.execute(CommandRequest::new_hget("t", "k")).next().await.unwrap();
+            assert_response_error(&data, 500, "storage is broken");
+        }
+
+        let data = service.execute(CommandRequest::new_hget("t", "k")).next().await.unwrap();
+        assert_response_error(&data, 503, "unavailable");
+        assert_eq!(data.error_code, ErrorCode::Unavailable as i32);
+
+        // the commands without storage access still work
+        let data = service.execute(CommandRequest::new_ping("")).next().await.unwrap();
+        assert_response_ok(&data, &["PONG".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn name_policy_should_reject_invalid_names() {
+        let policy = NamePolicy::default().forbid(b":");
+        let service: Service = ServiceInner::new(MemTable::new()).name_policy(policy).into();
+
+        let data = service.execute(CommandRequest::new_hset("a", "b:c", 1.into())).next().await.unwrap();
+        assert_response_error(&data, 400, "invalid key");
+        assert_eq!(service.inner.store.get("a", b"b:c").unwrap(), None);
+
+        let data = service.execute(CommandRequest::new_hset("a", "b", 1.into())).next().await.unwrap();
+        assert_response_ok(&data, &[Value::default()], &[]);
+    }
+
+    #[tokio::test]
+    async fn rewrite_hook_should_change_the_request() {
+        fn with_tenant(request: &mut CommandRequest) {
+            match &mut request.request_data {
+                Some(RequestData::Hset(v)) => v.table = format!("tenant1:{}", v.table),
+                Some(RequestData::Hget(v)) => v.table = format!("tenant1:{}", v.table),
+                _ => {}
+            }
+        }
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_rewrite(with_tenant)
+            .fn_received(|req| assert_eq!(req.table(), "tenant1:score"))
+            .into();
+
+        let data = service.execute(CommandRequest::new_hset("score", "math", 10.into())).next().await.unwrap();
+        assert_response_ok(&data, &[Value::default()], &[]);
+        assert_eq!(service.inner.store.get("tenant1:score", b"math").unwrap(), Some(10.into()));
+        assert_eq!(service.inner.store.get("score", b"math").unwrap(), None);
+
+        let data = service.execute(CommandRequest::new_hget("score", "math")).next().await.unwrap();
+        assert_response_ok(&data, &[10.into()], &[]);
+    }
+
     #[tokio::test]
     async fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) {
@@ -212,22 +1258,149 @@ mod tests {
         assert_eq!(data.message, "");
         assert_eq!(data.values, vec![Value::default()]);
     }
-}
 
-#[cfg(test)]
-pub fn assert_response_ok(response: &CommandResponse, values: &[Value], pairs: &[KvPair]) {
-    let mut sorted_pairs = response.pairs.clone();
-    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(response.status, 200);
-    assert_eq!(response.message, "");
-    assert_eq!(response.values, values);
-    assert_eq!(sorted_pairs, pairs);
-}
+    #[tokio::test]
+    async fn hooks_should_be_replaceable_on_running_service() {
+        fn created(res: &mut CommandResponse) {
+            res.status = StatusCode::CREATED.as_u16() as u32;
+        }
 
-#[cfg(test)]
-pub fn assert_response_error(response: &CommandResponse, code: u32, message: &str) {
-    assert_eq!(response.status, code);
-    assert!(response.message.contains(message));
-    assert_eq!(response.values, &[]);
-    assert_eq!(response.pairs, &[]);
+        let service: Service = ServiceInner::new(MemTable::new()).fn_before_send(created).into();
+        let cloned = service.clone();
+
+        let data = service.execute(CommandRequest::new_hget_all("score")).next().await.unwrap();
+        assert_eq!(data.status, StatusCode::CREATED.as_u16() as u32);
+
+        // the change is visible to all clones of the service
+        cloned.set_before_send_hooks(vec![]);
+        let data = service.execute(CommandRequest::new_hget_all("score")).next().await.unwrap();
+        assert_response_ok(&data, &[], &[]);
+    }
+
+    #[tokio::test]
+    async fn async_hooks_should_be_awaited() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+        static EXECUTED: AtomicUsize = AtomicUsize::new(0);
+
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_received_async(|_: &CommandRequest| {
+                Box::pin(async {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    RECEIVED.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .fn_executed_async(|res: &CommandResponse| {
+                let status = res.status;
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    // the received hook is finished before the command is executed
+                    assert_eq!(RECEIVED.load(Ordering::SeqCst), 1);
+                    assert_eq!(status, 200);
+                    EXECUTED.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .into();
+
+        let mut response = service.execute(CommandRequest::new_hset("score", "math", 25.into()));
+        let data = response.next().await.unwrap();
+        assert_response_ok(&data, &[Value::default()], &[]);
+        assert_eq!(RECEIVED.load(Ordering::SeqCst), 1);
+        assert_eq!(EXECUTED.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn replica_should_apply_the_write_commands_of_primary() {
+        let log = Arc::new(ReplicationLog::new(16));
+        let primary: Service = ServiceInner::new(MemTable::new()).with_replication_log(Arc::clone(&log)).into();
+        let replica: Service = ServiceInner::new(MemTable::new()).read_only().into();
+
+        primary.execute(CommandRequest::new_hset("score", "math", 10.into())).next().await;
+        // the reads and the failed writes are not logged
+        primary.execute(CommandRequest::new_hget("score", "math")).next().await;
+        primary.execute(CommandRequest::new_hswap("score", "math", "english")).next().await;
+        assert_eq!(log.next_offset(), 1);
+
+        let mut stream = primary.execute(CommandRequest::new_replicate_from(0));
+        assert_response_ok(&stream.next().await.unwrap(), &[1.into()], &[]);
+        assert_eq!(replica.apply_replicated(&stream.next().await.unwrap()).unwrap(), 0);
+
+        // the new writes are streamed as they're executed
+        primary.execute(CommandRequest::new_hincrfloat("score", "math", 2.5)).next().await;
+        primary.execute(CommandRequest::new_hset("score", "art", 5.into())).next().await;
+        assert_eq!(replica.apply_replicated(&stream.next().await.unwrap()).unwrap(), 1);
+        assert_eq!(replica.apply_replicated(&stream.next().await.unwrap()).unwrap(), 2);
+        let data = replica.execute(CommandRequest::new_hmget("score", vec!["math".into(), "art".into()])).next().await;
+        assert_response_ok(&data.unwrap(), &[12.5.into(), 5.into()], &[]);
+
+        // a replica resumes from the last applied offset + 1
+        let mut stream = primary.execute(CommandRequest::new_replicate_from(2));
+        stream.next().await.unwrap();
+        assert_eq!(stream.next().await.unwrap().message_id, 2);
+
+        let data = replica.execute(CommandRequest::new_replicate_from(0)).next().await.unwrap();
+        assert_response_error(&data, 400, "Replication is not enabled");
+    }
+
+    #[tokio::test]
+    async fn replication_should_fail_if_offset_is_not_kept() {
+        let log = Arc::new(ReplicationLog::new(1));
+        let primary: Service = ServiceInner::new(MemTable::new()).with_replication_log(log).into();
+        primary.execute(CommandRequest::new_hset("score", "math", 10.into())).next().await;
+        primary.execute(CommandRequest::new_hset("score", "art", 5.into())).next().await;
+
+        let data = primary.execute(CommandRequest::new_replicate_from(0)).next().await.unwrap();
+        assert_response_error(&data, 410, "not kept anymore");
+        assert_eq!(data.error_code, ErrorCode::ReplicationGap as i32);
+    }
+
+    #[tokio::test]
+    async fn log_level_should_gate_command_logs() {
+        use std::io;
+        use std::sync::Mutex;
+
+        // collect the formatted logs of the current thread
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        async fn logs_of(service: Service) -> String {
+            let logs = Logs::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish();
+            let _default = tracing::subscriber::set_default(subscriber);
+            service.execute(CommandRequest::new_hset("score", "math", 10.into())).next().await;
+            let logs = logs.0.lock().unwrap();
+            String::from_utf8_lossy(&logs).into_owned()
+        }
+
+        let quiet = logs_of(ServiceInner::new(MemTable::new()).fn_after_send(|| {}).into()).await;
+        assert_eq!(quiet, "");
+
+        let info = logs_of(ServiceInner::new(MemTable::new()).with_log_level(LogLevel::Info).into()).await;
+        assert!(info.contains("Executed with status 200"));
+        assert!(!info.contains("Received request"));
+
+        let service = ServiceInner::new(MemTable::new()).with_log_level(LogLevel::Debug).fn_after_send(|| {});
+        let verbose = logs_of(service.into()).await;
+        assert!(verbose.contains("Received request"));
+        assert!(verbose.contains("Executed with status 200"));
+        assert!(verbose.contains("Modified response"));
+    }
 }
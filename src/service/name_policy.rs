@@ -0,0 +1,85 @@
+use crate::{CommandRequest, KvError};
+
+// which table names and keys the service accepts, the default accepts everything
+// all the storages handle any table name and any binary key, so this is only for the application's own rules,
+// e.g. keep `:` out of the names if the keys are shown as `table:key` somewhere
+#[derive(Debug, Clone, Default)]
+pub struct NamePolicy {
+    // bytes which can't be used in a table name or a key
+    forbidden: Vec<u8>,
+    max_table_len: Option<usize>,
+    max_key_len: Option<usize>,
+}
+
+impl NamePolicy {
+    // reject the table names and keys containing any of the bytes
+    pub fn forbid(mut self, bytes: &[u8]) -> Self {
+        self.forbidden.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn max_table_len(mut self, len: usize) -> Self {
+        self.max_table_len = Some(len);
+        self
+    }
+
+    pub fn max_key_len(mut self, len: usize) -> Self {
+        self.max_key_len = Some(len);
+        self
+    }
+
+    // check all the tables and keys the command names
+    pub fn check(&self, request: &CommandRequest) -> Result<(), KvError> {
+        for table in request.tables() {
+            self.check_name("table", table.as_bytes(), self.max_table_len)?;
+        }
+        for key in request.keys() {
+            self.check_name("key", key, self.max_key_len)?;
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, kind: &str, name: &[u8], max_len: Option<usize>) -> Result<(), KvError> {
+        let invalid = |reason: String| {
+            let message = format!("invalid {} `{}`: {}", kind, name.escape_ascii(), reason);
+            Err(KvError::InvalidCommand(message))
+        };
+        if let Some(max_len) = max_len.filter(|max_len| name.len() > *max_len) {
+            return invalid(format!("longer than {} bytes", max_len));
+        }
+        match name.iter().find(|b| self.forbidden.contains(b)) {
+            Some(b) => invalid(format!("contains `{}`", b.escape_ascii())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::KvPair;
+
+    use super::*;
+
+    #[test]
+    fn default_policy_should_accept_everything() {
+        let policy = NamePolicy::default();
+        assert!(policy.check(&CommandRequest::new_hset("a:b", b"\xff:c".to_vec(), 1.into())).is_ok());
+    }
+
+    #[test]
+    fn policy_should_reject_invalid_names() {
+        let policy = NamePolicy::default().forbid(b":").max_table_len(8).max_key_len(4);
+        assert!(policy.check(&CommandRequest::new_hget("users", "1")).is_ok());
+
+        let err = policy.check(&CommandRequest::new_hset("a", "b:c", 1.into())).unwrap_err();
+        assert!(matches!(err, KvError::InvalidCommand(m) if m == "invalid key `b:c`: contains `:`"));
+        let pairs = vec![KvPair::new("k1", 1.into()), KvPair::new("key-2", 2.into())];
+        assert!(policy.check(&CommandRequest::new_hmset("a", pairs)).is_err());
+        assert!(policy.check(&CommandRequest::new_hget_all("a:b")).is_err());
+        assert!(policy.check(&CommandRequest::new_renametable("a", "long-table")).is_err());
+        assert!(policy.check(&CommandRequest::new_hmget_all(vec!["a".into(), "b:c".into()])).is_err());
+
+        // not names, so they're not checked
+        assert!(policy.check(&CommandRequest::new_hdelprefix("a", "b:")).is_ok());
+    }
+}
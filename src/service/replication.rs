@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use prost::Message;
+use tokio::sync::{mpsc, watch};
+
+use crate::{CommandRequest, CommandResponse, KvError, Value};
+use crate::pb::check_status;
+
+// how many commands a streaming replica can have in its channel
+const REPLICA_CAPACITY: usize = 128;
+
+// keep the recent write commands of a service in memory, so the replicas can stream them with ReplicateFrom
+//
+// every write command executed successfully is appended with an offset, the offsets start from 0.
+// only the last `capacity` commands are kept, a replica which falls further behind gets a 410 and needs a full copy.
+// a command is appended after it's executed, so the concurrent writes of the same key may be appended in another
+// order than they're applied. the replicas only converge with the primary if the same key isn't written concurrently
+pub struct ReplicationLog {
+    capacity: usize,
+    // the offset of the first kept command, and the kept commands
+    entries: Mutex<(u64, VecDeque<Arc<CommandRequest>>)>,
+    // the next offset, the streaming replicas are notified when it changes
+    next: watch::Sender<u64>,
+}
+
+impl ReplicationLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new((0, VecDeque::new())),
+            next: watch::channel(0).0,
+        }
+    }
+
+    // the offset of the next appended command
+    pub fn next_offset(&self) -> u64 {
+        *self.next.borrow()
+    }
+
+    // append a write command, return its offset
+    pub fn append(&self, request: CommandRequest) -> u64 {
+        let mut entries = self.entries.lock().unwrap();
+        let (first, commands) = &mut *entries;
+        if commands.len() >= self.capacity {
+            commands.pop_front();
+            *first += 1;
+        }
+        commands.push_back(Arc::new(request));
+        let offset = *first + commands.len() as u64 - 1;
+        // notify under the lock, so the offsets are seen in order
+        self.next.send_replace(offset + 1);
+        offset
+    }
+
+    // get the commands from the offset with their offsets, fail if some of them are not kept anymore
+    pub fn read_from(&self, offset: u64) -> Result<Vec<(u64, Arc<CommandRequest>)>, KvError> {
+        let entries = self.entries.lock().unwrap();
+        let (first, commands) = &*entries;
+        if offset < *first {
+            return Err(KvError::ReplicationGap(offset, *first));
+        }
+        let skipped = (offset - first) as usize;
+        let commands = commands.iter().enumerate().skip(skipped);
+        Ok(commands.map(|(i, command)| (first + i as u64, Arc::clone(command))).collect())
+    }
+
+    // stream the commands from the offset, then the new ones as they're appended, until the receiver is dropped
+    // the first response is the next offset when the stream starts, like the id of a subscription
+    pub(crate) fn stream_from(self: &Arc<Self>, offset: u64) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let (sender, receiver) = mpsc::channel(REPLICA_CAPACITY);
+        let log = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut notified = log.next.subscribe();
+            // mark the current offset as seen before reading, so an append during the read is not missed
+            let next = *notified.borrow_and_update();
+            let mut commands = log.read_from(offset);
+            if commands.is_ok() && sender.send(Arc::new(Value::from(next as i64).into())).await.is_err() {
+                return;
+            }
+
+            let mut offset = offset;
+            loop {
+                let batch = match commands {
+                    Ok(batch) => batch,
+                    Err(e) => {
+                        let _ = sender.send(Arc::new(e.into())).await;
+                        return;
+                    }
+                };
+                for (command_offset, command) in batch {
+                    if sender.send(Arc::new(replicated_response(command_offset, &command))).await.is_err() {
+                        return;
+                    }
+                    offset = command_offset + 1;
+                }
+
+                tokio::select! {
+                    changed = notified.changed() => if changed.is_err() { return },
+                    _ = sender.closed() => return,
+                }
+                notified.borrow_and_update();
+                commands = log.read_from(offset);
+            }
+        });
+        receiver
+    }
+}
+
+// a logged command sent by ReplicateFrom, the message_id is its offset
+fn replicated_response(offset: u64, request: &CommandRequest) -> CommandResponse {
+    let mut response: CommandResponse = Value::from(Bytes::from(request.encode_to_vec())).into();
+    response.message_id = offset;
+    response
+}
+
+// decode a command streamed by ReplicateFrom, return its offset and the command
+pub fn decode_replicated(data: &CommandResponse) -> Result<(u64, CommandRequest), KvError> {
+    check_status(data)?;
+    let value = data
+        .values
+        .first()
+        .ok_or_else(|| KvError::Internal("Replicated command is missing".into()))?;
+    let request = CommandRequest::decode(Bytes::try_from(value)?)?;
+    Ok((data.message_id, request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replication_log_should_keep_the_last_commands() {
+        let log = ReplicationLog::new(2);
+        assert_eq!(log.append(CommandRequest::new_hset("t1", "k1", 1.into())), 0);
+        assert_eq!(log.append(CommandRequest::new_hset("t1", "k2", 2.into())), 1);
+        assert_eq!(log.append(CommandRequest::new_hdel("t1", "k1")), 2);
+        assert_eq!(log.next_offset(), 3);
+
+        let commands = log.read_from(1).unwrap();
+        let offsets: Vec<_> = commands.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, vec![1, 2]);
+        assert_eq!(*commands[1].1, CommandRequest::new_hdel("t1", "k1"));
+        assert!(log.read_from(3).unwrap().is_empty());
+        assert!(matches!(log.read_from(0), Err(KvError::ReplicationGap(0, 1))));
+    }
+
+    #[test]
+    fn replicated_response_should_be_decoded() {
+        let request = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let data = replicated_response(7, &request);
+        assert_eq!(decode_replicated(&data).unwrap(), (7, request));
+        assert!(decode_replicated(&KvError::ReplicationGap(0, 1).into()).is_err());
+    }
+}
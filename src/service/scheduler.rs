@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// limit how many commands are executed at the same time
+//
+// fairness: every command takes one of the `limit` slots, the waiting commands get a slot in arrival order.
+// a scan (see CommandRequest::is_scan()) must also take one of the scan slots, which are half of the slots,
+// so a burst of scans can't take all the slots, the other half is always left for the cheap commands.
+// with a limit of 1 there's no half to leave, so the scans get one extra slot of their own instead,
+// a scan and a cheap command can run at the same time, but never two of either
+pub struct Scheduler {
+    all: Arc<Semaphore>,
+    scans: Arc<Semaphore>,
+    // false if the scan slot is the extra one
+    scans_take_all: bool,
+}
+
+// the slots taken by a command, they're released when it's dropped
+pub struct Slot {
+    _all: Option<OwnedSemaphorePermit>,
+    _scan: Option<OwnedSemaphorePermit>,
+}
+
+impl Scheduler {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            all: Arc::new(Semaphore::new(limit)),
+            scans: Arc::new(Semaphore::new((limit / 2).max(1))),
+            scans_take_all: limit > 1,
+        }
+    }
+
+    // wait until the command can be executed
+    pub async fn acquire(&self, scan: bool) -> Slot {
+        // the semaphores are never closed, so acquiring can't fail
+        let scan = match scan {
+            true => Some(Arc::clone(&self.scans).acquire_owned().await.unwrap()),
+            false => None,
+        };
+        let all = match scan.is_none() || self.scans_take_all {
+            true => Some(Arc::clone(&self.all).acquire_owned().await.unwrap()),
+            false => None,
+        };
+        Slot { _all: all, _scan: scan }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn scans_should_not_take_all_slots() {
+        let scheduler = Scheduler::new(4);
+
+        let _scan1 = scheduler.acquire(true).await;
+        let _scan2 = scheduler.acquire(true).await;
+        assert_eq!(scheduler.all.available_permits(), 2);
+
+        // the third scan waits, but the cheap commands still get the rest of the slots
+        assert!(time::timeout(Duration::from_millis(10), scheduler.acquire(true)).await.is_err());
+        let _cheap1 = scheduler.acquire(false).await;
+        let cheap2 = scheduler.acquire(false).await;
+        assert_eq!(scheduler.all.available_permits(), 0);
+
+        drop(cheap2);
+        assert_eq!(scheduler.all.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn scans_should_leave_a_slot_with_limit_one() {
+        let scheduler = Scheduler::new(1);
+
+        let _scan = scheduler.acquire(true).await;
+        assert!(time::timeout(Duration::from_millis(10), scheduler.acquire(true)).await.is_err());
+        let _cheap = scheduler.acquire(false).await;
+        assert!(time::timeout(Duration::from_millis(10), scheduler.acquire(false)).await.is_err());
+    }
+}
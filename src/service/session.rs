@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use dashmap::DashMap;
+use futures::StreamExt;
+use tracing::debug;
+
+use crate::{CommandResponse, KvPair, Value};
+use crate::service::topic_service::StreamingResponse;
+
+// the state of a client connection, shared by all the requests of the connection
+#[derive(Debug, Default)]
+pub struct Session {
+    // the live subscriptions made by the connection, id -> topic
+    subscriptions: DashMap<u32, String>,
+    // set by a valid Auth on any stream of the connection
+    authenticated: AtomicBool,
+}
+
+impl Session {
+    pub fn authenticate(&self) {
+        self.authenticated.store(true, Ordering::Release);
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::Acquire)
+    }
+
+    // the subscriptions as (id, topic), sorted by id
+    pub fn subscriptions(&self) -> Vec<(u32, String)> {
+        let mut subscriptions: Vec<_> = self
+            .subscriptions
+            .iter()
+            .map(|item| (*item.key(), item.value().clone()))
+            .collect();
+        subscriptions.sort();
+        subscriptions
+    }
+
+    // return a pair for every subscription, the key is the topic and the value is the subscription id
+    pub fn subscriptions_response(&self) -> CommandResponse {
+        self.subscriptions()
+            .into_iter()
+            .map(|(id, topic)| KvPair::new(topic, Value::from(id as i64)))
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    pub fn remove(&self, id: u32) {
+        self.subscriptions.remove(&id);
+    }
+
+    // record the subscription until its stream is dropped, the first response of the stream is the subscription id
+    pub fn track(self: &Arc<Self>, topic: String, response: StreamingResponse) -> StreamingResponse {
+        let mut tracked = Tracked { session: Arc::clone(self), topic, id: None };
+        Box::pin(response.inspect(move |data| tracked.observe(data)))
+    }
+}
+
+// a subscription in the session, removed from the session when the subscription's stream is dropped
+struct Tracked {
+    session: Arc<Session>,
+    topic: String,
+    id: Option<u32>,
+}
+
+impl Tracked {
+    fn observe(&mut self, data: &CommandResponse) {
+        if self.id.is_some() {
+            return;
+        }
+        // an error response has no id, the subscription is not made
+        if let Ok(id) = i64::try_from(data) {
+            let id = id as u32;
+            self.id = Some(id);
+            self.session.subscriptions.insert(id, self.topic.clone());
+            debug!("Subscription {} is added to the session", id);
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.session.remove(id);
+        }
+    }
+}
@@ -1,9 +1,15 @@
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
 
 use dashmap::{DashMap, DashSet};
-use tokio::sync::mpsc;
+use dashmap::mapref::entry::Entry;
+use futures::future::BoxFuture;
+use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
 use tokio::sync::mpsc::Receiver;
+use tokio::time;
 use tracing::{debug, info, warn};
 
 use crate::{CommandResponse, Value};
@@ -11,41 +17,324 @@ use crate::{CommandResponse, Value};
 // biggest data can be saved in the topic
 const BROADCAST_CAPACITY: usize = 128;
 
+// if a message published in ack mode is not acked within this duration, resend it
+const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+// a message published in ack mode is sent at most this many times to a subscriber, then it's dead-lettered or dropped
+const DEFAULT_MAX_DELIVERIES: usize = 5;
+
+// a publish waiting in the queue of the ordered mode, the sender is notified after it's delivered
+type OrderedPublish = (String, Arc<CommandResponse>, Option<oneshot::Sender<()>>);
+
 // next subscription id
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
+// next id of the messages published in ack mode
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+
 // get next subscription id
 fn get_next_subscription_id() -> u32 {
     NEXT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
-pub trait Topic: Send + Sync + 'static {
+pub trait Topic: Clone + Send + Sync + 'static {
     // subscribe a topic
     fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
+    // subscribe a topic, the recent messages kept in the history are received before the new ones
+    fn subscribe_with_history(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
+    // subscribe a topic, only receive the messages whose first value equals the filter
+    fn subscribe_with_filter(self, name: String, filter: Value, history: bool) -> mpsc::Receiver<Arc<CommandResponse>>;
+    // subscribe a topic, the subscription is removed automatically when either of the limits is hit
+    fn subscribe_with_limits(
+        self,
+        name: String,
+        filter: Option<Value>,
+        history: bool,
+        limits: SubscriptionLimits,
+    ) -> mpsc::Receiver<Arc<CommandResponse>>;
     // unsubscribe a topic
     fn unsubscribe(self, name: String, id: u32);
-    // publish data to a topic
+    // publish data to a topic, don't wait for the subscribers to receive it
     fn publish(self, name: String, value: Arc<CommandResponse>);
+    // publish data to a topic, the returned future resolves after all subscribers received it
+    fn publish_wait(self, name: String, value: Arc<CommandResponse>) -> BoxFuture<'static, ()>;
+    // publish data to a topic, resend it to a subscriber until the subscriber acks it, return the message id
+    fn publish_with_ack(self, name: String, value: CommandResponse) -> u64;
+    // a subscription received the message, return false if the message is not waiting for its ack
+    fn ack(self, id: u32, message_id: u64) -> bool;
+    // check if a topic has any subscribers
+    fn has_topic(&self, name: &str) -> bool;
+    // how many topics have subscribers
+    fn topic_count(&self) -> usize;
+}
+
+// a topic is created by its first subscriber and destroyed when its last subscriber leaves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicEvent {
+    Created(String),
+    Destroyed(String),
+}
+
+// bounds of a subscription's lifetime, the subscription is removed and its stream is closed when either is hit
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionLimits {
+    // remove the subscription after this duration
+    pub ttl: Option<Duration>,
+    // remove the subscription after it received this many published messages, the history replay doesn't count
+    pub max_messages: Option<usize>,
 }
 
 // data structure for topic publish and subscribe
 #[derive(Default)]
 pub struct Broadcaster {
-    // all topics list
-    topics: DashMap<String, DashSet<u32>>,
+    // all topics list, the subscription ids are protected by the topic's shard lock
+    topics: DashMap<String, HashSet<u32>>,
     // all subscribe list
     subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+    // if set, a subscriber whose channel is full for this many consecutive publishes is evicted
+    slow_consumer_max_pending: Option<usize>,
+    // the subscriptions which only want the messages whose first value equals the filter
+    filters: DashMap<u32, Value>,
+    // how many more messages the subscriptions with max_messages can receive
+    remaining: DashMap<u32, usize>,
+    // how many consecutive publishes found the subscriber's channel full
+    pending: DashMap<u32, usize>,
+    // resend a message published in ack mode if it's not acked within this duration, default is 5s
+    ack_timeout: Option<Duration>,
+    // messages published in ack mode which are not acked yet, (subscription id, message id)
+    pending_acks: DashSet<(u32, u64)>,
+    // how many times a message published in ack mode is sent to a subscriber, default is 5
+    max_deliveries: Option<usize>,
+    // the messages never acked after max_deliveries are published to this topic, they're dropped if it's None
+    dead_letter_topic: Option<String>,
+    // how many recent messages are kept for each topic, 0 means no history
+    history_size: usize,
+    // the recent messages of each topic, the oldest first
+    history: DashMap<String, VecDeque<Arc<CommandResponse>>>,
+    // called when a topic is created or destroyed
+    on_topic_event: Option<Box<dyn Fn(TopicEvent) + Send + Sync>>,
+    // if true, the publishes are delivered one by one by a single task, in the order they're published
+    ordered: bool,
+    // the queue of the ordered mode, the task is started by the first publish
+    ordered_queue: OnceLock<mpsc::UnboundedSender<OrderedPublish>>,
 }
 
-impl Topic for Arc<Broadcaster> {
-    fn subscribe(self, name: String) -> Receiver<Arc<CommandResponse>> {
-        let id = {
-            let entry = self.topics.entry(name).or_default();
-            let id = get_next_subscription_id();
-            entry.value().insert(id);
-            id
+impl Broadcaster {
+    // evict the subscribers whose channel has been full for `max_pending` consecutive publishes
+    // with this policy, publish never waits for a subscriber, data is dropped for a full channel
+    pub fn with_slow_consumer_policy(mut self, max_pending: usize) -> Self {
+        self.slow_consumer_max_pending = Some(max_pending);
+        self
+    }
+
+    // resend a message published in ack mode if the subscriber doesn't ack it within the timeout
+    pub fn with_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    // give up a message published in ack mode after it's sent `deliveries` times without an ack
+    pub fn with_max_deliveries(mut self, deliveries: usize) -> Self {
+        self.max_deliveries = Some(deliveries.max(1));
+        self
+    }
+
+    // publish the messages given up after max deliveries to the topic, e.g. to inspect or replay them later
+    // the message keeps its message_id, the subscribers of the topic don't need to ack it
+    pub fn with_dead_letter_topic(mut self, name: impl Into<String>) -> Self {
+        self.dead_letter_topic = Some(name.into());
+        self
+    }
+
+    // keep the last `size` messages of every topic, so a new subscriber can receive them
+    // the history is kept even if the topic has no subscribers, it takes up to
+    // (number of topics) * size * (message size) memory, so keep the size small
+    pub fn with_history(mut self, size: usize) -> Self {
+        self.history_size = size;
+        self
+    }
+
+    // deliver all messages in the order they're published, even if they're published to different topics
+    // latency tradeoff: all publishes are delivered one after another by a single task, so
+    // - a subscriber with a full channel delays the messages to all topics, unless the slow consumer policy is set
+    // - the queue is unbounded, it grows if the messages are published faster than they're delivered
+    // publish_with_ack is not ordered, its messages are resent independently
+    pub fn with_ordered_publish(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
+
+    // queue a publish of the ordered mode, start the delivering task if it's not started yet
+    fn enqueue(self: &Arc<Self>, name: String, value: Arc<CommandResponse>, done: Option<oneshot::Sender<()>>) {
+        let queue = self.ordered_queue.get_or_init(|| {
+            let (sender, mut receiver) = mpsc::unbounded_channel::<OrderedPublish>();
+            // the task doesn't keep the broadcaster alive, the queue is closed when the broadcaster is dropped
+            let broadcaster = Arc::downgrade(self);
+            tokio::spawn(async move {
+                while let Some((name, value, done)) = receiver.recv().await {
+                    let broadcaster = match broadcaster.upgrade() {
+                        Some(broadcaster) => broadcaster,
+                        None => break,
+                    };
+                    broadcaster.deliver(name, value).await;
+                    if let Some(done) = done {
+                        let _ = done.send(());
+                    }
+                }
+            });
+            sender
+        });
+        if queue.send((name, value, done)).is_err() {
+            warn!("Ordered publish queue is closed");
+        }
+    }
+
+    // send the message to all subscribers of the topic
+    async fn deliver(self: Arc<Self>, name: String, value: Arc<CommandResponse>) {
+        let subscribers = match self.history_size {
+            0 => self.subscribers(&name, &value),
+            size => {
+                // record the message and collect the subscribers under the history lock,
+                // so a new subscriber either gets it from the history or from the channel
+                let mut history = self.history.entry(name.clone()).or_default();
+                if history.len() >= size {
+                    history.pop_front();
+                }
+                history.push_back(value.clone());
+                self.subscribers(&name, &value)
+            }
         };
 
+        for (id, sender) in subscribers {
+            let last = match self.take_quota(id) {
+                Some(last) => last,
+                None => continue,
+            };
+            if let Some(max_pending) = self.slow_consumer_max_pending {
+                send_or_evict(&self, &name, id, &sender, value.clone(), max_pending);
+            } else {
+                // most of the time the channel has room, only wait if it's full
+                let result = match sender.try_send(value.clone()) {
+                    Err(TrySendError::Full(value)) => sender.send(value).await,
+                    Err(TrySendError::Closed(value)) => Err(SendError(value)),
+                    Ok(()) => Ok(()),
+                };
+                if let Err(e) = result {
+                    warn!("Publish to {} failed! Error: {:?}", id, e);
+                }
+            }
+            if last {
+                debug!("Subscription {} received its max messages", id);
+                self.clone().unsubscribe(name.clone(), id);
+            }
+        }
+    }
+
+    // take one message from the subscription's max_messages quota
+    // return None if the quota is used up, or Some(true) if it's the last message the subscription receives
+    fn take_quota(&self, id: u32) -> Option<bool> {
+        match self.remaining.get_mut(&id) {
+            Some(mut remaining) => {
+                if *remaining == 0 {
+                    return None;
+                }
+                *remaining -= 1;
+                Some(*remaining == 0)
+            }
+            None => Some(false),
+        }
+    }
+
+    // set the limits before the subscription can receive any data
+    fn set_max_messages(&self, id: u32, limits: SubscriptionLimits) {
+        if let Some(max_messages) = limits.max_messages {
+            self.remaining.insert(id, max_messages);
+        }
+    }
+
+    // remove the subscription when its ttl expires, the timer doesn't keep the broadcaster alive
+    fn start_ttl_timer(self: &Arc<Self>, name: String, id: u32, limits: SubscriptionLimits) {
+        let ttl = match limits.ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let broadcaster = Arc::downgrade(self);
+        tokio::spawn(async move {
+            time::sleep(ttl).await;
+            if let Some(broadcaster) = broadcaster.upgrade() {
+                if broadcaster.subscriptions.contains_key(&id) {
+                    debug!("Subscription {} expired", id);
+                    broadcaster.unsubscribe(name, id);
+                }
+            }
+        });
+    }
+
+    // get notified when a topic is created or destroyed, e.g. to count the live topics
+    // the callback is called without holding any lock, but it should return quickly
+    pub fn on_topic_event(mut self, f: impl Fn(TopicEvent) + Send + Sync + 'static) -> Self {
+        self.on_topic_event = Some(Box::new(f));
+        self
+    }
+
+    // add the subscription to the topic, create the topic if it doesn't exist
+    // return true if the topic is created, the caller should notify it after releasing its locks
+    fn add_to_topic(&self, name: &str, id: u32) -> bool {
+        match self.topics.entry(name.to_string()) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().insert(id);
+                false
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(HashSet::from([id]));
+                info!("Topic: {:?} is created", name);
+                true
+            }
+        }
+    }
+
+    fn notify_topic_event(&self, event: TopicEvent) {
+        if let Some(f) = &self.on_topic_event {
+            f(event);
+        }
+    }
+
+    // collect the senders of a topic's subscriptions which want the data
+    // don't hold the lock while sending, a full channel may block for a long time
+    fn subscribers(&self, name: &str, data: &CommandResponse) -> Vec<(u32, mpsc::Sender<Arc<CommandResponse>>)> {
+        match self.topics.get(name) {
+            Some(ids) => ids
+                .iter()
+                .filter(|id| self.wants(**id, data))
+                .filter_map(|id| self.subscriptions.get(id).map(|sender| (*id, sender.value().clone())))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // check the subscription's filter, a subscription without filter wants everything
+    fn wants(&self, id: u32, data: &CommandResponse) -> bool {
+        match self.filters.get(&id) {
+            Some(filter) => data.values.first() == Some(filter.value()),
+            None => true,
+        }
+    }
+
+    fn add_subscription(
+        self: &Arc<Self>,
+        name: String,
+        filter: Option<Value>,
+        limits: SubscriptionLimits,
+    ) -> Receiver<Arc<CommandResponse>> {
+        // set the filter before the subscription can receive any data
+        let id = get_next_subscription_id();
+        if let Some(filter) = filter {
+            self.filters.insert(id, filter);
+        }
+        self.set_max_messages(id, limits);
+        if self.add_to_topic(&name, id) {
+            self.notify_topic_event(TopicEvent::Created(name.clone()));
+        }
+
         // generate a mpsc channel
         let (sender, receiver) = mpsc::channel(BROADCAST_CAPACITY);
 
@@ -61,51 +350,243 @@ impl Topic for Arc<Broadcaster> {
         // save sender to the subscription table
         self.subscriptions.insert(id, sender);
         debug!("Subscription {} is added", id);
+        self.start_ttl_timer(name, id, limits);
 
         // return receiver to the context
         receiver
     }
 
+    fn add_subscription_with_history(
+        self: &Arc<Self>,
+        name: String,
+        filter: Option<Value>,
+        limits: SubscriptionLimits,
+    ) -> Receiver<Arc<CommandResponse>> {
+        if self.history_size == 0 {
+            return self.add_subscription(name, filter, limits);
+        }
+
+        // hold the history lock until subscribed, so no message is missed or received twice
+        let history = self.history.entry(name.clone()).or_default();
+
+        // the channel has room for the subscription id and all the history
+        let (sender, receiver) = mpsc::channel(BROADCAST_CAPACITY + history.len());
+        let id = get_next_subscription_id();
+        let v: Value = (id as i64).into();
+        let _ = sender.try_send(Arc::new(v.into()));
+        if let Some(filter) = filter {
+            self.filters.insert(id, filter);
+        }
+        for data in history.iter().filter(|data| self.wants(id, data)) {
+            let _ = sender.try_send(data.clone());
+        }
+        self.set_max_messages(id, limits);
+
+        let created = self.add_to_topic(&name, id);
+        self.subscriptions.insert(id, sender);
+        debug!("Subscription {} is added with {} history messages", id, history.len());
+        drop(history);
+        if created {
+            self.notify_topic_event(TopicEvent::Created(name.clone()));
+        }
+        self.start_ttl_timer(name, id, limits);
+
+        receiver
+    }
+
+    // check if a topic has any subscribers
+    pub fn has_topic(&self, name: &str) -> bool {
+        self.topics.contains_key(name)
+    }
+
+    pub fn topic_count(&self) -> usize {
+        self.topics.len()
+    }
+}
+
+// send data without waiting, evict the subscriber if its channel stays full
+fn send_or_evict(
+    broadcaster: &Arc<Broadcaster>,
+    name: &str,
+    id: u32,
+    sender: &mpsc::Sender<Arc<CommandResponse>>,
+    value: Arc<CommandResponse>,
+    max_pending: usize,
+) {
+    match sender.try_send(value) {
+        Ok(()) => {
+            broadcaster.pending.remove(&id);
+        }
+        Err(TrySendError::Full(_)) => {
+            let pending = {
+                let mut entry = broadcaster.pending.entry(id).or_default();
+                *entry += 1;
+                *entry
+            };
+            if pending >= max_pending {
+                warn!("Subscription {} is too slow ({} pending publishes), evicted from topic {}", id, pending, name);
+                Arc::clone(broadcaster).unsubscribe(name.to_string(), id);
+            }
+        }
+        Err(TrySendError::Closed(_)) => {
+            warn!("Publish to {} failed! Channel closed", id);
+        }
+    }
+}
+
+// send the message to the subscriber, and resend it until it's acked, the subscription is removed, or it's sent
+// max_deliveries times, then it's published to the dead letter topic if there is one.
+// if it's the last message of the subscription's max_messages, the subscription is removed after the first send
+async fn deliver_until_acked(
+    broadcaster: Arc<Broadcaster>,
+    name: String,
+    id: u32,
+    message_id: u64,
+    value: Arc<CommandResponse>,
+    last: bool,
+) {
+    let timeout = broadcaster.ack_timeout.unwrap_or(DEFAULT_ACK_TIMEOUT);
+    let max_deliveries = broadcaster.max_deliveries.unwrap_or(DEFAULT_MAX_DELIVERIES);
+    let mut deliveries = 0;
+    loop {
+        let sender = match broadcaster.subscriptions.get(&id) {
+            Some(sender) => sender.value().clone(),
+            None => break,
+        };
+        if let Err(e) = sender.send(value.clone()).await {
+            warn!("Publish to {} failed! Error: {:?}", id, e);
+            break;
+        }
+        if last {
+            debug!("Subscription {} received its max messages", id);
+            broadcaster.unsubscribe(name, id);
+            return;
+        }
+
+        deliveries += 1;
+
+        time::sleep(timeout).await;
+        if !broadcaster.pending_acks.contains(&(id, message_id)) {
+            return;
+        }
+        if deliveries >= max_deliveries {
+            warn!("Message {} is not acked by subscription {} after {} deliveries", message_id, id, deliveries);
+            broadcaster.pending_acks.remove(&(id, message_id));
+            if let Some(topic) = broadcaster.dead_letter_topic.clone() {
+                Arc::clone(&broadcaster).publish(topic, value);
+            }
+            return;
+        }
+        debug!("Message {} is not acked by subscription {}, resend it", message_id, id);
+    }
+    broadcaster.pending_acks.remove(&(id, message_id));
+}
+
+impl Topic for Arc<Broadcaster> {
+    fn subscribe(self, name: String) -> Receiver<Arc<CommandResponse>> {
+        self.add_subscription(name, None, SubscriptionLimits::default())
+    }
+
+    fn subscribe_with_history(self, name: String) -> Receiver<Arc<CommandResponse>> {
+        self.add_subscription_with_history(name, None, SubscriptionLimits::default())
+    }
+
+    fn subscribe_with_filter(self, name: String, filter: Value, history: bool) -> Receiver<Arc<CommandResponse>> {
+        self.subscribe_with_limits(name, Some(filter), history, SubscriptionLimits::default())
+    }
+
+    fn subscribe_with_limits(
+        self,
+        name: String,
+        filter: Option<Value>,
+        history: bool,
+        limits: SubscriptionLimits,
+    ) -> Receiver<Arc<CommandResponse>> {
+        match history {
+            true => self.add_subscription_with_history(name, filter, limits),
+            false => self.add_subscription(name, filter, limits),
+        }
+    }
+
     fn unsubscribe(self, name: String, id: u32) {
-        if let Some(v) = self.topics.get_mut(&name) {
+        if let Some(mut v) = self.topics.get_mut(&name) {
             v.remove(&id);
+            drop(v);
 
-            // if topic is empty, delete the topic too
-            if v.is_empty() {
+            // if topic is empty, delete the topic too, unless a new subscriber joined in between
+            if self.topics.remove_if(&name, |_, ids| ids.is_empty()).is_some() {
                 info!("Topic: {:?} is deleted", &name);
-                drop(v);
-                self.topics.remove(&name);
+                self.notify_topic_event(TopicEvent::Destroyed(name));
             }
         }
 
         debug!("Subscription {} is removed!", id);
 
         self.subscriptions.remove(&id);
+        self.filters.remove(&id);
+        self.remaining.remove(&id);
+        self.pending.remove(&id);
+        self.pending_acks.retain(|(subscription, _)| *subscription != id);
     }
 
     fn publish(self, name: String, value: Arc<CommandResponse>) {
-        tokio::spawn(async move {
-            match self.topics.get(&name) {
-                None => {}
-                Some(v) => {
-                    // copy all subscription ids under a topic
-                    let ids = v.value().clone();
-
-                    for id in ids.into_iter() {
-                        if let Some(sender) = self.subscriptions.get(&id) {
-                            if let Err(e) = sender.send(value.clone()).await {
-                                warn!("Publish to {} failed! Error: {:?}", id, e);
-                            }
-                        }
-                    }
-                }
+        match self.ordered {
+            true => self.enqueue(name, value, None),
+            false => {
+                tokio::spawn(self.deliver(name, value));
             }
-        });
+        }
+    }
+
+    fn publish_wait(self, name: String, value: Arc<CommandResponse>) -> BoxFuture<'static, ()> {
+        if !self.ordered {
+            return Box::pin(self.deliver(name, value));
+        }
+
+        // wait in the queue, so it's not delivered before the messages published earlier
+        let (done, delivered) = oneshot::channel();
+        self.enqueue(name, value, Some(done));
+        Box::pin(async move {
+            let _ = delivered.await;
+        })
+    }
+
+    fn publish_with_ack(self, name: String, mut value: CommandResponse) -> u64 {
+        let message_id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+        value.message_id = message_id;
+        let value = Arc::new(value);
+
+        for (id, _) in self.subscribers(&name, &value) {
+            let last = match self.take_quota(id) {
+                Some(last) => last,
+                None => continue,
+            };
+            self.pending_acks.insert((id, message_id));
+            tokio::spawn(deliver_until_acked(self.clone(), name.clone(), id, message_id, value.clone(), last));
+        }
+        message_id
+    }
+
+    fn ack(self, id: u32, message_id: u64) -> bool {
+        self.pending_acks.remove(&(id, message_id)).is_some()
+    }
+
+    fn has_topic(&self, name: &str) -> bool {
+        Broadcaster::has_topic(self, name)
+    }
+
+    fn topic_count(&self) -> usize {
+        Broadcaster::topic_count(self)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use tokio::time;
+
     use crate::assert_response_ok;
 
     use super::*;
@@ -133,7 +614,7 @@ mod tests {
         let res2 = stream2.recv().await.unwrap();
 
         assert_eq!(res1, res2);
-        assert_response_ok(&res1, &[v.clone()], &[]);
+        assert_response_ok(&res1, std::slice::from_ref(&v), &[]);
 
         // if unsubscribe, shouldn't receive new data
         b.clone().unsubscribe(lobby.clone(), id1 as _);
@@ -143,6 +624,263 @@ mod tests {
 
         assert!(stream1.recv().await.is_none());
         let res2 = stream2.recv().await.unwrap();
-        assert_response_ok(&res2, &[v.clone()], &[]);
+        assert_response_ok(&res2, std::slice::from_ref(&v), &[]);
+    }
+
+    #[tokio::test]
+    async fn subscribe_with_history_should_replay_recent_messages() {
+        let b = Arc::new(Broadcaster::default().with_history(2));
+        let lobby = "lobby".to_string();
+
+        for i in 1..=3 {
+            let v: Value = (i as i64).into();
+            b.clone().publish_wait(lobby.clone(), Arc::new(v.into())).await;
+        }
+
+        // only the last 2 messages are kept, then the new ones follow
+        let mut stream = b.clone().subscribe_with_history(lobby.clone());
+        let _id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+        b.clone().publish_wait(lobby.clone(), Arc::new(Value::from(4).into())).await;
+        for i in 2..=4 {
+            let res = stream.recv().await.unwrap();
+            assert_response_ok(&res, &[i.into()], &[]);
+        }
+
+        // a normal subscription doesn't get the history
+        let mut stream = b.clone().subscribe(lobby.clone());
+        let _id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+        assert!(time::timeout(Duration::from_millis(10), stream.recv()).await.is_err());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn subscribe_with_filter_should_only_receive_matched_messages() {
+        let b = Arc::new(Broadcaster::default().with_history(4));
+        let lobby = "lobby".to_string();
+
+        let publish = |event: &str, n: i64| {
+            let data: CommandResponse = vec![Value::from(event), n.into()].into();
+            b.clone().publish_wait(lobby.clone(), Arc::new(data))
+        };
+        publish("set", 1).await;
+        publish("del", 2).await;
+
+        let mut stream = b.clone().subscribe_with_filter(lobby.clone(), "set".into(), true);
+        let mut all = b.clone().subscribe(lobby.clone());
+        let _id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+        let _id: i64 = all.recv().await.unwrap().as_ref().try_into().unwrap();
+        publish("del", 3).await;
+        publish("set", 4).await;
+
+        // the history is filtered too
+        let res = stream.recv().await.unwrap();
+        assert_response_ok(&res, &["set".into(), 1.into()], &[]);
+        let res = stream.recv().await.unwrap();
+        assert_response_ok(&res, &["set".into(), 4.into()], &[]);
+        assert!(time::timeout(Duration::from_millis(10), stream.recv()).await.is_err());
+
+        // the other subscribers are not affected
+        let res = all.recv().await.unwrap();
+        assert_response_ok(&res, &["del".into(), 3.into()], &[]);
+        let res = all.recv().await.unwrap();
+        assert_response_ok(&res, &["set".into(), 4.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn topic_events_should_be_notified() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let events1 = events.clone();
+        let b = Arc::new(Broadcaster::default().on_topic_event(move |e| events1.lock().unwrap().push(e)));
+        let lobby = "lobby".to_string();
+
+        let mut stream1 = b.clone().subscribe(lobby.clone());
+        let mut stream2 = b.clone().subscribe(lobby.clone());
+        let id1: i64 = stream1.recv().await.unwrap().as_ref().try_into().unwrap();
+        let id2: i64 = stream2.recv().await.unwrap().as_ref().try_into().unwrap();
+        assert_eq!(*events.lock().unwrap(), vec![TopicEvent::Created(lobby.clone())]);
+
+        // the topic is destroyed only when the last subscriber leaves
+        b.clone().unsubscribe(lobby.clone(), id1 as _);
+        assert_eq!(events.lock().unwrap().len(), 1);
+        b.clone().unsubscribe(lobby.clone(), id2 as _);
+        let expected = vec![TopicEvent::Created(lobby.clone()), TopicEvent::Destroyed(lobby.clone())];
+        assert_eq!(*events.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn ordered_publish_should_keep_order_across_topics() {
+        let b = Arc::new(Broadcaster::default().with_ordered_publish());
+        let (topic_a, topic_b) = ("a".to_string(), "b".to_string());
+
+        let mut stream_a = b.clone().subscribe(topic_a.clone());
+        let mut stream_b = b.clone().subscribe(topic_b.clone());
+        let _id: i64 = stream_a.recv().await.unwrap().as_ref().try_into().unwrap();
+        let _id: i64 = stream_b.recv().await.unwrap().as_ref().try_into().unwrap();
+
+        for i in 0..100 {
+            b.clone().publish(topic_a.clone(), Arc::new(Value::from(i).into()));
+            b.clone().publish(topic_b.clone(), Arc::new(Value::from(i).into()));
+        }
+
+        // when a message of b arrives, the message published to a before it must be there already
+        for i in 0..100 {
+            let res = stream_b.recv().await.unwrap();
+            assert_response_ok(&res, &[i.into()], &[]);
+            let res = stream_a.try_recv().unwrap();
+            assert_response_ok(&res, &[i.into()], &[]);
+        }
+
+        // publish_wait is delivered after the earlier publishes
+        b.clone().publish(topic_a.clone(), Arc::new(Value::from(100).into()));
+        b.clone().publish_wait(topic_b.clone(), Arc::new(Value::from(100).into())).await;
+        assert_response_ok(&stream_a.try_recv().unwrap(), &[100.into()], &[]);
+        assert_response_ok(&stream_b.try_recv().unwrap(), &[100.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn publish_wait_should_apply_backpressure() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby = "lobby".to_string();
+
+        let mut stream = b.clone().subscribe(lobby.clone());
+        let _id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+
+        // fill the subscriber's channel
+        for i in 0..BROADCAST_CAPACITY {
+            let v: Value = (i as i64).into();
+            b.clone().publish_wait(lobby.clone(), Arc::new(v.into())).await;
+        }
+
+        // the channel is full, so the publisher should wait
+        let v: Value = "hello".into();
+        let mut fut = b.clone().publish_wait(lobby.clone(), Arc::new(v.into()));
+        assert!(time::timeout(Duration::from_millis(10), &mut fut).await.is_err());
+
+        // once the subscriber reads, the publisher can continue
+        let res = stream.recv().await.unwrap();
+        assert_response_ok(&res, &[0.into()], &[]);
+        fut.await;
+    }
+
+    #[tokio::test]
+    async fn unacked_message_should_be_resent() {
+        let b = Arc::new(Broadcaster::default().with_ack_timeout(Duration::from_millis(10)));
+        let lobby = "lobby".to_string();
+
+        let mut stream = b.clone().subscribe(lobby.clone());
+        let id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+
+        let v: Value = "hello".into();
+        let message_id = b.clone().publish_with_ack(lobby.clone(), v.clone().into());
+        let res = stream.recv().await.unwrap();
+        assert_eq!(res.message_id, message_id);
+        assert_eq!(res.values, std::slice::from_ref(&v));
+
+        // not acked in time, it's sent again
+        let res2 = stream.recv().await.unwrap();
+        assert_eq!(res, res2);
+
+        assert!(b.clone().ack(id as _, message_id));
+        assert!(!b.clone().ack(id as _, message_id));
+        assert!(time::timeout(Duration::from_millis(50), stream.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unacked_message_should_be_dead_lettered_after_max_deliveries() {
+        let b = Broadcaster::default()
+            .with_ack_timeout(Duration::from_millis(10))
+            .with_max_deliveries(2)
+            .with_dead_letter_topic("dead");
+        let b = Arc::new(b);
+        let lobby = "lobby".to_string();
+
+        let mut stream = b.clone().subscribe(lobby.clone());
+        let id: i64 = stream.recv().await.unwrap().as_ref().try_into().unwrap();
+        let mut dead = b.clone().subscribe("dead".into());
+        dead.recv().await.unwrap();
+
+        let message_id = b.clone().publish_with_ack(lobby.clone(), Value::from("hello").into());
+        for _ in 0..2 {
+            assert_eq!(stream.recv().await.unwrap().message_id, message_id);
+        }
+
+        // given up after 2 deliveries, the message goes to the dead letter topic
+        let res = time::timeout(Duration::from_secs(1), dead.recv()).await.unwrap().unwrap();
+        assert_eq!(res.message_id, message_id);
+        assert_eq!(res.values, vec![Value::from("hello")]);
+        assert!(!b.clone().ack(id as _, message_id));
+        assert!(time::timeout(Duration::from_millis(50), stream.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_be_evicted() {
+        let b = Arc::new(Broadcaster::default().with_slow_consumer_policy(2));
+        let lobby = "lobby".to_string();
+
+        let mut stream = b.clone().subscribe(lobby.clone());
+        // let the subscription id be sent
+        tokio::task::yield_now().await;
+
+        // fill the subscriber's channel, the subscription id took one slot
+        for i in 1..BROADCAST_CAPACITY {
+            let v: Value = (i as i64).into();
+            b.clone().publish_wait(lobby.clone(), Arc::new(v.into())).await;
+        }
+        assert!(b.has_topic(&lobby));
+
+        // publishing to a full channel should not block, and evicts after 2 attempts
+        let v: Value = "hello".into();
+        b.clone().publish_wait(lobby.clone(), Arc::new(v.clone().into())).await;
+        assert!(b.has_topic(&lobby));
+        b.clone().publish_wait(lobby.clone(), Arc::new(v.into())).await;
+        assert!(!b.has_topic(&lobby));
+        assert!(b.subscriptions.is_empty());
+        assert!(b.pending.is_empty());
+
+        // the subscriber can still drain the buffered data, then the stream ends
+        for _ in 0..BROADCAST_CAPACITY {
+            assert!(stream.recv().await.is_some());
+        }
+        assert!(stream.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn subscription_should_be_removed_after_max_messages() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby = "lobby".to_string();
+
+        let limits = SubscriptionLimits { max_messages: Some(2), ..Default::default() };
+        let mut stream = b.clone().subscribe_with_limits(lobby.clone(), None, false, limits);
+        // let the subscription id be sent
+        tokio::task::yield_now().await;
+        for i in 0..3 {
+            let v: Value = (i as i64).into();
+            b.clone().publish_wait(lobby.clone(), Arc::new(v.into())).await;
+        }
+        assert!(!b.has_topic(&lobby));
+        assert!(b.remaining.is_empty());
+
+        // the subscription id and the first 2 messages are received, then the stream ends
+        let _id = stream.recv().await.unwrap();
+        for i in 0..2 {
+            let v: Value = (i as i64).into();
+            assert_response_ok(&stream.recv().await.unwrap(), &[v], &[]);
+        }
+        assert!(stream.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn subscription_should_be_removed_after_ttl() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby = "lobby".to_string();
+
+        let limits = SubscriptionLimits { ttl: Some(Duration::from_millis(20)), ..Default::default() };
+        let mut stream = b.clone().subscribe_with_limits(lobby.clone(), None, false, limits);
+        assert!(stream.recv().await.is_some());
+        assert!(b.has_topic(&lobby));
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!b.has_topic(&lobby));
+        assert!(b.subscriptions.is_empty());
+        assert!(stream.recv().await.is_none());
+    }
+}
@@ -1,21 +1,38 @@
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::{Stream, stream};
 use tokio_stream::wrappers::ReceiverStream;
 
-use crate::{CommandResponse, Publish, Subscribe, Unsubscribe};
-use crate::service::topic::Topic;
+use crate::{Ack, CommandResponse, Publish, PublishAndSubscribe, Subscribe, Unsubscribe, Value, Watch};
+use crate::service::topic::{SubscriptionLimits, Topic};
 
 pub type StreamingResponse = Pin<Box<dyn Stream<Item=Arc<CommandResponse>> + Send>>;
 
+// prefix of the synthetic topics used by keyspace notifications
+pub const KEYSPACE_PREFIX: &str = "__keyspace__";
+
+// get the synthetic topic name which the changes of a key are published to
+pub fn keyspace_topic(table: &str, key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) => format!("{}:{}:{}", KEYSPACE_PREFIX, table, key),
+        // topic names are strings, escape the binary keys, e.g. `\xff`
+        Err(_) => format!("{}:{}:{}", KEYSPACE_PREFIX, table, key.escape_ascii()),
+    }
+}
+
 pub trait TopicService {
     fn execute(self, topic: impl Topic) -> StreamingResponse;
 }
 
 impl TopicService for Subscribe {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        let receiver = topic.subscribe(self.topic);
+        let limits = SubscriptionLimits {
+            ttl: (self.ttl_ms > 0).then(|| Duration::from_millis(self.ttl_ms)),
+            max_messages: (self.max_messages > 0).then_some(self.max_messages as usize),
+        };
+        let receiver = topic.subscribe_with_limits(self.topic, self.filter, self.history, limits);
         Box::pin(ReceiverStream::new(receiver))
     }
 }
@@ -29,7 +46,45 @@ impl TopicService for Unsubscribe {
 
 impl TopicService for Publish {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        topic.publish(self.topic, Arc::new(self.data.into()));
+        if self.ack {
+            let message_id = topic.publish_with_ack(self.topic, self.data.into());
+            let response = Value::from(message_id as i64).into();
+            return Box::pin(stream::once(async { Arc::new(response) }));
+        }
+
+        let value = Arc::new(self.data.into());
+        if self.wait {
+            let fut = topic.publish_wait(self.topic, value);
+            return Box::pin(stream::once(async move {
+                fut.await;
+                Arc::new(CommandResponse::ok())
+            }));
+        }
+
+        topic.publish(self.topic, value);
         Box::pin(stream::once(async { Arc::new(CommandResponse::ok()) }))
     }
-}
\ No newline at end of file
+}
+
+impl TopicService for Ack {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let acked = topic.ack(self.id, self.message_id);
+        Box::pin(stream::once(async move { Arc::new(Value::from(acked).into()) }))
+    }
+}
+
+impl TopicService for Watch {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        let receiver = topic.subscribe(keyspace_topic(&self.table, &self.key));
+        Box::pin(ReceiverStream::new(receiver))
+    }
+}
+
+impl TopicService for PublishAndSubscribe {
+    fn execute(self, topic: impl Topic) -> StreamingResponse {
+        // subscribe first, so a reply can't arrive before the subscription exists
+        let receiver = topic.clone().subscribe(self.reply_topic);
+        topic.publish(self.publish_topic, Arc::new(self.data.into()));
+        Box::pin(ReceiverStream::new(receiver))
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use dashmap::DashMap;
+use dashmap::mapref::one::RefMut;
+use prost::Message;
+
+use crate::{KvPair, Storage, StorageIter, TableStats, Value};
+use crate::storage::{add_float, expired, key_not_found, lpush_values, sadd_members, srem_members, table_not_found};
+use crate::error::KvError;
+
+// in-memory storage which keeps the keys of a table sorted, so range queries don't need to sort
+#[derive(Debug, Default, Clone)]
+pub struct BTreeMemTable {
+    tables: DashMap<String, BTreeMap<Vec<u8>, Value>>,
+}
+
+impl BTreeMemTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get_or_create_table(&self, table_name: &str) -> RefMut<'_, String, BTreeMap<Vec<u8>, Value>> {
+        self.tables.entry(table_name.to_string()).or_default()
+    }
+}
+
+impl Storage for BTreeMemTable {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        Ok(self.tables.get(table).and_then(|t| t.get(key).cloned()))
+    }
+
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        let mut table = self.get_or_create_table(table);
+        Ok(table.insert(key, value))
+    }
+
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let mut table = self.get_or_create_table(table);
+        if table.contains_key(&key) {
+            return Ok(false);
+        }
+        table.insert(key, value);
+        Ok(true)
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        // the table is locked while we hold it, so f runs once for a missing key
+        let mut table = self.get_or_create_table(table);
+        Ok(table.entry(key).or_insert_with(f).clone())
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let mut table = self.get_or_create_table(table);
+        if table.get(&key) == Some(&value) {
+            return Ok(false);
+        }
+        table.insert(key, value);
+        Ok(true)
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        let mut table = self.get_or_create_table(table);
+        if !table.is_empty() {
+            return Ok(false);
+        }
+        table.extend(pairs.into_iter().map(|pair| (pair.key.to_vec(), pair.value.unwrap_or_default())));
+        Ok(true)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        // the table is locked while we hold it, so the read-modify-write is atomic
+        let mut table = self.get_or_create_table(table);
+        let list = lpush_values(table.get(&key).cloned(), values)?;
+        let len = list.len();
+        table.insert(key, list.into());
+        Ok(len)
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        // the table is locked while we hold it, so the read-modify-write is atomic
+        let mut table = self.get_or_create_table(table);
+        let (set, added) = sadd_members(table.get(&key).cloned(), members)?;
+        table.insert(key, set.into());
+        Ok(added)
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        let mut table = self.get_or_create_table(table);
+        let new = add_float(table.get(&key).cloned(), delta)?;
+        table.insert(key, new.into());
+        Ok(new)
+    }
+
+    fn get_and_reset(&self, table_name: &str, key: &[u8]) -> Result<i64, KvError> {
+        let mut table = self.get_or_create_table(table_name);
+        let value = table.get_mut(key).ok_or_else(|| key_not_found(table_name, key))?;
+        let old = i64::try_from(&*value)?;
+        *value = 0.into();
+        Ok(old)
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let mut table = self.get_or_create_table(table);
+        match srem_members(table.get(&key).cloned(), &members)? {
+            Some((set, removed)) => {
+                table.insert(key, set.into());
+                Ok(removed)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        Ok(self.tables.get(table).map(|t| t.contains_key(key)).unwrap_or(false))
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        Ok(self.tables.get_mut(table).and_then(|mut t| t.remove(key)))
+    }
+
+    fn swap(&self, table_name: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        // the table is locked while we hold it, so the swap is atomic
+        let mut table = self.get_or_create_table(table_name);
+        let v1 = table.get(key1).cloned().ok_or_else(|| key_not_found(table_name, key1))?;
+        let v2 = table.get(key2).cloned().ok_or_else(|| key_not_found(table_name, key2))?;
+        table.insert(key1.to_vec(), v2.clone());
+        table.insert(key2.to_vec(), v1.clone());
+        Ok((v1, v2))
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        if from == to {
+            return self.get(from, key);
+        }
+        drop(self.get_or_create_table(to));
+        loop {
+            // iter_mut() locks the shards in order, so both tables can be locked even if they're in the same shard
+            let (mut src, mut dst) = (None, None);
+            for table in self.tables.iter_mut() {
+                if table.key() == from {
+                    src = Some(table);
+                } else if table.key() == to {
+                    dst = Some(table);
+                }
+                if src.is_some() && dst.is_some() {
+                    break;
+                }
+            }
+            let mut src = match src {
+                Some(t) => t,
+                None => return Ok(None),
+            };
+            // the target table was removed after it's created, try again
+            let Some(mut dst) = dst else {
+                drop(src);
+                drop(self.get_or_create_table(to));
+                continue;
+            };
+            let moved = src.remove(key);
+            if let Some(value) = &moved {
+                dst.insert(key.to_vec(), value.clone());
+            }
+            return Ok(moved);
+        }
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        let mut table = match self.tables.get_mut(table) {
+            Some(t) => t,
+            None => return Ok(0),
+        };
+        // the keys with the prefix are next to each other, starting from the prefix itself
+        let keys: Vec<_> = table
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in keys.iter() {
+            table.remove(key);
+        }
+        Ok(keys.len() as u64)
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        let mut table = match self.tables.get_mut(table) {
+            Some(t) => t,
+            None => return Ok(0),
+        };
+        let len = table.len();
+        table.retain(|_, value| !expired(value, cutoff));
+        Ok((len - table.len()) as u64)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        let pairs = match self.tables.get(table) {
+            Some(t) => t.iter().map(|(k, v)| KvPair::new(k.clone(), v.clone())).collect(),
+            None => vec![],
+        };
+        Ok(pairs)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
+        // use clone() to get a snapshot of the table
+        let table = self.tables.get(table).map(|t| t.clone()).unwrap_or_default();
+        let iter = StorageIter::new(table.into_iter());
+        Ok(Box::new(iter))
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        // collect a snapshot of all tables, don't hold the locks while the caller iterates
+        let items: Vec<_> = self
+            .tables
+            .iter()
+            .flat_map(|table| {
+                let name = table.key().clone();
+                table
+                    .iter()
+                    .map(|(k, v)| (name.clone(), KvPair::new(k.clone(), v.clone())))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Ok(Box::new(items.into_iter()))
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        // BTreeMap::range panics if start > end
+        if !end.is_empty() && start >= end {
+            return Ok(vec![]);
+        }
+        let upper = match end {
+            [] => Bound::Unbounded,
+            end => Bound::Excluded(end),
+        };
+        let pairs = match self.tables.get(table) {
+            Some(t) => t
+                .range::<[u8], _>((Bound::Included(start), upper))
+                .map(|(k, v)| KvPair::new(k.clone(), v.clone()))
+                .collect(),
+            None => vec![],
+        };
+        Ok(pairs)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        if from == to {
+            return match self.tables.get(from) {
+                Some(table) if !table.is_empty() => Ok(()),
+                _ => Err(table_not_found(from)),
+            };
+        }
+        // the `to` table is replaced in one insert, readers see either the old or the new data
+        let table = match self.tables.remove_if(from, |_, table| !table.is_empty()) {
+            Some((_, table)) => table,
+            None => return Err(table_not_found(from)),
+        };
+        self.tables.insert(to.to_string(), table);
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        let keys = self.tables.iter().map(|table| table.len() as u64).sum();
+        self.tables.clear();
+        Ok(keys)
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        let mut stats = TableStats::default();
+        if let Some(t) = self.tables.get(table) {
+            stats.keys = t.len() as u64;
+            stats.bytes = t.iter().map(|(k, v)| (k.len() + v.encoded_len()) as u64).sum();
+        }
+        Ok(stats)
+    }
+}
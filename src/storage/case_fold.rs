@@ -0,0 +1,171 @@
+use std::borrow::Cow;
+use std::time::Duration;
+
+use crate::{KvError, KvPair, Storage, TableStats, Value};
+
+// make the table names and keys case-insensitive, e.g. for keys typed by the users
+//
+// every table name and key is lowercased before it's passed to the inner store, so `Alice` and `alice` are the same key.
+// the keys are saved lowercased: get_all, get_iter, iter_all etc. return the normalized keys, not the ones written.
+// a UTF-8 key is lowercased as a string, a binary key only has its ASCII letters lowercased.
+// the prefixes, range bounds and glob patterns are lowercased too, the values are kept as they are
+pub struct CaseFoldingStore<S> {
+    inner: S,
+}
+
+impl<S: Storage> CaseFoldingStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+// the normalized table name or key, borrowed if it has nothing to lowercase
+fn fold_case(key: &[u8]) -> Cow<'_, [u8]> {
+    match std::str::from_utf8(key) {
+        Ok(s) if s.chars().any(char::is_uppercase) => Cow::Owned(s.to_lowercase().into_bytes()),
+        Ok(_) => Cow::Borrowed(key),
+        Err(_) if key.iter().any(u8::is_ascii_uppercase) => Cow::Owned(key.to_ascii_lowercase()),
+        Err(_) => Cow::Borrowed(key),
+    }
+}
+
+fn fold_table(table: &str) -> Cow<'_, str> {
+    match table.chars().any(char::is_uppercase) {
+        true => Cow::Owned(table.to_lowercase()),
+        false => Cow::Borrowed(table),
+    }
+}
+
+fn fold_key(key: Vec<u8>) -> Vec<u8> {
+    match fold_case(&key) {
+        Cow::Borrowed(_) => key,
+        Cow::Owned(folded) => folded,
+    }
+}
+
+impl<S: Storage> Storage for CaseFoldingStore<S> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.inner.get(&fold_table(table), &fold_case(key))
+    }
+
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        self.inner.set(&fold_table(table), fold_key(key), value)
+    }
+
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.inner.set_if_absent(&fold_table(table), fold_key(key), value)
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.inner.set_if_changed(&fold_table(table), fold_key(key), value)
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        self.inner.get_or_set_with(&fold_table(table), fold_key(key), f)
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        let pairs = pairs
+            .into_iter()
+            .map(|pair| KvPair { key: fold_key(pair.key.to_vec()).into(), value: pair.value })
+            .collect();
+        self.inner.init_if_empty(&fold_table(table), pairs)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        self.inner.lpush(&fold_table(table), fold_key(key), values)
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        self.inner.sadd(&fold_table(table), fold_key(key), members)
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        self.inner.srem(&fold_table(table), fold_key(key), members)
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        self.inner.incr_float(&fold_table(table), fold_key(key), delta)
+    }
+
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+        self.inner.get_and_reset(&fold_table(table), &fold_case(key))
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.inner.contains(&fold_table(table), &fold_case(key))
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.inner.del(&fold_table(table), &fold_case(key))
+    }
+
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        self.inner.swap(&fold_table(table), &fold_case(key1), &fold_case(key2))
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.inner.move_key(&fold_table(from), &fold_table(to), &fold_case(key))
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        self.inner.del_by_prefix(&fold_table(table), &fold_case(prefix))
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        self.inner.expire_before(&fold_table(table), cutoff)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_all(&fold_table(table))
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
+        self.inner.get_iter(&fold_table(table))
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        self.inner.iter_all()
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_range(&fold_table(table), &fold_case(start), &fold_case(end))
+    }
+
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_matched(&fold_table(table), &fold_table(pattern))
+    }
+
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        self.inner.value_size(&fold_table(table), &fold_case(key))
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        self.inner.rename_table(&fold_table(from), &fold_table(to))
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        self.inner.clear()
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        self.inner.table_stats(&fold_table(table))
+    }
+
+    // the field names are the keys of the map values, they're not folded
+    fn create_index(&self, table: &str, field: &str) -> Result<(), KvError> {
+        self.inner.create_index(&fold_table(table), field)
+    }
+
+    fn query_index(&self, table: &str, field: &str, value: &Value) -> Result<Vec<KvPair>, KvError> {
+        self.inner.query_index(&fold_table(table), field, value)
+    }
+
+    fn touch(&self, table: &str, key: &[u8], ttl: Duration) -> Result<bool, KvError> {
+        self.inner.touch(&fold_table(table), &fold_case(key), ttl)
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sled::{Batch, IVec};
+use tracing::debug;
+
+use crate::{KvError, KvPair, SledDb, Storage, TableStats, Value};
+
+// a set (Some) or a del (None) waiting to be written in a batch
+struct WriteOp {
+    key: Vec<u8>,
+    data: Option<Vec<u8>>,
+    reply: SyncSender<Result<Option<IVec>, KvError>>,
+}
+
+// coalesce the concurrent set/del into one sled batch, and flush it to disk once
+// the batch is written every `interval` or when it has `max_batch` writes, whichever comes first
+//
+// durability: set/del block until their batch is written and flushed, so when they return the data is on disk.
+// a write isn't durable before that, if the process crashes, the whole pending batch is lost.
+// the calling thread is blocked for up to `interval`, writes from different threads are coalesced.
+// set_if_absent, get_or_set_with, set_if_changed, init_if_empty, lpush, incr_float, get_and_reset, sadd, srem, swap, move_key, del_by_prefix, expire_before, rename_table and clear are not coalesced, they're applied to the db immediately.
+pub struct WriteCoalescer {
+    store: Arc<SledDb>,
+    sender: Sender<WriteOp>,
+    batches: Arc<AtomicU64>,
+}
+
+impl WriteCoalescer {
+    pub fn new(store: SledDb, interval: Duration, max_batch: usize) -> Self {
+        let store = Arc::new(store);
+        let batches = Arc::new(AtomicU64::new(0));
+        let (sender, receiver) = mpsc::channel();
+
+        let db = Arc::clone(&store);
+        let counter = Arc::clone(&batches);
+        // the thread exits after the coalescer is dropped and all pending writes are done
+        thread::spawn(move || {
+            while let Some(ops) = next_batch(&receiver, interval, max_batch) {
+                write_batch(&db, ops);
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        Self { store, sender, batches }
+    }
+
+    // how many batches have been written
+    pub fn batches(&self) -> u64 {
+        self.batches.load(Ordering::Relaxed)
+    }
+
+    // send the write to the batch thread, wait until the batch is written
+    fn write(&self, table: &str, key: &[u8], value: Option<Value>) -> Result<Option<Value>, KvError> {
+        let key = SledDb::get_full_key(table, key);
+        let data = value.map(|v| self.store.encode_value(&key, v)).transpose()?;
+        let (reply, result) = mpsc::sync_channel(1);
+        let op = WriteOp { key: key.clone(), data, reply };
+        self.sender
+            .send(op)
+            .map_err(|_| KvError::Internal("write coalescer is stopped".into()))?;
+
+        let old = result
+            .recv()
+            .map_err(|_| KvError::Internal("write coalescer is stopped".into()))??;
+        old.map(|v| self.store.decode_value(&key, v.as_ref())).transpose()
+    }
+}
+
+// wait for the first write, then collect more writes until the batch is full or the interval passes
+// return None if the coalescer is dropped
+fn next_batch(receiver: &Receiver<WriteOp>, interval: Duration, max_batch: usize) -> Option<Vec<WriteOp>> {
+    let first = receiver.recv().ok()?;
+    let deadline = Instant::now() + interval;
+    let mut ops = vec![first];
+    while ops.len() < max_batch {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(op) => ops.push(op),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Some(ops)
+}
+
+fn write_batch(store: &SledDb, ops: Vec<WriteOp>) {
+    let db = store.db();
+    let mut batch = Batch::default();
+    // the old value of a key may be changed by an earlier write in the same batch
+    let mut written: HashMap<&[u8], Option<IVec>> = HashMap::new();
+    let mut olds = Vec::with_capacity(ops.len());
+
+    for op in ops.iter() {
+        let old = match written.get(op.key.as_slice()) {
+            Some(v) => Ok(v.clone()),
+            None => db.get(&op.key).map_err(KvError::from),
+        };
+        olds.push(old);
+
+        let data = op.data.as_ref().map(|v| IVec::from(v.as_slice()));
+        match &data {
+            Some(v) => batch.insert(op.key.as_slice(), v.clone()),
+            None => batch.remove(op.key.as_slice()),
+        }
+        written.insert(op.key.as_slice(), data);
+    }
+
+    debug!("Write a batch of {} writes", ops.len());
+    let result = db.apply_batch(batch).and_then(|_| db.flush().map(|_| ()));
+
+    for (op, old) in ops.iter().zip(olds) {
+        let reply = match &result {
+            Ok(()) => old,
+            Err(e) => Err(KvError::Internal(format!("failed to write batch: {}", e))),
+        };
+        // the caller may be gone, nothing to do then
+        let _ = op.reply.send(reply);
+    }
+}
+
+impl Storage for WriteCoalescer {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(table, &key, Some(value))
+    }
+
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.store.set_if_absent(table, key, value)
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        self.store.get_or_set_with(table, key, f)
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.store.set_if_changed(table, key, value)
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        self.store.init_if_empty(table, pairs)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        self.store.lpush(table, key, values)
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        self.store.sadd(table, key, members)
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        self.store.incr_float(table, key, delta)
+    }
+
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+        self.store.get_and_reset(table, key)
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        self.store.srem(table, key, members)
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.write(table, key, None)
+    }
+
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        self.store.swap(table, key1, key2)
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.store.move_key(from, to, key)
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        self.store.del_by_prefix(table, prefix)
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        self.store.expire_before(table, cutoff)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        self.store.iter_all()
+    }
+
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        self.store.get_matched(table, pattern)
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        self.store.get_range(table, start, end)
+    }
+
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        self.store.value_size(table, key)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        self.store.rename_table(from, to)
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        self.store.clear()
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        self.store.table_stats(table)
+    }
+}
@@ -0,0 +1,169 @@
+use bincode::Options;
+use bytes::Bytes;
+use prost::encoding::decode_varint;
+use serde::{Deserialize, Serialize};
+
+use crate::{value, KvError, KvPair, Value, ValueList, ValueMap, ValueSet};
+
+// how SledDb encodes the values on disk, the frames sent to the clients are always protobuf
+//
+// migration: the codecs write different bytes, a db must be opened with the codec it was written with.
+// a value written by another codec fails to decode, get and the scans return an error, get_iter and iter_all skip it.
+// to switch the codec of an existing db, open it with the old codec, copy `iter_all` into a new db opened with the
+// new codec, then replace the old db with the new one
+pub trait ValueCodec: Send + Sync + 'static {
+    fn encode(&self, value: Value) -> Result<Vec<u8>, KvError>;
+
+    fn decode(&self, data: &[u8]) -> Result<Value, KvError>;
+
+    // get Value::size() from the encoded value, override it if it can be done without decoding the whole value
+    fn value_size(&self, data: &[u8]) -> Result<usize, KvError> {
+        self.decode(data).map(|value| value.size())
+    }
+}
+
+// the default codec, the values are saved as the protobuf Value messages
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtobufCodec;
+
+impl ValueCodec for ProtobufCodec {
+    fn encode(&self, value: Value) -> Result<Vec<u8>, KvError> {
+        value.try_into()
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Value, KvError> {
+        data.try_into()
+    }
+
+    // a string or binary value is encoded as | tag | length (varint) | bytes |, the other values use the encoded length
+    fn value_size(&self, data: &[u8]) -> Result<usize, KvError> {
+        const STRING_TAG: u8 = 1 << 3 | 2;
+        const BINARY_TAG: u8 = 2 << 3 | 2;
+        match data.first() {
+            Some(&STRING_TAG) | Some(&BINARY_TAG) => Ok(decode_varint(&mut &data[1..])? as usize),
+            _ => Ok(data.len()),
+        }
+    }
+}
+
+// save the values with bincode, it's faster than protobuf since there're no field tags to parse,
+// and the lists, sets and maps are smaller since their items don't need a length prefix each
+// the integers and lengths are varints, so the scalar values are as small as in protobuf
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl ValueCodec for BincodeCodec {
+    fn encode(&self, value: Value) -> Result<Vec<u8>, KvError> {
+        Ok(bincode::DefaultOptions::new().serialize(&StoredValue::from(value))?)
+    }
+
+    // limit the reads to the data, so a broken length can't allocate more than that
+    fn decode(&self, data: &[u8]) -> Result<Value, KvError> {
+        let options = bincode::DefaultOptions::new().with_limit(data.len() as u64);
+        Ok(options.deserialize::<StoredValue>(data)?.into())
+    }
+}
+
+// the Value generated by prost can't derive serde, so bincode (de)serializes this mirror of it
+#[derive(Debug, Serialize, Deserialize)]
+enum StoredValue {
+    Empty,
+    String(String),
+    Binary(Vec<u8>),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<StoredValue>),
+    Set(Vec<StoredValue>),
+    Map(Vec<(Vec<u8>, StoredValue)>),
+    // added last, so the values saved before keep their variant index
+    Timestamp(i64),
+}
+
+impl From<Value> for StoredValue {
+    fn from(value: Value) -> Self {
+        match value.value {
+            None => StoredValue::Empty,
+            Some(value::Value::String(s)) => StoredValue::String(s),
+            Some(value::Value::Binary(b)) => StoredValue::Binary(b.to_vec()),
+            Some(value::Value::Integer(i)) => StoredValue::Integer(i),
+            Some(value::Value::Float(f)) => StoredValue::Float(f),
+            Some(value::Value::Bool(b)) => StoredValue::Bool(b),
+            Some(value::Value::Timestamp(t)) => StoredValue::Timestamp(t),
+            Some(value::Value::List(list)) => StoredValue::List(list.values.into_iter().map(Into::into).collect()),
+            Some(value::Value::Set(set)) => StoredValue::Set(set.members.into_iter().map(Into::into).collect()),
+            Some(value::Value::Map(map)) => StoredValue::Map(
+                map.pairs
+                    .into_iter()
+                    .map(|pair| (pair.key.to_vec(), pair.value.unwrap_or_default().into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<StoredValue> for Value {
+    fn from(value: StoredValue) -> Self {
+        let value = match value {
+            StoredValue::Empty => None,
+            StoredValue::String(s) => Some(value::Value::String(s)),
+            StoredValue::Binary(b) => Some(value::Value::Binary(Bytes::from(b))),
+            StoredValue::Integer(i) => Some(value::Value::Integer(i)),
+            StoredValue::Float(f) => Some(value::Value::Float(f)),
+            StoredValue::Bool(b) => Some(value::Value::Bool(b)),
+            StoredValue::Timestamp(t) => Some(value::Value::Timestamp(t)),
+            StoredValue::List(values) => Some(value::Value::List(ValueList {
+                values: values.into_iter().map(Into::into).collect(),
+            })),
+            // the members were sorted when the set was saved, keep them as they are
+            StoredValue::Set(members) => Some(value::Value::Set(ValueSet {
+                members: members.into_iter().map(Into::into).collect(),
+            })),
+            StoredValue::Map(pairs) => Some(value::Value::Map(ValueMap {
+                pairs: pairs.into_iter().map(|(key, value)| KvPair::new(key, value.into())).collect(),
+            })),
+        };
+        Value { value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn values() -> Vec<Value> {
+        let map: HashMap<String, Value> = [("name".to_string(), "alice".into()), ("age".to_string(), 30.into())].into();
+        vec![
+            Value::default(),
+            "hello".into(),
+            b"binary".into(),
+            42.into(),
+            (-1.5).into(),
+            true.into(),
+            vec![1.into(), "two".into()].into(),
+            ValueSet { members: vec![1.into(), 2.into()] }.into(),
+            map.into(),
+            Value::timestamp(1_700_000_000_000),
+        ]
+    }
+
+    #[test]
+    fn codecs_should_round_trip_values() {
+        let codecs: [&dyn ValueCodec; 2] = [&ProtobufCodec, &BincodeCodec];
+        for codec in codecs {
+            for value in values() {
+                let data = codec.encode(value.clone()).unwrap();
+                assert_eq!(codec.decode(&data).unwrap(), value);
+                assert_eq!(codec.value_size(&data).unwrap(), value.size());
+            }
+        }
+    }
+
+    #[test]
+    fn bincode_codec_should_reject_broken_data() {
+        let data = BincodeCodec.encode("hello".into()).unwrap();
+        assert!(matches!(BincodeCodec.decode(&data[..data.len() - 1]), Err(KvError::BincodeError(_))));
+    }
+}
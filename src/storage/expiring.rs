@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::{KvError, KvPair, Storage, TableStats, Value};
+
+// expire the keys on top of a storage, see Storage::touch
+//
+// a key touched with a ttl is removed from the inner store on the first access after its deadline, it's never
+// returned after that. a set replaces the value and removes the expiry, the other writes keep it.
+// the expiries are kept in memory, a restarted server forgets them.
+// the expiry isn't atomic with the writes of the inner store, a key may be written while it's being expired
+pub struct ExpiringStore<S> {
+    inner: S,
+    // table -> key -> deadline, the table is locked while its expired keys are removed
+    deadlines: DashMap<String, HashMap<Vec<u8>, Instant>>,
+}
+
+impl<S: Storage> ExpiringStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, deadlines: DashMap::new() }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    // remove the key from the inner store if it's expired
+    fn purge(&self, table: &str, key: &[u8]) -> Result<(), KvError> {
+        if let Some(mut deadlines) = self.deadlines.get_mut(table) {
+            if matches!(deadlines.get(key), Some(deadline) if *deadline <= Instant::now()) {
+                deadlines.remove(key);
+                self.inner.del(table, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // remove the expired keys of the table from the inner store
+    fn purge_table(&self, table: &str) -> Result<(), KvError> {
+        if let Some(mut deadlines) = self.deadlines.get_mut(table) {
+            let now = Instant::now();
+            let expired: Vec<Vec<u8>> =
+                deadlines.iter().filter(|(_, deadline)| **deadline <= now).map(|(key, _)| key.clone()).collect();
+            for key in expired {
+                deadlines.remove(&key);
+                self.inner.del(table, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn purge_all(&self) -> Result<(), KvError> {
+        let tables: Vec<String> = self.deadlines.iter().map(|item| item.key().clone()).collect();
+        tables.iter().try_for_each(|table| self.purge_table(table))
+    }
+
+    // the key is removed or replaced by a set, it doesn't expire anymore
+    fn forget(&self, table: &str, key: &[u8]) {
+        if let Some(mut deadlines) = self.deadlines.get_mut(table) {
+            deadlines.remove(key);
+        }
+    }
+
+    // forget the keys of the table which are not in the inner store anymore
+    fn forget_missing(&self, table: &str) -> Result<(), KvError> {
+        if let Some(mut deadlines) = self.deadlines.get_mut(table) {
+            let mut missing = vec![];
+            for key in deadlines.keys() {
+                if !self.inner.contains(table, key)? {
+                    missing.push(key.clone());
+                }
+            }
+            missing.iter().for_each(|key| {
+                deadlines.remove(key);
+            });
+        }
+        Ok(())
+    }
+
+    fn take_deadline(&self, table: &str, key: &[u8]) -> Option<Instant> {
+        self.deadlines.get_mut(table)?.remove(key)
+    }
+
+    fn set_deadline(&self, table: &str, key: &[u8], deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => {
+                self.deadlines.entry(table.to_string()).or_default().insert(key.to_vec(), deadline);
+            }
+            None => self.forget(table, key),
+        }
+    }
+}
+
+impl<S: Storage> Storage for ExpiringStore<S> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.purge(table, key)?;
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        self.purge(table, &key)?;
+        self.forget(table, &key);
+        self.inner.set(table, key, value)
+    }
+
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.purge(table, &key)?;
+        self.inner.set_if_absent(table, key, value)
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.purge(table, &key)?;
+        self.inner.set_if_changed(table, key, value)
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        self.purge(table, &key)?;
+        self.inner.get_or_set_with(table, key, f)
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        self.purge_table(table)?;
+        self.inner.init_if_empty(table, pairs)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        self.purge(table, &key)?;
+        self.inner.lpush(table, key, values)
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        self.purge(table, &key)?;
+        self.inner.sadd(table, key, members)
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        self.purge(table, &key)?;
+        self.inner.srem(table, key, members)
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        self.purge(table, &key)?;
+        self.inner.incr_float(table, key, delta)
+    }
+
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+        self.purge(table, key)?;
+        self.inner.get_and_reset(table, key)
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.purge(table, key)?;
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.purge(table, key)?;
+        self.forget(table, key);
+        self.inner.del(table, key)
+    }
+
+    // the keys exchange their expiries with their values
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        self.purge(table, key1)?;
+        self.purge(table, key2)?;
+        let olds = self.inner.swap(table, key1, key2)?;
+        let (deadline1, deadline2) = (self.take_deadline(table, key1), self.take_deadline(table, key2));
+        self.set_deadline(table, key1, deadline2);
+        self.set_deadline(table, key2, deadline1);
+        Ok(olds)
+    }
+
+    // the moved key keeps its expiry, the replaced key's expiry is dropped
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.purge(from, key)?;
+        self.purge(to, key)?;
+        let moved = self.inner.move_key(from, to, key)?;
+        if moved.is_some() && from != to {
+            let deadline = self.take_deadline(from, key);
+            self.set_deadline(to, key, deadline);
+        }
+        Ok(moved)
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        self.purge_table(table)?;
+        let removed = self.inner.del_by_prefix(table, prefix)?;
+        if let Some(mut deadlines) = self.deadlines.get_mut(table) {
+            deadlines.retain(|key, _| !key.starts_with(prefix));
+        }
+        Ok(removed)
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        self.purge_table(table)?;
+        let removed = self.inner.expire_before(table, cutoff)?;
+        self.forget_missing(table)?;
+        Ok(removed)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.purge_table(table)?;
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
+        self.purge_table(table)?;
+        self.inner.get_iter(table)
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        self.purge_all()?;
+        self.inner.iter_all()
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        self.purge_table(table)?;
+        self.inner.get_range(table, start, end)
+    }
+
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        self.purge_table(table)?;
+        self.inner.get_matched(table, pattern)
+    }
+
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        self.purge(table, key)?;
+        self.inner.value_size(table, key)
+    }
+
+    // the expiries move with the keys, the old ones of `to` are dropped
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        self.purge_table(from)?;
+        self.purge_table(to)?;
+        self.inner.rename_table(from, to)?;
+        if from != to {
+            self.deadlines.remove(to);
+            if let Some((_, deadlines)) = self.deadlines.remove(from) {
+                self.deadlines.insert(to.to_string(), deadlines);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        self.purge_all()?;
+        let removed = self.inner.clear()?;
+        self.deadlines.clear();
+        Ok(removed)
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        self.purge_table(table)?;
+        self.inner.table_stats(table)
+    }
+
+    fn create_index(&self, table: &str, field: &str) -> Result<(), KvError> {
+        self.inner.create_index(table, field)
+    }
+
+    fn query_index(&self, table: &str, field: &str, value: &Value) -> Result<Vec<KvPair>, KvError> {
+        self.purge_table(table)?;
+        self.inner.query_index(table, field, value)
+    }
+
+    // the key is checked while its table is locked, so it can't expire between the check and the touch
+    fn touch(&self, table: &str, key: &[u8], ttl: Duration) -> Result<bool, KvError> {
+        let mut deadlines = self.deadlines.entry(table.to_string()).or_default();
+        if matches!(deadlines.get(key), Some(deadline) if *deadline <= Instant::now()) {
+            deadlines.remove(key);
+            self.inner.del(table, key)?;
+        }
+        if !self.inner.contains(table, key)? {
+            return Ok(false);
+        }
+        match ttl.is_zero() {
+            true => deadlines.remove(key),
+            false => deadlines.insert(key.to_vec(), Instant::now() + ttl),
+        };
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::MemTable;
+
+    use super::*;
+
+    #[test]
+    fn touched_keys_should_expire() {
+        let store = ExpiringStore::new(MemTable::new());
+        store.set("sessions", b"s1".to_vec(), "alice".into()).unwrap();
+        store.set("sessions", b"s2".to_vec(), "bob".into()).unwrap();
+
+        assert!(store.touch("sessions", b"s1", Duration::from_millis(20)).unwrap());
+        assert!(!store.touch("sessions", b"s3", Duration::from_millis(20)).unwrap());
+        assert_eq!(store.get("sessions", b"s1").unwrap(), Some("alice".into()));
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get("sessions", b"s1").unwrap(), None);
+        assert_eq!(store.get_all("sessions").unwrap(), vec![KvPair::new("s2", "bob".into())]);
+        // the expired key is removed from the inner store
+        assert_eq!(store.inner().get("sessions", b"s1").unwrap(), None);
+        assert!(!store.touch("sessions", b"s1", Duration::from_secs(10)).unwrap());
+    }
+
+    #[test]
+    fn touch_should_refresh_or_remove_the_expiry() {
+        let store = ExpiringStore::new(MemTable::new());
+        store.set("sessions", b"s1".to_vec(), "alice".into()).unwrap();
+        store.set("sessions", b"s2".to_vec(), "bob".into()).unwrap();
+        store.touch("sessions", b"s1", Duration::from_millis(20)).unwrap();
+        store.touch("sessions", b"s2", Duration::from_millis(20)).unwrap();
+
+        // s1 is kept alive, s2 doesn't expire anymore
+        thread::sleep(Duration::from_millis(10));
+        store.touch("sessions", b"s1", Duration::from_millis(40)).unwrap();
+        store.touch("sessions", b"s2", Duration::ZERO).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("sessions", b"s1").unwrap(), Some("alice".into()));
+        assert_eq!(store.get("sessions", b"s2").unwrap(), Some("bob".into()));
+    }
+
+    #[test]
+    fn writes_should_keep_or_drop_the_expiry() {
+        let store = ExpiringStore::new(MemTable::new());
+        store.set("t1", b"set".to_vec(), 1.into()).unwrap();
+        store.lpush("t1", b"list".to_vec(), vec![1.into()]).unwrap();
+        store.set("t1", b"moved".to_vec(), 1.into()).unwrap();
+        for key in [&b"set"[..], b"list", b"moved"] {
+            store.touch("t1", key, Duration::from_millis(20)).unwrap();
+        }
+
+        // a set removes the expiry, the other writes keep it, a moved key takes it along
+        store.set("t1", b"set".to_vec(), 2.into()).unwrap();
+        store.lpush("t1", b"list".to_vec(), vec![2.into()]).unwrap();
+        store.move_key("t1", "t2", b"moved").unwrap();
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get("t1", b"set").unwrap(), Some(2.into()));
+        assert_eq!(store.get("t1", b"list").unwrap(), None);
+        assert_eq!(store.get("t2", b"moved").unwrap(), None);
+
+        // a deleted key doesn't take its expiry to a new value
+        store.set("t1", b"k1".to_vec(), 1.into()).unwrap();
+        store.touch("t1", b"k1", Duration::from_millis(20)).unwrap();
+        store.del("t1", b"k1").unwrap();
+        store.set_if_absent("t1", b"k1".to_vec(), 2.into()).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get("t1", b"k1").unwrap(), Some(2.into()));
+    }
+
+    #[test]
+    fn storages_without_expiry_should_reject_touch() {
+        let store = MemTable::new();
+        store.set("t1", b"k1".to_vec(), 1.into()).unwrap();
+        assert!(matches!(store.touch("t1", b"k1", Duration::from_secs(1)), Err(KvError::InvalidCommand(_))));
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use dashmap::DashMap;
+use prost::Message;
+
+use crate::{KvError, KvPair, Storage, TableStats, Value, value};
+
+// the keys of a table grouped by the value of a field, the values are encoded so they can be hashed
+#[derive(Debug, Default)]
+struct FieldIndex {
+    // field value -> keys whose value has the field
+    keys: HashMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    // key -> field value, to remove the key from its old group when it's changed
+    values: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl FieldIndex {
+    // set the field value of a key, None if the key is removed or its value doesn't have the field
+    fn update(&mut self, key: &[u8], field_value: Option<Vec<u8>>) {
+        if let Some(old) = self.values.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&old) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&old);
+                }
+            }
+        }
+        if let Some(field_value) = field_value {
+            self.keys.entry(field_value.clone()).or_default().insert(key.to_vec());
+            self.values.insert(key.to_vec(), field_value);
+        }
+    }
+}
+
+// keep in-memory indexes of the map values on top of a storage, see Storage::create_index
+//
+// every write updates the indexes of its table from the key's new value in the inner store.
+// the indexes are not saved, create them again after restart, they're rebuilt from a scan of the table.
+// the tables without an index are not affected, their writes are passed to the inner store as they are
+pub struct IndexedStore<S> {
+    inner: S,
+    // table -> field path -> index, the table is locked while its indexes are updated
+    indexes: DashMap<String, HashMap<String, FieldIndex>>,
+}
+
+impl<S: Storage> IndexedStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, indexes: DashMap::new() }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    // update the indexes of the table with the current value of the keys
+    // the value is read while the table is locked, so the last update of a key always sees its latest value
+    fn sync_keys<'a>(&self, table: &str, keys: impl IntoIterator<Item=&'a [u8]>) -> Result<(), KvError> {
+        if let Some(mut indexes) = self.indexes.get_mut(table) {
+            for key in keys {
+                let value = self.inner.get(table, key)?;
+                for (field, index) in indexes.iter_mut() {
+                    index.update(key, encoded_field(value.as_ref(), field));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_key(&self, table: &str, key: &[u8]) -> Result<(), KvError> {
+        self.sync_keys(table, [key])
+    }
+
+    // build the indexes of the table again from a scan
+    fn rebuild(&self, table: &str) -> Result<(), KvError> {
+        if let Some(mut indexes) = self.indexes.get_mut(table) {
+            indexes.values_mut().for_each(|index| *index = FieldIndex::default());
+            for pair in self.inner.get_iter(table)? {
+                for (field, index) in indexes.iter_mut() {
+                    index.update(&pair.key, encoded_field(pair.value.as_ref(), field));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// get the value of a field path in a map value, None if a map on the path doesn't have the field
+fn field_value<'a>(value: &'a Value, field: &str) -> Option<&'a Value> {
+    field.split('.').try_fold(value, |value, name| match &value.value {
+        Some(value::Value::Map(map)) => map
+            .pairs
+            .iter()
+            .find(|pair| pair.key == name.as_bytes())
+            .and_then(|pair| pair.value.as_ref()),
+        _ => None,
+    })
+}
+
+fn encoded_field(value: Option<&Value>, field: &str) -> Option<Vec<u8>> {
+    value.and_then(|v| field_value(v, field)).map(|v| v.encode_to_vec())
+}
+
+impl<S: Storage> Storage for IndexedStore<S> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.inner.set(table, key.clone(), value)?;
+        self.sync_key(table, &key)?;
+        Ok(old)
+    }
+
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let set = self.inner.set_if_absent(table, key.clone(), value)?;
+        if set {
+            self.sync_key(table, &key)?;
+        }
+        Ok(set)
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let written = self.inner.set_if_changed(table, key.clone(), value)?;
+        if written {
+            self.sync_key(table, &key)?;
+        }
+        Ok(written)
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        let mut created = false;
+        let value = self.inner.get_or_set_with(table, key.clone(), || {
+            created = true;
+            f()
+        })?;
+        if created {
+            self.sync_key(table, &key)?;
+        }
+        Ok(value)
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        let keys: Vec<_> = pairs.iter().map(|pair| pair.key.clone()).collect();
+        let initialized = self.inner.init_if_empty(table, pairs)?;
+        if initialized {
+            self.sync_keys(table, keys.iter().map(|key| key.as_ref()))?;
+        }
+        Ok(initialized)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        let len = self.inner.lpush(table, key.clone(), values)?;
+        self.sync_key(table, &key)?;
+        Ok(len)
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let added = self.inner.sadd(table, key.clone(), members)?;
+        self.sync_key(table, &key)?;
+        Ok(added)
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let removed = self.inner.srem(table, key.clone(), members)?;
+        self.sync_key(table, &key)?;
+        Ok(removed)
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        let value = self.inner.incr_float(table, key.clone(), delta)?;
+        self.sync_key(table, &key)?;
+        Ok(value)
+    }
+
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+        let old = self.inner.get_and_reset(table, key)?;
+        self.sync_key(table, key)?;
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let old = self.inner.del(table, key)?;
+        self.sync_key(table, key)?;
+        Ok(old)
+    }
+
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        let olds = self.inner.swap(table, key1, key2)?;
+        self.sync_keys(table, [key1, key2])?;
+        Ok(olds)
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let moved = self.inner.move_key(from, to, key)?;
+        self.sync_key(from, key)?;
+        self.sync_key(to, key)?;
+        Ok(moved)
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        let removed = self.inner.del_by_prefix(table, prefix)?;
+        // only the indexed keys can be affected, the keys without the field are not in any index
+        let keys: BTreeSet<Vec<u8>> = match self.indexes.get(table) {
+            Some(indexes) => indexes
+                .values()
+                .flat_map(|index| index.values.keys().filter(|key| key.starts_with(prefix)).cloned())
+                .collect(),
+            None => return Ok(removed),
+        };
+        self.sync_keys(table, keys.iter().map(|key| &key[..]))?;
+        Ok(removed)
+    }
+
+    // only the timestamps expire, they don't have fields, so they're not in any index
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        self.inner.expire_before(table, cutoff)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        self.inner.iter_all()
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_range(table, start, end)
+    }
+
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_matched(table, pattern)
+    }
+
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        self.inner.value_size(table, key)
+    }
+
+    // the indexes stay with their tables, both tables are indexed again
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        self.inner.rename_table(from, to)?;
+        self.rebuild(from)?;
+        self.rebuild(to)
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        let removed = self.inner.clear()?;
+        let tables: Vec<String> = self.indexes.iter().map(|item| item.key().clone()).collect();
+        for table in tables {
+            self.rebuild(&table)?;
+        }
+        Ok(removed)
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        self.inner.table_stats(table)
+    }
+
+    // creating an existing index does nothing. the table is locked during the scan,
+    // so the writes to it wait until the index is built
+    fn create_index(&self, table: &str, field: &str) -> Result<(), KvError> {
+        if field.split('.').any(|name| name.is_empty()) {
+            return Err(KvError::InvalidCommand(format!("Invalid index field: `{}`", field)));
+        }
+        let mut indexes = self.indexes.entry(table.to_string()).or_default();
+        if indexes.contains_key(field) {
+            return Ok(());
+        }
+        let mut index = FieldIndex::default();
+        for pair in self.inner.get_iter(table)? {
+            index.update(&pair.key, encoded_field(pair.value.as_ref(), field));
+        }
+        indexes.insert(field.to_string(), index);
+        Ok(())
+    }
+
+    fn query_index(&self, table: &str, field: &str, value: &Value) -> Result<Vec<KvPair>, KvError> {
+        let value = value.encode_to_vec();
+        let keys: Vec<Vec<u8>> = match self.indexes.get(table).as_ref().and_then(|indexes| indexes.get(field)) {
+            Some(index) => index.keys.get(&value).into_iter().flatten().cloned().collect(),
+            None => return Err(KvError::InvalidCommand(format!("No index on field {} of table {}", field, table))),
+        };
+
+        // the keys may be changed after they're read from the index, skip those which don't match anymore
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(v) = self.inner.get(table, &key)? {
+                if encoded_field(Some(&v), field).as_ref() == Some(&value) {
+                    pairs.push(KvPair::new(key, v));
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    // an expired key is skipped by query_index like a changed one, it's not removed from the indexes
+    fn touch(&self, table: &str, key: &[u8], ttl: Duration) -> Result<bool, KvError> {
+        self.inner.touch(table, key, ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::MemTable;
+
+    use super::*;
+
+    fn user(name: &str, city: &str) -> Value {
+        let address = HashMap::from([("city".to_string(), Value::from(city))]);
+        HashMap::from([
+            ("name".to_string(), Value::from(name)),
+            ("address".to_string(), Value::from(address)),
+        ])
+        .into()
+    }
+
+    fn query(store: &impl Storage, city: &str) -> Vec<Vec<u8>> {
+        let pairs = store.query_index("users", "address.city", &city.into()).unwrap();
+        pairs.into_iter().map(|pair| pair.key.to_vec()).collect()
+    }
+
+    #[test]
+    fn index_should_follow_the_writes() {
+        let store = IndexedStore::new(MemTable::new());
+        store.set("users", b"alice".to_vec(), user("alice", "paris")).unwrap();
+        store.create_index("users", "address.city").unwrap();
+        store.set("users", b"bob".to_vec(), user("bob", "paris")).unwrap();
+        store.set("users", b"carol".to_vec(), user("carol", "rome")).unwrap();
+        // not a map, it's not indexed
+        store.set("users", b"dave".to_vec(), "paris".into()).unwrap();
+        assert_eq!(query(&store, "paris"), vec![b"alice".to_vec(), b"bob".to_vec()]);
+
+        store.set("users", b"alice".to_vec(), user("alice", "rome")).unwrap();
+        store.del("users", b"bob").unwrap();
+        assert!(query(&store, "paris").is_empty());
+        assert_eq!(query(&store, "rome"), vec![b"alice".to_vec(), b"carol".to_vec()]);
+
+        store.del_by_prefix("users", b"car").unwrap();
+        assert_eq!(query(&store, "rome"), vec![b"alice".to_vec()]);
+
+        store.rename_table("users", "members").unwrap();
+        assert!(query(&store, "rome").is_empty());
+    }
+
+    #[test]
+    fn query_without_index_should_fail() {
+        let store = IndexedStore::new(MemTable::new());
+        assert!(store.query_index("users", "address.city", &"paris".into()).is_err());
+        assert!(store.create_index("users", "address.").is_err());
+        assert!(MemTable::new().create_index("users", "name").is_err());
+    }
+}
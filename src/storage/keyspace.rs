@@ -0,0 +1,328 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{keyspace_topic, CommandResponse, KvError, KvPair, Storage, TableStats, Topic, Value};
+
+use super::expired;
+
+// publish the changes of the keys to their keyspace topics, which the Watch command subscribes
+//
+// a write is published after the inner store has applied it, as ["set", new value] or ["del"].
+// a write which changes nothing (e.g. a set_if_absent of an existing key) is not published.
+// the events are only built for the watched keys, a key without watchers costs a has_topic check.
+// rename_table and clear are not published, and neither are the keys expired by the inner store itself.
+// the topic must be the one the service subscribes the watchers to, e.g. the same Arc<Broadcaster>
+pub struct KeyspaceNotifier<S, T> {
+    inner: S,
+    topic: T,
+}
+
+impl<S: Storage, T: Topic> KeyspaceNotifier<S, T> {
+    pub fn new(inner: S, topic: T) -> Self {
+        Self { inner, topic }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    // the keyspace topic of a key if it has watchers
+    fn watched(&self, table: &str, key: &[u8]) -> Option<String> {
+        let topic = keyspace_topic(table, key);
+        self.topic.has_topic(&topic).then_some(topic)
+    }
+
+    fn notify(&self, table: &str, key: &[u8], event: impl FnOnce() -> CommandResponse) {
+        if let Some(topic) = self.watched(table, key) {
+            self.topic.clone().publish(topic, Arc::new(event()));
+        }
+    }
+
+    // publish the current value of a key, for the writes which don't return it, e.g. lpush
+    fn notify_current(&self, table: &str, key: &[u8]) -> Result<(), KvError> {
+        if let Some(topic) = self.watched(table, key) {
+            let event = match self.inner.get(table, key)? {
+                Some(value) => set_event(value),
+                None => del_event(),
+            };
+            self.topic.clone().publish(topic, Arc::new(event));
+        }
+        Ok(())
+    }
+
+    // delete the keys matched by the inner store, the watched ones are found before, so they can be published
+    fn del_matched(
+        &self,
+        table: &str,
+        matched: impl Fn(&KvPair) -> bool,
+        del: impl FnOnce(&S) -> Result<u64, KvError>,
+    ) -> Result<u64, KvError> {
+        let watched: Vec<_> = self
+            .inner
+            .get_iter(table)?
+            .filter(|pair| matched(pair) && self.watched(table, &pair.key).is_some())
+            .map(|pair| pair.key)
+            .collect();
+        let count = del(&self.inner)?;
+        for key in watched {
+            self.notify(table, &key, del_event);
+        }
+        Ok(count)
+    }
+}
+
+fn set_event(value: Value) -> CommandResponse {
+    vec!["set".into(), value].into()
+}
+
+fn del_event() -> CommandResponse {
+    vec![Value::from("del")].into()
+}
+
+impl<S: Storage, T: Topic> Storage for KeyspaceNotifier<S, T> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        let event = self.watched(table, &key).map(|topic| (topic, set_event(value.clone())));
+        let old = self.inner.set(table, key, value)?;
+        if let Some((topic, event)) = event {
+            self.topic.clone().publish(topic, Arc::new(event));
+        }
+        Ok(old)
+    }
+
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let event = self.watched(table, &key).map(|topic| (topic, set_event(value.clone())));
+        let set = self.inner.set_if_absent(table, key, value)?;
+        if let (true, Some((topic, event))) = (set, event) {
+            self.topic.clone().publish(topic, Arc::new(event));
+        }
+        Ok(set)
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let event = self.watched(table, &key).map(|topic| (topic, set_event(value.clone())));
+        let set = self.inner.set_if_changed(table, key, value)?;
+        if let (true, Some((topic, event))) = (set, event) {
+            self.topic.clone().publish(topic, Arc::new(event));
+        }
+        Ok(set)
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        let topic = self.watched(table, &key);
+        let mut set = false;
+        let value = self.inner.get_or_set_with(table, key, || {
+            set = true;
+            f()
+        })?;
+        if let (true, Some(topic)) = (set, topic) {
+            self.topic.clone().publish(topic, Arc::new(set_event(value.clone())));
+        }
+        Ok(value)
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        let events: Vec<_> = pairs
+            .iter()
+            .filter_map(|pair| {
+                let topic = self.watched(table, &pair.key)?;
+                Some((topic, set_event(pair.value.clone().unwrap_or_default())))
+            })
+            .collect();
+        let set = self.inner.init_if_empty(table, pairs)?;
+        if set {
+            for (topic, event) in events {
+                self.topic.clone().publish(topic, Arc::new(event));
+            }
+        }
+        Ok(set)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        let len = self.inner.lpush(table, key.clone(), values)?;
+        self.notify_current(table, &key)?;
+        Ok(len)
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let added = self.inner.sadd(table, key.clone(), members)?;
+        if added > 0 {
+            self.notify_current(table, &key)?;
+        }
+        Ok(added)
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let removed = self.inner.srem(table, key.clone(), members)?;
+        if removed > 0 {
+            self.notify_current(table, &key)?;
+        }
+        Ok(removed)
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        let new = self.inner.incr_float(table, key.clone(), delta)?;
+        self.notify(table, &key, || set_event(new.into()));
+        Ok(new)
+    }
+
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+        let old = self.inner.get_and_reset(table, key)?;
+        self.notify(table, key, || set_event(0.into()));
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let old = self.inner.del(table, key)?;
+        if old.is_some() {
+            self.notify(table, key, del_event);
+        }
+        Ok(old)
+    }
+
+    // each key gets the old value of the other one
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        let (old1, old2) = self.inner.swap(table, key1, key2)?;
+        self.notify(table, key1, || set_event(old2.clone()));
+        self.notify(table, key2, || set_event(old1.clone()));
+        Ok((old1, old2))
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let moved = self.inner.move_key(from, to, key)?;
+        if let Some(value) = &moved {
+            self.notify(from, key, del_event);
+            self.notify(to, key, || set_event(value.clone()));
+        }
+        Ok(moved)
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        self.del_matched(table, |pair| pair.key.starts_with(prefix), |inner| inner.del_by_prefix(table, prefix))
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        let matched = |pair: &KvPair| pair.value.as_ref().is_some_and(|value| expired(value, cutoff));
+        self.del_matched(table, matched, |inner| inner.expire_before(table, cutoff))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item = (String, KvPair)>>, KvError> {
+        self.inner.iter_all()
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_range(table, start, end)
+    }
+
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        self.inner.get_matched(table, pattern)
+    }
+
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        self.inner.value_size(table, key)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        self.inner.rename_table(from, to)
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        self.inner.clear()
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        self.inner.table_stats(table)
+    }
+
+    fn create_index(&self, table: &str, field: &str) -> Result<(), KvError> {
+        self.inner.create_index(table, field)
+    }
+
+    fn query_index(&self, table: &str, field: &str, value: &Value) -> Result<Vec<KvPair>, KvError> {
+        self.inner.query_index(table, field, value)
+    }
+
+    fn touch(&self, table: &str, key: &[u8], ttl: Duration) -> Result<bool, KvError> {
+        self.inner.touch(table, key, ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    use crate::{assert_response_ok, Broadcaster, MemTable};
+
+    use super::*;
+
+    async fn watch(topic: &Arc<Broadcaster>, table: &str, key: &str) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let mut receiver = topic.clone().subscribe(keyspace_topic(table, key.as_bytes()));
+        // the first message is the subscription id
+        receiver.recv().await.unwrap();
+        receiver
+    }
+
+    async fn next_event(receiver: &mut mpsc::Receiver<Arc<CommandResponse>>) -> CommandResponse {
+        let event = time::timeout(Duration::from_secs(1), receiver.recv()).await.unwrap().unwrap();
+        event.as_ref().clone()
+    }
+
+    #[tokio::test]
+    async fn keyspace_notifier_should_publish_the_writes() {
+        let topic = Arc::new(Broadcaster::default());
+        let store = KeyspaceNotifier::new(MemTable::new(), topic.clone());
+        let mut watcher = watch(&topic, "t1", "k1").await;
+
+        store.set("t1", "k2".into(), 1.into()).unwrap();
+        store.set("t1", "k1".into(), 1.into()).unwrap();
+        assert_response_ok(&next_event(&mut watcher).await, &["set".into(), 1.into()], &[]);
+
+        // the writes which change nothing or fail are not published
+        store.set_if_absent("t1", "k1".into(), 2.into()).unwrap();
+        store.set_if_changed("t1", "k1".into(), 1.into()).unwrap();
+        store.lpush("t1", "k1".into(), vec![2.into()]).unwrap_err();
+        store.del("t1", b"k1").unwrap();
+        assert_response_ok(&next_event(&mut watcher).await, &["del".into()], &[]);
+        store.del("t1", b"k1").unwrap();
+
+        // the writes which don't return the new value publish the current one
+        store.lpush("t1", "k1".into(), vec![1.into(), 2.into()]).unwrap();
+        let list = Value::from(vec![Value::from(2), Value::from(1)]);
+        assert_response_ok(&next_event(&mut watcher).await, &["set".into(), list], &[]);
+
+        // the keys deleted by a prefix are published one by one
+        store.del_by_prefix("t1", b"k").unwrap();
+        assert_response_ok(&next_event(&mut watcher).await, &["del".into()], &[]);
+        assert!(time::timeout(Duration::from_millis(100), watcher.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keyspace_notifier_should_publish_both_keys_of_a_move() {
+        let topic = Arc::new(Broadcaster::default());
+        let store = KeyspaceNotifier::new(MemTable::new(), topic.clone());
+        let mut from = watch(&topic, "t1", "k1").await;
+        let mut to = watch(&topic, "t2", "k1").await;
+
+        store.set("t1", "k1".into(), 1.into()).unwrap();
+        next_event(&mut from).await;
+        store.move_key("t1", "t2", b"k1").unwrap();
+        assert_response_ok(&next_event(&mut from).await, &["del".into()], &[]);
+        assert_response_ok(&next_event(&mut to).await, &["set".into(), 1.into()], &[]);
+    }
+}
@@ -1,12 +1,31 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use dashmap::DashMap;
+use prost::Message;
+use dashmap::mapref::entry::Entry;
 use dashmap::mapref::one::Ref;
 
-use crate::{KvPair, Storage, StorageIter, Value};
+use crate::{KvPair, Storage, StorageIter, TableStats, Value};
+use crate::storage::{add_float, expired, key_not_found, lpush_values, sadd_members, srem_members, table_not_found};
 use crate::error::KvError;
 
+// the memory a key takes besides its bytes and the encoded value, i.e. the key's Vec and the Value in the table
+const ENTRY_OVERHEAD: usize = mem::size_of::<(Vec<u8>, Value)>();
+
 #[derive(Debug, Default, Clone)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, DashMap<Vec<u8>, Value>>,
+    // reject the writes which would use more memory than this, None means unlimited
+    memory: Option<MemoryBudget>,
+}
+
+// the approximate memory used by all tables, see MemTable::with_memory_limit
+#[derive(Debug)]
+struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
 }
 
 impl MemTable {
@@ -14,35 +33,319 @@ impl MemTable {
         Self::default()
     }
 
-    fn get_or_create_table(&self, table_name: &str) -> Ref<String, DashMap<String, Value>> {
-        self.tables.entry(table_name.to_string()).or_insert_with(DashMap::new).downgrade()
+    // reject the writes with KvError::MemoryLimitExceeded once all tables would use more than `bytes`,
+    // deleting keys frees the memory. the memory is approximate: a key counts as its length, the encoded length of its value and a fixed overhead,
+    // updated on every write. the allocator's and the hash maps' own overhead (e.g. empty tables) is not counted
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        let used = self.tables.iter().map(|table| table_size(&table)).sum();
+        self.memory = Some(MemoryBudget { limit: bytes, used: AtomicUsize::new(used) });
+        self
+    }
+
+    // the approximate memory used by all tables, only counted if the memory is limited
+    pub fn memory_used(&self) -> Option<usize> {
+        self.memory.as_ref().map(|memory| memory.used.load(Ordering::Relaxed))
+    }
+
+    // get a point-in-time copy of a table, later writes to the table won't change it
+    // the whole table is cloned, so it takes as much memory as the table itself
+    pub fn snapshot(&self, table: &str) -> TableSnapshot {
+        let data = match self.tables.get(table) {
+            Some(t) => t.iter().map(|item| (item.key().clone(), item.value().clone())).collect(),
+            None => HashMap::new(),
+        };
+        TableSnapshot { data }
+    }
+
+    fn get_or_create_table(&self, table_name: &str) -> Ref<'_, String, DashMap<Vec<u8>, Value>> {
+        self.tables.entry(table_name.to_string()).or_default().downgrade()
+    }
+
+    // account a change of the memory, fail without changing anything if it goes over the limit
+    fn charge(&self, old: usize, new: usize) -> Result<(), KvError> {
+        let Some(memory) = &self.memory else {
+            return Ok(());
+        };
+        let result = memory.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            // the memory can be freed at any time, it can only be taken within the limit
+            let used = used.saturating_sub(old).checked_add(new)?;
+            (new <= old || used <= memory.limit).then_some(used)
+        });
+        result.map(|_| ()).map_err(|_| KvError::MemoryLimitExceeded(memory.limit))
+    }
+
+    // read-modify-write a key while holding the shard lock, f gets the current value and returns the new value
+    // (None keeps the current value) and the result. the new value is charged before it's written
+    fn modify<T>(
+        &self,
+        table: &str,
+        key: Vec<u8>,
+        f: impl FnOnce(Option<&Value>) -> Result<(Option<Value>, T), KvError>,
+    ) -> Result<T, KvError> {
+        let table = self.get_or_create_table(table);
+        let result = match table.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let (new, result) = f(Some(entry.get()))?;
+                if let Some(new) = new {
+                    self.charge(entry_size(entry.key(), Some(entry.get())), entry_size(entry.key(), Some(&new)))?;
+                    entry.insert(new);
+                }
+                result
+            }
+            Entry::Vacant(entry) => {
+                let (new, result) = f(None)?;
+                if let Some(new) = new {
+                    self.charge(0, entry_size(entry.key(), Some(&new)))?;
+                    entry.insert(new);
+                }
+                result
+            }
+        };
+        Ok(result)
+    }
+}
+
+impl Clone for MemoryBudget {
+    fn clone(&self) -> Self {
+        Self { limit: self.limit, used: AtomicUsize::new(self.used.load(Ordering::Relaxed)) }
+    }
+}
+
+fn entry_size(key: &[u8], value: Option<&Value>) -> usize {
+    value.map_or(0, |value| key.len() + value.encoded_len() + ENTRY_OVERHEAD)
+}
+
+fn table_size(table: &DashMap<Vec<u8>, Value>) -> usize {
+    table.iter().map(|item| entry_size(item.key(), Some(item.value()))).sum()
+}
+
+// frozen copy of a table, only supports reading
+#[derive(Debug, Default, Clone)]
+pub struct TableSnapshot {
+    data: HashMap<Vec<u8>, Value>,
+}
+
+impl TableSnapshot {
+    // get a value by key
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        self.data.get(key).cloned()
+    }
+
+    // check if a key exists
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.data.contains_key(key)
+    }
+
+    // get all KV pairs
+    pub fn get_all(&self) -> Vec<KvPair> {
+        self.data.iter().map(|(k, v)| KvPair::new(k.clone(), v.clone())).collect()
+    }
+
+    // get kv pairs' iterator
+    pub fn get_iter(&self) -> impl Iterator<Item=KvPair> + '_ {
+        self.data.iter().map(|(k, v)| KvPair::new(k.clone(), v.clone()))
+    }
+
+    // how many keys in the snapshot
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 }
 
 impl Storage for MemTable {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.clone()))
+    // the reads of a missing key don't create its table
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        Ok(self.tables.get(table).and_then(|t| t.get(key).map(|v| v.clone())))
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        self.modify(table, key, |old| Ok((Some(value), old.cloned())))
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.modify(table, key, |old| match old {
+            Some(_) => Ok((None, false)),
+            None => Ok((Some(value), true)),
+        })
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        // the entry holds the shard lock, so f runs once for a missing key
+        self.modify(table, key, |old| match old {
+            Some(old) => Ok((None, old.clone())),
+            None => {
+                let value = f();
+                Ok((Some(value.clone()), value))
+            }
+        })
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        self.modify(table, key, |old| match old {
+            Some(old) if *old == value => Ok((None, false)),
+            _ => Ok((Some(value), true)),
+        })
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        // write lock the table's shard, the writes of the table hold a read lock of it while they change the table
+        let table = self.tables.entry(table.to_string()).or_default();
+        if !table.is_empty() {
+            return Ok(false);
+        }
+        let entries: Vec<_> = pairs
+            .into_iter()
+            .map(|pair| (pair.key.to_vec(), pair.value.unwrap_or_default()))
+            .collect();
+        self.charge(0, entries.iter().map(|(key, value)| entry_size(key, Some(value))).sum())?;
+        for (key, value) in entries {
+            // a key given twice is only kept once
+            if let Some(old) = table.insert(key.clone(), value) {
+                self.charge(entry_size(&key, Some(&old)), 0)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        // the entry holds the shard lock, so the read-modify-write is atomic
+        self.modify(table, key, |old| {
+            let list = lpush_values(old.cloned(), values)?;
+            let len = list.len();
+            Ok((Some(list.into()), len))
+        })
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        // the entry holds the shard lock, so the read-modify-write is atomic
+        self.modify(table, key, |old| {
+            let (set, added) = sadd_members(old.cloned(), members)?;
+            Ok((Some(set.into()), added))
+        })
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        // the entry holds the shard lock, so the read-modify-write is atomic
+        self.modify(table, key, |old| {
+            let new = add_float(old.cloned(), delta)?;
+            Ok((Some(new.into()), new))
+        })
+    }
+
+    fn get_and_reset(&self, table_name: &str, key: &[u8]) -> Result<i64, KvError> {
+        // the entry holds the shard lock, so no write is lost between the read and the reset
+        self.modify(table_name, key.to_vec(), |old| match old {
+            Some(old) => Ok((Some(0.into()), i64::try_from(old)?)),
+            None => Err(key_not_found(table_name, key)),
+        })
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        self.modify(table, key, |old| match srem_members(old.cloned(), &members)? {
+            Some((set, removed)) => Ok((Some(set.into()), removed)),
+            None => Ok((None, 0)),
+        })
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        Ok(self.tables.get(table).map(|t| t.contains_key(key)).unwrap_or(false))
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        let old = table.remove(key).map(|(_, v)| v);
+        self.charge(entry_size(key, old.as_ref()), 0)?;
+        Ok(old)
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn swap(&self, table_name: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        // the entry write locks the table until the swap is done, other operations hold a read lock of it,
+        // so nobody can see the table in the middle of the swap
+        let table = self.tables.entry(table_name.into()).or_default();
+        let v1 = table.get(key1).map(|v| v.clone()).ok_or_else(|| key_not_found(table_name, key1))?;
+        let v2 = table.get(key2).map(|v| v.clone()).ok_or_else(|| key_not_found(table_name, key2))?;
+        // the keys exchange their values, so the memory doesn't change
+        table.insert(key1.to_vec(), v2.clone());
+        table.insert(key2.to_vec(), v1.clone());
+        Ok((v1, v2))
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        if from == to {
+            return self.get(from, key);
+        }
+        // both tables are read locked, a read lock of the same shard can be taken twice
+        let dst = self.get_or_create_table(to);
+        let src = match self.tables.get(from) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        // lock the key in both tables in the order of the table names, so two opposite moves can't deadlock
+        let (src_entry, dst_entry) = if from < to {
+            let src_entry = src.entry(key.to_vec());
+            (src_entry, dst.entry(key.to_vec()))
+        } else {
+            let dst_entry = dst.entry(key.to_vec());
+            (src.entry(key.to_vec()), dst_entry)
+        };
+        let moved = match src_entry {
+            Entry::Occupied(e) => {
+                let value = e.remove();
+                let replaced = match &dst_entry {
+                    Entry::Occupied(e) => entry_size(key, Some(e.get())),
+                    Entry::Vacant(_) => 0,
+                };
+                // the moved key takes as much memory as before, only the replaced value is freed
+                self.charge(replaced, 0)?;
+                dst_entry.insert(value.clone());
+                Some(value)
+            }
+            Entry::Vacant(_) => None,
+        };
+        Ok(moved)
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
         let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_, v)| v))
+        // count in retain(), the table may be changed by others at the same time
+        let (mut count, mut freed) = (0, 0);
+        table.retain(|key, value| {
+            let matched = key.starts_with(prefix);
+            if matched {
+                count += 1;
+                freed += entry_size(key, Some(value));
+            }
+            !matched
+        });
+        self.charge(freed, 0)?;
+        Ok(count)
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        let table = match self.tables.get(table) {
+            Some(t) => t,
+            None => return Ok(0),
+        };
+        let (mut count, mut freed) = (0, 0);
+        table.retain(|key, value| {
+            let matched = expired(value, cutoff);
+            if matched {
+                count += 1;
+                freed += entry_size(key, Some(value));
+            }
+            !matched
+        });
+        self.charge(freed, 0)?;
+        Ok(count)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
         let table = self.get_or_create_table(table);
-        Ok(table.iter().map(|item| KvPair::new(item.key(), item.value().clone())).collect())
+        Ok(table.iter().map(|item| KvPair::new(item.key().clone(), item.value().clone())).collect())
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
@@ -51,4 +354,74 @@ impl Storage for MemTable {
         let iter = StorageIter::new(table.into_iter());
         Ok(Box::new(iter))
     }
-}
\ No newline at end of file
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        // collect a snapshot of all tables, don't hold the locks while the caller iterates
+        let items: Vec<_> = self
+            .tables
+            .iter()
+            .flat_map(|table| {
+                let name = table.key().clone();
+                table
+                    .iter()
+                    .map(|item| (name.clone(), KvPair::new(item.key().clone(), item.value().clone())))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Ok(Box::new(items.into_iter()))
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        if from == to {
+            return match self.tables.get(from) {
+                Some(table) if !table.is_empty() => Ok(()),
+                _ => Err(table_not_found(from)),
+            };
+        }
+        // the `to` table is replaced in one insert, readers see either the old or the new data
+        let table = match self.tables.remove_if(from, |_, table| !table.is_empty()) {
+            Some((_, table)) => table,
+            None => return Err(table_not_found(from)),
+        };
+        match self.tables.insert(to.to_string(), table) {
+            Some(replaced) if self.memory.is_some() => self.charge(table_size(&replaced), 0),
+            _ => Ok(()),
+        }
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        // count the removed tables only, a table created at the same time is kept
+        let (mut keys, mut freed) = (0, 0);
+        self.tables.retain(|_, table| {
+            keys += table.len() as u64;
+            if self.memory.is_some() {
+                freed += table_size(table);
+            }
+            false
+        });
+        self.charge(freed, 0)?;
+        Ok(keys)
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        let table = self.get_or_create_table(table);
+        let bytes = table.iter().map(|item| item.key().len() + item.value().encoded_len()).sum::<usize>();
+        Ok(TableStats {
+            keys: table.len() as u64,
+            bytes: bytes as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_should_not_create_tables() {
+        let store = MemTable::new();
+        assert_eq!(store.get("t1", b"k1").unwrap(), None);
+        assert!(!store.contains("t2", b"k1").unwrap());
+        assert!(store.tables.is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::{KvError, KvPair, Storage, TableStats, Value};
+
+// what to do when a write is applied to the primary but fails on the secondary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorFailurePolicy {
+    // log a warning and return the primary's result
+    Log,
+    // return the secondary's error, the primary keeps the write
+    Error,
+    // return the primary's result silently
+    Ignore,
+}
+
+// apply every write to both stores, and read from the primary only, e.g. a MemTable mirrored to a SledDb
+//
+// a write is applied to the primary first, the secondary isn't touched if it fails.
+// set, del, del_by_prefix, expire_before, rename_table and clear are applied to the secondary as they are,
+// the other writes copy the primary's new value of the key, so the secondary can't diverge because of them.
+// the writes are not atomic across the stores, concurrent writes of the same key may be mirrored in a different order
+pub struct MirroredStore<A, B> {
+    primary: A,
+    secondary: B,
+    policy: MirrorFailurePolicy,
+}
+
+impl<A, B> MirroredStore<A, B>
+    where
+        A: Storage,
+        B: Storage,
+{
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self { primary, secondary, policy: MirrorFailurePolicy::Log }
+    }
+
+    // set what to do when the secondary write fails, default is Log
+    pub fn with_failure_policy(mut self, policy: MirrorFailurePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+
+    // copy the primary's value of the key to the secondary, remove it from the secondary if it's gone
+    fn sync_key(&self, table: &str, key: &[u8]) -> Result<(), KvError> {
+        match self.primary.get(table, key)? {
+            Some(value) => self.secondary.set(table, key.to_vec(), value).map(|_| ()),
+            None => self.secondary.del(table, key).map(|_| ()),
+        }
+    }
+
+    // handle the result of a secondary write according to the policy
+    fn mirror<T>(&self, op: &'static str, table: &str, result: Result<T, KvError>) -> Result<(), KvError> {
+        match (result, self.policy) {
+            (Ok(_), _) | (Err(_), MirrorFailurePolicy::Ignore) => Ok(()),
+            (Err(e), MirrorFailurePolicy::Log) => {
+                warn!("Failed to mirror {} of table {}. Error: {:?}", op, table, e);
+                Ok(())
+            }
+            (Err(e), MirrorFailurePolicy::Error) => Err(e),
+        }
+    }
+}
+
+impl<A, B> Storage for MirroredStore<A, B>
+    where
+        A: Storage,
+        B: Storage,
+{
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        self.primary.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.primary.set(table, key.clone(), value.clone())?;
+        self.mirror("set", table, self.secondary.set(table, key, value))?;
+        Ok(old)
+    }
+
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let set = self.primary.set_if_absent(table, key.clone(), value.clone())?;
+        if set {
+            self.mirror("set_if_absent", table, self.secondary.set(table, key, value))?;
+        }
+        Ok(set)
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        let mut created = false;
+        let value = self.primary.get_or_set_with(table, key.clone(), || {
+            created = true;
+            f()
+        })?;
+        if created {
+            self.mirror("get_or_set_with", table, self.secondary.set(table, key, value.clone()))?;
+        }
+        Ok(value)
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let written = self.primary.set_if_changed(table, key.clone(), value.clone())?;
+        if written {
+            self.mirror("set_if_changed", table, self.secondary.set(table, key, value))?;
+        }
+        Ok(written)
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        let initialized = self.primary.init_if_empty(table, pairs.clone())?;
+        if initialized {
+            let result = pairs.into_iter().try_for_each(|pair| {
+                self.secondary.set(table, pair.key.to_vec(), pair.value.unwrap_or_default()).map(|_| ())
+            });
+            self.mirror("init_if_empty", table, result)?;
+        }
+        Ok(initialized)
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        let len = self.primary.lpush(table, key.clone(), values)?;
+        self.mirror("lpush", table, self.sync_key(table, &key))?;
+        Ok(len)
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let added = self.primary.sadd(table, key.clone(), members)?;
+        self.mirror("sadd", table, self.sync_key(table, &key))?;
+        Ok(added)
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let removed = self.primary.srem(table, key.clone(), members)?;
+        self.mirror("srem", table, self.sync_key(table, &key))?;
+        Ok(removed)
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        let value = self.primary.incr_float(table, key.clone(), delta)?;
+        self.mirror("incr_float", table, self.sync_key(table, &key))?;
+        Ok(value)
+    }
+
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+        let old = self.primary.get_and_reset(table, key)?;
+        self.mirror("get_and_reset", table, self.sync_key(table, key))?;
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        self.primary.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let old = self.primary.del(table, key)?;
+        self.mirror("del", table, self.secondary.del(table, key))?;
+        Ok(old)
+    }
+
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        let olds = self.primary.swap(table, key1, key2)?;
+        let result = self.sync_key(table, key1).and_then(|_| self.sync_key(table, key2));
+        self.mirror("swap", table, result)?;
+        Ok(olds)
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        let moved = self.primary.move_key(from, to, key)?;
+        if moved.is_some() {
+            let result = self.sync_key(from, key).and_then(|_| self.sync_key(to, key));
+            self.mirror("move_key", from, result)?;
+        }
+        Ok(moved)
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        let removed = self.primary.del_by_prefix(table, prefix)?;
+        self.mirror("del_by_prefix", table, self.secondary.del_by_prefix(table, prefix))?;
+        Ok(removed)
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        let removed = self.primary.expire_before(table, cutoff)?;
+        self.mirror("expire_before", table, self.secondary.expire_before(table, cutoff))?;
+        Ok(removed)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        self.primary.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
+        self.primary.get_iter(table)
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        self.primary.iter_all()
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        self.primary.get_range(table, start, end)
+    }
+
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        self.primary.get_matched(table, pattern)
+    }
+
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        self.primary.value_size(table, key)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        self.primary.rename_table(from, to)?;
+        self.mirror("rename_table", from, self.secondary.rename_table(from, to))
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        let removed = self.primary.clear()?;
+        self.mirror("clear", "*", self.secondary.clear())?;
+        Ok(removed)
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        self.primary.table_stats(table)
+    }
+
+    // the reads are served by the primary, so only the primary is indexed
+    fn create_index(&self, table: &str, field: &str) -> Result<(), KvError> {
+        self.primary.create_index(table, field)
+    }
+
+    fn query_index(&self, table: &str, field: &str, value: &Value) -> Result<Vec<KvPair>, KvError> {
+        self.primary.query_index(table, field, value)
+    }
+
+    // the expiries are kept by the primary only, an expired key stays in the secondary
+    fn touch(&self, table: &str, key: &[u8], ttl: Duration) -> Result<bool, KvError> {
+        self.primary.touch(table, key, ttl)
+    }
+}
@@ -1,31 +1,300 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use prost::Message;
+
 use crate::error::KvError;
-use crate::{KvPair, Value};
+use crate::{KvPair, Value, ValueSet, value};
 
+mod btree;
+mod case_fold;
+mod codec;
+mod coalescer;
+mod expiring;
+mod index;
+mod keyspace;
 mod memory;
+mod mirror;
 mod sleddb;
 
-pub use memory::MemTable;
+pub use btree::BTreeMemTable;
+pub use case_fold::CaseFoldingStore;
+pub use codec::{BincodeCodec, ProtobufCodec, ValueCodec};
+pub use coalescer::WriteCoalescer;
+pub use expiring::ExpiringStore;
+pub use index::IndexedStore;
+pub use keyspace::KeyspaceNotifier;
+pub use memory::{MemTable, TableSnapshot};
+pub use mirror::{MirrorFailurePolicy, MirroredStore};
 pub use sleddb::SledDb;
 
 // we don't care where the data is saved, we need to define how the storage will be used
+// the methods with a default implementation are built on get, set, del and get_iter, so a new storage only needs
+// the others. the defaults are not atomic, a storage overrides them when it can do better
 pub trait Storage {
     // get a value from a table by key
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError>;
 
     // set a value to a table by key, return the old value if exists
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError>;
+
+    // set a value to a table by key only if the key does not exist, return true if the value is set
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError>;
+
+    // set a key-value pair only if the key doesn't have the same value, return true if the value is written
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError>;
+
+    // get the value of a key, or set it to f() and return it if the key does not exist, e.g. to fill a cache
+    // the in-memory storages lock the key (or table) while f runs, so f runs once however many callers race,
+    // f must not use the same storage. SledDb doesn't lock, racing callers may all run f, but only one value is set
+    // and all of them get it
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError>
+        where
+            Self: Sized;
+
+    // set all pairs to a table only if it's empty or doesn't exist, return true if they're set, e.g. to seed defaults
+    // the in-memory storages lock the table, so no write can happen between the check and the inserts.
+    // SledDb can't lock a table: the inits are serialized, and the pairs are inserted in a transaction which fails if
+    // any of their keys is written meanwhile, a concurrent write of another key may still land before the inserts
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError>;
+
+    // push values to the head of a list atomically, return the length of the list
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError>;
+
+    // add members to a set atomically, return how many members are added
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError>;
+
+    // remove members from a set atomically, return how many members are removed
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError>;
+
+    // add delta to a float value atomically, return the new value
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError>;
+
+    // replace an integer value with 0 atomically and return the old value, e.g. to report a counter per interval
+    // NotFound if the key doesn't exist, nothing is changed if the value is not an integer
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError>;
 
     // check if a key exists in a table
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
 
     // remove a key from a table, return the old value if exists
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError>;
+
+    // exchange the values of two keys atomically, return the old values of key1 and key2
+    // both keys must exist, otherwise NotFound is returned and nothing is changed
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError>;
+
+    // move a key to another table atomically, the key is never in both tables or neither
+    // the value of the key in `to` is replaced. return the moved value, None if the key doesn't exist in `from`
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError>;
+
+    // remove all keys starting with the prefix from a table, return the number of removed keys
+    // the default implementation deletes the matched keys of get_iter() one by one
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        let keys = self.get_iter(table)?.filter(|pair| pair.key.starts_with(prefix)).map(|pair| pair.key);
+        del_keys(self, table, keys.collect())
+    }
+
+    // remove the keys of a table whose value is a timestamp before the cutoff, return the number of removed keys
+    // only the Timestamp values expire, the cutoff is in milliseconds since the unix epoch like Time returns
+    // the default implementation deletes the expired keys of get_iter() one by one
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        let keys = self.get_iter(table)?.filter(|pair| pair.value.as_ref().is_some_and(|v| expired(v, cutoff)));
+        del_keys(self, table, keys.map(|pair| pair.key).collect())
+    }
 
     // get all KV pairs in a table
-    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError>;
+    fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
 
     // get kv pairs' iterator in a table
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>, KvError>;
+
+    // iterate all KV pairs in all tables, with the table name of each pair
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item = (String, KvPair)>>, KvError>;
+
+    // get KV pairs whose key is in start..end, sorted by key. an empty end means no upper bound
+    // the default implementation filters and sorts get_all(), storages with ordered keys should override it
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        let mut pairs: Vec<KvPair> = self
+            .get_all(table)?
+            .into_iter()
+            .filter(|pair| &pair.key[..] >= start && (end.is_empty() || &pair.key[..] < end))
+            .collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(pairs)
+    }
+
+    // get KV pairs whose key matches the glob pattern, see glob_match() for the syntax
+    // the default implementation filters get_all(), storages may use the literal prefix to scan less data
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        Ok(self
+            .get_all(table)?
+            .into_iter()
+            .filter(|pair| glob_match(pattern, &pair.key))
+            .collect())
+    }
+
+    // get the size of a value without returning it, see Value::size() for how it's counted
+    // the default implementation gets the whole value, storages may get the size without decoding the value
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        Ok(self.get(table, key)?.map(|v| v.size()))
+    }
+
+    // move all data of the `from` table to the `to` table, the old data of the `to` table is removed
+    // if `from` doesn't exist (has no keys), return NotFound and leave `to` as it is.
+    // writes to the tables during the rename may be lost
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError>;
+
+    // remove all data in all tables, return the number of removed keys
+    fn clear(&self) -> Result<u64, KvError>;
+
+    // get the key count and approximate size of a table
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        Ok(self.get_iter(table)?.fold(TableStats::default(), |stats, pair| {
+            let value = pair.value.map_or(0, |v| v.encoded_len());
+            TableStats { keys: stats.keys + 1, bytes: stats.bytes + (pair.key.len() + value) as u64 }
+        }))
+    }
+
+    // index the map values of a table by a field, so query_index can find the keys without a scan
+    // the field path is the keys of the nested maps joined by `.`, e.g. `address.city`
+    // the storages have no index by default, wrap them in an IndexedStore
+    fn create_index(&self, table: &str, field: &str) -> Result<(), KvError> {
+        Err(KvError::InvalidCommand(format!("Cannot index field {} of table {}, the storage has no index", field, table)))
+    }
+
+    // get the pairs of a table whose field equals the value, using the index created by create_index
+    fn query_index(&self, table: &str, field: &str, _value: &Value) -> Result<Vec<KvPair>, KvError> {
+        Err(KvError::InvalidCommand(format!("No index on field {} of table {}", field, table)))
+    }
+
+    // make a key expire after ttl without reading or writing its value, a zero ttl removes the expiry
+    // return false if the key doesn't exist. the storages have no expiry by default, wrap them in an ExpiringStore
+    fn touch(&self, table: &str, key: &[u8], _ttl: Duration) -> Result<bool, KvError> {
+        let key = String::from_utf8_lossy(key);
+        Err(KvError::InvalidCommand(format!("Cannot expire key {} of table {}, the storage has no expiry", key, table)))
+    }
+}
+
+// statistics of a table
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TableStats {
+    // how many keys in the table
+    pub keys: u64,
+    // approximate size: the bytes of the keys plus the encoded values
+    pub bytes: u64,
+}
+
+// check if the key matches the glob pattern, keys are binary so the pattern is matched byte by byte
+// `*` matches any sequence of bytes (including empty), `?` matches exactly one byte
+// all other bytes match themselves
+pub fn glob_match(pattern: &str, key: &[u8]) -> bool {
+    let pattern = pattern.as_bytes();
+    let (mut p, mut k) = (0, 0);
+    // the position of the last `*` in the pattern, and the key position it matched to
+    let mut star: Option<(usize, usize)> = None;
+
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            // mismatch, let the last `*` match one more byte
+            _ => match star {
+                Some((sp, sk)) => {
+                    star = Some((sp, sk + 1));
+                    p = sp + 1;
+                    k = sk + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// the literal part of the glob pattern before the first wildcard
+pub fn glob_prefix(pattern: &str) -> &str {
+    match pattern.find(['*', '?']) {
+        Some(i) => &pattern[..i],
+        None => pattern,
+    }
+}
+
+fn key_not_found(table: &str, key: &[u8]) -> KvError {
+    KvError::NotFound(table.to_string(), String::from_utf8_lossy(key).into())
+}
+
+// a table without keys doesn't exist
+fn table_not_found(table: &str) -> KvError {
+    KvError::NotFound(table.to_string(), "*".into())
+}
+
+// delete the keys one by one, return how many of them are deleted, a key deleted by others meanwhile isn't counted
+fn del_keys<S: Storage + ?Sized>(store: &S, table: &str, keys: Vec<Bytes>) -> Result<u64, KvError> {
+    let mut count = 0;
+    for key in keys {
+        if store.del(table, &key)?.is_some() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// the value is a timestamp before the cutoff, see Storage::expire_before
+fn expired(value: &Value, cutoff: i64) -> bool {
+    matches!(value.value, Some(value::Value::Timestamp(timestamp)) if timestamp < cutoff)
+}
+
+// push values to the head of the old list, the last value becomes the head
+fn lpush_values(old: Option<Value>, values: Vec<Value>) -> Result<Vec<Value>, KvError> {
+    let old: Vec<Value> = match old {
+        Some(v) => v.try_into()?,
+        None => vec![],
+    };
+    Ok(values.into_iter().rev().chain(old).collect())
+}
+
+// add delta to the old number, a missing value counts as 0
+fn add_float(old: Option<Value>, delta: f64) -> Result<f64, KvError> {
+    match old {
+        None => Ok(delta),
+        Some(v) => match i64::try_from(&v) {
+            Ok(i) => Ok(i as f64 + delta),
+            Err(_) => Ok(f64::try_from(&v)? + delta),
+        },
+    }
+}
+
+// add members to the old set, return the new set and how many members are added
+fn sadd_members(old: Option<Value>, members: Vec<Value>) -> Result<(ValueSet, usize), KvError> {
+    let mut set: ValueSet = match old {
+        Some(v) => v.try_into()?,
+        None => ValueSet::default(),
+    };
+    let added = members.into_iter().filter(|m| set.insert(m.clone())).count();
+    Ok((set, added))
+}
+
+// remove members from the old set, return the new set and how many members are removed
+// return None if there is no set, so nothing needs to be written
+fn srem_members(old: Option<Value>, members: &[Value]) -> Result<Option<(ValueSet, usize)>, KvError> {
+    let mut set: ValueSet = match old {
+        Some(v) => v.try_into()?,
+        None => return Ok(None),
+    };
+    let removed = members.iter().filter(|m| set.remove(m)).count();
+    Ok(Some((set, removed)))
 }
 
 pub struct StorageIter<T> {
@@ -52,6 +321,12 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use prost::Message;
     use tempfile::tempdir;
     use crate::storage::sleddb::SledDb;
     use super::*;
@@ -74,6 +349,344 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_set_if_absent_should_work() {
+        let store = MemTable::new();
+        test_set_if_absent(store);
+    }
+
+    #[test]
+    fn memtable_init_if_empty_should_work() {
+        test_init_if_empty(MemTable::new());
+        test_concurrent_init_if_empty(MemTable::new());
+    }
+
+    #[test]
+    fn memtable_get_or_set_with_should_work() {
+        test_get_or_set_with(MemTable::new());
+    }
+
+    #[test]
+    fn memtable_set_if_changed_should_work() {
+        let store = MemTable::new();
+        test_set_if_changed(store);
+    }
+
+    #[test]
+    fn memtable_incr_float_should_work() {
+        test_incr_float(MemTable::new());
+    }
+
+    #[test]
+    fn memtable_get_and_reset_should_work() {
+        test_get_and_reset(MemTable::new());
+    }
+
+    #[test]
+    fn memtable_lpush_should_work() {
+        let store = MemTable::new();
+        test_lpush(store);
+    }
+
+    #[test]
+    fn memtable_sets_should_work() {
+        let store = MemTable::new();
+        test_sets(store);
+    }
+
+    #[test]
+    fn memtable_table_stats_should_work() {
+        let store = MemTable::new();
+        test_table_stats(store);
+    }
+
+    #[test]
+    fn memtable_get_matched_should_work() {
+        let store = MemTable::new();
+        test_get_matched(store);
+    }
+
+    #[test]
+    fn memtable_iter_all_should_work() {
+        let store = MemTable::new();
+        test_iter_all(store);
+    }
+
+    #[test]
+    fn memtable_del_by_prefix_should_work() {
+        let store = MemTable::new();
+        test_del_by_prefix(store);
+    }
+
+    #[test]
+    fn memtable_expire_before_should_work() {
+        let store = MemTable::new();
+        test_expire_before(store);
+    }
+
+    #[test]
+    fn memtable_rename_table_should_work() {
+        let store = MemTable::new();
+        test_rename_table(store);
+    }
+
+    #[test]
+    fn memtable_swap_should_work() {
+        let store = MemTable::new();
+        test_swap(store);
+    }
+
+    #[test]
+    fn memtable_move_key_should_work() {
+        let store = MemTable::new();
+        test_move_key(store);
+    }
+
+    #[test]
+    fn memtable_clear_should_work() {
+        let store = MemTable::new();
+        test_clear(store);
+    }
+
+    #[test]
+    fn memtable_with_memory_limit_should_reject_writes_over_limit() {
+        let store = MemTable::new().with_memory_limit(1024);
+        let value: Value = "x".repeat(256).as_str().into();
+        let mut set = 0;
+        while store.set("t1", format!("key{}", set).into(), value.clone()).is_ok() {
+            set += 1;
+        }
+        assert!(set > 0 && set < 4);
+        assert!(matches!(
+            store.set("t1", "one_more".into(), value.clone()),
+            Err(KvError::MemoryLimitExceeded(1024))
+        ));
+        assert!(store.memory_used().unwrap() <= 1024);
+
+        // the rejected write changes nothing, a smaller value still fits after a key is deleted
+        assert_eq!(store.get("t1", b"one_more").unwrap(), None);
+        store.del("t1", b"key0").unwrap();
+        store.set("t1", "one_more".into(), value).unwrap();
+        assert!(store.lpush("t1", "list".into(), vec!["x".repeat(1024).as_str().into()]).is_err());
+    }
+
+    #[test]
+    fn memtable_memory_used_should_follow_writes() {
+        // every key counts its length, its encoded value and the entry overhead
+        let memory_of = |store: &MemTable| {
+            let overhead = std::mem::size_of::<(Vec<u8>, Value)>();
+            let pairs = store.iter_all().unwrap();
+            pairs.map(|(_, pair)| pair.key.len() + pair.value.unwrap().encoded_len() + overhead).sum::<usize>()
+        };
+
+        let store = MemTable::new().with_memory_limit(usize::MAX);
+        store.set("t1", "hello".into(), "world".into()).unwrap();
+        store.lpush("t1", "list".into(), vec![1.into(), 2.into()]).unwrap();
+        store.sadd("t2", "set".into(), vec!["a".into(), "b".into()]).unwrap();
+        store.incr_float("t2", "float".into(), 1.5).unwrap();
+        store.set("t3", "moved".into(), "value".into()).unwrap();
+        store.move_key("t3", "t1", b"moved").unwrap();
+        store.srem("t2", "set".into(), vec!["a".into()]).unwrap();
+        store.get_and_reset("t2", b"float").unwrap_err();
+        store.set("t2", "counter".into(), 100.into()).unwrap();
+        store.get_and_reset("t2", b"counter").unwrap();
+        assert_eq!(store.memory_used(), Some(memory_of(&store)));
+
+        store.del_by_prefix("t1", b"l").unwrap();
+        store.rename_table("t1", "t2").unwrap();
+        assert_eq!(store.memory_used(), Some(memory_of(&store)));
+
+        store.clear().unwrap();
+        assert_eq!(store.memory_used(), Some(0));
+        assert_eq!(MemTable::new().memory_used(), None);
+    }
+
+    #[test]
+    fn memtable_snapshot_should_not_change() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), 1.into()).unwrap();
+        store.set("t1", "k2".into(), 2.into()).unwrap();
+
+        let snapshot = store.snapshot("t1");
+        store.set("t1", "k1".into(), 10.into()).unwrap();
+        store.set("t1", "k3".into(), 3.into()).unwrap();
+        store.del("t1", b"k2").unwrap();
+
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get(b"k1"), Some(1.into()));
+        assert!(snapshot.contains(b"k2"));
+        assert!(!snapshot.contains(b"k3"));
+        let sum: i64 = snapshot.get_iter().map(|p| i64::try_from(&p.value.unwrap()).unwrap()).sum();
+        assert_eq!(sum, 3);
+
+        let mut pairs = snapshot.get_all();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(pairs, vec![KvPair::new("k1", 1.into()), KvPair::new("k2", 2.into())]);
+
+        assert!(store.snapshot("not_exist").is_empty());
+    }
+
+    #[test]
+    fn btree_memtable_basic_interface_should_work() {
+        let store = BTreeMemTable::new();
+        test_basic_interface(store);
+    }
+
+    #[test]
+    fn btree_memtable_get_all_should_work() {
+        let store = BTreeMemTable::new();
+        test_get_all(store);
+    }
+
+    #[test]
+    fn btree_memtable_iter_should_work() {
+        let store = BTreeMemTable::new();
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn btree_memtable_set_if_absent_should_work() {
+        let store = BTreeMemTable::new();
+        test_set_if_absent(store);
+    }
+
+    #[test]
+    fn btree_memtable_init_if_empty_should_work() {
+        test_init_if_empty(BTreeMemTable::new());
+        test_concurrent_init_if_empty(BTreeMemTable::new());
+    }
+
+    #[test]
+    fn btree_memtable_get_or_set_with_should_work() {
+        test_get_or_set_with(BTreeMemTable::new());
+    }
+
+    #[test]
+    fn btree_memtable_set_if_changed_should_work() {
+        let store = BTreeMemTable::new();
+        test_set_if_changed(store);
+    }
+
+    #[test]
+    fn btree_memtable_incr_float_should_work() {
+        test_incr_float(BTreeMemTable::new());
+    }
+
+    #[test]
+    fn btree_memtable_get_and_reset_should_work() {
+        test_get_and_reset(BTreeMemTable::new());
+    }
+
+    #[test]
+    fn btree_memtable_lpush_should_work() {
+        let store = BTreeMemTable::new();
+        test_lpush(store);
+    }
+
+    #[test]
+    fn btree_memtable_sets_should_work() {
+        let store = BTreeMemTable::new();
+        test_sets(store);
+    }
+
+    #[test]
+    fn btree_memtable_iter_all_should_work() {
+        let store = BTreeMemTable::new();
+        test_iter_all(store);
+    }
+
+    #[test]
+    fn btree_memtable_del_by_prefix_should_work() {
+        let store = BTreeMemTable::new();
+        test_del_by_prefix(store);
+    }
+
+    #[test]
+    fn btree_memtable_expire_before_should_work() {
+        let store = BTreeMemTable::new();
+        test_expire_before(store);
+    }
+
+    #[test]
+    fn btree_memtable_rename_table_should_work() {
+        let store = BTreeMemTable::new();
+        test_rename_table(store);
+    }
+
+    #[test]
+    fn btree_memtable_swap_should_work() {
+        let store = BTreeMemTable::new();
+        test_swap(store);
+    }
+
+    #[test]
+    fn btree_memtable_move_key_should_work() {
+        let store = BTreeMemTable::new();
+        test_move_key(store);
+    }
+
+    #[test]
+    fn btree_memtable_clear_should_work() {
+        let store = BTreeMemTable::new();
+        test_clear(store);
+    }
+
+    #[test]
+    fn btree_memtable_table_stats_should_work() {
+        let store = BTreeMemTable::new();
+        test_table_stats(store);
+    }
+
+    #[test]
+    fn get_range_should_be_same_as_naive_implementation() {
+        let naive = MemTable::new();
+        let btree = BTreeMemTable::new();
+        let dir = tempdir().unwrap();
+        let sled = SledDb::new(dir);
+        for store in [&naive as &dyn Storage, &btree, &sled] {
+            for key in ["d", "a", "c", "b", "e", "ab"] {
+                store.set("range", key.into(), key.into()).unwrap();
+            }
+            store.set("range2", "b".into(), "other table".into()).unwrap();
+        }
+
+        for (start, end) in [("a", "c"), ("ab", "e"), ("b", ""), ("", ""), ("c", "a"), ("x", "")] {
+            let (start, end) = (start.as_bytes(), end.as_bytes());
+            let expected = naive.get_range("range", start, end).unwrap();
+            assert_eq!(btree.get_range("range", start, end).unwrap(), expected);
+            assert_eq!(sled.get_range("range", start, end).unwrap(), expected);
+        }
+
+        let keys: Vec<_> = naive.get_range("range", b"ab", b"d").unwrap().into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec!["ab", "b", "c"]);
+    }
+
+    #[test]
+    fn btree_memtable_get_matched_should_work() {
+        let store = BTreeMemTable::new();
+        test_get_matched(store);
+    }
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("user:*:active", b"user:1:active"));
+        assert!(glob_match("user:*:active", b"user::active"));
+        assert!(!glob_match("user:*:active", b"user:1:inactive"));
+        assert!(glob_match("k?", b"k1"));
+        assert!(!glob_match("k?", b"k"));
+        assert!(!glob_match("k?", b"k12"));
+        assert!(glob_match("*", b""));
+        assert!(glob_match("a*b*c", b"aXbYbZc"));
+        assert!(!glob_match("a*b*c", b"aXbYbZ"));
+        assert!(glob_match("exact", b"exact"));
+        assert!(!glob_match("exact", b"exactly"));
+
+        assert_eq!(glob_prefix("user:*:active"), "user:");
+        assert_eq!(glob_prefix("k?"), "k");
+        assert_eq!(glob_prefix("*"), "");
+        assert_eq!(glob_prefix("exact"), "exact");
+    }
+
     #[test]
     fn sleddb_basic_interface_should_work() {
         let dir = tempdir().unwrap();
@@ -95,18 +708,966 @@ mod tests {
         test_get_iter(store);
     }
 
-    fn test_basic_interface(store: impl Storage) {
-        let table = "test_table";
-        let key = "test_key";
-        let value = "test_value";
-        assert_eq!(None, store.get(table, key).unwrap());
-        assert_eq!(None, store.set(table, key.to_string(), value.into()).unwrap());
-        assert_eq!(store.get(table, key).unwrap(), Some(value.into()));
-        assert!(store.contains(table, key).unwrap());
-        assert_eq!(store.del(table, key).unwrap(), Some(value.into()));
-        assert_eq!(None, store.del(table, key).unwrap());
-        assert_eq!(None, store.get(table, key).unwrap());
-        assert!(!store.contains(table, key).unwrap());
+    #[test]
+    fn sleddb_set_if_absent_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_set_if_absent(store);
+    }
+
+    #[test]
+    fn sleddb_init_if_empty_should_work() {
+        let dir = tempdir().unwrap();
+        test_init_if_empty(SledDb::new(dir.path().join("plain")));
+        test_init_if_empty(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+        test_concurrent_init_if_empty(SledDb::new(dir.path().join("concurrent")));
+    }
+
+    #[test]
+    fn sleddb_get_or_set_with_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_or_set_with(SledDb::new(dir));
+    }
+
+    #[test]
+    fn sleddb_set_if_changed_should_work() {
+        let dir = tempdir().unwrap();
+        test_set_if_changed(SledDb::new(dir.path().join("plain")));
+        test_set_if_changed(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_incr_float_should_work() {
+        let dir = tempdir().unwrap();
+        test_incr_float(SledDb::new(dir.path().join("plain")));
+        test_incr_float(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_get_and_reset_should_work() {
+        let dir = tempdir().unwrap();
+        test_get_and_reset(SledDb::new(dir.path().join("plain")));
+        test_get_and_reset(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_lpush_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_lpush(store);
+    }
+
+    #[test]
+    fn sleddb_sets_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_sets(store);
+    }
+
+    #[test]
+    fn sleddb_table_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_table_stats(store);
+    }
+
+    #[test]
+    fn sleddb_get_matched_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_matched(store);
+    }
+
+    #[test]
+    fn sleddb_iter_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_iter_all(store);
+    }
+
+    #[test]
+    fn sleddb_del_by_prefix_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_del_by_prefix(store);
+    }
+
+    #[test]
+    fn sleddb_expire_before_should_work() {
+        let dir = tempdir().unwrap();
+        test_expire_before(SledDb::new(dir.path().join("plain")));
+        test_expire_before(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_rename_table_should_work() {
+        let dir = tempdir().unwrap();
+        test_rename_table(SledDb::new(dir.path().join("plain")));
+        test_rename_table(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_swap_should_work() {
+        let dir = tempdir().unwrap();
+        test_swap(SledDb::new(dir.path().join("plain")));
+        test_swap(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_move_key_should_work() {
+        let dir = tempdir().unwrap();
+        test_move_key(SledDb::new(dir.path().join("plain")));
+        test_move_key(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_with_bincode_codec_should_work() {
+        let dir = tempdir().unwrap();
+        let new_store = |name: &str| SledDb::new(dir.path().join(name)).with_codec(BincodeCodec);
+        test_basic_interface(new_store("basic"));
+        test_get_all(new_store("get_all"));
+        test_get_iter(new_store("get_iter"));
+        test_incr_float(new_store("incr_float"));
+        test_lpush(new_store("lpush"));
+        test_sets(new_store("sets"));
+        test_value_size(new_store("value_size"));
+        test_iter_all(new_store("iter_all"));
+        let encrypted = SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]);
+        test_value_size(encrypted.with_codec(BincodeCodec));
+    }
+
+    #[test]
+    fn sleddb_should_read_values_with_its_codec() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path()).with_codec(BincodeCodec);
+        store.set("t1", "k1".into(), vec![1.into(), "two".into()].into()).unwrap();
+        let data = store.db().get(SledDb::get_full_key("t1", b"k1")).unwrap().unwrap();
+        assert_eq!(BincodeCodec.decode(&data).unwrap(), vec![1.into(), "two".into()].into());
+        assert!(data.len() < ProtobufCodec.encode(vec![1.into(), "two".into()].into()).unwrap().len());
+        drop(store);
+
+        let store = SledDb::new(dir.path()).with_codec(BincodeCodec);
+        assert_eq!(store.get("t1", b"k1").unwrap(), Some(vec![1.into(), "two".into()].into()));
+    }
+
+    #[test]
+    fn sleddb_clear_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_clear(store);
+    }
+
+    #[test]
+    fn sleddb_should_persist_data_after_drop() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        drop(store);
+
+        let store = SledDb::new(dir.path());
+        assert_eq!(store.get("t1", b"k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn sleddb_should_flush_on_drop() {
+        let dir = tempdir().unwrap();
+        // no flush in the background, the writes are only flushed when asked
+        let db = sled::Config::new().path(dir.path()).flush_every_ms(None).open().unwrap();
+        let store = SledDb::try_from(db.clone()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        drop(store);
+
+        // the db is still open, sled hasn't flushed it, so nothing is left to flush only if SledDb did
+        assert_eq!(db.flush().unwrap(), 0);
+    }
+
+    #[test]
+    fn encrypted_sleddb_should_work() {
+        let key = [7u8; 32];
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path().join("basic")).with_encryption_key(&key);
+        test_basic_interface(store);
+        let store = SledDb::new(dir.path().join("get_all")).with_encryption_key(&key);
+        test_get_all(store);
+        let store = SledDb::new(dir.path().join("iter")).with_encryption_key(&key);
+        test_get_iter(store);
+        let store = SledDb::new(dir.path().join("lpush")).with_encryption_key(&key);
+        test_lpush(store);
+        let store = SledDb::new(dir.path().join("sets")).with_encryption_key(&key);
+        test_sets(store);
+        let store = SledDb::new(dir.path().join("iter_all")).with_encryption_key(&key);
+        test_iter_all(store);
+    }
+
+    #[test]
+    fn encrypted_sleddb_should_save_ciphertext() {
+        let key = [7u8; 32];
+        let dir = tempdir().unwrap();
+        // share the db, so we can look at the raw data without reopening it
+        let db = sled::open(dir.path()).unwrap();
+        let secret = "my secret data";
+
+        let store = SledDb::try_from(db.clone()).unwrap().with_encryption_key(&key);
+        store.set("users", "alice".into(), secret.into()).unwrap();
+        assert_eq!(store.get("users", b"alice").unwrap(), Some(secret.into()));
+        assert_eq!(store.get_all("users").unwrap(), vec![KvPair::new("alice", secret.into())]);
+
+        // the raw data on disk should not contain the plaintext
+        let raw = db.get(SledDb::get_full_key("users", b"alice")).unwrap().unwrap();
+        assert!(!raw.windows(secret.len()).any(|w| w == secret.as_bytes()));
+
+        // the value is bound to its key, a copy under another key can't be decrypted
+        db.insert(SledDb::get_full_key("users", b"bob"), raw).unwrap();
+        assert!(matches!(store.get("users", b"bob"), Err(KvError::CryptoError)));
+        assert!(matches!(store.get_all("users"), Err(KvError::CryptoError)));
+        db.remove(SledDb::get_full_key("users", b"bob")).unwrap();
+
+        // a wrong key can't decrypt the value, the scans fail and the iterators count the skipped entries
+        let store = SledDb::try_from(db).unwrap().with_encryption_key(&[8u8; 32]);
+        assert!(matches!(store.get("users", b"alice"), Err(KvError::CryptoError)));
+        assert!(matches!(store.get_all("users"), Err(KvError::CryptoError)));
+        assert!(matches!(store.get_range("users", b"", b""), Err(KvError::CryptoError)));
+        assert!(matches!(store.get_matched("users", "a*"), Err(KvError::CryptoError)));
+        assert_eq!(store.get_iter("users").unwrap().count(), 0);
+        assert_eq!(store.iter_all().unwrap().count(), 0);
+        assert_eq!(store.skipped_entries(), 2);
+    }
+
+    #[test]
+    fn memtable_binary_keys_should_work() {
+        let store = MemTable::new();
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn btree_memtable_binary_keys_should_work() {
+        let store = BTreeMemTable::new();
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn sleddb_binary_keys_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_binary_keys(store);
+    }
+
+    #[test]
+    fn memtable_value_size_should_work() {
+        let store = MemTable::new();
+        test_value_size(store);
+    }
+
+    #[test]
+    fn sleddb_value_size_should_work() {
+        let dir = tempdir().unwrap();
+        test_value_size(SledDb::new(dir.path().join("plain")));
+        test_value_size(SledDb::new(dir.path().join("encrypted")).with_encryption_key(&[7u8; 32]));
+    }
+
+    #[test]
+    fn sleddb_should_migrate_keys_without_format_version() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        // the keys and values of a db written before the format version
+        for (key, value) in [("t1:k1", Value::from("v1")), ("t1:k2", 2.into()), ("t2:k1", true.into())] {
+            db.insert(key, Vec::<u8>::try_from(value).unwrap()).unwrap();
+        }
+
+        let store = SledDb::try_from(db.clone()).unwrap();
+        assert_eq!(store.get("t1", b"k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get_all("t1").unwrap(), vec![KvPair::new("k1", "v1".into()), KvPair::new("k2", 2.into())]);
+        assert_eq!(store.get_all("t2").unwrap(), vec![KvPair::new("k1", true.into())]);
+        assert!(db.get("t1:k1").unwrap().is_none());
+
+        // the version is saved, the db is not migrated again
+        store.set("t3", b"no:version".to_vec(), 3.into()).unwrap();
+        let store = SledDb::try_from(db).unwrap();
+        assert_eq!(store.get("t3", b"no:version").unwrap(), Some(3.into()));
+        assert_eq!(store.iter_all().unwrap().count(), 4);
+    }
+
+    #[test]
+    fn sleddb_should_split_keys_without_format_version_at_first_colon() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        db.insert("users:alice:1", Vec::<u8>::try_from(Value::from(1)).unwrap()).unwrap();
+        db.insert("users::", Vec::<u8>::try_from(Value::from(2)).unwrap()).unwrap();
+        db.insert("users:", Vec::<u8>::try_from(Value::from(3)).unwrap()).unwrap();
+
+        let store = SledDb::try_from(db).unwrap();
+        let mut pairs = store.get_all("users").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        let expected = vec![KvPair::new("", 3.into()), KvPair::new(":", 2.into()), KvPair::new("alice:1", 1.into())];
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn sleddb_should_refuse_unknown_format() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        // a key which isn't `table:key` can't be migrated, nothing is changed
+        db.insert(b"\xff\xfe", b"v1".to_vec()).unwrap();
+        assert!(matches!(SledDb::try_from(db.clone()), Err(KvError::StorageFormat(_))));
+        assert!(db.get(b"\xff\xfe").unwrap().is_some());
+        db.clear().unwrap();
+
+        // a db written by a newer version
+        db.open_tree("__meta__").unwrap().insert("format_version", &2u32.to_be_bytes()).unwrap();
+        assert!(matches!(SledDb::try_from(db), Err(KvError::StorageFormat(_))));
+    }
+
+    #[test]
+    fn sleddb_should_skip_malformed_keys() {
+        let dir = tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        let store = SledDb::try_from(db.clone()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+
+        // keys not written by SledDb: too short, a table longer than the key, and a table not in utf8
+        let value: Vec<u8> = Value::from("bad").try_into().unwrap();
+        db.insert(b"\x00", value.clone()).unwrap();
+        db.insert(b"\x00\x00\x00\x09t1", value.clone()).unwrap();
+        db.insert(b"\x00\x00\x00\x02\xff\xfek1", value).unwrap();
+
+        let expected = vec![KvPair::new("k1", "v1".into())];
+        assert_eq!(store.get_all("t1").unwrap(), expected);
+        assert_eq!(store.get_iter("t1").unwrap().collect::<Vec<_>>(), expected);
+        let items: Vec<_> = store.iter_all().unwrap().collect();
+        assert_eq!(items, vec![("t1".to_string(), KvPair::new("k1", "v1".into()))]);
+    }
+
+    #[test]
+    fn write_coalescer_should_work() {
+        let dir = tempdir().unwrap();
+        let new_store = |name: &str| {
+            WriteCoalescer::new(SledDb::new(dir.path().join(name)), Duration::from_millis(5), 64)
+        };
+        test_basic_interface(new_store("basic"));
+        test_get_all(new_store("get_all"));
+        test_get_iter(new_store("iter"));
+        test_set_if_absent(new_store("set_if_absent"));
+        test_init_if_empty(new_store("init_if_empty"));
+        test_get_or_set_with(new_store("get_or_set_with"));
+        test_set_if_changed(new_store("set_if_changed"));
+        test_lpush(new_store("lpush"));
+        test_sets(new_store("sets"));
+        test_move_key(new_store("move_key"));
+        test_expire_before(new_store("expire_before"));
+        test_iter_all(new_store("iter_all"));
+    }
+
+    #[test]
+    fn write_coalescer_should_batch_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let store = Arc::new(WriteCoalescer::new(SledDb::new(dir), Duration::from_millis(50), 64));
+
+        let handles: Vec<_> = (0..32)
+            .map(|i| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    assert_eq!(store.set("t1", format!("k{}", i).into(), i.into()).unwrap(), None);
+                    // return the old value of the shared key
+                    store.set("t1", "shared".into(), i.into()).unwrap()
+                })
+            })
+            .collect();
+        let olds: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+        // the writes are readable after set returns, and they're written in much fewer batches
+        assert_eq!(store.get_all("t1").unwrap().len(), 33);
+        assert!(store.batches() < 32, "batches: {}", store.batches());
+
+        // writes of the same key in a batch see the earlier writes' values, so only the first one gets None
+        assert_eq!(olds.iter().filter(|old| old.is_none()).count(), 1);
+
+        assert_eq!(store.del("t1", b"k1").unwrap(), Some(1.into()));
+        assert_eq!(store.del("t1", b"k1").unwrap(), None);
+    }
+
+    #[test]
+    fn mirrored_store_should_work() {
+        let dir = tempdir().unwrap();
+        let new_store = |name: &str| MirroredStore::new(MemTable::new(), SledDb::new(dir.path().join(name)));
+        test_basic_interface(new_store("basic"));
+        test_get_all(new_store("get_all"));
+        test_set_if_absent(new_store("set_if_absent"));
+        test_init_if_empty(new_store("init_if_empty"));
+        test_get_or_set_with(new_store("get_or_set_with"));
+        test_set_if_changed(new_store("set_if_changed"));
+        test_get_and_reset(new_store("get_and_reset"));
+        test_lpush(new_store("lpush"));
+        test_sets(new_store("sets"));
+        test_swap(new_store("swap"));
+        test_move_key(new_store("move_key"));
+        test_del_by_prefix(new_store("del_by_prefix"));
+        test_expire_before(new_store("expire_before"));
+        test_rename_table(new_store("rename_table"));
+        test_clear(new_store("clear"));
+    }
+
+    #[test]
+    fn case_folding_store_should_work() {
+        let new_store = || CaseFoldingStore::new(MemTable::new());
+        test_basic_interface(new_store());
+        test_get_all(new_store());
+        test_set_if_absent(new_store());
+        test_init_if_empty(new_store());
+        test_swap(new_store());
+        test_move_key(new_store());
+        test_del_by_prefix(new_store());
+        test_rename_table(new_store());
+    }
+
+    #[test]
+    fn expiring_store_should_work() {
+        let new_store = || ExpiringStore::new(MemTable::new());
+        test_basic_interface(new_store());
+        test_get_all(new_store());
+        test_set_if_absent(new_store());
+        test_init_if_empty(new_store());
+        test_lpush(new_store());
+        test_sets(new_store());
+        test_swap(new_store());
+        test_move_key(new_store());
+        test_del_by_prefix(new_store());
+        test_expire_before(new_store());
+        test_rename_table(new_store());
+        test_clear(new_store());
+        test_table_stats(new_store());
+    }
+
+    #[test]
+    fn case_folding_store_should_ignore_case() {
+        let store = CaseFoldingStore::new(MemTable::new());
+        assert_eq!(store.set("Users", "Alice".into(), "v1".into()).unwrap(), None);
+        assert_eq!(store.set("USERS", "ALICE".into(), "v2".into()).unwrap(), Some("v1".into()));
+        assert_eq!(store.get("users", b"alice").unwrap(), Some("v2".into()));
+        store.set("users", "ÄBC".into(), "v3".into()).unwrap();
+        assert_eq!(store.get("users", "äbc".as_bytes()).unwrap(), Some("v3".into()));
+
+        // the keys are saved normalized
+        let mut keys: Vec<_> = store.get_all("Users").unwrap().into_iter().map(|pair| pair.key).collect();
+        keys.sort();
+        assert_eq!(keys, vec![&b"alice"[..], "äbc".as_bytes()]);
+        assert_eq!(store.inner().get("users", b"alice").unwrap(), Some("v2".into()));
+        assert_eq!(store.inner().get("Users", b"Alice").unwrap(), None);
+
+        // binary keys only have their ASCII letters folded
+        store.set("users", vec![0xff, b'A'], "v4".into()).unwrap();
+        assert_eq!(store.get("users", &[0xff, b'a']).unwrap(), Some("v4".into()));
+        assert_eq!(store.del_by_prefix("USERS", b"AL").unwrap(), 1);
+    }
+
+    #[test]
+    fn mirrored_store_should_apply_writes_to_secondary() {
+        let dir = tempdir().unwrap();
+        let store = MirroredStore::new(MemTable::new(), SledDb::new(dir))
+            .with_failure_policy(MirrorFailurePolicy::Error);
+
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert!(store.set_if_absent("t1", "k3".into(), "v3".into()).unwrap());
+        store.lpush("t1", "list".into(), vec![1.into(), 2.into()]).unwrap();
+        store.sadd("t1", "set".into(), vec!["a".into(), "b".into()]).unwrap();
+        store.srem("t1", "set".into(), vec!["a".into()]).unwrap();
+        store.incr_float("t1", "float".into(), 1.5).unwrap();
+        store.swap("t1", b"k1", b"k2").unwrap();
+        store.del("t1", b"k3").unwrap();
+
+        let mut primary = store.primary().get_all("t1").unwrap();
+        let mut secondary = store.secondary().get_all("t1").unwrap();
+        primary.sort_by(|a, b| a.key.cmp(&b.key));
+        secondary.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(primary.len(), 5);
+        assert_eq!(primary, secondary);
+
+        store.rename_table("t1", "t2").unwrap();
+        assert_eq!(store.secondary().get("t2", b"k1").unwrap(), Some("v2".into()));
+        store.clear().unwrap();
+        assert!(store.secondary().get_all("t2").unwrap().is_empty());
+    }
+
+    // a storage with only the required methods, the others use the default implementations
+    struct RequiredOnly(MemTable);
+
+    impl Storage for RequiredOnly {
+        fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+            self.0.get(table, key)
+        }
+
+        fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
+            self.0.set(table, key, value)
+        }
+
+        fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+            self.0.set_if_absent(table, key, value)
+        }
+
+        fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+            self.0.set_if_changed(table, key, value)
+        }
+
+        fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+            self.0.get_or_set_with(table, key, f)
+        }
+
+        fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+            self.0.init_if_empty(table, pairs)
+        }
+
+        fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+            self.0.lpush(table, key, values)
+        }
+
+        fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+            self.0.sadd(table, key, members)
+        }
+
+        fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+            self.0.srem(table, key, members)
+        }
+
+        fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+            self.0.incr_float(table, key, delta)
+        }
+
+        fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+            self.0.get_and_reset(table, key)
+        }
+
+        fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+            self.0.del(table, key)
+        }
+
+        fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+            self.0.swap(table, key1, key2)
+        }
+
+        fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+            self.0.move_key(from, to, key)
+        }
+
+        fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>, KvError> {
+            self.0.get_iter(table)
+        }
+
+        fn iter_all(&self) -> Result<Box<dyn Iterator<Item = (String, KvPair)>>, KvError> {
+            self.0.iter_all()
+        }
+
+        fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+            self.0.rename_table(from, to)
+        }
+
+        fn clear(&self) -> Result<u64, KvError> {
+            self.0.clear()
+        }
+    }
+
+    #[test]
+    fn default_methods_should_work() {
+        test_basic_interface(RequiredOnly(MemTable::new()));
+        test_get_all(RequiredOnly(MemTable::new()));
+        test_table_stats(RequiredOnly(MemTable::new()));
+        test_del_by_prefix(RequiredOnly(MemTable::new()));
+        test_expire_before(RequiredOnly(MemTable::new()));
+        test_get_matched(RequiredOnly(MemTable::new()));
+        test_value_size(RequiredOnly(MemTable::new()));
+    }
+
+    fn test_basic_interface(store: impl Storage) {
+        let table = "test_table";
+        let key = b"test_key";
+        let value = "test_value";
+        assert_eq!(None, store.get(table, key).unwrap());
+        assert_eq!(None, store.set(table, key.to_vec(), value.into()).unwrap());
+        assert_eq!(store.get(table, key).unwrap(), Some(value.into()));
+        assert!(store.contains(table, key).unwrap());
+        assert_eq!(store.del(table, key).unwrap(), Some(value.into()));
+        assert_eq!(None, store.del(table, key).unwrap());
+        assert_eq!(None, store.get(table, key).unwrap());
+        assert!(!store.contains(table, key).unwrap());
+    }
+
+    fn test_init_if_empty(store: impl Storage) {
+        let pairs = vec![KvPair::new("k1", "v1".into()), KvPair::new("k2", 2.into())];
+        assert!(store.init_if_empty("seed", pairs.clone()).unwrap());
+        assert!(!store.init_if_empty("seed", vec![KvPair::new("k3", "v3".into())]).unwrap());
+        let mut all = store.get_all("seed").unwrap();
+        all.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(all, pairs);
+
+        // a table with any key is not initialized, its keys are kept
+        store.set("used", "k1".into(), "old".into()).unwrap();
+        assert!(!store.init_if_empty("used", pairs).unwrap());
+        assert_eq!(store.get("used", b"k1").unwrap(), Some("old".into()));
+        assert_eq!(store.get("used", b"k2").unwrap(), None);
+    }
+
+    // the racing inits seed different keys, only one of them may see the empty table
+    fn test_concurrent_init_if_empty(store: impl Storage + Send + Sync + 'static) {
+        let store = Arc::new(store);
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = Arc::clone(&store);
+                let pairs = vec![KvPair::new(format!("k{}", i), i.into())];
+                thread::spawn(move || store.init_if_empty("seed", pairs).unwrap())
+            })
+            .collect();
+        let initialized = handles.into_iter().map(|handle| handle.join().unwrap());
+        assert_eq!(initialized.filter(|initialized| *initialized).count(), 1);
+        assert_eq!(store.get_all("seed").unwrap().len(), 1);
+    }
+
+    fn test_set_if_absent(store: impl Storage) {
+        let table = "lock";
+        assert!(store.set_if_absent(table, "k1".into(), "v1".into()).unwrap());
+        assert!(!store.set_if_absent(table, "k1".into(), "v2".into()).unwrap());
+        assert_eq!(store.get(table, b"k1").unwrap(), Some("v1".into()));
+    }
+
+    fn test_get_or_set_with(store: impl Storage + Send + Sync + 'static) {
+        let table = "cache";
+        assert_eq!(store.get_or_set_with(table, "k1".into(), || "v1".into()).unwrap(), "v1".into());
+        assert_eq!(store.get_or_set_with(table, "k1".into(), || "v2".into()).unwrap(), "v1".into());
+        assert_eq!(store.get(table, b"k1").unwrap(), Some("v1".into()));
+
+        // all concurrent callers get the same value
+        let store = Arc::new(store);
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || store.get_or_set_with(table, "k2".into(), || i.into()).unwrap())
+            })
+            .collect();
+        let values: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert!(values.iter().all(|v| *v == values[0]));
+        assert_eq!(store.get(table, b"k2").unwrap(), Some(values[0].clone()));
+    }
+
+    fn test_set_if_changed(store: impl Storage) {
+        let table = "changed";
+        assert!(store.set_if_changed(table, "k1".into(), "v1".into()).unwrap());
+        assert!(!store.set_if_changed(table, "k1".into(), "v1".into()).unwrap());
+        assert!(store.set_if_changed(table, "k1".into(), "v2".into()).unwrap());
+        assert_eq!(store.get(table, b"k1").unwrap(), Some("v2".into()));
+
+        // the same data with a different type is a change
+        store.set(table, "k2".into(), Value::from(b"1")).unwrap();
+        assert!(store.set_if_changed(table, "k2".into(), "1".into()).unwrap());
+    }
+
+    fn test_incr_float(store: impl Storage + Send + Sync + 'static) {
+        let table = "counter";
+        assert_eq!(store.incr_float(table, "k1".into(), 1.5).unwrap(), 1.5);
+        assert_eq!(store.incr_float(table, "k1".into(), -0.25).unwrap(), 1.25);
+        assert_eq!(store.get(table, b"k1").unwrap(), Some(1.25.into()));
+
+        // an integer is converted to float
+        store.set(table, "k2".into(), 2.into()).unwrap();
+        assert_eq!(store.incr_float(table, "k2".into(), 0.5).unwrap(), 2.5);
+
+        store.set(table, "k3".into(), "v3".into()).unwrap();
+        assert!(store.incr_float(table, "k3".into(), 1.0).is_err());
+        assert_eq!(store.get(table, b"k3").unwrap(), Some("v3".into()));
+
+        // no increment is lost when they run concurrently
+        let store = Arc::new(store);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        store.incr_float(table, "k4".into(), 0.5).unwrap();
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+        assert_eq!(store.get(table, b"k4").unwrap(), Some(200.0.into()));
+    }
+
+    fn test_get_and_reset(store: impl Storage + Send + Sync + 'static) {
+        let table = "counter";
+        assert!(matches!(store.get_and_reset(table, b"k1"), Err(KvError::NotFound(..))));
+        assert_eq!(store.get(table, b"k1").unwrap(), None);
+
+        store.set(table, "k1".into(), 5.into()).unwrap();
+        assert_eq!(store.get_and_reset(table, b"k1").unwrap(), 5);
+        assert_eq!(store.get_and_reset(table, b"k1").unwrap(), 0);
+        assert_eq!(store.get(table, b"k1").unwrap(), Some(0.into()));
+
+        store.set(table, "k2".into(), 1.5.into()).unwrap();
+        assert!(matches!(store.get_and_reset(table, b"k2"), Err(KvError::ConvertError(..))));
+        assert_eq!(store.get(table, b"k2").unwrap(), Some(1.5.into()));
+
+        // only one of the concurrent resets gets the old value
+        let store = Arc::new(store);
+        store.set(table, "k3".into(), 100.into()).unwrap();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || store.get_and_reset(table, b"k3").unwrap())
+            })
+            .collect();
+        let olds: Vec<i64> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(olds.iter().sum::<i64>(), 100);
+        assert_eq!(olds.iter().filter(|old| **old == 100).count(), 1);
+    }
+
+    fn test_lpush(store: impl Storage) {
+        let table = "list";
+        assert_eq!(store.lpush(table, "k1".into(), vec![1.into(), 2.into()]).unwrap(), 2);
+        assert_eq!(store.lpush(table, "k1".into(), vec![3.into()]).unwrap(), 3);
+        let list: Value = vec![3.into(), 2.into(), 1.into()].into();
+        assert_eq!(store.get(table, b"k1").unwrap(), Some(list));
+
+        // push to a non-list value should fail
+        store.set(table, "k2".into(), "v2".into()).unwrap();
+        assert!(store.lpush(table, "k2".into(), vec![1.into()]).is_err());
+    }
+
+    fn test_sets(store: impl Storage) {
+        let table = "set";
+        assert_eq!(store.sadd(table, "k1".into(), vec![3.into(), 1.into(), 3.into()]).unwrap(), 2);
+        assert_eq!(store.sadd(table, "k1".into(), vec![1.into(), 2.into()]).unwrap(), 1);
+        let set: Value = ValueSet::new(vec![1.into(), 2.into(), 3.into()]).into();
+        assert_eq!(store.get(table, b"k1").unwrap(), Some(set));
+
+        assert_eq!(store.srem(table, "k1".into(), vec![2.into(), 4.into()]).unwrap(), 1);
+        let set: Value = ValueSet::new(vec![1.into(), 3.into()]).into();
+        assert_eq!(store.get(table, b"k1").unwrap(), Some(set));
+
+        // removing from a missing set removes nothing and doesn't create it
+        assert_eq!(store.srem(table, "k2".into(), vec![1.into()]).unwrap(), 0);
+        assert_eq!(store.get(table, b"k2").unwrap(), None);
+
+        // add to a non-set value should fail
+        store.set(table, "k3".into(), "v3".into()).unwrap();
+        assert!(store.sadd(table, "k3".into(), vec![1.into()]).is_err());
+        assert!(store.srem(table, "k3".into(), vec![1.into()]).is_err());
+    }
+
+    fn test_table_stats(store: impl Storage) {
+        assert_eq!(store.table_stats("t4").unwrap(), TableStats::default());
+
+        let v1: Value = "v1".into();
+        let v2: Value = 10.into();
+        let bytes = 2 + v1.encoded_len() + 2 + v2.encoded_len();
+        store.set("t4", "k1".into(), v1).unwrap();
+        store.set("t4", "k2".into(), v2).unwrap();
+        store.set("t5", "k1".into(), "other table".into()).unwrap();
+
+        let stats = store.table_stats("t4").unwrap();
+        assert_eq!(stats, TableStats { keys: 2, bytes: bytes as u64 });
+    }
+
+    fn test_move_key(store: impl Storage) {
+        store.set("pending", "job1".into(), "v1".into()).unwrap();
+        store.set("done", "job1".into(), "old".into()).unwrap();
+        assert_eq!(store.move_key("pending", "done", b"job1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("pending", b"job1").unwrap(), None);
+        assert_eq!(store.get("done", b"job1").unwrap(), Some("v1".into()));
+
+        // nothing is changed if the key doesn't exist
+        assert_eq!(store.move_key("pending", "done", b"job1").unwrap(), None);
+        assert_eq!(store.move_key("unknown", "done", b"job1").unwrap(), None);
+        assert_eq!(store.get("done", b"job1").unwrap(), Some("v1".into()));
+
+        assert_eq!(store.move_key("done", "done", b"job1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("done", b"job1").unwrap(), Some("v1".into()));
+    }
+
+    fn test_expire_before(store: impl Storage) {
+        store.set("events", b"e1".to_vec(), Value::timestamp(1_000)).unwrap();
+        store.set("events", b"e2".to_vec(), Value::timestamp(2_000)).unwrap();
+        store.set("events", b"e3".to_vec(), Value::timestamp(3_000)).unwrap();
+        store.set("events", b"name".to_vec(), "not a timestamp".into()).unwrap();
+        // a counter is an integer, not a timestamp
+        store.set("events", b"count".to_vec(), 3.into()).unwrap();
+        store.set("others", b"e1".to_vec(), Value::timestamp(1_000)).unwrap();
+
+        // the cutoff itself is kept
+        assert_eq!(store.expire_before("events", 2_000).unwrap(), 1);
+        let mut pairs = store.get_all("events").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected = vec![
+            KvPair::new("count", 3.into()),
+            KvPair::new("e2", Value::timestamp(2_000)),
+            KvPair::new("e3", Value::timestamp(3_000)),
+            KvPair::new("name", "not a timestamp".into()),
+        ];
+        assert_eq!(pairs, expected);
+        // other tables are not touched
+        assert_eq!(store.get("others", b"e1").unwrap(), Some(Value::timestamp(1_000)));
+
+        assert_eq!(store.expire_before("events", i64::MAX).unwrap(), 2);
+        assert_eq!(store.get("events", b"name").unwrap(), Some("not a timestamp".into()));
+        assert_eq!(store.get("events", b"count").unwrap(), Some(3.into()));
+        assert_eq!(store.expire_before("not_exist", i64::MAX).unwrap(), 0);
+    }
+
+    fn test_del_by_prefix(store: impl Storage) {
+        store.set("t15", b"session:1".to_vec(), 1.into()).unwrap();
+        store.set("t15", b"session:2".to_vec(), 2.into()).unwrap();
+        store.set("t15", b"session".to_vec(), 3.into()).unwrap();
+        store.set("t15", b"user:1".to_vec(), 4.into()).unwrap();
+        store.set("t16", b"session:1".to_vec(), 5.into()).unwrap();
+
+        assert_eq!(store.del_by_prefix("t15", b"session:").unwrap(), 2);
+        let mut pairs = store.get_all("t15").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(pairs, vec![KvPair::new("session", 3.into()), KvPair::new("user:1", 4.into())]);
+        // other tables are not touched
+        assert_eq!(store.get("t16", b"session:1").unwrap(), Some(5.into()));
+
+        assert_eq!(store.del_by_prefix("t15", b"session:").unwrap(), 0);
+        assert_eq!(store.del_by_prefix("not_exist", b"").unwrap(), 0);
+        // an empty prefix removes the whole table
+        assert_eq!(store.del_by_prefix("t15", b"").unwrap(), 2);
+        assert!(store.get_all("t15").unwrap().is_empty());
+    }
+
+    fn test_rename_table(store: impl Storage) {
+        store.set("scores_v2", b"k1".to_vec(), 1.into()).unwrap();
+        store.set("scores_v2", b"k2".to_vec(), 2.into()).unwrap();
+        store.set("scores", b"k1".to_vec(), 10.into()).unwrap();
+        store.set("scores", b"old".to_vec(), 30.into()).unwrap();
+        // a table whose name starts with the renamed one is not touched
+        store.set("scores_v2_backup", b"k1".to_vec(), 100.into()).unwrap();
+
+        // the old data of `scores` is replaced
+        store.rename_table("scores_v2", "scores").unwrap();
+        let mut pairs = store.get_all("scores").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(pairs, vec![KvPair::new("k1", 1.into()), KvPair::new("k2", 2.into())]);
+        assert!(store.get_all("scores_v2").unwrap().is_empty());
+        assert_eq!(store.get("scores_v2_backup", b"k1").unwrap(), Some(100.into()));
+
+        // renaming to itself changes nothing
+        store.rename_table("scores", "scores").unwrap();
+        assert_eq!(store.get_all("scores").unwrap().len(), 2);
+
+        // renaming a table which doesn't exist fails and keeps the target
+        assert!(matches!(store.rename_table("not_exist", "scores"), Err(KvError::NotFound(t, _)) if t == "not_exist"));
+        assert!(matches!(store.rename_table("not_exist", "not_exist"), Err(KvError::NotFound(..))));
+        assert_eq!(store.get_all("scores").unwrap().len(), 2);
+    }
+
+    fn test_swap(store: impl Storage) {
+        store.set("t17", b"k1".to_vec(), "v1".into()).unwrap();
+        store.set("t17", b"k2".to_vec(), "v2".into()).unwrap();
+
+        assert_eq!(store.swap("t17", b"k1", b"k2").unwrap(), ("v1".into(), "v2".into()));
+        assert_eq!(store.get("t17", b"k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t17", b"k2").unwrap(), Some("v1".into()));
+
+        // nothing changes if a key doesn't exist
+        assert!(matches!(store.swap("t17", b"k1", b"k3"), Err(KvError::NotFound(_, k)) if k == "k3"));
+        assert!(matches!(store.swap("t17", b"k3", b"k1"), Err(KvError::NotFound(_, k)) if k == "k3"));
+        assert_eq!(store.get("t17", b"k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t17", b"k3").unwrap(), None);
+    }
+
+    fn test_clear(store: impl Storage) {
+        store.set("t6", "k1".into(), "v1".into()).unwrap();
+        store.set("t6", "k2".into(), "v2".into()).unwrap();
+        store.set("t7", "k1".into(), "v1".into()).unwrap();
+
+        assert_eq!(store.clear().unwrap(), 3);
+        assert!(store.get_all("t6").unwrap().is_empty());
+        assert!(store.get_all("t7").unwrap().is_empty());
+        assert_eq!(store.clear().unwrap(), 0);
+    }
+
+    fn test_get_matched(store: impl Storage) {
+        store.set("t8", "user:1:active".into(), 1.into()).unwrap();
+        store.set("t8", "user:2:inactive".into(), 2.into()).unwrap();
+        store.set("t8", "user:3:active".into(), 3.into()).unwrap();
+        store.set("t8", "admin:4:active".into(), 4.into()).unwrap();
+        store.set("t9", "user:5:active".into(), 5.into()).unwrap();
+
+        let mut pairs = store.get_matched("t8", "user:*:active").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            pairs,
+            vec![
+                KvPair::new("user:1:active", 1.into()),
+                KvPair::new("user:3:active", 3.into()),
+            ]
+        );
+
+        let mut pairs = store.get_matched("t8", "*:?:active").unwrap();
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let keys: Vec<_> = pairs.into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec!["admin:4:active", "user:1:active", "user:3:active"]);
+    }
+
+    fn test_iter_all(store: impl Storage) {
+        store.set("t10", "k1".into(), "v1".into()).unwrap();
+        store.set("t10", "k2".into(), "v2".into()).unwrap();
+        store.set("t11", "k1".into(), 1.into()).unwrap();
+        store.set("t12", "user:1".into(), true.into()).unwrap();
+
+        let mut items = store.iter_all().unwrap().collect::<Vec<_>>();
+        items.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        assert_eq!(
+            items,
+            vec![
+                ("t10".into(), KvPair::new("k1", "v1".into())),
+                ("t10".into(), KvPair::new("k2", "v2".into())),
+                ("t11".into(), KvPair::new("k1", 1.into())),
+                ("t12".into(), KvPair::new("user:1", true.into())),
+            ]
+        );
+    }
+
+    fn test_binary_keys(store: impl Storage) {
+        // keys may contain any byte, including the ones which are not utf8, `:` or the table name
+        let keys: [&[u8]; 4] = [b"\x00\xff", b"\x00", b"t13:\xfe", b""];
+        for (i, key) in keys.iter().enumerate() {
+            store.set("t13", key.to_vec(), (i as i64).into()).unwrap();
+        }
+        store.set("t1", b"3:\xfe".to_vec(), "other table".into()).unwrap();
+
+        assert_eq!(store.get("t13", b"\x00\xff").unwrap(), Some(0.into()));
+        assert_eq!(store.get("t13", b"").unwrap(), Some(3.into()));
+        assert!(!store.contains("t13", b"3:\xfe").unwrap());
+        assert_eq!(store.get_all("t13").unwrap().len(), 4);
+
+        // the keys are sorted by bytes
+        let keys: Vec<_> = store.get_range("t13", b"", b"").unwrap().into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec![&b""[..], b"\x00", b"\x00\xff", b"t13:\xfe"]);
+        let keys: Vec<_> = store.get_range("t13", b"\x00", b"t").unwrap().into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec![&b"\x00"[..], b"\x00\xff"]);
+
+        assert_eq!(store.del("t13", b"\x00").unwrap(), Some(1.into()));
+        assert_eq!(store.get("t13", b"\x00").unwrap(), None);
+        assert_eq!(store.get("t13", b"\x00\xff").unwrap(), Some(0.into()));
+    }
+
+    fn test_value_size(store: impl Storage) {
+        // a big string needs a multi-byte varint for its length
+        let long = "a".repeat(300);
+        let values: Vec<Value> = vec![
+            long.as_str().into(),
+            "".into(),
+            Bytes::from(vec![0u8; 1024]).into(),
+            42.into(),
+            vec![Value::from(1), Value::from("list")].into(),
+        ];
+        for (i, value) in values.iter().enumerate() {
+            store.set("t14", vec![i as u8], value.clone()).unwrap();
+        }
+
+        let sizes: Vec<_> = (0..values.len())
+            .map(|i| store.value_size("t14", &[i as u8]).unwrap().unwrap())
+            .collect();
+        assert_eq!(sizes, values.iter().map(|v| v.size()).collect::<Vec<_>>());
+        assert_eq!(&sizes[..3], &[300, 0, 1024]);
+        assert_eq!(store.value_size("t14", b"missing").unwrap(), None);
     }
 
     fn test_get_all(store: impl Storage) {
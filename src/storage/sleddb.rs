@@ -1,82 +1,636 @@
-use std::{path::Path, str};
-use sled::{Db, Error, IVec};
-use crate::{KvError, KvPair, Storage, StorageIter, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{fmt, path::Path, str, sync::{Arc, Mutex}};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use sled::transaction::{abort, TransactionError, TransactionResult, Transactional};
+use sled::{Batch, Db, IVec};
+use tracing::{info, warn};
+use crate::{KvError, KvPair, Storage, TableStats, Value};
+use crate::storage::codec::{ProtobufCodec, ValueCodec};
+use crate::storage::{
+    add_float, expired, glob_match, glob_prefix, key_not_found, lpush_values, sadd_members, srem_members,
+    table_not_found,
+};
 
-#[derive(Debug)]
-pub struct SledDb(Db);
+// the nonce is saved in front of the encrypted value
+const NONCE_LEN: usize = 12;
+// the length of the table name is saved in front of the full key
+const TABLE_LEN_SIZE: usize = 4;
+// the version of the format on disk, it's saved in the meta tree when the db is opened
+// 1: the full keys are | table len | table | key |, an encrypted value is bound to its full key.
+// a db without a version is from before, its `table:key` keys are migrated when it's opened
+const FORMAT_VERSION: u32 = 1;
+const META_TREE: &str = "__meta__";
+const FORMAT_VERSION_KEY: &[u8] = b"format_version";
+
+pub struct SledDb {
+    db: Db,
+    // if set, the values are encrypted on disk, the keys are kept in plaintext so we can still scan them
+    // the full key is the associated data of its value, so a value copied to another key can't be decrypted
+    cipher: Option<Aes256Gcm>,
+    // how the values are encoded before they're encrypted, protobuf by default
+    codec: Arc<dyn ValueCodec>,
+    // held while init_if_empty checks and writes a table
+    init_lock: Mutex<()>,
+    // the entries get_iter and iter_all skipped since their value couldn't be read
+    skipped: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for SledDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SledDb")
+            .field("db", &self.db)
+            .field("encrypted", &self.cipher.is_some())
+            .finish()
+    }
+}
+
+// flush the pending writes on a graceful shutdown
+// sled flushes by itself only when the last handle of the Db is dropped, the Db may still be shared after that
+// a crash can still lose the writes since the last flush, sled recovers to its last consistent state then
+impl Drop for SledDb {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.flush() {
+            warn!("Failed to flush sled db on drop: {:?}", e);
+        }
+    }
+}
+
+// check the format of the db, migrate it if it's written by an older version
+impl TryFrom<Db> for SledDb {
+    type Error = KvError;
+
+    fn try_from(db: Db) -> Result<Self, Self::Error> {
+        check_format(&db)?;
+        Ok(Self {
+            db,
+            cipher: None,
+            codec: Arc::new(ProtobufCodec),
+            init_lock: Mutex::new(()),
+            skipped: Default::default(),
+        })
+    }
+}
 
 impl SledDb {
+    // panic if the db can't be opened, use try_new to get the error
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self::try_new(path).unwrap()
+    }
+
+    // open the db, fail if it's written in a format this version doesn't know
+    pub fn try_new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        sled::open(path)?.try_into()
+    }
+
+    // encrypt the values with AES-256-GCM, the same key must be used every time the db is opened
+    pub fn with_encryption_key(mut self, key: &[u8; 32]) -> Self {
+        self.cipher = Some(Aes256Gcm::new(key.into()));
+        self
+    }
+
+    // save the values with another codec, e.g. BincodeCodec, the same codec must be used every time the db is opened
+    // it only changes the values on disk, see ValueCodec for how to migrate an existing db
+    pub fn with_codec(mut self, codec: impl ValueCodec) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    pub(crate) fn db(&self) -> &Db {
+        &self.db
+    }
+
+    // how many entries get_iter and iter_all skipped since their value couldn't be decrypted or decoded,
+    // an iterator can't return the error. the other scans fail instead
+    pub fn skipped_entries(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    // encode the value of the full key, and encrypt it if encryption is enabled
+    pub(crate) fn encode_value(&self, key: &[u8], value: Value) -> Result<Vec<u8>, KvError> {
+        let data = self.codec.encode(value)?;
+        match &self.cipher {
+            Some(cipher) => encrypt(cipher, key, &data),
+            None => Ok(data),
+        }
+    }
+
+    pub(crate) fn decode_value(&self, key: &[u8], data: &[u8]) -> Result<Value, KvError> {
+        decode_value(self.cipher.as_ref(), self.codec.as_ref(), key, data)
+    }
+
+    // the data of a value moved from one full key to another, an encrypted value is encrypted again for the new key
+    fn move_value(&self, data: &[u8], from: &[u8], to: &[u8]) -> Result<Vec<u8>, KvError> {
+        match &self.cipher {
+            Some(cipher) => encrypt(cipher, to, &decrypt(cipher, from, data)?),
+            None => Ok(data.to_vec()),
+        }
     }
 
     // since sled can scan_prefix, so we can use `prefix` to simulate `table`
-    pub fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    // the key is binary and may contain any byte, so the table is length-prefixed instead of split by a separator:
+    // | table len (u32, big endian) | table | key |, a db in the older layout is migrated, see FORMAT_VERSION
+    pub fn get_full_key(table: &str, key: &[u8]) -> Vec<u8> {
+        let mut full_key = Vec::with_capacity(TABLE_LEN_SIZE + table.len() + key.len());
+        full_key.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        full_key.extend_from_slice(table.as_bytes());
+        full_key.extend_from_slice(key);
+        full_key
     }
 }
 
+// the nonce is saved in front of the ciphertext
+fn encrypt(cipher: &Aes256Gcm, key: &[u8], data: &[u8]) -> Result<Vec<u8>, KvError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted = cipher.encrypt(&nonce, Payload { msg: data, aad: key }).map_err(|_| KvError::CryptoError)?;
+    Ok(nonce.into_iter().chain(encrypted).collect())
+}
+
+fn decrypt(cipher: &Aes256Gcm, key: &[u8], data: &[u8]) -> Result<Vec<u8>, KvError> {
+    if data.len() < NONCE_LEN {
+        return Err(KvError::CryptoError);
+    }
+    let (nonce, encrypted) = data.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: encrypted, aad: key })
+        .map_err(|_| KvError::CryptoError)
+}
+
+fn decode_value(
+    cipher: Option<&Aes256Gcm>,
+    codec: &dyn ValueCodec,
+    key: &[u8],
+    data: &[u8],
+) -> Result<Value, KvError> {
+    match cipher {
+        Some(cipher) => codec.decode(&decrypt(cipher, key, data)?),
+        None => codec.decode(data),
+    }
+}
+
+// convert a sled item to a KvPair, see decode_item
+fn decode_pair(
+    cipher: Option<&Aes256Gcm>,
+    codec: &dyn ValueCodec,
+    item: Result<(IVec, IVec), sled::Error>,
+) -> Result<Option<KvPair>, KvError> {
+    Ok(decode_item(cipher, codec, item)?.map(|(_, pair)| pair))
+}
+
+// convert a sled item to the table name and the KvPair
+// a key not written by SledDb is skipped (None), a value which can't be decrypted or decoded is an error
+fn decode_item(
+    cipher: Option<&Aes256Gcm>,
+    codec: &dyn ValueCodec,
+    item: Result<(IVec, IVec), sled::Error>,
+) -> Result<Option<(String, KvPair)>, KvError> {
+    let (full_key, value) = item?;
+    let (table, key) = match split_full_key(full_key.as_ref()) {
+        Ok(split) => split,
+        Err(e) => {
+            warn!("Skip a malformed key: {:?}", e);
+            return Ok(None);
+        }
+    };
+    let value = decode_value(cipher, codec, full_key.as_ref(), value.as_ref())?;
+    Ok(Some((table.to_string(), KvPair::new(key.to_vec(), value))))
+}
+
+// the items of get_iter and iter_all, an item which can't be read is logged and counted, then skipped
+fn skip_broken<T>(result: Result<Option<T>, KvError>, skipped: &AtomicU64) -> Option<T> {
+    result.unwrap_or_else(|e| {
+        warn!("Skip an entry which can't be read: {:?}", e);
+        skipped.fetch_add(1, Ordering::Relaxed);
+        None
+    })
+}
+
 fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
     x.map_or(Ok(None), |x| x.map(Some))
 }
 
 impl Storage for SledDb {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn get(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let key = SledDb::get_full_key(table, key);
-        let result = self.0.get(key.as_bytes())?.map(|v| v.as_ref().try_into());
+        let result = self.db.get(&key)?.map(|v| self.decode_value(&key, v.as_ref()));
         flip(result)
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+    fn set(&self, table: &str, key: Vec<u8>, value: Value) -> Result<Option<Value>, KvError> {
         let key = SledDb::get_full_key(table, &key);
-        let data: Vec<u8> = value.try_into()?;
-        let result = self.0.insert(key.as_bytes(), data)?.map(|v| v.as_ref().try_into());
+        let data = self.encode_value(&key, value)?;
+        let result = self.db.insert(&key, data)?.map(|v| self.decode_value(&key, v.as_ref()));
         flip(result)
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn set_if_absent(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let key = SledDb::get_full_key(table, &key);
+        let data = self.encode_value(&key, value)?;
+        let result = self.db.compare_and_swap(&key, None as Option<&[u8]>, Some(data))?;
+        Ok(result.is_ok())
+    }
+
+    fn get_or_set_with(&self, table: &str, key: Vec<u8>, f: impl FnOnce() -> Value) -> Result<Value, KvError> {
+        let key = SledDb::get_full_key(table, &key);
+        let mut f = Some(f);
+        let mut value = None;
+        // retry until the value is read or set, the losers of a race get the winner's value
+        loop {
+            if let Some(old) = self.db.get(&key)? {
+                return self.decode_value(&key, old.as_ref());
+            }
+            let new = value.get_or_insert_with(|| (f.take().unwrap())()).clone();
+            let data = self.encode_value(&key, new.clone())?;
+            if self.db.compare_and_swap(&key, None as Option<&[u8]>, Some(data))?.is_ok() {
+                return Ok(new);
+            }
+        }
+    }
+
+    fn set_if_changed(&self, table: &str, key: Vec<u8>, value: Value) -> Result<bool, KvError> {
+        let key = SledDb::get_full_key(table, &key);
+        // compare the decoded values, an encrypted value is different on disk every time it's written
+        loop {
+            let old = self.db.get(&key)?;
+            let old_value = flip(old.as_ref().map(|v| self.decode_value(&key, v.as_ref())))?;
+            if old_value.as_ref() == Some(&value) {
+                return Ok(false);
+            }
+            let data = self.encode_value(&key, value.clone())?;
+            if self.db.compare_and_swap(&key, old, Some(data))?.is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn init_if_empty(&self, table: &str, pairs: Vec<KvPair>) -> Result<bool, KvError> {
+        // the last value of a key given twice wins, like setting the pairs one by one
+        let mut entries = BTreeMap::new();
+        for pair in pairs {
+            let key = SledDb::get_full_key(table, &pair.key);
+            let data = self.encode_value(&key, pair.value.unwrap_or_default())?;
+            entries.insert(key, data);
+        }
+        // sled can't lock a range of keys, serialize the inits so only one of them sees the empty table
+        let _guard = self.init_lock.lock().unwrap();
+        if self.db.scan_prefix(SledDb::get_full_key(table, b"")).next().transpose()?.is_some() {
+            return Ok(false);
+        }
+        let result: Result<_, TransactionError<KvError>> = self.db.transaction(|tx| {
+            // a key written after the scan makes the table not empty anymore
+            for key in entries.keys() {
+                if tx.get(key.as_slice())?.is_some() {
+                    return Ok(false);
+                }
+            }
+            for (key, data) in &entries {
+                tx.insert(key.as_slice(), data.as_slice())?;
+            }
+            Ok(true)
+        });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+    }
+
+    fn lpush(&self, table: &str, key: Vec<u8>, values: Vec<Value>) -> Result<usize, KvError> {
+        let key = SledDb::get_full_key(table, &key);
+        // retry until no one else changed the value between our read and write
+        loop {
+            let old = self.db.get(&key)?;
+            let old_value = flip(old.as_ref().map(|v| self.decode_value(&key, v.as_ref())))?;
+            let list = lpush_values(old_value, values.clone())?;
+            let len = list.len();
+            let data = self.encode_value(&key, list.into())?;
+            if self.db.compare_and_swap(&key, old, Some(data))?.is_ok() {
+                return Ok(len);
+            }
+        }
+    }
+
+    fn sadd(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let key = SledDb::get_full_key(table, &key);
+        // retry until no one else changed the value between our read and write
+        loop {
+            let old = self.db.get(&key)?;
+            let old_value = flip(old.as_ref().map(|v| self.decode_value(&key, v.as_ref())))?;
+            let (set, added) = sadd_members(old_value, members.clone())?;
+            let data = self.encode_value(&key, set.into())?;
+            if self.db.compare_and_swap(&key, old, Some(data))?.is_ok() {
+                return Ok(added);
+            }
+        }
+    }
+
+    fn incr_float(&self, table: &str, key: Vec<u8>, delta: f64) -> Result<f64, KvError> {
+        let key = SledDb::get_full_key(table, &key);
+        // retry until no one else changed the value between our read and write
+        loop {
+            let old = self.db.get(&key)?;
+            let old_value = flip(old.as_ref().map(|v| self.decode_value(&key, v.as_ref())))?;
+            let new = add_float(old_value, delta)?;
+            let data = self.encode_value(&key, new.into())?;
+            if self.db.compare_and_swap(&key, old, Some(data))?.is_ok() {
+                return Ok(new);
+            }
+        }
+    }
+
+    fn get_and_reset(&self, table: &str, key: &[u8]) -> Result<i64, KvError> {
+        let full_key = SledDb::get_full_key(table, key);
+        let reset = self.encode_value(&full_key, 0.into())?;
+        // retry until no one else changed the value between our read and write
+        loop {
+            let old = self.db.get(&full_key)?.ok_or_else(|| key_not_found(table, key))?;
+            let old_value = i64::try_from(&self.decode_value(&full_key, old.as_ref())?)?;
+            if self.db.compare_and_swap(&full_key, Some(old), Some(reset.clone()))?.is_ok() {
+                return Ok(old_value);
+            }
+        }
+    }
+
+    fn srem(&self, table: &str, key: Vec<u8>, members: Vec<Value>) -> Result<usize, KvError> {
+        let key = SledDb::get_full_key(table, &key);
+        loop {
+            let old = self.db.get(&key)?;
+            let old_value = flip(old.as_ref().map(|v| self.decode_value(&key, v.as_ref())))?;
+            let (set, removed) = match srem_members(old_value, &members)? {
+                Some(result) => result,
+                None => return Ok(0),
+            };
+            let data = self.encode_value(&key, set.into())?;
+            if self.db.compare_and_swap(&key, old, Some(data))?.is_ok() {
+                return Ok(removed);
+            }
+        }
+    }
+
+    fn contains(&self, table: &str, key: &[u8]) -> Result<bool, KvError> {
         let key = SledDb::get_full_key(table, key);
-        let result = self.0.contains_key(key.as_bytes())?;
+        let result = self.db.contains_key(&key)?;
         Ok(result)
     }
 
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+    fn del(&self, table: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
         let key = SledDb::get_full_key(table, key);
-        let result = self.0.remove(key.as_bytes())?.map(|v| v.as_ref().try_into());
+        let result = self.db.remove(&key)?.map(|v| self.decode_value(&key, v.as_ref()));
         flip(result)
     }
 
+    fn swap(&self, table: &str, key1: &[u8], key2: &[u8]) -> Result<(Value, Value), KvError> {
+        let (full_key1, full_key2) = (SledDb::get_full_key(table, key1), SledDb::get_full_key(table, key2));
+        let result = self.db.transaction(|tx| {
+            let v1 = tx.get(&full_key1)?;
+            let v2 = tx.get(&full_key2)?;
+            match (v1, v2) {
+                (Some(v1), Some(v2)) => {
+                    // an encrypted value is bound to its key, it's encrypted again for the other key
+                    let moved = self.move_value(&v2, &full_key2, &full_key1).and_then(|data2| {
+                        Ok((data2, self.move_value(&v1, &full_key1, &full_key2)?))
+                    });
+                    let (data2, data1) = match moved {
+                        Ok(moved) => moved,
+                        Err(e) => return abort(e),
+                    };
+                    tx.insert(full_key1.as_slice(), data2)?;
+                    tx.insert(full_key2.as_slice(), data1)?;
+                    Ok((v1, v2))
+                }
+                (None, _) => abort(key_not_found(table, key1)),
+                (_, None) => abort(key_not_found(table, key2)),
+            }
+        });
+        let (v1, v2) = result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+        Ok((self.decode_value(&full_key1, v1.as_ref())?, self.decode_value(&full_key2, v2.as_ref())?))
+    }
+
+    fn move_key(&self, from: &str, to: &str, key: &[u8]) -> Result<Option<Value>, KvError> {
+        if from == to {
+            return self.get(from, key);
+        }
+        let (src, dst) = (SledDb::get_full_key(from, key), SledDb::get_full_key(to, key));
+        let result: Result<_, TransactionError<KvError>> = self.db.transaction(|tx| {
+            let value = tx.remove(src.as_slice())?;
+            if let Some(value) = &value {
+                // an encrypted value is bound to its key, it's encrypted again for the new key
+                match self.move_value(value, &src, &dst) {
+                    Ok(data) => tx.insert(dst.as_slice(), data)?,
+                    Err(e) => return abort(e),
+                };
+            }
+            Ok(value)
+        });
+        let value = result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+        value.map(|v| self.decode_value(&src, v.as_ref())).transpose()
+    }
+
+    fn del_by_prefix(&self, table: &str, prefix: &[u8]) -> Result<u64, KvError> {
+        let prefix = SledDb::get_full_key(table, prefix);
+        let mut batch = Batch::default();
+        let mut count = 0;
+        for item in self.db.scan_prefix(&prefix).keys() {
+            batch.remove(item?);
+            count += 1;
+        }
+        self.db.apply_batch(batch)?;
+        Ok(count)
+    }
+
+    fn expire_before(&self, table: &str, cutoff: i64) -> Result<u64, KvError> {
+        let prefix = SledDb::get_full_key(table, b"");
+        let mut count = 0;
+        for item in self.db.scan_prefix(&prefix) {
+            let (key, data) = item?;
+            if !expired(&self.decode_value(&key, &data)?, cutoff) {
+                continue;
+            }
+            // only remove the value we've checked, it may be changed after the scan
+            if self.db.compare_and_swap(&key, Some(&data), None as Option<&[u8]>)?.is_ok() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>, KvError> {
-        let prefix = SledDb::get_full_key(table, "");
-        let iter = self.0.scan_prefix(prefix.as_bytes());
-        let result = iter
-            .map(|item| {
-                item.into()
-            })
-            .collect();
-        Ok(result)
+        let prefix = SledDb::get_full_key(table, b"");
+        let iter = self.db.scan_prefix(&prefix);
+        iter.filter_map(|item| decode_pair(self.cipher.as_ref(), self.codec.as_ref(), item).transpose()).collect()
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item=KvPair>>, KvError> {
-        let prefix = SledDb::get_full_key(table, "");
-        let iter = self.0.scan_prefix(prefix.as_bytes());
-        Ok(Box::new(StorageIter::new(iter)))
+        let prefix = SledDb::get_full_key(table, b"");
+        let (cipher, codec, skipped) = (self.cipher.clone(), Arc::clone(&self.codec), Arc::clone(&self.skipped));
+        let iter = self
+            .db
+            .scan_prefix(&prefix)
+            .filter_map(move |item| skip_broken(decode_pair(cipher.as_ref(), codec.as_ref(), item), &skipped));
+        Ok(Box::new(iter))
+    }
+
+    fn iter_all(&self) -> Result<Box<dyn Iterator<Item=(String, KvPair)>>, KvError> {
+        // all tables are in the same tree, split the full key to get the table
+        let (cipher, codec, skipped) = (self.cipher.clone(), Arc::clone(&self.codec), Arc::clone(&self.skipped));
+        let iter = self
+            .db
+            .iter()
+            .filter_map(move |item| skip_broken(decode_item(cipher.as_ref(), codec.as_ref(), item), &skipped));
+        Ok(Box::new(iter))
+    }
+
+    fn get_matched(&self, table: &str, pattern: &str) -> Result<Vec<KvPair>, KvError> {
+        // only scan the keys starting with the literal prefix of the pattern
+        let prefix = SledDb::get_full_key(table, glob_prefix(pattern).as_bytes());
+        self.db
+            .scan_prefix(&prefix)
+            .filter_map(|item| decode_pair(self.cipher.as_ref(), self.codec.as_ref(), item).transpose())
+            .filter(|pair| pair.as_ref().map_or(true, |pair| glob_match(pattern, &pair.key)))
+            .collect()
+    }
+
+    fn get_range(&self, table: &str, start: &[u8], end: &[u8]) -> Result<Vec<KvPair>, KvError> {
+        if !end.is_empty() && start >= end {
+            return Ok(vec![]);
+        }
+        // sled keeps keys sorted, full_key(start)..full_key(end) is the range within the table
+        let prefix = SledDb::get_full_key(table, b"");
+        let start = SledDb::get_full_key(table, start);
+        let iter = match end {
+            [] => self.db.range(start..),
+            end => self.db.range(start..SledDb::get_full_key(table, end)),
+        };
+        iter.take_while(|item| match item {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })
+        .filter_map(|item| decode_pair(self.cipher.as_ref(), self.codec.as_ref(), item).transpose())
+        .collect()
+    }
+
+    fn value_size(&self, table: &str, key: &[u8]) -> Result<Option<usize>, KvError> {
+        let key = SledDb::get_full_key(table, key);
+        let result = self.db.get(&key)?.map(|v| match &self.cipher {
+            // the size can't be read from the ciphertext, decrypt it
+            Some(_) => self.decode_value(&key, v.as_ref()).map(|value| value.size()),
+            None => self.codec.value_size(v.as_ref()),
+        });
+        flip(result)
+    }
+
+    fn rename_table(&self, from: &str, to: &str) -> Result<(), KvError> {
+        let from_prefix = SledDb::get_full_key(from, b"");
+        if self.db.scan_prefix(&from_prefix).next().transpose()?.is_none() {
+            return Err(table_not_found(from));
+        }
+        if from == to {
+            return Ok(());
+        }
+        // re-key all entries in one batch, so the rename is atomic on disk
+        let mut batch = Batch::default();
+        for key in self.db.scan_prefix(SledDb::get_full_key(to, b"")).keys() {
+            batch.remove(key?);
+        }
+        for item in self.db.scan_prefix(&from_prefix) {
+            let (key, value) = item?;
+            let new_key = SledDb::get_full_key(to, &key[from_prefix.len()..]);
+            batch.insert(new_key.as_slice(), self.move_value(&value, &key, &new_key)?);
+            batch.remove(key);
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<u64, KvError> {
+        let keys = self.db.len() as u64;
+        self.db.clear()?;
+        Ok(keys)
+    }
+
+    fn table_stats(&self, table: &str) -> Result<TableStats, KvError> {
+        let prefix = SledDb::get_full_key(table, b"");
+        let mut stats = TableStats::default();
+        for item in self.db.scan_prefix(&prefix) {
+            let (key, value) = item?;
+            stats.keys += 1;
+            // the value is stored encoded, only count the key without the table prefix
+            stats.bytes += (key.len() - prefix.len() + value.len()) as u64;
+        }
+        Ok(stats)
     }
 }
 
-impl From<Result<(IVec, IVec), sled::Error>> for KvPair {
-    fn from(data: Result<(IVec, IVec), Error>) -> Self {
-        match data {
-            Ok((key, value)) => match value.as_ref().try_into() {
-                Ok(value) => KvPair::new(ivec_to_key(key.as_ref()), value),
-                Err(_) => KvPair::default(),
-            },
-            _ => KvPair::default(),
+// save the format version in a new db, or migrate a db without a version
+// the data written by a newer version with another format is not touched, the db can't be opened
+fn check_format(db: &Db) -> Result<(), KvError> {
+    let meta = db.open_tree(META_TREE)?;
+    match meta.get(FORMAT_VERSION_KEY)? {
+        Some(version) if version.as_ref() == FORMAT_VERSION.to_be_bytes() => return Ok(()),
+        Some(version) => {
+            let message = format!("format version {:?} of the sled db, expect {}", version.as_ref(), FORMAT_VERSION);
+            return Err(KvError::StorageFormat(message));
         }
+        None => {}
     }
+
+    // before the version, the full keys were `table:key` in utf8, the values were protobuf and never encrypted
+    let mut migrated = vec![];
+    for item in db.iter() {
+        let (old_key, value) = item?;
+        let (table, key) = split_legacy_key(&old_key).ok_or_else(|| {
+            KvError::StorageFormat(format!("key {:?} of the sled db has no format version", old_key.as_ref()))
+        })?;
+        migrated.push((SledDb::get_full_key(table, key), old_key, value));
+    }
+    // the keys and the version are written together, a crash can't leave a half migrated db
+    let result: TransactionResult<(), ()> = (&**db, &meta).transaction(|(data, meta)| {
+        for (new_key, old_key, value) in &migrated {
+            data.remove(old_key)?;
+            data.insert(new_key.as_slice(), value)?;
+        }
+        meta.insert(FORMAT_VERSION_KEY, &FORMAT_VERSION.to_be_bytes())?;
+        Ok(())
+    });
+    result.map_err(|e| match e {
+        TransactionError::Abort(()) => KvError::Internal("format migration aborted".into()),
+        TransactionError::Storage(e) => e.into(),
+    })?;
+    if !migrated.is_empty() {
+        info!("Migrated {} keys of the sled db to format version {}", migrated.len(), FORMAT_VERSION);
+    }
+    Ok(())
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let key = str::from_utf8(ivec).unwrap();
-    key.split(':').last().unwrap()
+// split a `table:key` key written before the format version, at the first `:`
+// a key may contain `:`, e.g. `users:alice:1` is the key `alice:1` of the table `users`,
+// a table name with `:` can't be told apart, its part after the `:` becomes a part of the key.
+// the keys were read the same way since Hgetall got its pattern, before that only the part after the last `:`
+// was returned, which cut the keys containing `:`
+fn split_legacy_key(full_key: &[u8]) -> Option<(&str, &[u8])> {
+    let full_key = str::from_utf8(full_key).ok()?;
+    full_key.split_once(':').map(|(table, key)| (table, key.as_bytes()))
+}
+
+// split a full key back to the table and the key
+// keys not written by us (e.g. too short, or the table is not utf8) can't be split
+fn split_full_key(ivec: &[u8]) -> Result<(&str, &[u8]), KvError> {
+    let malformed = || KvError::ConvertError(format!("{:?}", ivec), "key");
+    if ivec.len() < TABLE_LEN_SIZE {
+        return Err(malformed());
+    }
+    let (len, rest) = ivec.split_at(TABLE_LEN_SIZE);
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return Err(malformed());
+    }
+    let (table, key) = rest.split_at(len);
+    let table = str::from_utf8(table).map_err(|_| malformed())?;
+    Ok((table, key))
 }
\ No newline at end of file